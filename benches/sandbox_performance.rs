@@ -1,10 +1,9 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sos::config::ServerConfig;
 use sos::http::{SoSState, create_app};
-use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use bollard::Docker;
-use tokio::sync::{Mutex, Semaphore};
+use sos::runtime::RuntimeConfig;
 use serde_json::{json, Value};
 use tokio::time::Instant;
 
@@ -13,12 +12,11 @@ async fn benchmark_sandbox_throughput(
     semaphore_limit: usize,
 ) -> anyhow::Result<(Duration, usize)> {
     // Set up test server
-    let semaphore = Arc::new(Semaphore::new(semaphore_limit));
-    let state = Arc::new(SoSState {
-        docker: Arc::new(Docker::connect_with_local_defaults()?),
-        sandboxes: Arc::new(Mutex::new(HashMap::new())),
-        semaphore,
-    });
+    let config = ServerConfig {
+        max_sandboxes: semaphore_limit,
+        ..Default::default()
+    };
+    let state = Arc::new(SoSState::new(RuntimeConfig::default().connect().await?, config));
 
     let app = create_app(state);
 
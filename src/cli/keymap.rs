@@ -0,0 +1,175 @@
+//! Key bindings of the TUI overridden in the `[keys]` table of the configuration file.
+//!
+//! Each entry moves a built-in binding, named by its key, to another key:
+//!
+//! ```toml
+//! [keys]
+//! "x" = "X"            # stop with X only
+//! "ctrl-u" = "pageup"
+//! "f1" = "f2"
+//! ```
+//!
+//! The built-in key stops doing anything on the screens it was bound on. Outside of
+//! text entry, and for keys that do not type text inside it, the new key acts as the
+//! built-in one did.
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+/// A key and its modifiers. Letters keep their case, without the shift modifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    /// Parses `a`, `G`, `?`, `ctrl-u`, `alt-enter`, `backtab`, `f1`, `pageup`, ...
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = spec;
+        // A lone `-` is the key itself
+        while let Some((modifier, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => bail!("Unknown modifier {} in key {}", modifier, spec),
+            };
+            rest = key;
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" => KeyCode::Insert,
+                "space" => KeyCode::Char(' '),
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                name => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=24) => KeyCode::F(n),
+                    _ => bail!("Unknown key {}", spec),
+                },
+            },
+        };
+        Ok(Self::new(code, modifiers))
+    }
+
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let modifiers = match (code, modifiers.contains(KeyModifiers::CONTROL)) {
+            // Terminals send ctrl-letters in lowercase, whatever the shift
+            (KeyCode::Char(c), true) if c.is_ascii_alphabetic() => {
+                return Self {
+                    code: KeyCode::Char(c.to_ascii_lowercase()),
+                    modifiers: modifiers - KeyModifiers::SHIFT,
+                };
+            }
+            (KeyCode::Char(_), _) => modifiers - KeyModifiers::SHIFT,
+            _ => modifiers,
+        };
+        Self { code, modifiers }
+    }
+
+    /// Whether the key types a character into text entries.
+    fn types_text(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_)) && !self.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    }
+
+    /// The key as the terminal sends it, uppercase letters with the shift modifier.
+    fn event(&self, kind: KeyEventKind) -> KeyEvent {
+        let mut modifiers = self.modifiers;
+        if matches!(self.code, KeyCode::Char(c) if c.is_uppercase()) {
+            modifiers |= KeyModifiers::SHIFT;
+        }
+        KeyEvent::new_with_kind(self.code, modifiers, kind)
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(event: KeyEvent) -> Self {
+        Self::new(event.code, event.modifiers)
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (modifier, name) in [(KeyModifiers::CONTROL, "Ctrl-"), (KeyModifiers::ALT, "Alt-"), (KeyModifiers::SHIFT, "Shift-")] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) if self.modifiers.contains(KeyModifiers::CONTROL) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Up => f.write_str("↑"),
+            KeyCode::Down => f.write_str("↓"),
+            KeyCode::Left => f.write_str("←"),
+            KeyCode::Right => f.write_str("→"),
+            KeyCode::PageUp => f.write_str("PgUp"),
+            KeyCode::PageDown => f.write_str("PgDn"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Built-in keys moved to other keys.
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    /// Built-in key by the key replacing it
+    pressed: HashMap<Key, Key>,
+    /// Key replacing each built-in key
+    bound: HashMap<Key, Key>,
+}
+
+impl Keymap {
+    /// Reads the `[keys]` table, built-in key to the key replacing it.
+    pub fn new(overrides: &HashMap<String, String>) -> Result<Self> {
+        let mut keymap = Self::default();
+        for (builtin, key) in overrides {
+            let builtin = Key::parse(builtin).context("Invalid [keys] entry")?;
+            let key = Key::parse(key).with_context(|| format!("Invalid key bound to {}", builtin))?;
+            if let Some(other) = keymap.pressed.insert(key, builtin) {
+                bail!("Key {} is bound to both {} and {}", key, other, builtin);
+            }
+            keymap.bound.insert(builtin, key);
+        }
+        Ok(keymap)
+    }
+
+    /// The built-in key `event` stands for, `None` when it was moved to another key.
+    /// While `typing`, the keys typing text are left alone.
+    pub fn translate(&self, event: KeyEvent, typing: bool) -> Option<KeyEvent> {
+        let key = Key::from(event);
+        if typing && key.types_text() {
+            return Some(event);
+        }
+        match (self.pressed.get(&key), self.bound.contains_key(&key)) {
+            (Some(builtin), _) => Some(builtin.event(event.kind)),
+            (None, true) => None,
+            (None, false) => Some(event),
+        }
+    }
+
+    /// Name of the key bound to the built-in key `spec`, for the help.
+    pub fn display(&self, spec: &str) -> String {
+        match Key::parse(spec) {
+            Ok(builtin) => self.bound.get(&builtin).unwrap_or(&builtin).to_string(),
+            Err(_) => spec.to_string(),
+        }
+    }
+}
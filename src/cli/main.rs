@@ -1,28 +1,75 @@
-use std::collections::HashMap;
 use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
-use bollard::Docker;
-use clap::{Parser, Subcommand};
-use sos::http::{CreatePayload, ExecPayload, SoSState, StopPayload};
-use sos::sandbox::SandboxStatus;
-use tokio::sync::{Mutex, Semaphore};
+use clap::{Parser, Subcommand, ValueEnum};
+use rustyline::{Editor, error::ReadlineError, history::DefaultHistory};
+use sos::config::ServerConfig;
+use serde::Serialize;
+use sos::api::{CreatePayload, CreateResponse, ExecPayload, LogSource, ServerEventKind};
+use sos::client::{ClientError, SosClient};
+use sos::http::SoSState;
+use sos::daemon::PidFile;
+use sos::listen::BindAddress;
+use sos::runtime::Runtime;
+use sos::sandbox::{ResourceUsage, Shell, TerminalSize};
+use sos::tls::TlsConfig;
 use tracing::{info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod keymap;
+mod profile;
+mod session;
+mod task;
+mod theme;
 mod tui;
 
+use keymap::Keymap;
+use profile::{ClientConfig, Profile};
+use session::SessionHelper;
+use theme::Theme;
+
 #[derive(Parser)]
 #[command(name = "sos")]
 #[command(about = "A CLI for managing sandboxed containers for shell agents")]
 #[command(version)]
 struct Cli {
+    /// API key sent to the server by the client commands
+    #[arg(long, global = true, env = "SOS_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Output of the client commands: text for people, or JSON alone for scripts
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    /// Same as `--output json`
+    #[arg(long, global = true)]
+    json: bool,
+    /// Profile of ~/.config/sos/config.toml the client commands use
+    #[arg(long, global = true, env = "SOS_PROFILE")]
+    profile: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// State `sos sandbox wait` waits for.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WaitState {
+    Started,
+    /// The session shell exited
+    Exited,
+    /// Stopped or removed
+    Stopped,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start the sandbox server
@@ -30,38 +77,152 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value = "3000")]
         port: u16,
-        /// Maximum number of concurrent sandboxes
-        #[arg(short, long, default_value = "10")]
-        max_sandboxes: usize,
+        /// Interface to listen on (127.0.0.1 by default, 0.0.0.0 for all), or a Unix
+        /// socket as unix:///run/sos.sock
+        #[arg(short, long)]
+        bind: Option<BindAddress>,
+        /// Octal permissions of the Unix socket, e.g. 660
+        #[arg(long, value_parser = parse_socket_mode)]
+        socket_mode: Option<u32>,
+        /// File to write the pid of the server to while it runs
+        #[arg(long)]
+        pid_file: Option<PathBuf>,
+        /// Maximum number of concurrent sandboxes. Default is 10.
+        #[arg(short, long)]
+        max_sandboxes: Option<usize>,
         /// Sandbox timeout in seconds. Default is 10 minutes.
         #[arg(long, default_value = "600")]
         timeout: u64,
+        /// Path to the server configuration file (TOML)
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// PEM certificate chain to serve HTTPS with
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key of the TLS certificate
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// PEM file with the CAs that sign client certificates (enables mTLS)
+        #[arg(long, requires = "tls_cert")]
+        tls_client_ca: Option<PathBuf>,
+        /// Reject clients without a valid certificate
+        #[arg(long, requires = "tls_client_ca")]
+        require_client_cert: bool,
+        /// Directory to persist trajectories to
+        #[arg(long)]
+        trajectory_dir: Option<PathBuf>,
+        /// Container runtime to run sandboxes on (docker, podman, containerd or local)
+        #[arg(long)]
+        runtime: Option<Runtime>,
+        /// Unix socket of the container runtime API
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// OCI runtime to run containers with, e.g. a VM-based one like kata
+        #[arg(long)]
+        oci_runtime: Option<String>,
+        /// Docker host to drive instead of the local one (tcp://host:2376, ssh://user@host)
+        #[arg(long)]
+        docker_host: Option<String>,
+        /// Directory with ca.pem, cert.pem and key.pem for a TCP Docker host with TLS
+        #[arg(long, requires = "docker_host")]
+        docker_cert_path: Option<PathBuf>,
+        /// Give each sandbox its own network, so sandboxes cannot reach each other
+        #[arg(long)]
+        isolate_networks: bool,
     },
     /// Sandbox client commands
     Sandbox {
-        /// Server URL
-        #[arg(short, long, default_value = "http://localhost:3000")]
-        server: String,
+        /// Server URL, the profile's or http://localhost:3000 by default
+        #[arg(short, long)]
+        server: Option<String>,
         #[command(subcommand)]
         action: SandboxCommands,
     },
     /// Start an interactive session with a sandbox
     Session {
-        /// Server URL
-        #[arg(short, long, default_value = "http://localhost:3000")]
-        server: String,
-        /// Container image to use
-        #[arg(short, long, default_value = "ubuntu:latest")]
-        image: String,
-        /// Setup commands to run after container start
+        /// Server URL, the profile's or http://localhost:3000 by default
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Run the server in-process, on the local container runtime, instead
+        #[arg(long, conflicts_with = "server")]
+        local: bool,
+        /// Container image to use, the profile's or ubuntu:latest by default
+        #[arg(short, long)]
+        image: Option<String>,
+        /// Setup commands to run after container start, the profile's by default
         #[arg(long)]
         setup: Vec<String>,
     },
+    /// Run a command in a new sandbox, removed once the command finished
+    ///
+    /// `sos run --image python:3.12 -- "pytest -x"` prints the output of the command and
+    /// exits with its exit code. The command runs in standalone mode.
+    Run {
+        /// Server URL, the profile's or http://localhost:3000 by default
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Run the server in-process, on the local container runtime, instead
+        #[arg(long, conflicts_with = "server")]
+        local: bool,
+        /// Container image to use, the profile's or ubuntu:latest by default
+        #[arg(short, long)]
+        image: Option<String>,
+        /// Setup commands to run after container start, the profile's by default
+        #[arg(long)]
+        setup: Vec<String>,
+        /// Keep the sandbox instead of removing it
+        #[arg(long)]
+        keep: bool,
+        /// Fail the command when it runs for longer, in seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Command to run, its arguments joined with spaces
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Task client commands
+    Task {
+        /// Server URL, the profile's or http://localhost:3000 by default
+        #[arg(short, long)]
+        server: Option<String>,
+        #[command(subcommand)]
+        action: TaskCommands,
+    },
     /// Start the Terminal User Interface
     Tui {
-        /// Server URL
-        #[arg(short, long, default_value = "http://localhost:3000")]
-        server: String,
+        /// Server URL, the profile's or http://localhost:3000 by default
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Color theme: dark, light, solarized or one of the configuration's `[themes]`
+        #[arg(long, env = "SOS_THEME")]
+        theme: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TaskCommands {
+    /// Run task definitions and verify them, printing a summary and a report
+    ///
+    /// Each task is instantiated in a new sandbox, the agent command is run against it and
+    /// the verifier of the task scores the result. Exits with 1 when a task did not pass.
+    Run {
+        /// Task files, or directories of them
+        #[arg(required = true)]
+        tasks: Vec<PathBuf>,
+        /// Number of tasks run at the same time
+        #[arg(short, long, default_value = "4")]
+        concurrency: usize,
+        /// Command run locally with `sh` for each task, before verifying it. The sandbox
+        /// is in `SOS_SERVER` and `SOS_SANDBOX_ID`, the API key in `SOS_API_KEY` and the
+        /// instructions on its stdin.
+        #[arg(long)]
+        agent: Option<String>,
+        /// Write the JSON report to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Keep the sandboxes instead of removing them
+        #[arg(long)]
+        keep: bool,
     },
 }
 
@@ -69,12 +230,33 @@ enum Commands {
 enum SandboxCommands {
     /// Create a new sandbox
     Create {
-        /// Container image to use
-        #[arg(short, long, default_value = "ubuntu:latest")]
-        image: String,
-        /// Setup commands to run after container start
+        /// Container image to use, the profile's or ubuntu:latest by default
+        #[arg(short, long)]
+        image: Option<String>,
+        /// Setup commands to run after container start, the profile's by default
         #[arg(short, long)]
         setup: Vec<String>,
+        /// Labels to attach to the sandbox (key=value)
+        #[arg(short, long, value_parser = parse_label)]
+        label: Vec<(String, String)>,
+        /// DNS server of the sandbox, instead of the runtime's
+        #[arg(long)]
+        dns: Vec<String>,
+        /// DNS search domain of the sandbox
+        #[arg(long)]
+        dns_search: Vec<String>,
+        /// Extra /etc/hosts entry of the sandbox (host:ip)
+        #[arg(long)]
+        add_host: Vec<String>,
+        /// Shell of the session: bash, sh, zsh or fish
+        #[arg(long)]
+        shell: Option<Shell>,
+        /// Columns of the session terminal
+        #[arg(long)]
+        cols: Option<u16>,
+        /// Rows of the session terminal
+        #[arg(long)]
+        rows: Option<u16>,
     },
     /// List all sandboxes
     List,
@@ -84,14 +266,27 @@ enum SandboxCommands {
         id: String,
     },
     /// Execute a command in a sandbox
+    ///
+    /// The command is read from standard input when it is `-`, so multi-line scripts need
+    /// no escaping: `echo "ls /" | sos sandbox exec <id> -`.
     Exec {
         /// Sandbox ID
         id: String,
-        /// Command to execute
-        command: String,
+        /// Command to execute, `-` to read it from standard input
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        command: Option<String>,
+        /// Read the command from a script file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
         /// Whether to execute the command in standalone mode
         #[arg(short, long, default_value = "false")]
         standalone: Option<bool>,
+        /// Print the output with its terminal colors
+        #[arg(long)]
+        raw: bool,
+        /// Fail the command when it runs for longer, in seconds
+        #[arg(long)]
+        timeout: Option<u64>,
     },
     /// Stop and remove a sandbox
     Stop {
@@ -100,6 +295,57 @@ enum SandboxCommands {
         #[arg(short, long, default_value = "false")]
         remove: Option<bool>,
     },
+    /// Copy files between a sandbox and the local machine, into the destination directory
+    ///
+    /// `sos sandbox cp <id>:/workspace/out ./artifacts` downloads, `sos sandbox cp ./data
+    /// <id>:/workspace` uploads. Needs `tar` locally.
+    Cp {
+        /// Source, a local path or <id>:<path>
+        source: String,
+        /// Destination directory, a local path or <id>:<path>, created if missing
+        destination: String,
+    },
+    /// Open an interactive shell in a sandbox, with the local terminal in raw mode
+    ///
+    /// The shell runs next to the session of the sandbox. Press Ctrl-] to detach.
+    Attach {
+        /// Sandbox ID
+        id: String,
+    },
+    /// Print the logs of a sandbox: the output of its container, then of its session
+    Logs {
+        /// Sandbox ID
+        id: String,
+        /// Keep printing new output until the sandbox is removed
+        #[arg(short, long)]
+        follow: bool,
+        /// Logs to print: all, container or session
+        #[arg(long, default_value = "all")]
+        source: LogSource,
+    },
+    /// Wait until a sandbox reaches a state
+    ///
+    /// Exits with 0 once it did, 1 when it cannot anymore, such as a sandbox stopped while
+    /// waiting for it to start, and 124 on timeout.
+    Wait {
+        /// Sandbox ID
+        id: String,
+        /// State to wait for
+        #[arg(long = "for", value_enum, default_value_t = WaitState::Started)]
+        state: WaitState,
+        /// Seconds to wait at most, forever when not given
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// Show the CPU, memory and network usage of a sandbox, or of all the running ones,
+    /// refreshed in place until interrupted
+    Stats {
+        /// Sandbox ID, all the running sandboxes when not given
+        id: Option<String>,
+        /// Print a single sample and exit
+        #[arg(long)]
+        no_stream: bool,
+    },
     /// View the command trajectory of a sandbox
     Trajectory {
         /// Sandbox ID
@@ -112,6 +358,17 @@ enum SandboxCommands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let output = match cli.json {
+        true => OutputFormat::Json,
+        false => cli.output,
+    };
+    // Logs would be mixed with the JSON on stdout
+    let writer = match output {
+        OutputFormat::Text => BoxMakeWriter::new(io::stdout),
+        OutputFormat::Json => BoxMakeWriter::new(io::stderr),
+    };
+
     // Initialize tracing subscriber
     tracing_subscriber::registry()
         .with(
@@ -120,263 +377,584 @@ async fn main() -> Result<()> {
                 "sos=info,bollard=warn,hyper=warn,tower=warn,axum=info".into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer().with_target(true))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(true)
+                .with_writer(writer),
+        )
         .init();
 
     info!("Starting SoS (Sea of Simulation)");
 
-    let cli = Cli::parse();
+    let config = match &cli.command {
+        Commands::Serve { .. } => ClientConfig::default(),
+        _ => ClientConfig::load()?,
+    };
+    let profile = match &cli.command {
+        Commands::Serve { .. } => Profile::default(),
+        _ => config.profile(cli.profile.as_deref())?,
+    };
+    let api_key = cli.api_key.as_deref().or(profile.api_key.as_deref());
 
     match cli.command {
         Commands::Serve {
             port,
+            bind,
+            socket_mode,
+            pid_file,
             max_sandboxes,
             timeout,
-        } => serve_command(port, max_sandboxes, timeout).await,
-        Commands::Sandbox { server, action } => sandbox_command(server, action).await,
+            config,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            require_client_cert,
+            trajectory_dir,
+            runtime,
+            socket,
+            oci_runtime,
+            docker_host,
+            docker_cert_path,
+            isolate_networks,
+        } => {
+            let tls = tls_cert
+                .zip(tls_key)
+                .map(|(cert, key)| TlsConfig {
+                    cert,
+                    key,
+                    client_ca: tls_client_ca,
+                    require_client_cert,
+                });
+            let options = ServeOptions {
+                bind,
+                socket_mode,
+                pid_file,
+                max_sandboxes,
+                tls,
+                trajectory_dir,
+                runtime,
+                socket,
+                oci_runtime,
+                docker_host,
+                docker_cert_path,
+                isolate_networks,
+            };
+            serve_command(port, timeout, config, options).await
+        }
+        Commands::Sandbox { server, action } => {
+            let client = sos_client(profile.server(server), api_key)?;
+            sandbox_command(client, action, &profile, output).await
+        }
         Commands::Session {
             server,
+            local,
             image,
             setup,
-        } => session_command(server, image, setup).await,
-        Commands::Tui { server } => tui_command(server).await,
+        } => {
+            let client = match local {
+                true => SosClient::new(local_server().await?),
+                false => sos_client(profile.server(server), api_key)?,
+            };
+            session_command(client, profile.image(image), profile.setup(setup)).await
+        }
+        Commands::Run {
+            server,
+            local,
+            image,
+            setup,
+            keep,
+            timeout,
+            command,
+        } => {
+            let client = match local {
+                true => SosClient::new(local_server().await?),
+                false => sos_client(profile.server(server), api_key)?,
+            };
+            let payload = CreatePayload {
+                image: profile.image(image),
+                setup_commands: profile.setup(setup),
+                ..Default::default()
+            };
+            let exec = ExecPayload {
+                command: command.join(" "),
+                standalone: Some(true),
+                timeout_secs: timeout,
+                ..Default::default()
+            };
+            run_command(client, payload, exec, keep, output).await
+        }
+        Commands::Task { server, action } => {
+            let client = sos_client(profile.server(server), api_key)?;
+            task_command(client, api_key, action, output).await
+        }
+        Commands::Tui { server, theme } => {
+            let keymap = Keymap::new(&config.keys)?;
+            let theme = theme.or(config.theme).unwrap_or_else(|| "dark".to_string());
+            let theme = Theme::load(&theme, &config.themes)?;
+            tui_command(sos_client(profile.server(server), api_key)?, &profile, keymap, theme).await
+        }
     }
 }
 
-async fn serve_command(port: u16, max_sandboxes: usize, timeout: u64) -> Result<()> {
+/// Options of `sos serve` that override the configuration file.
+struct ServeOptions {
+    bind: Option<BindAddress>,
+    socket_mode: Option<u32>,
+    pid_file: Option<PathBuf>,
+    max_sandboxes: Option<usize>,
+    tls: Option<TlsConfig>,
+    trajectory_dir: Option<PathBuf>,
+    runtime: Option<Runtime>,
+    socket: Option<PathBuf>,
+    oci_runtime: Option<String>,
+    docker_host: Option<String>,
+    docker_cert_path: Option<PathBuf>,
+    isolate_networks: bool,
+}
+
+async fn serve_command(
+    port: u16,
+    timeout: u64,
+    config_path: Option<PathBuf>,
+    options: ServeOptions,
+) -> Result<()> {
+    let mut config = match &config_path {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    if let Some(bind) = options.bind {
+        config.bind = bind;
+    }
+    if options.socket_mode.is_some() {
+        config.socket_mode = options.socket_mode;
+    }
+    if options.pid_file.is_some() {
+        config.pid_file = options.pid_file;
+    }
+    if let Some(max_sandboxes) = options.max_sandboxes {
+        config.max_sandboxes = max_sandboxes;
+    }
+    if options.tls.is_some() {
+        config.tls = options.tls;
+    }
+    if options.trajectory_dir.is_some() {
+        config.trajectory_dir = options.trajectory_dir;
+    }
+    if let Some(runtime) = options.runtime {
+        config.runtime.kind = runtime;
+    }
+    if options.socket.is_some() {
+        config.runtime.socket = options.socket;
+    }
+    if options.oci_runtime.is_some() {
+        config.runtime.oci_runtime = options.oci_runtime;
+    }
+    if options.docker_host.is_some() {
+        config.runtime.host = options.docker_host;
+        config.runtime.tls_cert_path = options.docker_cert_path;
+    }
+    if options.isolate_networks {
+        config.runtime.isolate_networks = true;
+    }
+    config.runtime.validate()?;
+    if matches!(config.bind, BindAddress::Unix(_)) && config.tls.is_some() {
+        anyhow::bail!("TLS is not supported on a Unix socket");
+    }
+
     info!(
         port = port,
-        max_sandboxes = max_sandboxes,
+        max_sandboxes = config.max_sandboxes,
         timeout_seconds = timeout,
+        templates = config.templates.len(),
+        tasks = config.tasks.len(),
+        tenants = config.tenants.len(),
+        tls = config.tls.is_some(),
+        rate_limit = config.rate_limit.is_some(),
+        trajectory_dir = ?config.trajectory_dir,
+        runtime = %config.runtime.kind,
+        oci_runtime = ?config.runtime.oci_runtime,
+        runtime_host = ?config.runtime.host,
+        isolate_networks = config.runtime.isolate_networks,
+        proxy = ?config.proxy.as_ref().map(|proxy| proxy.listen),
         "Starting sandbox server"
     );
 
-    // For podman, use the podman socket path
-    let docker = Docker::connect_with_local_defaults()?;
-    let semaphore = Arc::new(Semaphore::new(max_sandboxes));
-    let state = Arc::new(SoSState {
-        docker: Arc::new(docker),
-        sandboxes: Arc::new(Mutex::new(HashMap::new())),
-        semaphore,
-    });
-
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        let timeout_duration = Duration::from_secs(timeout);
-        loop {
-            // Check every minute
-            tokio::time::sleep(Duration::from_secs(60)).await;
-
-            let mut sandboxes_to_remove = Vec::new();
-            let sandboxes = state_clone.sandboxes.lock().await;
-
-            for (id, sandbox_arc) in sandboxes.iter() {
-                let sandbox = sandbox_arc.lock().await;
-                if let Some(start_time) = sandbox.start_time {
-                    if start_time.elapsed() > timeout_duration {
-                        warn!(sandbox_id = %id, elapsed_seconds = start_time.elapsed().as_secs(), "Sandbox timed out, removing");
-                        sandboxes_to_remove.push(id.clone());
-                    }
-                }
-            }
-            drop(sandboxes); // Release the lock before removing
-
-            for id in sandboxes_to_remove {
-                // This is a simplified version of the stop_sandbox logic
-                let sandbox_arc = {
-                    let mut sandboxes = state_clone.sandboxes.lock().await;
-                    sandboxes.remove(&id)
-                };
-
-                if let Some(sandbox_arc) = sandbox_arc {
-                    let mut sandbox = sandbox_arc.lock().await;
-                    if let SandboxStatus::Started(_) = sandbox.get_status() {
-                        let _ = sandbox.stop().await;
-                    }
-                }
-            }
+    let runtime = config.runtime.connect().await?;
+    let tls = config.tls.as_ref().map(|tls| tls.server_config()).transpose()?;
+    let inherited = sos::daemon::inherited_listener()?;
+    // Removed on shutdown when the server created it, not when systemd passed it
+    let socket_path = match (&inherited, &config.bind) {
+        (None, BindAddress::Unix(path)) => Some(path.clone()),
+        _ => None,
+    };
+    let (listener, bind_addr) = match inherited {
+        Some(listener) => (listener, "socket activation".to_string()),
+        None => {
+            let listener = config.bind.bind(port, config.socket_mode).await?;
+            let bind_addr = match &config.bind {
+                BindAddress::Ip(ip) => std::net::SocketAddr::new(*ip, port).to_string(),
+                unix => unix.to_string(),
+            };
+            (listener, bind_addr)
         }
-    });
+    };
+    let _pid_file = config.pid_file.as_ref().map(PidFile::create).transpose()?;
+    let state = Arc::new(SoSState::new(runtime, config));
+
+    state.manager.spawn_reaper(Duration::from_secs(timeout));
+    sos::daemon::spawn_reload_on_hangup(state.clone())?;
 
     let app = sos::http::create_app(state);
 
-    let bind_addr = format!("0.0.0.0:{}", port);
     info!(bind_address = %bind_addr, "Server listening");
+    sos::daemon::notify("READY=1");
+    tokio::select! {
+        result = sos::listen::serve(listener, app, tls) => result?,
+        _ = sos::daemon::shutdown_signal() => {
+            info!("Shutting down");
+            sos::daemon::notify("STOPPING=1");
+        }
+    }
+    if let Some(path) = socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Parses the octal permissions of `--socket-mode`.
+fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid socket mode '{}', expected octal permissions like 660", s))
+}
 
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    axum::serve(listener, app.into_make_service()).await?;
+/// Starts the server in the background, with the default configuration, listening on an
+/// ephemeral port of the loopback interface. Returns its URL.
+///
+/// Backs `--local`, for single-user use without `sos serve`. The sandboxes are not timed
+/// out: the commands using it remove theirs before exiting.
+async fn local_server() -> Result<String> {
+    let config = ServerConfig::default();
+    let runtime = config.runtime.connect().await?;
+    let app = sos::http::create_app(Arc::new(SoSState::new(runtime, config)));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        let service = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+        if let Err(error) = axum::serve(listener, service).await {
+            warn!("Local server failed: {}", error);
+        }
+    });
+    Ok(url)
+}
 
-    Ok(())
+/// Builds the API client used by the client commands, authenticating every
+/// request with the API key when one is given.
+fn sos_client(server: String, api_key: Option<&str>) -> Result<SosClient> {
+    Ok(match api_key {
+        Some(api_key) => SosClient::with_api_key(server, api_key)?,
+        None => SosClient::new(server),
+    })
 }
 
-async fn sandbox_command(server: String, action: SandboxCommands) -> Result<()> {
-    let client = reqwest::Client::new();
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("invalid label '{}', expected key=value", s))
+}
 
+async fn sandbox_command(
+    client: SosClient,
+    action: SandboxCommands,
+    profile: &Profile,
+    output: OutputFormat,
+) -> Result<()> {
+    let json = output == OutputFormat::Json;
     match action {
-        SandboxCommands::Create { image, setup } => {
-            println!("Creating sandbox with image: {}", image);
-            if !setup.is_empty() {
-                println!("Setup commands: {:?}", setup);
+        SandboxCommands::Create {
+            image,
+            setup,
+            label,
+            dns,
+            dns_search,
+            add_host,
+            shell,
+            cols,
+            rows,
+        } => {
+            let image = profile.image(image);
+            let setup = profile.setup(setup);
+            if !json {
+                println!("Creating sandbox with image: {}", image);
+                if !setup.is_empty() {
+                    println!("Setup commands: {:?}", setup);
+                }
             }
 
             let payload = CreatePayload {
                 image,
                 setup_commands: setup,
+                labels: label.into_iter().collect(),
+                dns,
+                dns_search,
+                extra_hosts: add_host,
+                shell,
+                cols,
+                rows,
+                ..Default::default()
             };
 
-            let response = client
-                .post(&format!("{}/sandboxes", server))
-                .json(&payload)
-                .send()
-                .await?;
-
-            if response.status().is_success() {
-                let result: serde_json::Value = response.json().await?;
-                let id = result["id"].as_str().unwrap();
-                println!("✓ Sandbox created with ID: {}", id);
-                println!("  Use 'sos sandbox start {}' to start it", id);
-            } else {
-                let error = response.text().await?;
-                eprintln!("✗ Failed to create sandbox: {}", error);
-                std::process::exit(1);
+            match client.create(&payload).await {
+                Ok(id) if json => print_json(&CreateResponse { id })?,
+                Ok(id) => {
+                    println!("✓ Sandbox created with ID: {}", id);
+                    println!("  Use 'sos sandbox start {}' to start it", id);
+                }
+                Err(error) => {
+                    eprintln!("✗ Failed to create sandbox: {}", error);
+                    std::process::exit(1);
+                }
             }
         }
         SandboxCommands::List => {
-            println!("Listing all sandboxes...");
-
-            let response = client.get(&format!("{}/sandboxes", server)).send().await?;
-
-            if response.status().is_success() {
-                let sandboxes: Vec<serde_json::Value> = response.json().await?;
+            if !json {
+                println!("Listing all sandboxes...");
+            }
 
-                if sandboxes.is_empty() {
-                    println!("No sandboxes found");
-                } else {
-                    println!("{:<36} {:<20} {:<10} {}", "ID", "IMAGE", "STATUS", "SETUP");
-                    println!("{}", "-".repeat(80));
-
-                    for sandbox in sandboxes {
-                        let id = sandbox["id"].as_str().unwrap_or("N/A");
-                        let image = sandbox["image"].as_str().unwrap_or("N/A");
-                        let status = sandbox["status"].as_str().unwrap_or("N/A");
-                        let setup = sandbox["setup_commands"].as_str().unwrap_or("");
-                        let setup_display = if setup.is_empty() {
-                            "none".to_string()
-                        } else if setup.len() > 30 {
-                            format!("{}...", &setup[..27])
-                        } else {
-                            setup.to_string()
-                        };
-
-                        println!("{:<36} {:<20} {:<10} {}", id, image, status, setup_display);
+            match client.list().await {
+                Ok(sandboxes) if json => print_json(&sandboxes)?,
+                Ok(sandboxes) => {
+                    if sandboxes.is_empty() {
+                        println!("No sandboxes found");
+                    } else {
+                        println!("{:<36} {:<20} {:<10} {}", "ID", "IMAGE", "STATUS", "SETUP");
+                        println!("{}", "-".repeat(80));
+
+                        for sandbox in sandboxes {
+                            let setup = &sandbox.setup_commands;
+                            let setup_display = if setup.is_empty() {
+                                "none".to_string()
+                            } else if setup.len() > 30 {
+                                format!("{}...", &setup[..27])
+                            } else {
+                                setup.to_string()
+                            };
+
+                            println!(
+                                "{:<36} {:<20} {:<10} {}",
+                                sandbox.id, sandbox.image, sandbox.status, setup_display
+                            );
+                        }
                     }
                 }
-            } else {
-                let error = response.text().await?;
-                eprintln!("✗ Failed to list sandboxes: {}", error);
-                std::process::exit(1);
+                Err(error) => {
+                    eprintln!("✗ Failed to list sandboxes: {}", error);
+                    std::process::exit(1);
+                }
             }
         }
         SandboxCommands::Start { id } => {
             println!("Starting sandbox: {}", id);
 
-            let response = client
-                .post(&format!("{}/sandboxes/{}/start", server, id))
-                .send()
-                .await?;
-
-            if response.status().is_success() {
-                println!("✓ Sandbox {} started successfully", id);
-                println!("  Use 'sos sandbox exec {} <command>' to run commands", id);
-            } else {
-                let error = response.text().await?;
-                eprintln!("✗ Failed to start sandbox: {}", error);
-                std::process::exit(1);
+            match start_with_progress(&client, &id).await {
+                Ok(()) => {
+                    println!("✓ Sandbox {} started successfully", id);
+                    println!("  Use 'sos sandbox exec {} <command>' to run commands", id);
+                }
+                Err(error) => {
+                    eprintln!("✗ Failed to start sandbox: {}", error);
+                    std::process::exit(1);
+                }
             }
         }
         SandboxCommands::Exec {
             id,
             command,
+            file,
             standalone,
+            raw,
+            timeout,
         } => {
-            println!("Executing command in sandbox {}: {}", id, command);
+            let command = match read_command(command, file) {
+                Ok(command) => command,
+                Err(error) => {
+                    eprintln!("✗ Failed to read the command: {}", error);
+                    std::process::exit(1);
+                }
+            };
+            if !json {
+                println!("Executing command in sandbox {}: {}", id, command);
+            }
 
             let payload = ExecPayload {
                 command,
                 standalone,
+                raw: Some(raw),
+                timeout_secs: timeout,
             };
 
-            let response = client
-                .post(&format!("{}/sandboxes/{}/exec", server, id))
-                .json(&payload)
-                .send()
-                .await?;
-
-            if response.status().is_success() {
-                let result: serde_json::Value = response.json().await?;
-                let output = result["output"].as_str().unwrap_or("");
-                let exit_code = result["exit_code"].as_i64().unwrap_or(-1);
-
-                if !output.is_empty() {
-                    println!("{}", output);
+            match client.exec_with(&id, &payload).await {
+                Ok(result) if json => {
+                    print_json(&result)?;
+                    if result.exit_code != 0 {
+                        std::process::exit(result.exit_code as i32);
+                    }
                 }
+                Ok(result) => {
+                    let output = result.raw_output.as_ref().unwrap_or(&result.output);
+                    if !output.is_empty() {
+                        println!("{}", output);
+                    }
 
-                if exit_code != 0 {
-                    eprintln!("Command failed with exit code: {}", exit_code);
-                    std::process::exit(exit_code as i32);
+                    if result.exit_code != 0 {
+                        eprintln!("Command failed with exit code: {}", result.exit_code);
+                        std::process::exit(result.exit_code as i32);
+                    }
+                }
+                Err(error) => {
+                    eprintln!("✗ Failed to execute command: {}", error);
+                    std::process::exit(1);
                 }
-            } else {
-                let error = response.text().await?;
-                eprintln!("✗ Failed to execute command: {}", error);
-                std::process::exit(1);
             }
         }
         SandboxCommands::Stop { id, remove } => {
             println!("Stopping sandbox: {}", id);
 
-            let response = client
-                .post(&format!("{}/sandboxes/{}/stop", server, id))
-                .json(&StopPayload { remove })
-                .send()
-                .await?;
+            match client.stop(&id, remove.unwrap_or(false)).await {
+                Ok(response) => {
+                    println!("✓ Sandbox {} stopped", id);
+                    if let Some(url) = response.archive_url {
+                        println!("  Archived to {}", url);
+                    }
+                    println!("  Use 'sos trajectory {}' to view command history", id);
+                }
+                Err(error) => {
+                    eprintln!("✗ Failed to stop sandbox: {}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SandboxCommands::Cp {
+            source,
+            destination,
+        } => {
+            let result = match (sandbox_path(&source), sandbox_path(&destination)) {
+                (Some((id, path)), None) => download(&client, id, path, &destination).await,
+                (None, Some((id, dir))) => upload(&client, &source, id, dir).await,
+                _ => Err(anyhow::anyhow!(
+                    "Exactly one of the source and the destination must be <id>:<path>"
+                )),
+            };
 
-            if response.status().is_success() {
-                println!("✓ Sandbox {} stopped", id);
-                println!("  Use 'sos trajectory {}' to view command history", id);
-            } else {
-                let error = response.text().await?;
-                eprintln!("✗ Failed to stop sandbox: {}", error);
+            match result {
+                Ok(()) => println!("✓ Copied {} to {}", source, destination),
+                Err(error) => {
+                    eprintln!("✗ Failed to copy: {}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SandboxCommands::Attach { id } => {
+            if let Err(error) = attach(&client, &id).await {
+                eprintln!("✗ Failed to attach: {}", error);
                 std::process::exit(1);
             }
+            // Reading stdin blocks a thread of the runtime, which would not shut down
+            std::process::exit(0);
         }
-        SandboxCommands::Trajectory { id, formatted } => {
-            println!("Viewing trajectory for sandbox: {}", id);
-
-            if formatted {
-                let response = client
-                    .get(&format!("{}/sandboxes/{}/trajectory/formatted", server, id))
-                    .send()
-                    .await?;
+        SandboxCommands::Logs { id, follow, source } => {
+            use futures::StreamExt;
+
+            let result = async {
+                let mut logs = Box::pin(client.logs(&id, source, follow).await?);
+                let mut stdout = io::stdout();
+                while let Some(chunk) = logs.next().await {
+                    stdout.write_all(&chunk?)?;
+                    stdout.flush()?;
+                }
+                anyhow::Ok(())
+            }
+            .await;
 
-                if response.status().is_success() {
-                    let formatted_trajectory = response.text().await?;
-                    println!("{}", formatted_trajectory);
-                } else {
-                    let error = response.text().await?;
-                    eprintln!("✗ Failed to get trajectory: {}", error);
+            if let Err(error) = result {
+                eprintln!("✗ Failed to get logs: {}", error);
+                std::process::exit(1);
+            }
+        }
+        SandboxCommands::Wait { id, state, timeout } => {
+            let wait = wait_for(&client, &id, state);
+            let result = match timeout {
+                Some(secs) => tokio::time::timeout(Duration::from_secs(secs), wait).await,
+                None => Ok(wait.await),
+            };
+            match result {
+                Ok(Ok(status)) => {
+                    if !json {
+                        println!("✓ Sandbox {} is {}", id, status);
+                    }
+                }
+                Ok(Err(error)) => {
+                    eprintln!("✗ {}", error);
                     std::process::exit(1);
                 }
-            } else {
-                let response = client
-                    .get(&format!("{}/sandboxes/{}/trajectory", server, id))
-                    .send()
-                    .await?;
-
-                if response.status().is_success() {
-                    let trajectory_data: serde_json::Value = response.json().await?;
-                    println!("{}", serde_json::to_string_pretty(&trajectory_data)?);
+                Err(_) => {
+                    eprintln!("✗ Timed out waiting for sandbox {}", id);
+                    std::process::exit(124);
+                }
+            }
+        }
+        SandboxCommands::Stats { id, no_stream } => {
+            // A single sample for scripts, a live table otherwise
+            let once = json || no_stream;
+            loop {
+                let stats = match sample_stats(&client, id.as_deref()).await {
+                    Ok(stats) => stats,
+                    Err(error) => {
+                        eprintln!("✗ Failed to get stats: {}", error);
+                        std::process::exit(1);
+                    }
+                };
+                if json {
+                    let stats: BTreeMap<_, _> = stats
+                        .into_iter()
+                        .filter_map(|(id, usage)| usage.map(|usage| (id, usage)))
+                        .collect();
+                    print_json(&stats)?;
                 } else {
-                    let error = response.text().await?;
+                    if !once {
+                        crossterm::execute!(
+                            io::stdout(),
+                            crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
+                            crossterm::cursor::MoveTo(0, 0)
+                        )?;
+                    }
+                    print_stats(&stats);
+                }
+                if once {
+                    break;
+                }
+                tokio::time::sleep(STATS_INTERVAL).await;
+            }
+        }
+        SandboxCommands::Trajectory { id, formatted } => {
+            if !json {
+                println!("Viewing trajectory for sandbox: {}", id);
+            }
+
+            let result = if formatted && !json {
+                client.trajectory_formatted(&id).await
+            } else {
+                match client.trajectory(&id).await {
+                    Ok(trajectory) => Ok(serde_json::to_string_pretty(&trajectory)?),
+                    Err(error) => Err(error),
+                }
+            };
+
+            match result {
+                Ok(trajectory) => println!("{}", trajectory),
+                Err(error) => {
                     eprintln!("✗ Failed to get trajectory: {}", error);
                     std::process::exit(1);
                 }
@@ -387,121 +965,535 @@ async fn sandbox_command(server: String, action: SandboxCommands) -> Result<()>
     Ok(())
 }
 
-async fn session_command(server: String, image: String, setup: Vec<String>) -> Result<()> {
+/// Time between the polls of `sos sandbox wait`.
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Polls sandbox `id` until it reaches `state`, returning its status then. Fails once it
+/// cannot reach it anymore.
+async fn wait_for(client: &SosClient, id: &str, state: WaitState) -> Result<String> {
+    loop {
+        let sandboxes = client.list().await?;
+        let status = sandboxes
+            .into_iter()
+            .find(|sandbox| sandbox.id == id)
+            .map(|sandbox| sandbox.status);
+        match (state, status.as_deref()) {
+            (WaitState::Stopped, None) => return Ok("removed".to_string()),
+            (_, None) => anyhow::bail!("Sandbox {} not found", id),
+            (WaitState::Started, Some("started"))
+            | (WaitState::Exited, Some("exited"))
+            | (WaitState::Stopped, Some("stopped")) => return Ok(status.unwrap_or_default()),
+            (WaitState::Started | WaitState::Exited, Some("stopped")) => {
+                anyhow::bail!("Sandbox {} stopped", id)
+            }
+            (WaitState::Exited, Some("exhausted")) => {
+                anyhow::bail!("Sandbox {} used up its command budget", id)
+            }
+            (WaitState::Started | WaitState::Exited, Some("crashed")) => {
+                anyhow::bail!("Sandbox {} crashed", id)
+            }
+            _ => {}
+        }
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
+
+/// Time between the samples of `sos sandbox stats`.
+const STATS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Samples the usage of sandbox `id`, or of every running sandbox. Sandboxes that stopped
+/// meanwhile have no usage.
+async fn sample_stats(
+    client: &SosClient,
+    id: Option<&str>,
+) -> Result<Vec<(String, Option<ResourceUsage>)>, ClientError> {
+    let ids = match id {
+        Some(id) => return Ok(vec![(id.to_string(), Some(client.stats(id).await?))]),
+        None => client
+            .list()
+            .await?
+            .into_iter()
+            .filter(|sandbox| sandbox.status == "started")
+            .map(|sandbox| sandbox.id),
+    };
+    let samples = ids.map(|id| async move {
+        let usage = client.stats(&id).await.ok();
+        (id, usage)
+    });
+    Ok(futures::future::join_all(samples).await)
+}
+
+fn print_stats(stats: &[(String, Option<ResourceUsage>)]) {
+    println!(
+        "{:<36} {:>7} {:>21} {:>6} {:>21} {:>21} {:>5}",
+        "ID", "CPU %", "MEM USAGE / LIMIT", "MEM %", "NET RX / TX", "BLOCK R / W", "PIDS"
+    );
+    if stats.is_empty() {
+        println!("No running sandboxes");
+    }
+    for (id, usage) in stats {
+        let Some(usage) = usage else {
+            println!("{:<36} {:>7}", id, "-");
+            continue;
+        };
+        let memory_percent = match usage.memory_limit_bytes {
+            0 => 0.0,
+            limit => usage.memory_usage_bytes as f64 * 100.0 / limit as f64,
+        };
+        println!(
+            "{:<36} {:>6.1}% {:>21} {:>5.1}% {:>21} {:>21} {:>5}",
+            id,
+            usage.cpu_percent,
+            format!(
+                "{} / {}",
+                format_bytes(usage.memory_usage_bytes),
+                format_bytes(usage.memory_limit_bytes)
+            ),
+            memory_percent,
+            format!(
+                "{} / {}",
+                format_bytes(usage.network_rx_bytes),
+                format_bytes(usage.network_tx_bytes)
+            ),
+            format!(
+                "{} / {}",
+                format_bytes(usage.block_read_bytes),
+                format_bytes(usage.block_write_bytes)
+            ),
+            usage.pids
+        );
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+/// Prints `value` to stdout as pretty JSON, for `--output json`.
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Byte detaching from `sos sandbox attach`, Ctrl-].
+const DETACH_KEY: u8 = 0x1d;
+
+fn terminal_size() -> Option<TerminalSize> {
+    crossterm::terminal::size()
+        .ok()
+        .map(|(cols, rows)| TerminalSize { cols, rows })
+}
+
+/// Bridges the local terminal, in raw mode, to an interactive shell of a sandbox until
+/// the shell exits or the detach key is pressed. The remote terminal follows the size of
+/// the local one.
+async fn attach(client: &SosClient, id: &str) -> Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let size = terminal_size();
+    let (mut sender, mut receiver) = client.attach(id, size).await?.split();
+    eprintln!("Attached to {}, press Ctrl-] to detach", id);
+    crossterm::terminal::enable_raw_mode()?;
+
+    let output = async {
+        let mut stdout = tokio::io::stdout();
+        while let Some(message) = receiver.next().await {
+            match message? {
+                Message::Binary(bytes) => {
+                    stdout.write_all(&bytes).await?;
+                    stdout.flush().await?;
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        anyhow::Ok(())
+    };
+    let input = async {
+        let mut stdin = tokio::io::stdin();
+        let mut buf = [0u8; 1024];
+        let mut size = size;
+        let mut ticks = tokio::time::interval(Duration::from_millis(250));
+        loop {
+            tokio::select! {
+                read = stdin.read(&mut buf) => {
+                    let data = &buf[..read?];
+                    let detach = data.iter().position(|byte| *byte == DETACH_KEY);
+                    let data = &data[..detach.unwrap_or(data.len())];
+                    if !data.is_empty() {
+                        sender.send(Message::Binary(data.to_vec().into())).await?;
+                    }
+                    if detach.is_some() || data.is_empty() {
+                        break;
+                    }
+                }
+                _ = ticks.tick() => {
+                    let current = terminal_size();
+                    if let Some(current) = current.filter(|current| Some(*current) != size) {
+                        let resize = serde_json::to_string(&current)?;
+                        sender.send(Message::Text(resize.into())).await?;
+                    }
+                    size = current;
+                }
+            }
+        }
+        let _ = sender.send(Message::Close(None)).await;
+        anyhow::Ok(())
+    };
+    let result = tokio::select! {
+        result = output => result,
+        result = input => result,
+    };
+
+    crossterm::terminal::disable_raw_mode()?;
+    eprintln!();
+    result
+}
+
+/// Command of `sos sandbox exec`: the argument, standard input when it is `-`, or the
+/// script file. The trailing newline of a script is dropped, the session would otherwise
+/// wait for the output of an extra command.
+fn read_command(command: Option<String>, file: Option<PathBuf>) -> io::Result<String> {
+    let script = match (command.as_deref(), file) {
+        (_, Some(file)) => std::fs::read_to_string(file)?,
+        (Some("-"), None) => io::read_to_string(io::stdin())?,
+        (_, None) => return Ok(command.unwrap_or_default()),
+    };
+    Ok(script.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Splits `<id>:<path>` into the sandbox ID and the path. Local paths containing a colon
+/// are told apart by a slash before it, as in `./a:b`.
+fn sandbox_path(arg: &str) -> Option<(&str, &str)> {
+    arg.split_once(':')
+        .filter(|(id, path)| !id.is_empty() && !id.contains('/') && !path.is_empty())
+}
+
+/// Prints the progress of a transfer on a single line.
+fn print_progress(action: &str, done: u64, total: Option<u64>) {
+    const MIB: f64 = 1024.0 * 1024.0;
+    let done_mib = done as f64 / MIB;
+    match total.filter(|total| *total > 0) {
+        Some(total) => eprint!(
+            "\r  {}: {:.1}/{:.1} MiB ({:.0}%)",
+            action,
+            done_mib,
+            total as f64 / MIB,
+            done as f64 * 100.0 / total as f64
+        ),
+        None => eprint!("\r  {}: {:.1} MiB", action, done_mib),
+    }
+    let _ = io::stderr().flush();
+}
+
+/// Downloads `path` of a sandbox into the local directory `dir`, extracting the archive
+/// with the local `tar`.
+async fn download(client: &SosClient, id: &str, path: &str, dir: &str) -> Result<()> {
+    let archive = client
+        .download(id, path, |done, total| {
+            print_progress("Downloading", done, total)
+        })
+        .await;
+    eprintln!();
+    extract_archive(&archive?, dir).await
+}
+
+/// Extracts a tar archive into the local directory `dir` with the local `tar`, creating
+/// the directory if missing.
+async fn extract_archive(archive: &[u8], dir: &str) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    tokio::fs::create_dir_all(dir).await?;
+    let mut tar = tokio::process::Command::new("tar")
+        .args(["-xf", "-", "-C", dir])
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = tar.stdin.take().expect("stdin is piped");
+    stdin.write_all(archive).await?;
+    drop(stdin);
+    if !tar.wait().await?.success() {
+        anyhow::bail!("tar failed to extract the archive into {}", dir);
+    }
+    Ok(())
+}
+
+/// Uploads the local `path` into the directory `dir` of a sandbox, archived with the
+/// local `tar`.
+async fn upload(client: &SosClient, path: &str, id: &str, dir: &str) -> Result<()> {
+    let path = std::path::Path::new(path.trim_end_matches('/'));
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Cannot copy {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let output = tokio::process::Command::new("tar")
+        .arg("-cf")
+        .arg("-")
+        .arg("-C")
+        .arg(parent)
+        .arg(name)
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "tar failed to archive {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let result = client
+        .upload(id, dir, output.stdout, |done, total| {
+            print_progress("Uploading", done, Some(total))
+        })
+        .await;
+    eprintln!();
+    Ok(result?)
+}
+
+/// Starts a sandbox, printing its queueing and the progress of its image pull from the
+/// events stream.
+async fn start_with_progress(client: &SosClient, id: &str) -> Result<(), ClientError> {
+    use futures::StreamExt;
+
+    // Progress is best effort, the start goes ahead without it
+    let printer = match client.events().await {
+        Ok(events) => {
+            let id = id.to_string();
+            Some(tokio::spawn(async move {
+                let mut events = Box::pin(events);
+                while let Some(Ok(event)) = events.next().await {
+                    if event.sandbox_id != id {
+                        continue;
+                    }
+                    if event.kind == ServerEventKind::Queued {
+                        eprintln!("  Server at capacity, waiting for a slot");
+                    }
+                    if let Some(progress) = event.progress {
+                        let percent = progress
+                            .percent()
+                            .map_or(String::new(), |p| format!(" ({:.0}%)", p));
+                        eprintln!(
+                            "  Pulling image{}: {}/{} layers",
+                            percent, progress.layers_done, progress.layers
+                        );
+                    }
+                }
+            }))
+        }
+        Err(_) => None,
+    };
+
+    let result = client.start_and_wait(id).await;
+    if let Some(printer) = printer {
+        printer.abort();
+    }
+    result
+}
+
+async fn task_command(
+    client: SosClient,
+    api_key: Option<&str>,
+    action: TaskCommands,
+    output: OutputFormat,
+) -> Result<()> {
+    match action {
+        TaskCommands::Run {
+            tasks,
+            concurrency,
+            agent,
+            report: report_path,
+            keep,
+        } => {
+            let tasks = task::load_tasks(&tasks)?;
+            eprintln!("Running {} tasks, {} at a time", tasks.len(), concurrency);
+            let report =
+                task::run_tasks(&client, api_key, tasks, agent.as_deref(), concurrency, keep).await;
+            match output {
+                OutputFormat::Json => print_json(&report)?,
+                OutputFormat::Text => task::print_summary(&report),
+            }
+            if let Some(path) = report_path {
+                std::fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+                eprintln!("Report written to {}", path.display());
+            }
+            if report.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates a sandbox, runs `exec` in it and removes it unless `keep`. Exits with the exit
+/// code of the command; what `sos run` reports itself goes to stderr.
+async fn run_command(
+    client: SosClient,
+    payload: CreatePayload,
+    exec: ExecPayload,
+    keep: bool,
+    output: OutputFormat,
+) -> Result<()> {
+    let id = match client.create(&payload).await {
+        Ok(id) => id,
+        Err(error) => {
+            eprintln!("✗ Failed to create sandbox: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let result = match start_with_progress(&client, &id).await {
+        Ok(()) => client.exec_with(&id, &exec).await,
+        Err(error) => Err(error),
+    };
+    match keep {
+        true => eprintln!("Sandbox {} kept, stop it with 'sos sandbox stop {}'", id, id),
+        false => {
+            if let Err(error) = client.stop(&id, true).await {
+                eprintln!("✗ Failed to remove sandbox {}: {}", id, error);
+            }
+        }
+    }
+
+    let result = match result {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("✗ Failed to run command: {}", error);
+            std::process::exit(1);
+        }
+    };
+    match output {
+        OutputFormat::Json => print_json(&result)?,
+        OutputFormat::Text => {
+            print!("{}", result.stdout);
+            eprint!("{}", result.stderr);
+            // Exiting does not flush stdout
+            io::stdout().flush()?;
+        }
+    }
+    if result.exit_code != 0 {
+        std::process::exit(result.exit_code as i32);
+    }
+    Ok(())
+}
+
+async fn session_command(client: SosClient, image: String, setup: Vec<String>) -> Result<()> {
     println!("Starting interactive session with image: {}", image);
     if !setup.is_empty() {
         println!("Setup commands: {:?}", setup);
     }
 
-    let client = reqwest::Client::new();
-
     // Create the sandbox
     let payload = CreatePayload {
         image,
         setup_commands: setup,
+        ..Default::default()
     };
 
-    let response = client
-        .post(&format!("{}/sandboxes", server))
-        .json(&payload)
-        .send()
-        .await?;
-
-    let id = if response.status().is_success() {
-        let result: serde_json::Value = response.json().await?;
-        let id = result["id"].as_str().unwrap().to_string();
-        println!("✓ Sandbox created with ID: {}", id);
-        id
-    } else {
-        let error = response.text().await?;
-        eprintln!("✗ Failed to create sandbox: {}", error);
-        std::process::exit(1);
+    let id = match client.create(&payload).await {
+        Ok(id) => {
+            println!("✓ Sandbox created with ID: {}", id);
+            id
+        }
+        Err(error) => {
+            eprintln!("✗ Failed to create sandbox: {}", error);
+            std::process::exit(1);
+        }
     };
 
     // Start the sandbox
     println!("Starting sandbox...");
-    let response = client
-        .post(&format!("{}/sandboxes/{}/start", server, id))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("✓ Sandbox started successfully");
-    } else {
-        let error = response.text().await?;
+    if let Err(error) = start_with_progress(&client, &id).await {
         eprintln!("✗ Failed to start sandbox: {}", error);
         std::process::exit(1);
     }
+    println!("✓ Sandbox started successfully");
 
     // Enter interactive mode
-    println!("Entering interactive session. Type 'exit' to quit.");
+    println!("Entering interactive session. Type 'exit' or press Ctrl-D to quit.");
     println!("Session ID: {}", id);
     println!("{}", "=".repeat(50));
 
-    loop {
-        print!("\nsandbox:{}> ", &id[..8]); // Show first 8 chars of ID as prompt
-        io::stdout().flush()?;
+    let mut editor: Editor<SessionHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(SessionHelper::new(client.clone(), id.clone())));
+    let history = session::history_path(&id);
+    if let Some(path) = &history {
+        // Only exists when a session of this sandbox ran before
+        let _ = editor.load_history(path);
+    }
+    let prompt = format!("sandbox:{}> ", &id[..8]); // Show first 8 chars of ID as prompt
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    loop {
+        println!();
+        // Reading the terminal blocks, the runtime moves its other tasks off this thread
+        let input = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(input) => input,
+            // Ctrl-C clears the line, as in a shell
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error.into()),
+        };
         let command = input.trim();
 
         if command.is_empty() {
             continue;
         }
+        editor.add_history_entry(command)?;
 
         if command.eq_ignore_ascii_case("exit") || command.eq_ignore_ascii_case("quit") {
             break;
         }
 
-        let payload = ExecPayload {
-            command: command.to_string(),
-            standalone: None,
-        };
-
-        let response = client
-            .post(&format!("{}/sandboxes/{}/exec", server, id))
-            .json(&payload)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            let result: serde_json::Value = response.json().await?;
-            let output = result["output"].as_str().unwrap_or("");
-            let exit_code = result["exit_code"].as_i64().unwrap_or(-1);
+        match client.exec(&id, command).await {
+            Ok(result) => {
+                if !result.output.is_empty() {
+                    print!("{}", result.output);
+                }
 
-            if !output.is_empty() {
-                print!("{}", output);
+                // Don't exit the session on command failure, just show exit code
+                if result.exit_code != 0 {
+                    eprintln!("(exit code: {})", result.exit_code);
+                }
             }
+            Err(error) => eprintln!("✗ Failed to execute command: {}", error),
+        }
+    }
 
-            // Don't exit the session on command failure, just show exit code
-            if exit_code != 0 {
-                eprintln!("(exit code: {})", exit_code);
-            }
-        } else {
-            let error = response.text().await?;
-            eprintln!("✗ Failed to execute command: {}", error);
+    if let Some(path) = &history {
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .map_err(ReadlineError::from)
+            .and_then(|_| editor.save_history(path));
+        if let Err(error) = saved {
+            eprintln!("⚠ Warning: Failed to save the history: {}", error);
         }
     }
 
     // Clean up the sandbox
     println!("Stopping and removing sandbox...");
-    let response = client
-        .post(&format!("{}/sandboxes/{}/stop", server, id))
-        .json(&StopPayload { remove: Some(true) })
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("✓ Sandbox session ended");
-    } else {
-        let error = response.text().await?;
-        eprintln!("⚠ Warning: Failed to clean up sandbox: {}", error);
+    match client.stop(&id, true).await {
+        Ok(_) => println!("✓ Sandbox session ended"),
+        Err(error) => eprintln!("⚠ Warning: Failed to clean up sandbox: {}", error),
     }
 
     Ok(())
 }
 
-async fn tui_command(server: String) -> Result<()> {
-    tui::run_tui(server).await
+async fn tui_command(client: SosClient, profile: &Profile, keymap: Keymap, theme: Theme) -> Result<()> {
+    tui::run_tui(client, profile.image(None), profile.setup(Vec::new()), keymap, theme).await
 }
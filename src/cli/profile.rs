@@ -0,0 +1,120 @@
+//! Client configuration file, `~/.config/sos/config.toml`.
+//!
+//! Holds named profiles of the server to talk to and the defaults of new sandboxes, picked
+//! with `--profile`, or `default_profile` when not given. Flags override the profile.
+//!
+//! ```toml
+//! default_profile = "local"
+//!
+//! [profiles.local]
+//! server = "http://localhost:3000"
+//!
+//! [profiles.staging]
+//! server = "https://sos.staging.example.com"
+//! api_key = "sk-..."
+//! image = "python:3.12"
+//! setup = ["pip install pytest"]
+//!
+//! [keys]
+//! "x" = "X"
+//! ```
+//!
+//! `[keys]` moves key bindings of the TUI to other keys, see [`crate::keymap`], and `theme`
+//! picks its colors, see [`crate::theme`].
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::theme::Palette;
+
+pub const DEFAULT_SERVER: &str = "http://localhost:3000";
+pub const DEFAULT_IMAGE: &str = "ubuntu:latest";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Profile used when `--profile` is not given
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// Key bindings of the TUI, built-in key to the key replacing it
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// Color theme of the TUI, built-in or of `themes`
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub themes: HashMap<String, Palette>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Server URL
+    pub server: Option<String>,
+    /// API key sent to the server
+    pub api_key: Option<String>,
+    /// Image of new sandboxes
+    pub image: Option<String>,
+    /// Setup commands of new sandboxes
+    #[serde(default)]
+    pub setup: Vec<String>,
+}
+
+/// Path of the configuration file, under `$XDG_CONFIG_HOME` or `~/.config`.
+pub fn config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("sos").join("config.toml"))
+}
+
+impl ClientConfig {
+    /// Reads the configuration file, empty when there is none.
+    pub fn load() -> Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        toml::from_str(&text).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    /// Profile `name`, or the default profile. Without either, the empty profile.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .with_context(|| format!("No profile named {} in the configuration", name)),
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+impl Profile {
+    /// Server URL: the flag, the profile's, or the local server.
+    pub fn server(&self, flag: Option<String>) -> String {
+        flag.or_else(|| self.server.clone())
+            .unwrap_or_else(|| DEFAULT_SERVER.to_string())
+    }
+
+    /// Image of a new sandbox: the flag, the profile's, or [`DEFAULT_IMAGE`].
+    pub fn image(&self, flag: Option<String>) -> String {
+        flag.or_else(|| self.image.clone())
+            .unwrap_or_else(|| DEFAULT_IMAGE.to_string())
+    }
+
+    /// Setup commands of a new sandbox: the flags, or the profile's when none is given.
+    pub fn setup(&self, flags: Vec<String>) -> Vec<String> {
+        match flags.is_empty() {
+            true => self.setup.clone(),
+            false => flags,
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Line editing of `sos session`.
+//!
+//! Input is read with rustyline: arrow-key history, Ctrl-R search, and continuation lines
+//! while the command is incomplete, where the shell would print its PS2 prompt. The history
+//! of each sandbox is kept in `~/.local/state/sos/history/<id>`.
+//!
+//! Tab completes commands and paths by running bash's `compgen` in the sandbox, as a
+//! standalone command from the working directory of the session. Images without bash
+//! get no completions.
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper, Highlighter, Hinter};
+use sos::client::SosClient;
+use tokio::runtime::Handle;
+
+/// Most completions listed.
+const MAX_COMPLETIONS: usize = 200;
+
+/// Characters ending the word being completed.
+const WORD_BREAKS: &[char] = &[' ', '\t', '\n', ';', '|', '&', '(', '<', '>'];
+
+#[derive(Helper, Hinter, Highlighter)]
+pub struct SessionHelper {
+    client: SosClient,
+    id: String,
+    /// Runtime the completions are requested on, from the thread blocked reading input
+    runtime: Handle,
+}
+
+impl SessionHelper {
+    /// Helper of the session of sandbox `id`. Must be called within the Tokio runtime, and
+    /// the editor read from `tokio::task::block_in_place`.
+    pub fn new(client: SosClient, id: String) -> Self {
+        Self {
+            client,
+            id,
+            runtime: Handle::current(),
+        }
+    }
+}
+
+impl Completer for SessionHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(WORD_BREAKS).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let before = line[..start].trim_end();
+        let command = before.is_empty() || before.ends_with([';', '|', '&', '(']);
+        let cmd = completion_cmd(word, command && !word.contains('/'));
+        // Completion is best effort, failures complete nothing
+        let candidates = match self
+            .runtime
+            .block_on(self.client.exec_standalone(&self.id, &cmd))
+        {
+            Ok(result) => result
+                .stdout
+                .lines()
+                .take(MAX_COMPLETIONS)
+                .map(|candidate| Pair {
+                    display: display_name(candidate).to_string(),
+                    replacement: escape(candidate),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Validator for SessionHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(match is_incomplete(ctx.input()) {
+            true => ValidationResult::Incomplete,
+            false => ValidationResult::Valid(None),
+        })
+    }
+}
+
+/// Command listing the completions of `word` with `compgen`, commands or files, one per
+/// line. Directories end with a slash.
+fn completion_cmd(word: &str, command: bool) -> String {
+    // The session shell, started first, is the oldest interactive one
+    let cwd = "for p in $(ls /proc | grep -E '^[0-9]+$' | sort -n); do \
+               case \"$(tr '\\0' ' ' < /proc/$p/cmdline)\" in \
+               'bash -i '|'sh -i '|'zsh -i '|'fish -i ') cd \"$(readlink /proc/$p/cwd)\"; break;; \
+               esac; done 2>/dev/null";
+    let list = match command {
+        true => format!("compgen -c -- {}", quote(word)),
+        false => format!(
+            "compgen -f -- {} | while IFS= read -r f; do [ -d \"$f\" ] && echo \"$f/\" || echo \"$f\"; done",
+            quote(word)
+        ),
+    };
+    format!("{}; {} | sort -u", cwd, list)
+}
+
+/// Last component of a path completion, what the list of candidates shows.
+fn display_name(candidate: &str) -> &str {
+    match candidate.trim_end_matches('/').rfind('/') {
+        Some(i) => &candidate[i + 1..],
+        None => candidate,
+    }
+}
+
+/// Escapes the characters of a completion the shell would split or expand.
+fn escape(candidate: &str) -> String {
+    let mut escaped = String::with_capacity(candidate.len());
+    for c in candidate.chars() {
+        if c.is_whitespace() || "'\"\\$`!&;|()<>*?[]{}#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Quotes a string for the shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// History file of the session of sandbox `id`, under `$XDG_STATE_HOME` or `~/.local/state`.
+pub fn history_path(id: &str) -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })
+        .map(|dir| dir.join("sos").join("history").join(id))
+}
+
+/// Whether the shell would wait for more input after `input`: an open quote, bracket or
+/// compound command, a trailing backslash or a trailing `|`, `&&` or `||`. Here documents
+/// are not followed.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut comment = false;
+    let mut depth = 0i32;
+    let mut blocks = 0i32;
+    let mut word = String::new();
+    let mut previous = ' ';
+    // The trailing separator ends the last word
+    for c in input.chars().chain(std::iter::once(' ')) {
+        if comment {
+            comment = c != '\n';
+        } else if escaped {
+            escaped = false;
+        } else {
+            match (quote, c) {
+                (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+                (Some('"'), '\\') => escaped = true,
+                (Some(_), _) => {}
+                (None, '\\') => escaped = true,
+                (None, '\'' | '"') => quote = Some(c),
+                (None, '#') if previous.is_whitespace() || previous == ';' => comment = true,
+                (None, '(' | '{') => depth += 1,
+                (None, ')' | '}') => depth -= 1,
+                (None, _) if c.is_whitespace() || matches!(c, ';' | '&' | '|') => {
+                    blocks += match word.as_str() {
+                        "if" | "case" | "do" => 1,
+                        "fi" | "esac" | "done" => -1,
+                        _ => 0,
+                    };
+                    word.clear();
+                }
+                (None, _) => word.push(c),
+            }
+        }
+        previous = c;
+    }
+    let trimmed = input.trim_end();
+    // A trailing backslash escapes the newline
+    let continued = trimmed.len() == input.len() && escaped_end(input);
+    quote.is_some()
+        || continued
+        || depth > 0
+        || blocks > 0
+        || trimmed.ends_with('|')
+        || trimmed.ends_with("&&")
+}
+
+/// Whether `input` ends with an odd number of backslashes.
+fn escaped_end(input: &str) -> bool {
+    input.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
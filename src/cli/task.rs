@@ -0,0 +1,210 @@
+//! `sos task run`: batch evaluation of task definitions.
+//!
+//! Each task is instantiated into a started sandbox of the caller's tenant, without
+//! registering it with the server, which only admin tenants can do. The agent command,
+//! if any, then runs locally with the sandbox to work in, before the verifier of the task
+//! scores the result and the sandbox is removed.
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, stream};
+use serde::Serialize;
+use sos::client::SosClient;
+use sos::task::Task;
+use tokio::io::AsyncWriteExt;
+
+/// Result of a task of `sos task run`.
+#[derive(Debug, Serialize)]
+pub struct TaskReport {
+    pub task: String,
+    pub sandbox_id: Option<String>,
+    pub score: Option<f64>,
+    pub passed: bool,
+    pub exit_code: Option<i64>,
+    pub duration_secs: f64,
+    /// Why the task could not be run or verified
+    pub error: Option<String>,
+}
+
+impl TaskReport {
+    fn outcome(&self) -> &'static str {
+        match (&self.error, self.passed) {
+            (Some(_), _) => "error",
+            (None, true) => "passed",
+            (None, false) => "failed",
+        }
+    }
+}
+
+/// Report of `sos task run`.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub tasks: Vec<TaskReport>,
+    pub passed: usize,
+    pub failed: usize,
+    /// Mean score of the tasks, those that errored counting as 0
+    pub mean_score: f64,
+}
+
+/// Reads the tasks of `paths`, files or directories of task files.
+pub fn load_tasks(paths: &[PathBuf]) -> Result<Vec<Task>> {
+    let mut tasks = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => tasks.extend(Task::load_dir(path)?),
+            false => tasks.push(
+                Task::load(path).with_context(|| format!("Invalid task {}", path.display()))?,
+            ),
+        }
+    }
+    Ok(tasks)
+}
+
+/// Runs `tasks`, `concurrency` at a time. The reports are in the order of the tasks.
+/// The agent is given `api_key`, the key of the client, to reach the server with.
+pub async fn run_tasks(
+    client: &SosClient,
+    api_key: Option<&str>,
+    tasks: Vec<Task>,
+    agent: Option<&str>,
+    concurrency: usize,
+    keep: bool,
+) -> RunReport {
+    let reports: Vec<TaskReport> = stream::iter(tasks)
+        .map(|task| run_task(client, api_key, task, agent, keep))
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+    let passed = reports.iter().filter(|report| report.passed).count();
+    let total: f64 = reports.iter().filter_map(|report| report.score).sum();
+    RunReport {
+        failed: reports.len() - passed,
+        passed,
+        mean_score: match reports.is_empty() {
+            true => 0.0,
+            false => total / reports.len() as f64,
+        },
+        tasks: reports,
+    }
+}
+
+async fn run_task(
+    client: &SosClient,
+    api_key: Option<&str>,
+    task: Task,
+    agent: Option<&str>,
+    keep: bool,
+) -> TaskReport {
+    let start = Instant::now();
+    let mut report = TaskReport {
+        task: task.name.clone(),
+        sandbox_id: None,
+        score: None,
+        passed: false,
+        exit_code: None,
+        duration_secs: 0.0,
+        error: None,
+    };
+    let result = async {
+        let instance = client.instantiate_task(&task).await?;
+        report.sandbox_id = Some(instance.id.clone());
+        eprintln!("  {}: started in sandbox {}", task.name, instance.id);
+        if let Some(agent) = agent {
+            let sandbox = Sandbox {
+                id: &instance.id,
+                task: &task.name,
+                api_key,
+            };
+            run_agent(client, agent, sandbox, &instance.instructions).await?;
+        }
+        anyhow::Ok(client.verify(&instance.id).await?)
+    }
+    .await;
+    match result {
+        Ok(verification) => {
+            report.score = Some(verification.score);
+            report.passed = verification.passed;
+            report.exit_code = Some(verification.exit_code);
+        }
+        Err(error) => report.error = Some(error.to_string()),
+    }
+    let removed = match (&report.sandbox_id, keep) {
+        (Some(id), false) => client.stop(id, true).await.map(|_| ()),
+        _ => Ok(()),
+    };
+    if let Err(error) = removed {
+        eprintln!("  {}: failed to remove its sandbox: {}", task.name, error);
+    }
+    report.duration_secs = start.elapsed().as_secs_f64();
+    eprintln!("  {}: {}", task.name, report.outcome());
+    report
+}
+
+/// Sandbox an agent works in, with the API key it reaches the server with.
+struct Sandbox<'a> {
+    id: &'a str,
+    task: &'a str,
+    api_key: Option<&'a str>,
+}
+
+/// Runs the agent command with `sh`, the instructions of the task on its stdin. It finds
+/// the sandbox to work in in `SOS_SERVER` and `SOS_SANDBOX_ID`, and the key of the tenant
+/// it was created for in `SOS_API_KEY`. Its output goes to stderr, stdout being kept for
+/// the report.
+async fn run_agent(
+    client: &SosClient,
+    agent: &str,
+    sandbox: Sandbox<'_>,
+    instructions: &str,
+) -> Result<()> {
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(agent)
+        .env("SOS_SERVER", client.base_url())
+        .env("SOS_SANDBOX_ID", sandbox.id)
+        .env("SOS_TASK", sandbox.task);
+    if let Some(api_key) = sandbox.api_key {
+        command.env("SOS_API_KEY", api_key);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(std::io::stderr()))
+        .spawn()
+        .context("Failed to run the agent")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // The agent may exit without reading its instructions
+    let _ = stdin.write_all(instructions.as_bytes()).await;
+    drop(stdin);
+    let status = child.wait().await?;
+    anyhow::ensure!(status.success(), "Agent failed with {}", status);
+    Ok(())
+}
+
+/// Prints the summary table of the report.
+pub fn print_summary(report: &RunReport) {
+    println!(
+        "{:<32} {:<36} {:>6} {:<7} {:>8}",
+        "TASK", "SANDBOX", "SCORE", "RESULT", "TIME"
+    );
+    println!("{}", "-".repeat(93));
+    for task in &report.tasks {
+        println!(
+            "{:<32} {:<36} {:>6} {:<7} {:>7.1}s",
+            task.task,
+            task.sandbox_id.as_deref().unwrap_or("-"),
+            task.score.map_or("-".to_string(), |score| format!("{:.2}", score)),
+            task.outcome(),
+            task.duration_secs
+        );
+        if let Some(error) = &task.error {
+            println!("  {}", error);
+        }
+    }
+    println!(
+        "\n{} passed, {} failed, mean score {:.2}",
+        report.passed, report.failed, report.mean_score
+    );
+}
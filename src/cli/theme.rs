@@ -0,0 +1,206 @@
+//! Color themes of the TUI: the built-in `dark`, `light` and `solarized`, and palettes of the
+//! configuration file.
+//!
+//! ```toml
+//! theme = "mine"
+//!
+//! [themes.mine]
+//! base = "light"
+//! accent = "#005f87"
+//! selection_bg = "lightblue"
+//! ```
+//!
+//! Colors are names (`red`, `lightblue`, ...), `#rrggbb` or indexes of the terminal's palette.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+
+/// Colors of the TUI, by their role.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    /// Headers of the screens
+    pub title: Color,
+    /// Labels, sandbox IDs and the output of trajectories
+    pub accent: Color,
+    /// Help lines and instructions
+    pub hint: Color,
+    /// Timestamps and unfocused borders
+    pub muted: Color,
+    /// Text being typed, status messages and the focused border
+    pub input: Color,
+    /// Commands of trajectories and sessions
+    pub command: Color,
+    pub success: Color,
+    /// Exit codes and warnings
+    pub warning: Color,
+    pub error: Color,
+    /// Session separators and the memory sparkline
+    pub emphasis: Color,
+    /// Directories of the file browser
+    pub directory: Color,
+    pub selection_fg: Color,
+    pub selection_bg: Color,
+    /// The search match jumped to, and the others
+    pub match_fg: Color,
+    pub match_bg: Color,
+    pub other_match_bg: Color,
+    /// syntect theme of file previews
+    pub syntax: String,
+}
+
+/// Theme of the configuration file, each color replacing the one of `base`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Palette {
+    /// Built-in theme the palette starts from, `dark` by default
+    pub base: Option<String>,
+    pub title: Option<String>,
+    pub accent: Option<String>,
+    pub hint: Option<String>,
+    pub muted: Option<String>,
+    pub input: Option<String>,
+    pub command: Option<String>,
+    pub success: Option<String>,
+    pub warning: Option<String>,
+    pub error: Option<String>,
+    pub emphasis: Option<String>,
+    pub directory: Option<String>,
+    pub selection_fg: Option<String>,
+    pub selection_bg: Option<String>,
+    pub match_fg: Option<String>,
+    pub match_bg: Option<String>,
+    pub other_match_bg: Option<String>,
+    pub syntax: Option<String>,
+}
+
+impl Theme {
+    /// The colors the TUI always had, for dark terminals.
+    pub fn dark() -> Self {
+        Self {
+            title: Color::Cyan,
+            accent: Color::Cyan,
+            hint: Color::Gray,
+            muted: Color::DarkGray,
+            input: Color::Yellow,
+            command: Color::Green,
+            success: Color::Green,
+            warning: Color::Yellow,
+            error: Color::Red,
+            emphasis: Color::Magenta,
+            directory: Color::Blue,
+            selection_fg: Color::White,
+            selection_bg: Color::Blue,
+            match_fg: Color::Black,
+            match_bg: Color::Yellow,
+            other_match_bg: Color::DarkGray,
+            syntax: "base16-ocean.dark".to_string(),
+        }
+    }
+
+    /// Dark colors, readable on white.
+    pub fn light() -> Self {
+        Self {
+            title: Color::Blue,
+            accent: Color::Rgb(0x00, 0x5f, 0x87),
+            hint: Color::DarkGray,
+            muted: Color::Gray,
+            input: Color::Rgb(0x87, 0x5f, 0x00),
+            command: Color::Rgb(0x00, 0x6f, 0x00),
+            success: Color::Rgb(0x00, 0x6f, 0x00),
+            warning: Color::Rgb(0xaf, 0x5f, 0x00),
+            error: Color::Rgb(0xaf, 0x00, 0x00),
+            emphasis: Color::Rgb(0x87, 0x00, 0x87),
+            directory: Color::Blue,
+            selection_fg: Color::White,
+            selection_bg: Color::Blue,
+            match_fg: Color::Black,
+            match_bg: Color::Rgb(0xff, 0xd7, 0x5f),
+            other_match_bg: Color::Rgb(0xe4, 0xe4, 0xe4),
+            syntax: "InspiredGitHub".to_string(),
+        }
+    }
+
+    /// The accents of Solarized, readable on its dark and light backgrounds.
+    pub fn solarized() -> Self {
+        Self {
+            title: Color::Rgb(0x26, 0x8b, 0xd2),
+            accent: Color::Rgb(0x2a, 0xa1, 0x98),
+            hint: Color::Rgb(0x83, 0x94, 0x96),
+            muted: Color::Rgb(0x58, 0x6e, 0x75),
+            input: Color::Rgb(0xb5, 0x89, 0x00),
+            command: Color::Rgb(0x85, 0x99, 0x00),
+            success: Color::Rgb(0x85, 0x99, 0x00),
+            warning: Color::Rgb(0xcb, 0x4b, 0x16),
+            error: Color::Rgb(0xdc, 0x32, 0x2f),
+            emphasis: Color::Rgb(0xd3, 0x36, 0x82),
+            directory: Color::Rgb(0x6c, 0x71, 0xc4),
+            selection_fg: Color::Rgb(0xfd, 0xf6, 0xe3),
+            selection_bg: Color::Rgb(0x26, 0x8b, 0xd2),
+            match_fg: Color::Rgb(0x00, 0x2b, 0x36),
+            match_bg: Color::Rgb(0xb5, 0x89, 0x00),
+            other_match_bg: Color::Rgb(0x58, 0x6e, 0x75),
+            syntax: "Solarized (dark)".to_string(),
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "solarized" => Some(Self::solarized()),
+            _ => None,
+        }
+    }
+
+    /// Theme `name`, a palette of `palettes` or a built-in one.
+    pub fn load(name: &str, palettes: &HashMap<String, Palette>) -> Result<Self> {
+        let Some(palette) = palettes.get(name) else {
+            return Self::builtin(name).with_context(|| format!("No theme named {}, expected dark, light, solarized or one of [themes]", name));
+        };
+        let base = palette.base.as_deref().unwrap_or("dark");
+        let mut theme = Self::builtin(base).with_context(|| format!("Theme {} is based on {}, not a built-in theme", name, base))?;
+        for (color, value) in [
+            (&mut theme.title, &palette.title),
+            (&mut theme.accent, &palette.accent),
+            (&mut theme.hint, &palette.hint),
+            (&mut theme.muted, &palette.muted),
+            (&mut theme.input, &palette.input),
+            (&mut theme.command, &palette.command),
+            (&mut theme.success, &palette.success),
+            (&mut theme.warning, &palette.warning),
+            (&mut theme.error, &palette.error),
+            (&mut theme.emphasis, &palette.emphasis),
+            (&mut theme.directory, &palette.directory),
+            (&mut theme.selection_fg, &palette.selection_fg),
+            (&mut theme.selection_bg, &palette.selection_bg),
+            (&mut theme.match_fg, &palette.match_fg),
+            (&mut theme.match_bg, &palette.match_bg),
+            (&mut theme.other_match_bg, &palette.other_match_bg),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            *color = match Color::from_str(value) {
+                Ok(parsed) => parsed,
+                Err(_) => bail!("Invalid color {} in theme {}", value, name),
+            };
+        }
+        if let Some(syntax) = &palette.syntax {
+            theme.syntax = syntax.clone();
+        }
+        Ok(theme)
+    }
+
+    /// Headers of the screens.
+    pub fn header(&self) -> Style {
+        Style::default().fg(self.title).add_modifier(Modifier::BOLD)
+    }
+
+    /// The selected entry of lists.
+    pub fn selected(&self) -> Style {
+        Style::default().bg(self.selection_bg).fg(self.selection_fg)
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::VecDeque;
 use std::io;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use futures::{FutureExt, SinkExt, StreamExt};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers, MouseEvent, MouseEventKind},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -13,8 +17,19 @@ use ratatui::{
     backend::CrosstermBackend,
     Terminal,
 };
-use serde_json::Value;
-use sos::http::{CreatePayload, ExecPayload, SandboxInfo, StopPayload};
+use rustyline::history::{DefaultHistory, History};
+use serde::Serialize;
+use sos::api::{CreatePayload, ExecPayload, ExecResponse, LogSource, SandboxDetail, SandboxInfo, ServerEvent, ServerEventKind, Template, TrajectoryEntry};
+use sos::client::SosClient;
+use sos::sandbox::{ResourceUsage, TerminalSize};
+use syntect::{easy::HighlightLines, highlighting::ThemeSet, parsing::SyntaxSet, util::LinesWithEndings};
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::keymap::Keymap;
+use crate::session;
+use crate::theme::Theme;
 
 #[derive(Debug, Clone)]
 enum AppScreen {
@@ -22,6 +37,9 @@ enum AppScreen {
     SandboxDetail(String), // sandbox ID
     NewSandbox,
     SandboxSession(String), // sandbox ID
+    FileBrowser(String), // sandbox ID
+    Terminal(String), // sandbox ID
+    Events,
 }
 
 #[derive(Debug, Clone)]
@@ -29,10 +47,931 @@ struct SandboxDetailState {
     trajectory: String,
     formatted: bool,
     scroll_offset: usize,
+    /// Search query or step typed after `/` or `:`, or the path of the export after `e`
+    prompt: Option<(char, String)>,
+    /// Last search, lowercased
+    search: String,
+    export_format: ExportFormat,
+    /// Details of the sandbox shown in the sidebar, when they could be loaded
+    metadata: Option<SandboxDetail>,
+}
+
+/// Formats the detail screen exports the trajectory in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    Formatted,
+    Json,
+    Jsonl,
+    Cast,
+}
+
+impl ExportFormat {
+    fn next(self) -> Self {
+        match self {
+            ExportFormat::Formatted => ExportFormat::Json,
+            ExportFormat::Json => ExportFormat::Jsonl,
+            ExportFormat::Jsonl => ExportFormat::Cast,
+            ExportFormat::Cast => ExportFormat::Formatted,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Formatted => "txt",
+            ExportFormat::Json => "json",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Cast => "cast",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Formatted => "formatted text",
+            ExportFormat::Json => "JSON",
+            ExportFormat::Jsonl => "JSON Lines",
+            ExportFormat::Cast => "asciicast",
+        }
+    }
+}
+
+impl SandboxDetailState {
+    /// Lines matching the search.
+    fn search_matches(&self) -> Vec<usize> {
+        if self.search.is_empty() {
+            return Vec::new();
+        }
+        self.trajectory
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.to_lowercase().contains(&self.search))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Whether `line` starts a step: its command, or its index in the raw JSON.
+    fn is_step_line(&self, line: &str) -> bool {
+        match self.formatted {
+            true => line.starts_with("$ "),
+            false => line.trim_start().starts_with("\"index\": "),
+        }
+    }
+
+    /// Line of step `step`, counted from 1.
+    fn step_line(&self, step: usize) -> Option<usize> {
+        self.trajectory
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| self.is_step_line(line))
+            .nth(step.checked_sub(1)?)
+            .map(|(i, _)| i)
+    }
+
+    /// Step shown at the top line, counted from 1: the last one starting at or above it.
+    fn step_at(&self, line: usize) -> usize {
+        let steps = self.trajectory.lines().take(line + 1).filter(|line| self.is_step_line(line)).count();
+        steps.max(1)
+    }
+
+    /// Scrolls to the next match of the search after the top line, or the previous one
+    /// before it, wrapping around. Returns the status message.
+    fn jump_to_match(&mut self, forward: bool, include_current: bool) -> String {
+        let matches = self.search_matches();
+        let current = self.scroll_offset;
+        let next = match forward {
+            true => matches.iter().position(|&line| line > current || (include_current && line == current)).or((!matches.is_empty()).then_some(0)),
+            false => matches.iter().rposition(|&line| line < current).or(matches.len().checked_sub(1)),
+        };
+        match next {
+            Some(i) => {
+                self.scroll_offset = matches[i];
+                format!("Match {}/{} for \"{}\"", i + 1, matches.len(), self.search)
+            }
+            None => format!("Pattern not found: {}", self.search),
+        }
+    }
+}
+
+/// Bindings listed by the help, as keys of the built-in keymap and what they do.
+type KeyHelp = &'static [(&'static [&'static str], &'static str)];
+
+const SCROLL_HELP: KeyHelp = &[
+    (&["up", "k", "down", "j"], "Scroll"),
+    (&["g"], "Top, pressed twice"),
+    (&["G"], "Bottom"),
+    (&["ctrl-u", "ctrl-d"], "Half page up/down"),
+];
+
+/// Scrolling of the session screen, whose other keys go to the command line.
+const SESSION_SCROLL_HELP: KeyHelp = &[
+    (&["ctrl-u", "ctrl-d"], "Half page up/down"),
+];
+
+const GLOBAL_HELP: KeyHelp = &[
+    (&["f1"], "Toggle mouse / text selection"),
+    (&["ctrl-c"], "Copy the content of the screen"),
+    (&["?"], "Toggle this help"),
+];
+
+impl AppScreen {
+    /// Bindings of the screen, besides the scroll and global ones.
+    fn key_help(&self) -> KeyHelp {
+        match self {
+            AppScreen::SandboxList => &[
+                (&["enter"], "View details"),
+                (&["/"], "Search"),
+                (&["f"], "Cycle the status filter"),
+                (&["esc"], "Clear the filter"),
+                (&["n"], "New sandbox"),
+                (&["e"], "Server events"),
+                (&["r"], "Refresh"),
+                (&["q"], "Quit"),
+            ],
+            AppScreen::SandboxDetail(_) => &[
+                (&["/"], "Search"),
+                (&["n", "N"], "Next/previous match"),
+                (&[":"], "Go to step"),
+                (&["t"], "Toggle format"),
+                (&["o"], "Open the output of the step at the top"),
+                (&["c", "y"], "Copy the command/output of the step at the top"),
+                (&["e"], "Export to a file, Tab cycling the format"),
+                (&["s"], "Start session"),
+                (&["a"], "Attach terminal"),
+                (&["f"], "Browse files"),
+                (&["x"], "Stop, removing or keeping the sandbox"),
+                (&["esc", "q"], "Back"),
+            ],
+            AppScreen::NewSandbox => &[
+                (&["up", "k", "down", "j"], "Pick a template"),
+                (&["enter"], "Next step / create"),
+                (&["esc"], "Cancel"),
+            ],
+            AppScreen::SandboxSession(_) => &[
+                (&["enter"], "Run the command"),
+                (&["up", "down"], "Previous/next command"),
+                (&["ctrl-r"], "Search the commands entered"),
+                (&["ctrl-o"], "Open the output of the command at the top"),
+                (&["ctrl-v"], "Open a session beside"),
+                (&["tab"], "Switch pane"),
+                (&["ctrl-w"], "Close pane"),
+                (&["ctrl-t"], "Terminal"),
+                (&["esc"], "Exit session"),
+            ],
+            AppScreen::FileBrowser(_) => &[
+                (&["enter", "l", "right"], "Open"),
+                (&["h", "left", "backspace"], "Parent directory / close preview"),
+                (&["d"], "Download"),
+                (&["r"], "Refresh"),
+                (&["esc", "q"], "Back"),
+            ],
+            AppScreen::Terminal(_) => &[
+                (&["ctrl-]"], "Detach"),
+            ],
+            AppScreen::Events => &[
+                (&["/"], "Filter by sandbox"),
+                (&["c"], "Clear"),
+                (&["esc", "q"], "Back"),
+            ],
+        }
+    }
+}
+
+/// Time between the checks of a reachable server.
+const CONNECTION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest wait between the retries of an unreachable server, the wait doubling from a second.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Reachability of the server, as shown by the status bar.
+#[derive(Debug, Clone)]
+enum ConnectionState {
+    Connecting,
+    /// Reachable, with the tenant of the credentials
+    Connected(String),
+    /// Unreachable or rejecting the credentials, checked again at `retry_at`
+    Down { error: String, retry_at: Instant },
+}
+
+/// Checks of the server in the background, through `/whoami`, retried with backoff while
+/// it is down. Picked up on the next tick.
+struct Connection {
+    server: String,
+    state: ConnectionState,
+    updates: mpsc::UnboundedReceiver<ConnectionState>,
+    /// Wakes the checker up before its next check
+    recheck: Arc<Notify>,
+    checker: JoinHandle<()>,
+}
+
+impl Connection {
+    fn start(client: SosClient) -> Self {
+        let (sender, updates) = mpsc::unbounded_channel();
+        let server = client.base_url().to_string();
+        let recheck = Arc::new(Notify::new());
+        let notified = recheck.clone();
+        let checker = tokio::spawn(async move {
+            let mut backoff = Duration::from_secs(1);
+            while !sender.is_closed() {
+                let wait = match client.whoami().await {
+                    Ok(tenant) => {
+                        backoff = Duration::from_secs(1);
+                        let _ = sender.send(ConnectionState::Connected(tenant));
+                        CONNECTION_INTERVAL
+                    }
+                    Err(error) => {
+                        let wait = backoff;
+                        backoff = (backoff * 2).min(MAX_RETRY_INTERVAL);
+                        let _ = sender.send(ConnectionState::Down { error: error.to_string(), retry_at: Instant::now() + wait });
+                        wait
+                    }
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = notified.notified() => {}
+                }
+            }
+        });
+        Self {
+            server,
+            state: ConnectionState::Connecting,
+            updates,
+            recheck,
+            checker,
+        }
+    }
+
+    /// Takes in the checks that finished since the last tick. Returns whether the server
+    /// came back.
+    fn tick(&mut self) -> bool {
+        let mut reconnected = false;
+        while let Ok(state) = self.updates.try_recv() {
+            reconnected |= self.is_down() && matches!(state, ConnectionState::Connected(_));
+            self.state = state;
+        }
+        reconnected
+    }
+
+    fn is_down(&self) -> bool {
+        matches!(self.state, ConnectionState::Down { .. })
+    }
+
+    /// Checks the server right away after a request failed to reach it. Returns whether the
+    /// error is one of the connection, shown by the status bar rather than by the screen.
+    fn report(&self, error: &sos::client::ClientError) -> bool {
+        if error.is_connect() {
+            self.recheck.notify_one();
+        }
+        error.is_connect()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.checker.abort();
+    }
+}
+
+/// Time within which a second click on a row opens it.
+const DOUBLE_CLICK_INTERVAL: Duration = Duration::from_millis(400);
+
+/// What can be clicked on the last frame drawn.
+#[derive(Debug, Default)]
+struct ClickTargets {
+    /// Rows of the list of the screen, inside its borders, with the index of the first one
+    list: Option<(Rect, usize)>,
+    /// Panes of the session screen, in their order
+    panes: Vec<Rect>,
+}
+
+/// Whether the cell at `column` and `row` is inside `area`.
+fn hit(area: Rect, column: u16, row: u16) -> bool {
+    (area.x..area.right()).contains(&column) && (area.y..area.bottom()).contains(&row)
+}
+
+/// Width of the metadata sidebar of the detail screen.
+const METADATA_WIDTH: u16 = 36;
+
+/// Samples kept for the sparklines of the resource pane.
+const RESOURCE_HISTORY: usize = 120;
+
+/// Time between the stats samples of the resource pane.
+const RESOURCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resource usage of the sandbox of the detail screen. Samples are taken in the background,
+/// the stats endpoint being too slow to call on the tick, and picked up on the next tick.
+struct ResourceMonitor {
+    samples: mpsc::UnboundedReceiver<ResourceUsage>,
+    sampler: JoinHandle<()>,
+    last: Option<ResourceUsage>,
+    /// CPU in tenths of a percent, for the sparkline
+    cpu: VecDeque<u64>,
+    memory: VecDeque<u64>,
+    /// Bytes received and sent since the previous sample
+    network: VecDeque<u64>,
+}
+
+impl ResourceMonitor {
+    fn start(client: SosClient, sandbox_id: String) -> Self {
+        let (sender, samples) = mpsc::unbounded_channel();
+        let sampler = tokio::spawn(async move {
+            while !sender.is_closed() {
+                // Stopped sandboxes have no stats, the pane keeps the last sample
+                if let Ok(usage) = client.stats(&sandbox_id).await {
+                    let _ = sender.send(usage);
+                }
+                tokio::time::sleep(RESOURCE_INTERVAL).await;
+            }
+        });
+        Self {
+            samples,
+            sampler,
+            last: None,
+            cpu: VecDeque::new(),
+            memory: VecDeque::new(),
+            network: VecDeque::new(),
+        }
+    }
+
+    /// Takes in the samples that arrived since the last tick.
+    fn tick(&mut self) {
+        while let Ok(usage) = self.samples.try_recv() {
+            let network = match &self.last {
+                Some(last) => (usage.network_rx_bytes + usage.network_tx_bytes)
+                    .saturating_sub(last.network_rx_bytes + last.network_tx_bytes),
+                None => 0,
+            };
+            for (history, value) in [
+                (&mut self.cpu, (usage.cpu_percent * 10.0) as u64),
+                (&mut self.memory, usage.memory_usage_bytes),
+                (&mut self.network, network),
+            ] {
+                if history.len() == RESOURCE_HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(value);
+            }
+            self.last = Some(usage);
+        }
+    }
+}
+
+impl Drop for ResourceMonitor {
+    fn drop(&mut self) {
+        self.sampler.abort();
+    }
+}
+
+/// Events kept by the events screen.
+const EVENT_HISTORY: usize = 1000;
+
+/// Events of the server's `/events` stream, tailed by the events screen.
+struct EventLog {
+    events: VecDeque<ServerEvent>,
+    /// Events as they come, or why the stream ended
+    receiver: mpsc::UnboundedReceiver<Result<ServerEvent, String>>,
+    tail: JoinHandle<()>,
+    error: Option<String>,
+    /// Sandbox ID prefix the events are filtered by
+    filter: String,
+    /// Whether keys are typed into the filter
+    editing: bool,
+    /// Whether the view follows the latest events, or stays at `scroll_offset`
+    follow: bool,
+    scroll_offset: usize,
+}
+
+impl EventLog {
+    fn start(client: SosClient) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let tail = tokio::spawn(async move {
+            let result = async {
+                let mut events = Box::pin(client.events().await?);
+                while let Some(event) = events.next().await {
+                    if sender.send(Ok(event?)).is_err() {
+                        break;
+                    }
+                }
+                Ok::<_, sos::client::ClientError>(())
+            }
+            .await;
+            let _ = sender.send(Err(match result {
+                Ok(()) => "The events stream ended".to_string(),
+                Err(error) => format!("The events stream failed: {}", error),
+            }));
+        });
+        Self {
+            events: VecDeque::new(),
+            receiver,
+            tail,
+            error: None,
+            filter: String::new(),
+            editing: false,
+            follow: true,
+            scroll_offset: 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        while let Ok(event) = self.receiver.try_recv() {
+            match event {
+                Ok(event) => {
+                    if self.events.len() == EVENT_HISTORY {
+                        self.events.pop_front();
+                        self.scroll_offset = self.scroll_offset.saturating_sub(1);
+                    }
+                    self.events.push_back(event);
+                }
+                Err(error) => self.error = Some(error),
+            }
+        }
+    }
+
+    /// Events passing the filter.
+    fn visible(&self) -> Vec<&ServerEvent> {
+        self.events.iter().filter(|event| event.sandbox_id.starts_with(&self.filter)).collect()
+    }
+
+    /// Scrolls `lines` down, or up when negative. Scrolling past the last events follows
+    /// them again.
+    fn scroll(&mut self, lines: isize, viewport_height: usize) {
+        let last = self.visible().len().saturating_sub(viewport_height);
+        let current = match self.follow {
+            true => last,
+            false => self.scroll_offset,
+        };
+        let target = current.saturating_add_signed(lines);
+        self.follow = target >= last;
+        self.scroll_offset = target.min(last);
+    }
+
+    /// Line of an event, colored by its severity.
+    fn event_line(theme: &Theme, event: &ServerEvent) -> Line<'static> {
+        let (severity, color) = match event.kind {
+            ServerEventKind::StartFailed | ServerEventKind::TimedOut => ("ERROR", theme.error),
+            // Without an exit code, the exec itself failed
+            ServerEventKind::ExecFinished if event.exit_code.is_none() => ("ERROR", theme.error),
+            ServerEventKind::ExecFinished if event.exit_code != Some(0) => ("WARN", theme.warning),
+            ServerEventKind::Queued | ServerEventKind::Stopped | ServerEventKind::Removed | ServerEventKind::Frozen => ("WARN", theme.warning),
+            ServerEventKind::ExecStarted | ServerEventKind::Pulling => ("DEBUG", theme.hint),
+            _ => ("INFO", theme.success),
+        };
+        let mut details = Vec::new();
+        if let Some(command) = &event.command {
+            details.push(format!("$ {}", command));
+        }
+        if let Some(exit_code) = event.exit_code {
+            details.push(format!("exit code {}", exit_code));
+        }
+        if let Some(progress) = &event.progress {
+            let percent = progress.percent().map_or(String::new(), |percent| format!(" ({:.0}%)", percent));
+            details.push(format!("{}/{} layers{}", progress.layers_done, progress.layers, percent));
+        }
+        if let Some(error) = &event.error {
+            details.push(error.clone());
+        }
+        Line::from(vec![
+            Span::styled(event.timestamp.with_timezone(&chrono::Local).format("%H:%M:%S ").to_string(), Style::default().fg(theme.muted)),
+            Span::styled(format!("{:<6}", severity), Style::default().fg(color).add_modifier(Modifier::BOLD)),
+            Span::styled(format!("{:<15}", event.kind.as_str()), Style::default().fg(color)),
+            Span::styled(format!("{:<9}", &event.sandbox_id[..8.min(event.sandbox_id.len())]), Style::default().fg(theme.accent)),
+            Span::raw(details.join(" | ")),
+        ])
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        self.tail.abort();
+    }
+}
+
+/// Lines of the scrollback of the terminal screen.
+const TERMINAL_SCROLLBACK: usize = 1000;
+
+/// Interactive shell of a sandbox attached over the WebSocket of `/sandboxes/{id}/attach`,
+/// its output run through a VT100 emulator so that full-screen programs render.
+struct TerminalPane {
+    parser: vt100::Parser,
+    input: mpsc::UnboundedSender<Message>,
+    output: mpsc::UnboundedReceiver<Vec<u8>>,
+    bridge: JoinHandle<()>,
+    /// Screen the terminal was opened from, returned to when it closes
+    previous: AppScreen,
+    closed: bool,
+}
+
+impl TerminalPane {
+    async fn start(client: &SosClient, sandbox_id: &str, size: TerminalSize, previous: AppScreen) -> sos::client::Result<Self> {
+        let (mut socket_sender, mut socket_receiver) = client.attach(sandbox_id, Some(size)).await?.split();
+        let (input, mut inputs) = mpsc::unbounded_channel::<Message>();
+        let (sender, output) = mpsc::unbounded_channel();
+        // Ends with the socket, or when the pane is dropped
+        let bridge = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    message = socket_receiver.next() => match message {
+                        Some(Ok(Message::Binary(bytes))) => {
+                            if sender.send(bytes.to_vec()).is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    },
+                    message = inputs.recv() => match message {
+                        Some(message) => {
+                            if socket_sender.send(message).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => {
+                            let _ = socket_sender.send(Message::Close(None)).await;
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+        Ok(Self {
+            parser: vt100::Parser::new(size.rows, size.cols, TERMINAL_SCROLLBACK),
+            input,
+            output,
+            bridge,
+            previous,
+            closed: false,
+        })
+    }
+
+    /// Feeds the output received since the last tick to the emulator.
+    fn tick(&mut self) {
+        loop {
+            match self.output.try_recv() {
+                Ok(bytes) => self.parser.process(&bytes),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Follows the size of the pane, resizing the remote terminal.
+    fn resize(&mut self, rows: u16, cols: u16) {
+        if (rows, cols) == self.parser.screen().size() || rows == 0 || cols == 0 {
+            return;
+        }
+        self.parser.set_size(rows, cols);
+        if let Ok(resize) = serde_json::to_string(&TerminalSize { cols, rows }) {
+            let _ = self.input.send(Message::Text(resize.into()));
+        }
+    }
+
+    fn send(&mut self, bytes: Vec<u8>) {
+        // Typing brings the screen back from the scrollback
+        self.parser.set_scrollback(0);
+        let _ = self.input.send(Message::Binary(bytes.into()));
+    }
+
+    /// Scrolls through the scrollback, `lines` up when positive.
+    fn scroll(&mut self, lines: isize) {
+        let current = self.parser.screen().scrollback();
+        self.parser.set_scrollback(current.saturating_add_signed(lines));
+    }
+
+    /// Bytes a terminal sends for the key, if any.
+    fn key_bytes(&self, key: event::KeyEvent) -> Option<Vec<u8>> {
+        // Arrows differ in the application cursor mode of full-screen programs
+        let arrow = |code: u8| match self.parser.screen().application_cursor() {
+            true => vec![0x1b, b'O', code],
+            false => vec![0x1b, b'[', code],
+        };
+        let bytes = match key.code {
+            KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => match c {
+                'a'..='z' => vec![c as u8 - b'a' + 1],
+                '@' | ' ' => vec![0],
+                '[' => vec![0x1b],
+                '\\' => vec![0x1c],
+                '^' => vec![0x1e],
+                '_' => vec![0x1f],
+                _ => return None,
+            },
+            KeyCode::Char(c) => {
+                let mut bytes = Vec::new();
+                if key.modifiers.contains(KeyModifiers::ALT) {
+                    bytes.push(0x1b);
+                }
+                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                bytes
+            }
+            KeyCode::Enter => vec![b'\r'],
+            KeyCode::Backspace => vec![0x7f],
+            KeyCode::Tab => vec![b'\t'],
+            KeyCode::BackTab => b"\x1b[Z".to_vec(),
+            KeyCode::Esc => vec![0x1b],
+            KeyCode::Up => arrow(b'A'),
+            KeyCode::Down => arrow(b'B'),
+            KeyCode::Right => arrow(b'C'),
+            KeyCode::Left => arrow(b'D'),
+            KeyCode::Home => arrow(b'H'),
+            KeyCode::End => arrow(b'F'),
+            KeyCode::Insert => b"\x1b[2~".to_vec(),
+            KeyCode::Delete => b"\x1b[3~".to_vec(),
+            KeyCode::PageUp => b"\x1b[5~".to_vec(),
+            KeyCode::PageDown => b"\x1b[6~".to_vec(),
+            _ => return None,
+        };
+        Some(bytes)
+    }
+
+    /// Lines of the screen, with the colors and attributes of its cells.
+    fn lines(&self) -> Vec<Line<'static>> {
+        let screen = self.parser.screen();
+        let (rows, cols) = screen.size();
+        (0..rows)
+            .map(|row| {
+                let spans: Vec<Span> = (0..cols)
+                    .filter_map(|col| screen.cell(row, col))
+                    // The second half of a wide character is empty, the first covering it
+                    .filter(|cell| !cell.is_wide_continuation())
+                    .map(|cell| {
+                        let contents = match cell.has_contents() {
+                            true => cell.contents(),
+                            false => " ".to_string(),
+                        };
+                        Span::styled(contents, Self::cell_style(cell))
+                    })
+                    .collect();
+                Line::from(spans)
+            })
+            .collect()
+    }
+
+    fn cell_style(cell: &vt100::Cell) -> Style {
+        let color = |color| match color {
+            vt100::Color::Default => Color::Reset,
+            vt100::Color::Idx(index) => Color::Indexed(index),
+            vt100::Color::Rgb(r, g, b) => Color::Rgb(r, g, b),
+        };
+        let mut style = Style::default().fg(color(cell.fgcolor())).bg(color(cell.bgcolor()));
+        if cell.bold() {
+            style = style.add_modifier(Modifier::BOLD);
+        }
+        if cell.italic() {
+            style = style.add_modifier(Modifier::ITALIC);
+        }
+        if cell.underline() {
+            style = style.add_modifier(Modifier::UNDERLINED);
+        }
+        if cell.inverse() {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        style
+    }
+}
+
+impl Drop for TerminalPane {
+    fn drop(&mut self) {
+        self.bridge.abort();
+    }
+}
+
+/// Bytes of a file read for its preview.
+const PREVIEW_BYTES: usize = 64 * 1024;
+
+lazy_static::lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
+}
+
+#[derive(Debug, Clone)]
+struct FileEntry {
+    name: String,
+    dir: bool,
+}
+
+/// Directory of the sandbox shown by the file browser, listed with `ls` as there is no
+/// listing endpoint.
+#[derive(Debug, Clone)]
+struct FileBrowserState {
+    /// Absolute path of the directory
+    path: String,
+    entries: Vec<FileEntry>,
+    selected: usize,
+    scroll_offset: usize,
+    /// File shown in place of the listing
+    preview: Option<FilePreview>,
+}
+
+#[derive(Debug, Clone)]
+struct FilePreview {
+    path: String,
+    lines: Vec<Line<'static>>,
+    scroll_offset: usize,
+}
+
+impl FileBrowserState {
+    /// Position of the cursor, the scroll offset of the preview or the selected entry.
+    fn position(&mut self) -> &mut usize {
+        match &mut self.preview {
+            Some(preview) => &mut preview.scroll_offset,
+            None => &mut self.selected,
+        }
+    }
+
+    fn last_position(&self, viewport_height: usize) -> usize {
+        match &self.preview {
+            Some(preview) => preview.lines.len().saturating_sub(viewport_height),
+            None => self.entries.len().saturating_sub(1),
+        }
+    }
+
+    /// Path of `name` in the directory.
+    fn child(&self, name: &str) -> String {
+        match name {
+            ".." => match self.path.trim_end_matches('/').rfind('/') {
+                Some(0) | None => "/".to_string(),
+                Some(i) => self.path[..i].to_string(),
+            },
+            _ => format!("{}/{}", self.path.trim_end_matches('/'), name),
+        }
+    }
+}
+
+/// Quotes a path for the shell.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+/// `1h 02m`, `3m 05s` or `12s`.
+fn format_uptime(duration: chrono::TimeDelta) -> String {
+    let secs = duration.num_seconds().max(0);
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+/// Lines of the file `path`, highlighted by the syntax of its name or first line in the syntect
+/// theme `theme`. Files of unknown syntax are plain.
+fn highlight(path: &str, content: &str, theme: &str) -> Vec<Line<'static>> {
+    let content = content.replace('\t', "    ");
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let syntax = name
+        .rsplit_once('.')
+        .and_then(|(_, extension)| SYNTAXES.find_syntax_by_extension(extension))
+        .or_else(|| SYNTAXES.find_syntax_by_extension(name))
+        .or_else(|| SYNTAXES.find_syntax_by_first_line(content.lines().next().unwrap_or("")));
+    let Some(syntax) = syntax else {
+        return content.lines().map(|line| Line::from(line.to_string())).collect();
+    };
+    // Unknown themes of the configuration fall back to the default one
+    let theme = THEMES.themes.get(theme).unwrap_or(&THEMES.themes["base16-ocean.dark"]);
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    LinesWithEndings::from(&content)
+        .map(|line| match highlighter.highlight_line(line, &SYNTAXES) {
+            Ok(ranges) => Line::from(
+                ranges
+                    .into_iter()
+                    .map(|(style, text)| {
+                        let color = Color::Rgb(style.foreground.r, style.foreground.g, style.foreground.b);
+                        Span::styled(text.trim_end_matches(['\n', '\r']).to_string(), Style::default().fg(color))
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(_) => Line::from(line.trim_end_matches(['\n', '\r']).to_string()),
+        })
+        .collect()
+}
+
+/// Time a command of the session screen may run before it is interrupted.
+const COMMAND_TIMEOUT_SECS: u64 = 600;
+
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+lazy_static::lazy_static! {
+    /// Markers of the session shell in its raw output, see `sos::sandbox::shell::Markers`
+    static ref SHELL_MARKER: regex::Regex =
+        regex::Regex::new(r"#(PS1|PS2|EXIT|ERR)-[0-9a-f]{32}#:(\d+:)?").expect("Invalid marker regex");
+}
+
+/// Command of the session screen waiting for its response. Its output is followed in the
+/// raw session log meanwhile, shown until the response replaces it.
+struct RunningCommand {
+    command: String,
+    started: Instant,
+    exec: JoinHandle<sos::client::Result<ExecResponse>>,
+    output: mpsc::UnboundedReceiver<bytes::Bytes>,
+    follower: JoinHandle<()>,
+    lines: Vec<String>,
+    /// Output after the last complete line
+    partial: Vec<u8>,
+}
+
+impl RunningCommand {
+    async fn start(client: SosClient, sandbox_id: String, command: String) -> Self {
+        let (sender, output) = mpsc::unbounded_channel();
+        // The follow stream replays the log first, the output of the previous commands
+        let logs = async {
+            let mut backlog = Box::pin(client.logs(&sandbox_id, LogSource::Session, false).await?);
+            let mut skip = 0;
+            while let Some(chunk) = backlog.next().await {
+                skip += chunk?.len();
+            }
+            let logs = client.logs(&sandbox_id, LogSource::Session, true).await?;
+            Ok::<_, sos::client::ClientError>((skip, logs))
+        }
+        .await;
+        // Without the log the response still comes, the command just shows no output until then
+        let follower = tokio::spawn(async move {
+            let Ok((mut skip, logs)) = logs else {
+                return;
+            };
+            let mut logs = Box::pin(logs);
+            while let Some(Ok(mut chunk)) = logs.next().await {
+                let skipped = skip.min(chunk.len());
+                skip -= skipped;
+                let _ = chunk.split_to(skipped);
+                if !chunk.is_empty() && sender.send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        let payload = ExecPayload {
+            command: command.clone(),
+            standalone: None,
+            raw: None,
+            timeout_secs: Some(COMMAND_TIMEOUT_SECS),
+        };
+        let exec = tokio::spawn(async move { client.exec_with(&sandbox_id, &payload).await });
+        Self {
+            command,
+            started: Instant::now(),
+            exec,
+            output,
+            follower,
+            lines: Vec::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    /// Picks up the output received since the last tick, and the response once it came.
+    fn tick(&mut self) -> Option<Result<ExecResponse>> {
+        while let Ok(chunk) = self.output.try_recv() {
+            self.partial.extend_from_slice(&chunk);
+        }
+        if let Some(end) = self.partial.iter().rposition(|byte| *byte == b'\n') {
+            let complete: Vec<u8> = self.partial.drain(..=end).collect();
+            let lines = complete.split(|byte| *byte == b'\n').filter_map(Self::clean_line);
+            self.lines.extend(lines);
+        }
+        match self.exec.is_finished() {
+            true => (&mut self.exec).now_or_never().map(|result| Ok(result??)),
+            false => None,
+        }
+    }
+
+    /// Line of raw session output as the terminal would show it. Prompt lines are dropped.
+    fn clean_line(raw: &[u8]) -> Option<String> {
+        let text = strip_ansi_escapes::strip_str(String::from_utf8_lossy(raw));
+        // What a carriage return did not overwrite, progress bars
+        let text = text.rsplit('\r').find(|part| !part.is_empty()).unwrap_or("");
+        let line = SHELL_MARKER.replace_all(text, "");
+        match line.is_empty() && SHELL_MARKER.is_match(text) {
+            true => None,
+            false => Some(line.into_owned()),
+        }
+    }
+
+    /// Lines shown for the command: the output so far, with a spinner.
+    fn display_lines(&self, theme: &Theme) -> Vec<Line<'static>> {
+        let elapsed = self.started.elapsed();
+        let frame = SPINNER[(elapsed.as_millis() / 100) as usize % SPINNER.len()];
+        let mut lines = vec![App::colorize_session_line(theme, &format!("$ {}", self.command))];
+        lines.extend(self.lines.iter().map(|line| App::colorize_session_line(theme, line)));
+        if let Some(partial) = Self::clean_line(&self.partial).filter(|line| !line.is_empty()) {
+            lines.push(App::colorize_session_line(theme, &partial));
+        }
+        lines.push(Line::from(format!("{} Running... {}s", frame, elapsed.as_secs())).style(Style::default().fg(theme.input)));
+        lines
+    }
+}
+
+impl Drop for RunningCommand {
+    fn drop(&mut self) {
+        self.follower.abort();
+        self.exec.abort();
+    }
 }
 
 #[derive(Debug, Clone)]
 struct NewSandboxState {
+    /// Templates of the server, offered before the manual entry
+    templates: Vec<Template>,
+    /// Entry selected in the picker, the manual entry being the first
+    selected_template: usize,
+    /// Template the sandbox is created from
+    template: Option<String>,
     image: String,
     setup_commands: Vec<String>,
     current_command: String,
@@ -43,52 +982,374 @@ struct NewSandboxState {
 
 #[derive(Debug, Clone)]
 enum NewSandboxStep {
+    PickTemplate,
     EnterImage,
     EnterSetupCommands,
     Creating,
     SessionReady,
 }
 
-#[derive(Debug, Clone)]
+/// Columns the pager scrolls sideways by.
+const PAGER_COLUMN_STEP: usize = 8;
+
+/// Full output of a command, opened in place of the detail or session screen.
+struct Pager {
+    title: String,
+    command: String,
+    lines: Vec<String>,
+    scroll_offset: usize,
+    /// First column shown, when not wrapping
+    column: usize,
+    wrap: bool,
+    /// Lines shown by the last frame, for paging
+    viewport_height: usize,
+}
+
+impl Pager {
+    fn new(title: String, command: String, output: &str) -> Self {
+        Self {
+            title,
+            command,
+            lines: output.lines().map(|line| line.to_string()).collect(),
+            scroll_offset: 0,
+            column: 0,
+            wrap: false,
+            viewport_height: 0,
+        }
+    }
+
+    fn scroll(&mut self, lines: isize) {
+        let last = self.lines.len().saturating_sub(self.viewport_height.max(1));
+        self.scroll_offset = self.scroll_offset.saturating_add_signed(lines).min(last);
+    }
+
+    fn scroll_sideways(&mut self, columns: isize) {
+        let widest = self.lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        self.column = self.column.saturating_add_signed(columns).min(widest.saturating_sub(1));
+    }
+
+    /// Handles a key, returning whether it closes the pager.
+    fn handle_key(&mut self, key: event::KeyEvent) -> bool {
+        let half_page = (self.viewport_height / 2).max(1) as isize;
+        match (key.code, key.modifiers) {
+            (KeyCode::Esc | KeyCode::Char('q'), _) => return true,
+            (KeyCode::Char('u'), KeyModifiers::CONTROL) | (KeyCode::PageUp, _) => self.scroll(-half_page),
+            (KeyCode::Char('d'), KeyModifiers::CONTROL) | (KeyCode::PageDown, _) => self.scroll(half_page),
+            (KeyCode::Up | KeyCode::Char('k'), _) => self.scroll(-1),
+            (KeyCode::Down | KeyCode::Char('j'), _) => self.scroll(1),
+            (KeyCode::Char('g') | KeyCode::Home, _) => self.scroll_offset = 0,
+            (KeyCode::Char('G') | KeyCode::End, _) => self.scroll(isize::MAX),
+            (KeyCode::Left | KeyCode::Char('h'), _) => self.scroll_sideways(-(PAGER_COLUMN_STEP as isize)),
+            (KeyCode::Right | KeyCode::Char('l'), _) if !self.wrap => self.scroll_sideways(PAGER_COLUMN_STEP as isize),
+            (KeyCode::Char('0'), _) => self.column = 0,
+            (KeyCode::Char('w'), _) => {
+                self.wrap = !self.wrap;
+                self.column = 0;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 struct SessionState {
     history: Vec<String>,
     current_input: String,
     scroll_offset: usize,
+    /// Commands entered before, recalled into `current_input`
+    inputs: InputHistory,
+}
+
+impl SessionState {
+    /// Command shown at the top line, the last one starting at or above it or else the
+    /// first, with the lines of its output.
+    fn command_at(&self, line: usize) -> Option<(&str, &[String])> {
+        let is_command = |line: &String| line.starts_with("$ ");
+        let start = self.history[..(line + 1).min(self.history.len())]
+            .iter()
+            .rposition(is_command)
+            .or_else(|| self.history.iter().position(is_command))?;
+        let output = &self.history[start + 1..];
+        let end = output.iter().position(|line| is_command(line) || line == "--- Continued session ---").unwrap_or(output.len());
+        Some((&self.history[start][2..], &output[..end]))
+    }
+}
+
+/// Commands entered in the sessions of a sandbox, oldest first, in the history file that
+/// `sos session` reads too. Up and Down recall them, Ctrl-R searches them.
+#[derive(Debug, Clone, Default)]
+struct InputHistory {
+    path: Option<PathBuf>,
+    commands: Vec<String>,
+    /// Command recalled into the input, `None` on the line being typed
+    recalled: Option<usize>,
+    /// The line being typed, kept while recalling commands
+    draft: String,
+    /// Query of the Ctrl-R search, with the command it found
+    search: Option<(String, Option<usize>)>,
+}
+
+impl InputHistory {
+    /// History of the sandbox, empty before its first session.
+    fn load(sandbox_id: &str) -> Self {
+        let path = session::history_path(sandbox_id);
+        let mut file = DefaultHistory::new();
+        if let Some(path) = &path {
+            let _ = file.load(path);
+        }
+        Self {
+            commands: file.iter().cloned().collect(),
+            path,
+            ..Self::default()
+        }
+    }
+
+    /// Adds a command run, saving it to the history file.
+    fn push(&mut self, command: &str) -> rustyline::Result<()> {
+        self.recalled = None;
+        self.draft.clear();
+        if self.commands.last().is_some_and(|last| last == command) {
+            return Ok(());
+        }
+        self.commands.push(command.to_string());
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Read again, keeping the commands of other sessions
+        let mut file = DefaultHistory::new();
+        let _ = file.load(path);
+        file.add(command)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        file.save(path)
+    }
+
+    /// Replaces `input` with the command before the one recalled.
+    fn previous(&mut self, input: &mut String) {
+        let index = match self.recalled {
+            Some(index) => index.saturating_sub(1),
+            None if self.commands.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(input);
+                self.commands.len() - 1
+            }
+        };
+        self.recalled = Some(index);
+        *input = self.commands[index].clone();
+    }
+
+    /// Replaces `input` with the command after the one recalled, back to the line being
+    /// typed after the last.
+    fn next(&mut self, input: &mut String) {
+        let Some(index) = self.recalled else {
+            return;
+        };
+        if index + 1 < self.commands.len() {
+            self.recalled = Some(index + 1);
+            *input = self.commands[index + 1].clone();
+        } else {
+            self.recalled = None;
+            *input = std::mem::take(&mut self.draft);
+        }
+    }
+
+    /// Latest command containing `query` before `end`.
+    fn find(&self, query: &str, end: usize) -> Option<usize> {
+        self.commands[..end].iter().rposition(|command| command.contains(query))
+    }
+
+    /// Handles a key of the Ctrl-R search, putting the command found into `input` on Enter.
+    fn search_key(&mut self, key: event::KeyEvent, input: &mut String) {
+        let Some((mut query, mut found)) = self.search.take() else {
+            return;
+        };
+        match (key.code, key.modifiers) {
+            // An older match, staying on the oldest
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                found = found.and_then(|end| self.find(&query, end)).or(found);
+            }
+            (KeyCode::Enter, _) => {
+                if let Some(index) = found {
+                    *input = self.commands[index].clone();
+                }
+                return;
+            }
+            (KeyCode::Esc, _) => return,
+            (KeyCode::Backspace, _) => {
+                query.pop();
+                found = self.find(&query, self.commands.len());
+            }
+            (KeyCode::Char(c), _) => {
+                query.push(c);
+                // The command found so far or an older one
+                found = self.find(&query, found.map_or(self.commands.len(), |index| index + 1));
+            }
+            _ => {}
+        }
+        self.search = Some((query, found));
+    }
+}
+
+/// Stop of a sandbox waiting for confirmation in a dialog, which removes it or keeps it
+/// stopped.
+struct Confirmation {
+    sandbox_id: String,
+}
+
+/// Session of the split view, besides the focused one.
+struct SessionPane {
+    sandbox_id: String,
+    state: SessionState,
+    running: Option<RunningCommand>,
+}
+
+/// Statuses the quick filter of the list screen cycles through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatusFilter {
+    All,
+    Started,
+    Exited,
+}
+
+impl StatusFilter {
+    fn next(self) -> Self {
+        match self {
+            StatusFilter::All => StatusFilter::Started,
+            StatusFilter::Started => StatusFilter::Exited,
+            StatusFilter::Exited => StatusFilter::All,
+        }
+    }
+}
+
+/// Filter of the list screen: the search query, every term of which must match the ID
+/// prefix, image, status or a `key=value` label, and the status filter.
+#[derive(Debug, Clone)]
+struct ListFilter {
+    query: String,
+    status: StatusFilter,
+    /// Whether keys are typed into the query
+    editing: bool,
+}
+
+impl ListFilter {
+    fn is_active(&self) -> bool {
+        !self.query.is_empty() || self.status != StatusFilter::All
+    }
+
+    fn matches(&self, sandbox: &SandboxInfo) -> bool {
+        let status = match self.status {
+            StatusFilter::All => true,
+            StatusFilter::Started => sandbox.status == "started",
+            StatusFilter::Exited => sandbox.status == "exited",
+        };
+        status && self.query.to_lowercase().split_whitespace().all(|term| {
+            sandbox.id.starts_with(term)
+                || sandbox.image.to_lowercase().contains(term)
+                || sandbox.status == term
+                || sandbox.labels.iter().any(|(key, value)| format!("{}={}", key, value).to_lowercase().contains(term))
+        })
+    }
+
+    /// Description shown in the title of the list
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.query.is_empty() {
+            parts.push(format!("/{}", self.query));
+        }
+        match self.status {
+            StatusFilter::All => {}
+            StatusFilter::Started => parts.push("started only".to_string()),
+            StatusFilter::Exited => parts.push("exited only".to_string()),
+        }
+        parts.join(", ")
+    }
 }
 
 struct App {
     should_quit: bool,
     current_screen: AppScreen,
+    /// Sandboxes of the server, and those passing the filter, shown by the list
+    all_sandboxes: Vec<SandboxInfo>,
     sandbox_list: Vec<SandboxInfo>,
+    list_filter: ListFilter,
     selected_sandbox: usize,
     list_scroll_offset: usize,
     detail_state: SandboxDetailState,
+    /// Resource usage of the sandbox of the detail screen
+    resources: Option<ResourceMonitor>,
     new_sandbox_state: NewSandboxState,
     session_state: SessionState,
-    server_url: String,
-    client: reqwest::Client,
+    /// Command of the session screen waiting for its response
+    running: Option<RunningCommand>,
+    /// Other sessions of the split view. The focused one, in `session_state` and `running`,
+    /// comes at `focus_index` among them.
+    split: Vec<SessionPane>,
+    focus_index: usize,
+    /// Whether the list screen picks the sandbox of a new pane of the split view
+    picking_split: bool,
+    files: FileBrowserState,
+    /// Shell of the terminal screen
+    terminal: Option<TerminalPane>,
+    /// Events tailed by the events screen
+    event_log: Option<EventLog>,
+    client: SosClient,
+    /// Image and setup commands new sandboxes start with, from the profile
+    default_image: String,
+    default_setup: Vec<String>,
     status_message: Option<String>,
     input_mode: bool,
     vim_command_buffer: String,
     mouse_enabled: bool,
+    /// Built-in keys moved to other keys by the configuration
+    keymap: Keymap,
+    /// Whether the help overlay is shown
+    show_help: bool,
+    /// Dialog confirming a stop, over the current screen
+    confirmation: Option<Confirmation>,
+    /// Reachability of the server, shown by the status bar
+    connection: Connection,
+    /// Output of a command, shown in place of the screen
+    pager: Option<Pager>,
+    /// Opened on the first copy
+    clipboard: Option<arboard::Clipboard>,
+    click_targets: ClickTargets,
+    /// Time and row of the last click, telling double clicks apart
+    last_click: Option<(Instant, usize)>,
+    theme: Theme,
 }
 
 impl App {
-    fn new(server_url: String) -> Self {
+    fn new(client: SosClient, default_image: String, default_setup: Vec<String>, keymap: Keymap, theme: Theme) -> Self {
         Self {
             should_quit: false,
             current_screen: AppScreen::SandboxList,
+            all_sandboxes: Vec::new(),
             sandbox_list: Vec::new(),
+            list_filter: ListFilter {
+                query: String::new(),
+                status: StatusFilter::All,
+                editing: false,
+            },
             selected_sandbox: 0,
             list_scroll_offset: 0,
             detail_state: SandboxDetailState {
                 trajectory: String::new(),
                 formatted: true,
                 scroll_offset: 0,
+                prompt: None,
+                search: String::new(),
+                export_format: ExportFormat::Formatted,
+                metadata: None,
             },
+            resources: None,
             new_sandbox_state: NewSandboxState {
-                image: "ubuntu:latest".to_string(),
-                setup_commands: Vec::new(),
+                templates: Vec::new(),
+                selected_template: 0,
+                template: None,
+                image: default_image.clone(),
+                setup_commands: default_setup.clone(),
                 current_command: String::new(),
                 step: NewSandboxStep::EnterImage,
                 session_active: false,
@@ -99,34 +1360,62 @@ impl App {
                 current_input: String::new(),
                 scroll_offset: 0,
             },
-            server_url,
-            client: reqwest::Client::new(),
+            running: None,
+            split: Vec::new(),
+            focus_index: 0,
+            picking_split: false,
+            files: FileBrowserState {
+                path: String::new(),
+                entries: Vec::new(),
+                selected: 0,
+                scroll_offset: 0,
+                preview: None,
+            },
+            terminal: None,
+            event_log: None,
+            client,
+            default_image,
+            default_setup,
             status_message: None,
             input_mode: false,
             vim_command_buffer: String::new(),
             mouse_enabled: true,
+            keymap,
+            show_help: false,
+            confirmation: None,
+            connection: Connection::start(client.clone()),
+            pager: None,
+            clipboard: None,
+            click_targets: ClickTargets::default(),
+            last_click: None,
+            theme,
         }
     }
 
     async fn refresh_sandbox_list(&mut self) -> Result<()> {
-        let response = self
-            .client
-            .get(&format!("{}/sandboxes", self.server_url))
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            self.sandbox_list = response.json().await?;
-            if self.selected_sandbox >= self.sandbox_list.len() && !self.sandbox_list.is_empty() {
-                self.selected_sandbox = self.sandbox_list.len() - 1;
-            }
-            self.update_list_scroll();
-        } else {
-            self.status_message = Some(format!("Failed to refresh: {}", response.text().await?));
+        match self.client.list().await {
+            Ok(sandbox_list) => {
+                self.all_sandboxes = sandbox_list;
+                self.apply_filter();
+            }
+            // The status bar shows that the server is down
+            Err(error) if self.connection.report(&error) => {}
+            Err(error) => {
+                self.status_message = Some(format!("Failed to refresh: {}", error));
+            }
         }
         Ok(())
     }
 
+    /// Lists the sandboxes passing the filter, keeping the selection in bounds.
+    fn apply_filter(&mut self) {
+        self.sandbox_list = self.all_sandboxes.iter().filter(|sandbox| self.list_filter.matches(sandbox)).cloned().collect();
+        if self.selected_sandbox >= self.sandbox_list.len() {
+            self.selected_sandbox = self.sandbox_list.len().saturating_sub(1);
+        }
+        self.update_list_scroll();
+    }
+
     fn update_list_scroll(&mut self) {
         // This will be called with viewport height when drawing
         // For now, just ensure we don't scroll past bounds
@@ -164,61 +1453,89 @@ impl App {
         }
     }
 
-    async fn load_trajectory(&mut self, sandbox_id: &str) -> Result<()> {
-        let endpoint = if self.detail_state.formatted {
-            format!("{}/sandboxes/{}/trajectory/formatted", self.server_url, sandbox_id)
-        } else {
-            format!("{}/sandboxes/{}/trajectory", self.server_url, sandbox_id)
-        };
-
-        let response = self.client.get(&endpoint).send().await?;
-
-        if response.status().is_success() {
-            if self.detail_state.formatted {
-                self.detail_state.trajectory = response.text().await?;
-            } else {
-                let json: Value = response.json().await?;
-                // Use custom pretty printing for better formatting
-                self.detail_state.trajectory = self.format_json_pretty(&json);
-            }
+    async fn load_trajectory(&mut self, sandbox_id: &str) -> Result<()> {
+        let trajectory = if self.detail_state.formatted {
+            self.client.trajectory_formatted(sandbox_id).await
         } else {
-            self.detail_state.trajectory = format!("Failed to load trajectory: {}", response.text().await?);
+            // Use custom pretty printing for better formatting
+            self.client
+                .trajectory(sandbox_id)
+                .await
+                .map(|trajectory| self.format_json_pretty(&trajectory))
+        };
+
+        match trajectory {
+            Ok(trajectory) => self.detail_state.trajectory = trajectory,
+            // Keeps what was loaded, the status bar shows that the server is down
+            Err(error) if self.connection.report(&error) => {}
+            Err(error) => self.detail_state.trajectory = format!("Failed to load trajectory: {}", error),
         }
+        self.detail_state.metadata = self.client.sandbox(sandbox_id).await.ok();
         Ok(())
     }
 
+    /// Step at the top of the trajectory, counted from 1, with its entry.
+    async fn step_at_top(&mut self, sandbox_id: &str) -> Option<(usize, TrajectoryEntry)> {
+        let step = self.detail_state.step_at(self.detail_state.scroll_offset);
+        let trajectory = match self.client.trajectory(sandbox_id).await {
+            Ok(trajectory) => trajectory,
+            Err(error) => {
+                self.status_message = Some(format!("Failed to load the trajectory: {}", error));
+                return None;
+            }
+        };
+        let Some(entry) = trajectory.trajectory.into_iter().nth(step - 1) else {
+            self.status_message = Some("No command run yet".to_string());
+            return None;
+        };
+        Some((step, entry))
+    }
+
+    /// Opens the pager on the output of the step at the top of the trajectory.
+    async fn open_step_output(&mut self, sandbox_id: &str) {
+        let Some((step, entry)) = self.step_at_top(sandbox_id).await else {
+            return;
+        };
+        let (title, output) = match &entry.result {
+            Some(result) => (format!("Step {}: $ {} (exit code: {})", step, entry.command, result.exit_code), result.output.as_str()),
+            None if entry.blocked.is_some() => (format!("Step {}: $ {} (blocked)", step, entry.command), ""),
+            None => (format!("Step {}: $ {} (running)", step, entry.command), ""),
+        };
+        self.pager = Some(Pager::new(title, entry.command.clone(), output));
+    }
+
     async fn load_trajectory_into_session_history(&mut self, sandbox_id: &str) -> Result<()> {
+        self.session_state.inputs = InputHistory::load(sandbox_id);
         // Always load the formatted trajectory for session history
-        let endpoint = format!("{}/sandboxes/{}/trajectory/formatted", self.server_url, sandbox_id);
-        let response = self.client.get(&endpoint).send().await?;
-
-        if response.status().is_success() {
-            let trajectory_text = response.text().await?;
-            self.session_state.history.clear();
+        match self.client.trajectory_formatted(sandbox_id).await {
+            Ok(trajectory_text) => {
+                self.session_state.history.clear();
+            
+                // Parse trajectory into session history
+                for line in trajectory_text.lines() {
+                    // Skip empty lines at the start, but include them if they're between commands
+                    if !line.trim().is_empty() || !self.session_state.history.is_empty() {
+                        self.session_state.history.push(line.to_string());
+                    }
+                }
             
-            // Parse trajectory into session history
-            for line in trajectory_text.lines() {
-                // Skip empty lines at the start, but include them if they're between commands
-                if !line.trim().is_empty() || !self.session_state.history.is_empty() {
-                    self.session_state.history.push(line.to_string());
+                // If we have history, add a separator to distinguish old vs new commands
+                if !self.session_state.history.is_empty() {
+                    self.session_state.history.push("--- Continued session ---".to_string());
                 }
-            }
             
-            // If we have history, add a separator to distinguish old vs new commands
-            if !self.session_state.history.is_empty() {
-                self.session_state.history.push("--- Continued session ---".to_string());
+                // Auto-scroll to bottom to show the latest content
+                self.session_state.scroll_offset = self.session_state.history.len().saturating_sub(20);
+            }
+            Err(error) => {
+                self.session_state.history.clear();
+                self.session_state.history.push(format!("Failed to load command history: {}", error));
             }
-            
-            // Auto-scroll to bottom to show the latest content
-            self.session_state.scroll_offset = self.session_state.history.len().saturating_sub(20);
-        } else {
-            self.session_state.history.clear();
-            self.session_state.history.push(format!("Failed to load command history: {}", response.text().await?));
         }
         Ok(())
     }
 
-    fn format_json_pretty(&self, value: &Value) -> String {
+    fn format_json_pretty(&self, value: &impl Serialize) -> String {
         // Use serde_json's built-in pretty printing which handles indentation correctly
         match serde_json::to_string_pretty(value) {
             Ok(json) => json,
@@ -237,6 +1554,28 @@ impl App {
             AppScreen::SandboxSession(_) | AppScreen::NewSandbox => {
                 self.session_state.scroll_offset = 0;
             }
+            AppScreen::FileBrowser(_) => {
+                *self.files.position() = 0;
+            }
+            AppScreen::Terminal(_) => {
+                self.scroll_terminal(isize::MIN);
+            }
+            AppScreen::Events => {
+                self.scroll_events(isize::MAX, 0);
+            }
+        }
+    }
+
+    fn scroll_events(&mut self, lines: isize, viewport_height: usize) {
+        if let Some(event_log) = &mut self.event_log {
+            event_log.scroll(lines, viewport_height);
+        }
+    }
+
+    /// Scrolls the scrollback of the terminal screen, `lines` up when positive.
+    fn scroll_terminal(&mut self, lines: isize) {
+        if let Some(terminal) = &mut self.terminal {
+            terminal.scroll(lines);
         }
     }
 
@@ -259,6 +1598,15 @@ impl App {
                         AppScreen::SandboxSession(_) | AppScreen::NewSandbox => {
                             self.session_state.scroll_offset = 0;
                         }
+                        AppScreen::FileBrowser(_) => {
+                            *self.files.position() = 0;
+                        }
+                        AppScreen::Terminal(_) => {
+                            self.scroll_terminal(TERMINAL_SCROLLBACK as isize);
+                        }
+                        AppScreen::Events => {
+                            self.scroll_events(isize::MIN, viewport_height);
+                        }
                     }
                     self.vim_command_buffer.clear();
                     return true;
@@ -279,6 +1627,16 @@ impl App {
                         let max_lines = self.session_state.history.len();
                         self.session_state.scroll_offset = max_lines.saturating_sub(viewport_height);
                     }
+                    AppScreen::FileBrowser(_) => {
+                        let last = self.files.last_position(viewport_height);
+                        *self.files.position() = last;
+                    }
+                    AppScreen::Terminal(_) => {
+                        self.scroll_terminal(isize::MIN);
+                    }
+                    AppScreen::Events => {
+                        self.scroll_events(isize::MAX, viewport_height);
+                    }
                 }
                 self.vim_command_buffer.clear();
                 return true;
@@ -295,6 +1653,16 @@ impl App {
                     AppScreen::SandboxSession(_) | AppScreen::NewSandbox => {
                         self.session_state.scroll_offset = self.session_state.scroll_offset.saturating_sub(half_page);
                     }
+                    AppScreen::FileBrowser(_) => {
+                        let position = self.files.position();
+                        *position = position.saturating_sub(half_page);
+                    }
+                    AppScreen::Terminal(_) => {
+                        self.scroll_terminal(half_page as isize);
+                    }
+                    AppScreen::Events => {
+                        self.scroll_events(-(half_page as isize), viewport_height);
+                    }
                 }
                 return true;
             }
@@ -315,6 +1683,17 @@ impl App {
                         let max_scroll = max_lines.saturating_sub(viewport_height);
                         self.session_state.scroll_offset = (self.session_state.scroll_offset + half_page).min(max_scroll);
                     }
+                    AppScreen::FileBrowser(_) => {
+                        let last = self.files.last_position(viewport_height);
+                        let position = self.files.position();
+                        *position = (*position + half_page).min(last);
+                    }
+                    AppScreen::Terminal(_) => {
+                        self.scroll_terminal(-(half_page as isize));
+                    }
+                    AppScreen::Events => {
+                        self.scroll_events(half_page as isize, viewport_height);
+                    }
                 }
                 return true;
             }
@@ -332,6 +1711,16 @@ impl App {
                     AppScreen::SandboxSession(_) | AppScreen::NewSandbox => {
                         self.session_state.scroll_offset = self.session_state.scroll_offset.saturating_sub(1);
                     }
+                    AppScreen::FileBrowser(_) => {
+                        let position = self.files.position();
+                        *position = position.saturating_sub(1);
+                    }
+                    AppScreen::Terminal(_) => {
+                        self.scroll_terminal(1);
+                    }
+                    AppScreen::Events => {
+                        self.scroll_events(-1, viewport_height);
+                    }
                 }
                 return true;
             }
@@ -356,6 +1745,17 @@ impl App {
                             self.session_state.scroll_offset += 1;
                         }
                     }
+                    AppScreen::FileBrowser(_) => {
+                        let last = self.files.last_position(viewport_height);
+                        let position = self.files.position();
+                        *position = (*position + 1).min(last);
+                    }
+                    AppScreen::Terminal(_) => {
+                        self.scroll_terminal(-1);
+                    }
+                    AppScreen::Events => {
+                        self.scroll_events(1, viewport_height);
+                    }
                 }
                 return true;
             }
@@ -373,94 +1773,277 @@ impl App {
         let payload = CreatePayload {
             image: self.new_sandbox_state.image.clone(),
             setup_commands: self.new_sandbox_state.setup_commands.clone(),
+            template: self.new_sandbox_state.template.clone(),
+            ..Default::default()
         };
 
-        let response = self
-            .client
-            .post(&format!("{}/sandboxes", self.server_url))
-            .json(&payload)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            let result: Value = response.json().await?;
-            let id = result["id"].as_str().unwrap().to_string();
-            self.new_sandbox_state.sandbox_id = Some(id.clone());
-            self.status_message = Some(format!("Sandbox created: {}", id));
-            
-            // Start the sandbox
-            let start_response = self
-                .client
-                .post(&format!("{}/sandboxes/{}/start", self.server_url, id))
-                .send()
-                .await?;
-                
-            if start_response.status().is_success() {
-                self.new_sandbox_state.step = NewSandboxStep::SessionReady;
-                self.new_sandbox_state.session_active = true;
-                self.session_state.history.clear();
-                self.session_state.history.push(format!("Sandbox {} started successfully", id));
-                self.input_mode = true; // Enable input mode for session
-            } else {
-                self.status_message = Some(format!("Failed to start sandbox: {}", start_response.text().await?));
+        match self.client.create(&payload).await {
+            Ok(id) => {
+                self.new_sandbox_state.sandbox_id = Some(id.clone());
+                self.status_message = Some(format!("Sandbox created: {}", id));
+
+                // Start the sandbox
+                match self.client.start_and_wait(&id).await {
+                    Ok(()) => {
+                        self.new_sandbox_state.step = NewSandboxStep::SessionReady;
+                        self.new_sandbox_state.session_active = true;
+                        self.session_state.history.clear();
+                        self.session_state.history.push(format!("Sandbox {} started successfully", id));
+                        self.input_mode = true; // Enable input mode for session
+                    }
+                    Err(error) => {
+                        self.status_message = Some(format!("Failed to start sandbox: {}", error));
+                    }
+                }
+            }
+            Err(error) => {
+                self.status_message = Some(format!("Failed to create sandbox: {}", error));
             }
-        } else {
-            self.status_message = Some(format!("Failed to create sandbox: {}", response.text().await?));
         }
         Ok(())
     }
 
     async fn execute_command(&mut self, command: &str, sandbox_id: &str) -> Result<()> {
-        let payload = ExecPayload {
-            command: command.to_string(),
-            standalone: None,
-        };
+        if self.running.is_some() {
+            self.status_message = Some("Wait for the running command to finish".to_string());
+            return Ok(());
+        }
+        self.running = Some(RunningCommand::start(self.client.clone(), sandbox_id.to_string(), command.to_string()).await);
+        Ok(())
+    }
+
+    /// Picks up the output of the running commands of the sessions, and adds it to their
+    /// history once done.
+    fn tick_command(&mut self) {
+        Self::tick_session(&mut self.session_state, &mut self.running);
+        for pane in &mut self.split {
+            Self::tick_session(&mut pane.state, &mut pane.running);
+        }
+    }
 
-        let response = self
-            .client
-            .post(&format!("{}/sandboxes/{}/exec", self.server_url, sandbox_id))
-            .json(&payload)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            let result: Value = response.json().await?;
-            let output = result["output"].as_str().unwrap_or("");
-            let exit_code = result["exit_code"].as_i64().unwrap_or(-4);
-
-            self.session_state.history.push(format!("$ {}", command));
-            if !output.is_empty() {
-                for line in output.lines() {
-                    self.session_state.history.push(line.to_string());
+    fn tick_session(state: &mut SessionState, running: &mut Option<RunningCommand>) {
+        let Some(command) = running else {
+            return;
+        };
+        let Some(result) = command.tick() else {
+            return;
+        };
+        let command = command.command.clone();
+        *running = None;
+        match result {
+            Ok(result) => {
+                state.history.push(format!("$ {}", command));
+                if !result.output.is_empty() {
+                    for line in result.output.lines() {
+                        state.history.push(line.to_string());
+                    }
+                }
+                if result.exit_code != 0 {
+                    state.history.push(format!("(exit code: {})", result.exit_code));
                 }
             }
-            if exit_code != 0 {
-                self.session_state.history.push(format!("(exit code: {})", exit_code));
+            Err(error) => {
+                state.history.push(format!("$ {}", command));
+                state.history.push(format!("Failed to execute: {}", error));
             }
-        } else {
-            self.session_state.history.push(format!("Failed to execute: {}", response.text().await?));
         }
-        Ok(())
+        state.scroll_offset = state.history.len().saturating_sub(20);
+    }
+
+    /// Moves the focused session into the split panes, at its position.
+    fn stash_focused_session(&mut self, sandbox_id: String) {
+        let pane = SessionPane {
+            sandbox_id,
+            state: std::mem::take(&mut self.session_state),
+            running: self.running.take(),
+        };
+        self.split.insert(self.focus_index.min(self.split.len()), pane);
+    }
+
+    /// Focuses the split pane `index`, dropping the session focused so far.
+    fn focus_session(&mut self, index: usize) {
+        let pane = self.split.remove(index);
+        self.focus_index = index;
+        self.session_state = pane.state;
+        self.running = pane.running;
+        self.current_screen = AppScreen::SandboxSession(pane.sandbox_id);
     }
 
     async fn stop_sandbox(&mut self, sandbox_id: &str, remove: bool) -> Result<()> {
-        let payload = StopPayload { remove: Some(remove) };
-        
-        let response = self
-            .client
-            .post(&format!("{}/sandboxes/{}/stop", self.server_url, sandbox_id))
-            .json(&payload)
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            self.status_message = Some(format!("Sandbox {} stopped", sandbox_id));
-        } else {
-            self.status_message = Some(format!("Failed to stop sandbox: {}", response.text().await?));
+        match self.client.stop(sandbox_id, remove).await {
+            Ok(_) => {
+                self.status_message = Some(format!("Sandbox {} stopped", sandbox_id));
+            }
+            Err(error) => {
+                self.status_message = Some(format!("Failed to stop sandbox: {}", error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the sandbox of the confirmed dialog, removing it or not, and goes back to the
+    /// list.
+    async fn confirm_stop(&mut self, remove: bool) -> Result<()> {
+        let Some(confirmation) = self.confirmation.take() else {
+            return Ok(());
+        };
+        self.resources = None;
+        self.stop_sandbox(&confirmation.sandbox_id, remove).await?;
+        self.current_screen = AppScreen::SandboxList;
+        self.reset_scroll();
+        self.refresh_sandbox_list().await
+    }
+
+    /// Lists the directory `path` of the sandbox, relative to its working directory.
+    async fn load_directory(&mut self, sandbox_id: &str, path: &str) -> Result<()> {
+        // The first line is the absolute path of the directory, the next its entries
+        let command = format!("cd {} && pwd && ls -1Ap", quote(path));
+        let result = match self.client.exec_standalone(sandbox_id, &command).await {
+            Ok(result) if result.exit_code == 0 => result,
+            Ok(result) => {
+                self.status_message = Some(format!("Cannot open {}: {}", path, result.stderr.trim()));
+                return Ok(());
+            }
+            Err(error) => {
+                self.status_message = Some(format!("Failed to list {}: {}", path, error));
+                return Ok(());
+            }
+        };
+        let mut lines = result.stdout.lines();
+        let directory = lines.next().unwrap_or("/").to_string();
+        let mut entries: Vec<FileEntry> = lines
+            .map(|line| FileEntry {
+                name: line.trim_end_matches('/').to_string(),
+                dir: line.ends_with('/'),
+            })
+            .collect();
+        entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+        if directory != "/" {
+            entries.insert(0, FileEntry { name: "..".to_string(), dir: true });
+        }
+        self.files = FileBrowserState {
+            path: directory,
+            entries,
+            selected: 0,
+            scroll_offset: 0,
+            preview: None,
+        };
+        Ok(())
+    }
+
+    /// Opens the selected entry, the directory or the preview of the file.
+    async fn open_file_entry(&mut self, sandbox_id: &str) -> Result<()> {
+        let Some(entry) = self.files.entries.get(self.files.selected).cloned() else {
+            return Ok(());
+        };
+        let path = self.files.child(&entry.name);
+        if entry.dir {
+            return self.load_directory(sandbox_id, &path).await;
+        }
+        let command = format!("head -c {} {}", PREVIEW_BYTES, quote(&path));
+        match self.client.exec_standalone(sandbox_id, &command).await {
+            Ok(result) if result.exit_code == 0 => {
+                let lines = if result.stdout.contains('\0') {
+                    vec![Line::from("Binary file, press d to download it").style(Style::default().fg(self.theme.hint))]
+                } else {
+                    let mut lines = highlight(&path, &result.stdout, &self.theme.syntax);
+                    if result.stdout.len() >= PREVIEW_BYTES {
+                        lines.push(Line::from(format!("(preview truncated to {} KiB, press d to download the file)", PREVIEW_BYTES / 1024)).style(Style::default().fg(self.theme.hint)));
+                    }
+                    lines
+                };
+                self.files.preview = Some(FilePreview { path, lines, scroll_offset: 0 });
+            }
+            Ok(result) => {
+                self.status_message = Some(format!("Cannot read {}: {}", path, result.stderr.trim()));
+            }
+            Err(error) => {
+                self.status_message = Some(format!("Failed to read {}: {}", path, error));
+            }
         }
         Ok(())
     }
 
+    /// Downloads the previewed file or the selected entry into the working directory of
+    /// the client.
+    /// Writes the trajectory to the local file `path`, in the export format of the detail screen.
+    async fn export_trajectory(&mut self, sandbox_id: &str, path: &str) {
+        let content = match self.detail_state.export_format {
+            ExportFormat::Formatted => self.client.trajectory_formatted(sandbox_id).await,
+            ExportFormat::Json => self.client.trajectory(sandbox_id).await.map(|trajectory| self.format_json_pretty(&trajectory)),
+            ExportFormat::Jsonl => self.client.export_trajectory(sandbox_id, "jsonl").await,
+            ExportFormat::Cast => self.client.export_trajectory(sandbox_id, "cast").await,
+        };
+        let written = match content {
+            Ok(content) => tokio::fs::write(path, content).await.map_err(anyhow::Error::from),
+            Err(error) => Err(error.into()),
+        };
+        self.status_message = Some(match written {
+            Ok(()) => format!("Exported the trajectory as {} to {}", self.detail_state.export_format.name(), path),
+            Err(error) => format!("Failed to export the trajectory to {}: {}", path, error),
+        });
+    }
+
+    async fn download_file_entry(&mut self, sandbox_id: &str) -> Result<()> {
+        let path = match (&self.files.preview, self.files.entries.get(self.files.selected)) {
+            (Some(preview), _) => preview.path.clone(),
+            (None, Some(entry)) if entry.name != ".." => self.files.child(&entry.name),
+            _ => return Ok(()),
+        };
+        let downloaded = match self.client.download(sandbox_id, &path, |_, _| {}).await {
+            Ok(archive) => super::extract_archive(&archive, ".").await,
+            Err(error) => Err(error.into()),
+        };
+        self.status_message = Some(match downloaded {
+            Ok(()) => format!("Downloaded {} into the current directory", path),
+            Err(error) => format!("Failed to download {}: {}", path, error),
+        });
+        Ok(())
+    }
+
+    /// Attaches a shell of the sandbox in the terminal screen, returned from to the current
+    /// screen.
+    async fn open_terminal(&mut self, sandbox_id: String) {
+        // Sized to the screen less the header, help and borders, until the first draw
+        let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+        let size = TerminalSize {
+            cols: cols.saturating_sub(4).max(1),
+            rows: rows.saturating_sub(8).max(1),
+        };
+        match TerminalPane::start(&self.client, &sandbox_id, size, self.current_screen.clone()).await {
+            Ok(terminal) => {
+                self.terminal = Some(terminal);
+                self.resources = None;
+                self.current_screen = AppScreen::Terminal(sandbox_id);
+            }
+            Err(error) => {
+                self.status_message = Some(format!("Failed to attach: {}", error));
+            }
+        }
+    }
+
+    fn close_terminal(&mut self) {
+        let Some(terminal) = self.terminal.take() else {
+            return;
+        };
+        self.current_screen = terminal.previous.clone();
+        if let AppScreen::SandboxDetail(sandbox_id) = &self.current_screen {
+            self.resources = Some(ResourceMonitor::start(self.client.clone(), sandbox_id.clone()));
+        }
+    }
+
+    /// Picks up the output of the terminal, closing it once the shell exited.
+    fn tick_terminal(&mut self) {
+        let Some(terminal) = &mut self.terminal else {
+            return;
+        };
+        terminal.tick();
+        if terminal.closed {
+            self.close_terminal();
+            self.status_message = Some("The shell of the terminal exited".to_string());
+        }
+    }
+
     async fn toggle_mouse_mode(&mut self) -> Result<()> {
         self.mouse_enabled = !self.mouse_enabled;
         if self.mouse_enabled {
@@ -478,6 +2061,14 @@ impl App {
             return Ok(());
         }
 
+        if let Some(pager) = &mut self.pager {
+            match mouse.kind {
+                MouseEventKind::ScrollUp => pager.scroll(-3),
+                MouseEventKind::ScrollDown => pager.scroll(3),
+                _ => {}
+            }
+            return Ok(());
+        }
         match mouse.kind {
             MouseEventKind::ScrollUp => {
                 self.handle_scroll_keys(KeyCode::Up, KeyModifiers::NONE, 20);
@@ -485,15 +2076,135 @@ impl App {
             MouseEventKind::ScrollDown => {
                 self.handle_scroll_keys(KeyCode::Down, KeyModifiers::NONE, 20);
             }
+            MouseEventKind::Down(MouseButton::Left) => {
+                self.handle_click(mouse.column, mouse.row).await?;
+            }
             _ => {}
         }
         Ok(())
     }
 
+    /// Selects the clicked row of a list, opening it as `Enter` would on a double click, or
+    /// focuses the clicked pane of the session screen.
+    async fn handle_click(&mut self, column: u16, row: u16) -> Result<()> {
+        if self.show_help || self.confirmation.is_some() {
+            return Ok(());
+        }
+        if let Some((area, first)) = self.click_targets.list.filter(|(area, _)| hit(*area, column, row)) {
+            let index = first + (row - area.y) as usize;
+            let count = match self.current_screen {
+                AppScreen::SandboxList => self.sandbox_list.len(),
+                AppScreen::FileBrowser(_) => self.files.entries.len(),
+                // The manual entry comes first
+                AppScreen::NewSandbox => self.new_sandbox_state.templates.len() + 1,
+                _ => 0,
+            };
+            if index >= count {
+                return Ok(());
+            }
+            match self.current_screen {
+                AppScreen::SandboxList => self.selected_sandbox = index,
+                AppScreen::FileBrowser(_) => self.files.selected = index,
+                _ => self.new_sandbox_state.selected_template = index,
+            }
+            let double = self.last_click.is_some_and(|(at, clicked)| clicked == index && at.elapsed() < DOUBLE_CLICK_INTERVAL);
+            if double {
+                self.last_click = None;
+                self.handle_key(event::KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).await?;
+            } else {
+                self.last_click = Some((Instant::now(), index));
+            }
+            return Ok(());
+        }
+        if let AppScreen::SandboxSession(sandbox_id) = self.current_screen.clone() {
+            let Some(index) = self.click_targets.panes.iter().position(|pane| hit(*pane, column, row)) else {
+                return Ok(());
+            };
+            if index != self.focus_index && !self.split.is_empty() {
+                self.stash_focused_session(sandbox_id);
+                self.focus_session(index);
+            }
+            self.input_mode = true;
+        }
+        Ok(())
+    }
+
+    /// Keys of the pager: `c` and `y` copy the command and its output, the others move
+    /// around the output.
+    fn handle_pager_key(&mut self, key: event::KeyEvent) {
+        let Some(pager) = &mut self.pager else {
+            return;
+        };
+        let (what, content) = match (key.code, key.modifiers) {
+            (KeyCode::Char('c'), KeyModifiers::NONE) => ("Command", pager.command.clone()),
+            (KeyCode::Char('y'), KeyModifiers::NONE) | (KeyCode::Char('c'), KeyModifiers::CONTROL) => ("Output", pager.lines.join("\n")),
+            _ => {
+                if pager.handle_key(key) {
+                    self.pager = None;
+                }
+                return;
+            }
+        };
+        self.copy_to_clipboard(what, content);
+    }
+
+    /// Whether keys are typed into a text entry.
+    fn typing(&self) -> bool {
+        // The answers of the dialog are taken as typed
+        if self.confirmation.is_some() {
+            return true;
+        }
+        if self.pager.is_some() {
+            return false;
+        }
+        match self.current_screen {
+            AppScreen::SandboxList => self.list_filter.editing,
+            AppScreen::SandboxDetail(_) => self.detail_state.prompt.is_some(),
+            AppScreen::NewSandbox | AppScreen::SandboxSession(_) => self.input_mode,
+            AppScreen::Terminal(_) => true,
+            AppScreen::Events => self.event_log.as_ref().is_some_and(|event_log| event_log.editing),
+        }
+    }
+
     async fn handle_key_event(&mut self, key: event::KeyEvent) -> Result<()> {
         if key.kind != KeyEventKind::Press {
             return Ok(());
         }
+        let Some(key) = self.keymap.translate(key, self.typing()) else {
+            return Ok(());
+        };
+        self.handle_key(key).await
+    }
+
+    /// Handles a built-in key, as translated from the one pressed.
+    async fn handle_key(&mut self, key: event::KeyEvent) -> Result<()> {
+        // Any key closes the help
+        if self.show_help {
+            self.show_help = false;
+            return Ok(());
+        }
+        if self.pager.is_some() {
+            self.handle_pager_key(key);
+            return Ok(());
+        }
+        // Sessions take it when nothing is typed, as the scroll keys
+        let session_idle = matches!(self.current_screen, AppScreen::SandboxSession(_))
+            && self.session_state.current_input.is_empty()
+            && self.session_state.inputs.search.is_none();
+        if key.code == KeyCode::Char('?') && (!self.typing() || session_idle) {
+            self.show_help = true;
+            return Ok(());
+        }
+        if self.confirmation.is_some() {
+            match key.code {
+                // Removing cannot be undone, so only an explicit `y` does it
+                KeyCode::Char('y') => self.confirm_stop(true).await?,
+                KeyCode::Char('s') => self.confirm_stop(false).await?,
+                KeyCode::Char('n') | KeyCode::Esc | KeyCode::Enter => self.confirmation = None,
+                _ => {}
+            }
+            return Ok(());
+        }
 
         // Global key bindings that work on all screens
         match (key.code, key.modifiers) {
@@ -501,8 +2212,9 @@ impl App {
                 self.toggle_mouse_mode().await?;
                 return Ok(());
             }
-            (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
-                let _ = self.copy_content_to_clipboard().await;
+            // The terminal screen sends it to the shell
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) if !matches!(self.current_screen, AppScreen::Terminal(_)) => {
+                self.copy_content_to_clipboard();
                 return Ok(());
             }
             _ => {}
@@ -510,6 +2222,55 @@ impl App {
 
         match self.current_screen.clone() {
             AppScreen::SandboxList => {
+                if self.list_filter.editing {
+                    match key.code {
+                        KeyCode::Enter => {
+                            self.list_filter.editing = false;
+                        }
+                        KeyCode::Esc => {
+                            self.list_filter.editing = false;
+                            self.list_filter.query.clear();
+                            self.apply_filter();
+                        }
+                        KeyCode::Char(c) => {
+                            self.list_filter.query.push(c);
+                            self.apply_filter();
+                        }
+                        KeyCode::Backspace => {
+                            self.list_filter.query.pop();
+                            self.apply_filter();
+                        }
+                        _ => {}
+                    }
+                    return Ok(());
+                }
+
+                if self.picking_split {
+                    match key.code {
+                        KeyCode::Enter if !self.sandbox_list.is_empty() => {
+                            let sandbox_id = self.sandbox_list[self.selected_sandbox].id.clone();
+                            self.picking_split = false;
+                            self.focus_index = self.split.len();
+                            self.current_screen = AppScreen::SandboxSession(sandbox_id.clone());
+                            self.load_trajectory_into_session_history(&sandbox_id).await?;
+                            self.input_mode = true;
+                            return Ok(());
+                        }
+                        KeyCode::Esc | KeyCode::Char('q') => {
+                            self.picking_split = false;
+                            self.focus_session(self.focus_index.min(self.split.len() - 1));
+                            self.input_mode = true;
+                            return Ok(());
+                        }
+                        // Only the sandbox is picked, searching the list
+                        KeyCode::Char('/' | 'f') => {}
+                        _ => {
+                            self.handle_scroll_keys(key.code, key.modifiers, 20);
+                            return Ok(());
+                        }
+                    }
+                }
+
                 // Handle scroll keys first
                 if self.handle_scroll_keys(key.code, key.modifiers, 20) {
                     return Ok(());
@@ -517,16 +2278,40 @@ impl App {
 
                 match key.code {
                     KeyCode::Char('q') => self.should_quit = true,
+                    KeyCode::Char('/') => {
+                        self.list_filter.editing = true;
+                    }
+                    KeyCode::Char('f') => {
+                        self.list_filter.status = self.list_filter.status.next();
+                        self.apply_filter();
+                    }
+                    KeyCode::Char('e') => {
+                        self.current_screen = AppScreen::Events;
+                        self.event_log = Some(EventLog::start(self.client.clone()));
+                    }
+                    KeyCode::Esc => {
+                        self.list_filter.query.clear();
+                        self.list_filter.status = StatusFilter::All;
+                        self.apply_filter();
+                    }
                     KeyCode::Char('r') => {
                         self.refresh_sandbox_list().await?;
                     }
                     KeyCode::Char('n') => {
                         self.current_screen = AppScreen::NewSandbox;
+                        // Without templates, or the server unreachable, straight to the manual entry
+                        let templates = self.client.templates().await.unwrap_or_default();
                         self.new_sandbox_state = NewSandboxState {
-                            image: "ubuntu:latest".to_string(),
-                            setup_commands: Vec::new(),
+                            step: match templates.is_empty() {
+                                true => NewSandboxStep::EnterImage,
+                                false => NewSandboxStep::PickTemplate,
+                            },
+                            templates,
+                            selected_template: 0,
+                            template: None,
+                            image: self.default_image.clone(),
+                            setup_commands: self.default_setup.clone(),
                             current_command: String::new(),
-                            step: NewSandboxStep::EnterImage,
                             session_active: false,
                             sandbox_id: None,
                         };
@@ -538,21 +2323,74 @@ impl App {
                             let sandbox_id = self.sandbox_list[self.selected_sandbox].id.clone();
                             self.current_screen = AppScreen::SandboxDetail(sandbox_id.clone());
                             self.reset_scroll();
+                            self.detail_state.search.clear();
                             self.load_trajectory(&sandbox_id).await?;
+                            self.resources = Some(ResourceMonitor::start(self.client.clone(), sandbox_id));
                         }
                     }
                     _ => {}
                 }
             }
             AppScreen::SandboxDetail(sandbox_id) => {
+                if let Some((kind, input)) = &mut self.detail_state.prompt {
+                    match key.code {
+                        KeyCode::Enter => {
+                            let (kind, input) = (*kind, input.clone());
+                            self.detail_state.prompt = None;
+                            match kind {
+                                '/' => {
+                                    self.detail_state.search = input.to_lowercase();
+                                    self.status_message = Some(self.detail_state.jump_to_match(true, true));
+                                }
+                                'e' => self.export_trajectory(&sandbox_id, input.trim()).await,
+                                _ => match input.trim().parse().ok().and_then(|step| self.detail_state.step_line(step)) {
+                                    Some(line) => self.detail_state.scroll_offset = line,
+                                    None => self.status_message = Some(format!("No step {}", input.trim())),
+                                },
+                            }
+                        }
+                        // Next format, the default file name following it
+                        KeyCode::Tab if *kind == 'e' => {
+                            let format = self.detail_state.export_format;
+                            self.detail_state.export_format = format.next();
+                            if let Some(stem) = input.strip_suffix(&format!(".{}", format.extension())) {
+                                *input = format!("{}.{}", stem, format.next().extension());
+                            }
+                        }
+                        KeyCode::Esc => {
+                            self.detail_state.prompt = None;
+                        }
+                        KeyCode::Char(c) => {
+                            input.push(c);
+                        }
+                        KeyCode::Backspace => {
+                            input.pop();
+                        }
+                        _ => {}
+                    }
+                    return Ok(());
+                }
+
                 // Handle scroll keys first (estimate viewport height)
                 if !self.input_mode && self.handle_scroll_keys(key.code, key.modifiers, 20) {
                     return Ok(());
                 }
 
                 match key.code {
+                    KeyCode::Char(c @ ('/' | ':')) => {
+                        self.detail_state.prompt = Some((c, String::new()));
+                    }
+                    KeyCode::Char('e') => {
+                        let path = format!("{}.{}", &sandbox_id[..8.min(sandbox_id.len())], self.detail_state.export_format.extension());
+                        self.detail_state.prompt = Some(('e', path));
+                    }
+                    KeyCode::Char('n') | KeyCode::Char('N') if !self.detail_state.search.is_empty() => {
+                        let forward = key.code == KeyCode::Char('n');
+                        self.status_message = Some(self.detail_state.jump_to_match(forward, false));
+                    }
                     KeyCode::Esc | KeyCode::Char('q') => {
                         self.current_screen = AppScreen::SandboxList;
+                        self.resources = None;
                         self.reset_scroll();
                         self.refresh_sandbox_list().await?;
                     }
@@ -560,18 +2398,37 @@ impl App {
                         self.detail_state.formatted = !self.detail_state.formatted;
                         self.load_trajectory(&sandbox_id).await?;
                     }
+                    KeyCode::Char('o') => {
+                        self.open_step_output(&sandbox_id).await;
+                    }
+                    KeyCode::Char('c') => {
+                        if let Some((_, entry)) = self.step_at_top(&sandbox_id).await {
+                            self.copy_to_clipboard("Command", entry.command);
+                        }
+                    }
+                    KeyCode::Char('y') => {
+                        if let Some((_, entry)) = self.step_at_top(&sandbox_id).await {
+                            self.copy_to_clipboard("Output", entry.result.map(|result| result.output).unwrap_or_default());
+                        }
+                    }
                     KeyCode::Char('s') => {
                         self.current_screen = AppScreen::SandboxSession(sandbox_id.clone());
+                        self.resources = None;
                         self.load_trajectory_into_session_history(&sandbox_id).await?;
                         self.session_state.current_input.clear();
                         self.input_mode = true;
                         self.reset_scroll();
                     }
+                    KeyCode::Char('a') => {
+                        self.open_terminal(sandbox_id).await;
+                    }
+                    KeyCode::Char('f') => {
+                        self.current_screen = AppScreen::FileBrowser(sandbox_id.clone());
+                        self.resources = None;
+                        self.load_directory(&sandbox_id, ".").await?;
+                    }
                     KeyCode::Char('x') => {
-                        self.stop_sandbox(&sandbox_id, true).await?;
-                        self.current_screen = AppScreen::SandboxList;
-                        self.reset_scroll();
-                        self.refresh_sandbox_list().await?;
+                        self.confirmation = Some(Confirmation { sandbox_id });
                     }
                     _ => {}
                 }
@@ -579,6 +2436,37 @@ impl App {
             AppScreen::NewSandbox => {
                 if self.input_mode {
                     match &self.new_sandbox_state.step {
+                        NewSandboxStep::PickTemplate => {
+                            let state = &mut self.new_sandbox_state;
+                            match key.code {
+                                KeyCode::Up | KeyCode::Char('k') => {
+                                    state.selected_template = state.selected_template.saturating_sub(1);
+                                }
+                                KeyCode::Down | KeyCode::Char('j') => {
+                                    state.selected_template = (state.selected_template + 1).min(state.templates.len());
+                                }
+                                KeyCode::Enter => {
+                                    match state.selected_template.checked_sub(1).and_then(|i| state.templates.get(i)) {
+                                        Some(template) => {
+                                            // The template's image and setup commands, none added
+                                            state.template = Some(template.name.clone());
+                                            state.image.clear();
+                                            state.setup_commands.clear();
+                                            state.step = NewSandboxStep::Creating;
+                                            self.input_mode = false;
+                                            let _ = self.create_sandbox().await;
+                                        }
+                                        None => state.step = NewSandboxStep::EnterImage,
+                                    }
+                                }
+                                KeyCode::Esc => {
+                                    self.current_screen = AppScreen::SandboxList;
+                                    self.input_mode = false;
+                                    self.reset_scroll();
+                                }
+                                _ => {}
+                            }
+                        }
                         NewSandboxStep::EnterImage => {
                             match key.code {
                                 KeyCode::Enter => {
@@ -645,6 +2533,7 @@ impl App {
                                 KeyCode::Esc => {
                                     // Leave sandbox running, just exit session
                                     self.current_screen = AppScreen::SandboxList;
+                                    self.running = None;
                                     self.input_mode = false;
                                     self.reset_scroll();
                                     self.refresh_sandbox_list().await?;
@@ -674,11 +2563,65 @@ impl App {
             }
             AppScreen::SandboxSession(sandbox_id) => {
                 if self.input_mode {
+                    let state = &mut self.session_state;
+                    if state.inputs.search.is_some() {
+                        state.inputs.search_key(key, &mut state.current_input);
+                        return Ok(());
+                    }
+                    match (key.code, key.modifiers) {
+                        (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                            state.inputs.search = Some((String::new(), None));
+                            return Ok(());
+                        }
+                        (KeyCode::Up, _) => {
+                            state.inputs.previous(&mut state.current_input);
+                            return Ok(());
+                        }
+                        (KeyCode::Down, _) => {
+                            state.inputs.next(&mut state.current_input);
+                            return Ok(());
+                        }
+                        (KeyCode::Char('o'), KeyModifiers::CONTROL) => {
+                            match state.command_at(state.scroll_offset) {
+                                Some((command, output)) => self.pager = Some(Pager::new(format!("$ {}", command), command.to_string(), &output.join("\n"))),
+                                None => self.status_message = Some("No command run yet".to_string()),
+                            }
+                            return Ok(());
+                        }
+                        // Switch the focus to the next pane of the split view
+                        (KeyCode::Tab, _) if !self.split.is_empty() => {
+                            let next = (self.focus_index + 1) % (self.split.len() + 1);
+                            self.stash_focused_session(sandbox_id);
+                            self.focus_session(next);
+                            return Ok(());
+                        }
+                        // Pick the sandbox of a new pane in the list
+                        (KeyCode::Char('v'), KeyModifiers::CONTROL) => {
+                            self.stash_focused_session(sandbox_id);
+                            self.picking_split = true;
+                            self.current_screen = AppScreen::SandboxList;
+                            self.input_mode = false;
+                            self.refresh_sandbox_list().await?;
+                            return Ok(());
+                        }
+                        (KeyCode::Char('t'), KeyModifiers::CONTROL) => {
+                            self.open_terminal(sandbox_id).await;
+                            return Ok(());
+                        }
+                        (KeyCode::Char('w'), KeyModifiers::CONTROL) if !self.split.is_empty() => {
+                            self.focus_session(self.focus_index.min(self.split.len() - 1));
+                            return Ok(());
+                        }
+                        _ => {}
+                    }
                     match key.code {
                         KeyCode::Enter => {
                             if !self.session_state.current_input.is_empty() {
                                 let command = self.session_state.current_input.clone();
                                 self.session_state.current_input.clear();
+                                if let Err(error) = self.session_state.inputs.push(&command) {
+                                    self.status_message = Some(format!("Failed to save the history: {}", error));
+                                }
                                 self.execute_command(&command, &sandbox_id).await?;
                             }
                         }
@@ -690,6 +2633,9 @@ impl App {
                         }
                         KeyCode::Esc => {
                             self.current_screen = AppScreen::SandboxList;
+                            self.running = None;
+                            self.split.clear();
+                            self.focus_index = 0;
                             self.input_mode = false;
                             self.reset_scroll();
                             self.refresh_sandbox_list().await?;
@@ -703,11 +2649,101 @@ impl App {
                     }
                 }
             }
+            AppScreen::FileBrowser(sandbox_id) => {
+                if self.handle_scroll_keys(key.code, key.modifiers, 20) {
+                    return Ok(());
+                }
+
+                match key.code {
+                    KeyCode::Char('d') => {
+                        self.download_file_entry(&sandbox_id).await?;
+                    }
+                    // Close the preview first
+                    KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('h') | KeyCode::Left | KeyCode::Backspace if self.files.preview.is_some() => {
+                        self.files.preview = None;
+                    }
+                    KeyCode::Enter | KeyCode::Char('l') | KeyCode::Right => {
+                        self.open_file_entry(&sandbox_id).await?;
+                    }
+                    KeyCode::Char('h') | KeyCode::Left | KeyCode::Backspace => {
+                        let parent = self.files.child("..");
+                        self.load_directory(&sandbox_id, &parent).await?;
+                    }
+                    KeyCode::Char('r') => {
+                        let path = self.files.path.clone();
+                        self.load_directory(&sandbox_id, &path).await?;
+                    }
+                    KeyCode::Esc | KeyCode::Char('q') => {
+                        self.current_screen = AppScreen::SandboxDetail(sandbox_id.clone());
+                        self.resources = Some(ResourceMonitor::start(self.client.clone(), sandbox_id));
+                    }
+                    _ => {}
+                }
+            }
+            AppScreen::Events => {
+                let Some(event_log) = &mut self.event_log else {
+                    return Ok(());
+                };
+                if event_log.editing {
+                    match key.code {
+                        KeyCode::Enter => event_log.editing = false,
+                        KeyCode::Esc => {
+                            event_log.editing = false;
+                            event_log.filter.clear();
+                        }
+                        KeyCode::Char(c) => event_log.filter.push(c),
+                        KeyCode::Backspace => {
+                            event_log.filter.pop();
+                        }
+                        _ => {}
+                    }
+                    // The filtered events are followed from their end
+                    event_log.follow = true;
+                    return Ok(());
+                }
+
+                if self.handle_scroll_keys(key.code, key.modifiers, 20) {
+                    return Ok(());
+                }
+
+                match key.code {
+                    KeyCode::Char('/') => {
+                        if let Some(event_log) = &mut self.event_log {
+                            event_log.editing = true;
+                        }
+                    }
+                    KeyCode::Char('c') => {
+                        if let Some(event_log) = &mut self.event_log {
+                            event_log.events.clear();
+                            event_log.follow = true;
+                        }
+                    }
+                    KeyCode::Esc | KeyCode::Char('q') => {
+                        self.current_screen = AppScreen::SandboxList;
+                        self.event_log = None;
+                        self.refresh_sandbox_list().await?;
+                    }
+                    _ => {}
+                }
+            }
+            AppScreen::Terminal(_) => {
+                // Ctrl-] detaches, as in `sos sandbox attach`
+                if key.code == KeyCode::Char(']') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                    self.close_terminal();
+                    return Ok(());
+                }
+                let Some(terminal) = &mut self.terminal else {
+                    return Ok(());
+                };
+                if let Some(bytes) = terminal.key_bytes(key) {
+                    terminal.send(bytes);
+                }
+            }
         }
         Ok(())
     }
 
-    async fn copy_content_to_clipboard(&mut self) -> Result<()> {
+    fn copy_content_to_clipboard(&mut self) {
         let content = match &self.current_screen {
             AppScreen::SandboxDetail(_) => {
                 // Copy just the trajectory content without borders
@@ -717,6 +2753,15 @@ impl App {
                 // Copy session history without UI elements
                 self.session_state.history.join("\n")
             }
+            AppScreen::Events => match &self.event_log {
+                Some(event_log) => event_log.visible().into_iter().map(|event| EventLog::event_line(&self.theme, event).to_string()).collect::<Vec<_>>().join("\n"),
+                None => String::new(),
+            },
+            AppScreen::Terminal(_) => self.terminal.as_ref().map(|terminal| terminal.parser.screen().contents()).unwrap_or_default(),
+            AppScreen::FileBrowser(_) => match &self.files.preview {
+                Some(preview) => preview.lines.iter().map(|line| line.to_string()).collect::<Vec<_>>().join("\n"),
+                None => self.files.entries.iter().map(|entry| entry.name.clone()).collect::<Vec<_>>().join("\n"),
+            },
             AppScreen::SandboxList => {
                 // Copy sandbox list as plain text
                 self.sandbox_list
@@ -740,86 +2785,96 @@ impl App {
             }
         };
 
-        // Try to copy to clipboard using system command
-        #[cfg(target_os = "macos")]
-        {
-            use std::process::Command;
-            let mut child = Command::new("pbcopy")
-                .stdin(std::process::Stdio::piped())
-                .spawn()?;
-            
-            if let Some(stdin) = child.stdin.as_mut() {
-                use std::io::Write;
-                stdin.write_all(content.as_bytes())?;
-            }
-            child.wait()?;
-        }
-        
-        #[cfg(target_os = "linux")]
-        {
-            use std::process::Command;
-            let mut child = Command::new("xclip")
-                .args(["-selection", "clipboard"])
-                .stdin(std::process::Stdio::piped())
-                .spawn()?;
-            
-            if let Some(stdin) = child.stdin.as_mut() {
-                use std::io::Write;
-                stdin.write_all(content.as_bytes())?;
-            }
-            child.wait()?;
-        }
+        self.copy_to_clipboard("Content", content);
+    }
 
-        self.status_message = Some("Content copied to clipboard".to_string());
-        Ok(())
+    /// Puts `content` on the clipboard, kept open afterwards as X11 loses what it holds
+    /// when it is closed.
+    fn copy_to_clipboard(&mut self, what: &str, content: String) {
+        let clipboard = match self.clipboard.take() {
+            Some(clipboard) => Ok(clipboard),
+            None => arboard::Clipboard::new(),
+        };
+        let copied = clipboard.and_then(|mut clipboard| {
+            let copied = clipboard.set_text(content);
+            self.clipboard = Some(clipboard);
+            copied
+        });
+        self.status_message = Some(match copied {
+            Ok(()) => format!("{} copied to clipboard", what),
+            Err(error) => format!("Failed to copy to clipboard: {}", error),
+        });
     }
 
-    fn colorize_trajectory_line(line: &str) -> Line<'static> {
+    fn colorize_trajectory_line(theme: &Theme, line: &str) -> Line<'static> {
         if line.trim_start().starts_with("$ ") {
-            // Command line - green and bold
-            Line::from(line.to_string()).style(Style::default().fg(Color::Green).add_modifier(Modifier::BOLD))
+            // Command line - bold
+            Line::from(line.to_string()).style(Style::default().fg(theme.command).add_modifier(Modifier::BOLD))
         } else if line.trim_start().starts_with("(exit code:") {
-            // Exit code - yellow
-            Line::from(line.to_string()).style(Style::default().fg(Color::Yellow))
+            // Exit code
+            Line::from(line.to_string()).style(Style::default().fg(theme.warning))
         } else if line.trim().is_empty() {
             // Empty line
             Line::from(line.to_string())
         } else {
-            // Regular output - cyan
-            Line::from(line.to_string()).style(Style::default().fg(Color::Cyan))
+            // Regular output
+            Line::from(line.to_string()).style(Style::default().fg(theme.accent))
         }
     }
 
-    fn colorize_session_line(line: &str) -> Line<'static> {
+    fn colorize_session_line(theme: &Theme, line: &str) -> Line<'static> {
         if line.starts_with("$ ") {
-            // Command line - green and bold
-            Line::from(line.to_string()).style(Style::default().fg(Color::Green).add_modifier(Modifier::BOLD))
+            // Command line - bold
+            Line::from(line.to_string()).style(Style::default().fg(theme.command).add_modifier(Modifier::BOLD))
         } else if line.starts_with("(exit code:") {
-            // Exit code - yellow
-            Line::from(line.to_string()).style(Style::default().fg(Color::Yellow))
+            // Exit code
+            Line::from(line.to_string()).style(Style::default().fg(theme.warning))
         } else if line.starts_with("Sandbox") && line.contains("started successfully") {
-            // Success message - green
-            Line::from(line.to_string()).style(Style::default().fg(Color::Green))
+            // Success message
+            Line::from(line.to_string()).style(Style::default().fg(theme.success))
         } else if line.starts_with("--- Continued session ---") {
-            // Session separator - magenta and bold
-            Line::from(line.to_string()).style(Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD))
+            // Session separator - bold
+            Line::from(line.to_string()).style(Style::default().fg(theme.emphasis).add_modifier(Modifier::BOLD))
         } else {
             // Regular output - default color
             Line::from(line.to_string())
         }
     }
 
+    /// Tick of the status bar: sandboxes are listed again once the server is back.
+    async fn tick_connection(&mut self) {
+        if self.connection.tick() {
+            self.status_message = Some("Reconnected to the server".to_string());
+            if matches!(self.current_screen, AppScreen::SandboxList) {
+                let _ = self.refresh_sandbox_list().await;
+            }
+        }
+    }
+
     fn draw(&mut self, frame: &mut Frame) {
         let area = frame.area();
         
         // Clear status message after drawing
         let status = self.status_message.take();
+        self.click_targets = ClickTargets::default();
         
         match self.current_screen.clone() {
+            // In place of the screen it was opened from
+            _ if self.pager.is_some() => self.draw_pager(frame, area),
             AppScreen::SandboxList => self.draw_sandbox_list(frame, area),
             AppScreen::SandboxDetail(sandbox_id) => self.draw_sandbox_detail(frame, area, &sandbox_id),
             AppScreen::NewSandbox => self.draw_new_sandbox(frame, area),
             AppScreen::SandboxSession(sandbox_id) => self.draw_sandbox_session(frame, area, &sandbox_id),
+            AppScreen::FileBrowser(sandbox_id) => self.draw_file_browser(frame, area, &sandbox_id),
+            AppScreen::Terminal(sandbox_id) => self.draw_terminal(frame, area, &sandbox_id),
+            AppScreen::Events => self.draw_events(frame, area),
+        }
+        self.draw_status_bar(frame, Rect { height: 1.min(area.height), ..area });
+        if self.show_help {
+            self.draw_help(frame, area);
+        }
+        if let Some(confirmation) = &self.confirmation {
+            self.draw_confirmation(frame, area, confirmation);
         }
         
         // Draw status message at the bottom
@@ -831,12 +2886,157 @@ impl App {
                 height: 1,
             };
             frame.render_widget(
-                Paragraph::new(msg).style(Style::default().fg(Color::Yellow)),
+                Paragraph::new(msg).style(Style::default().fg(self.theme.input)),
                 status_area,
             );
         }
     }
 
+    /// Overlay listing the bindings of the screen, under the keys they were moved to.
+    fn draw_help(&self, frame: &mut Frame, area: Rect) {
+        let sections = [
+            ("Screen", self.current_screen.key_help()),
+            ("Scrolling", match self.current_screen {
+                AppScreen::SandboxSession(_) => SESSION_SCROLL_HELP,
+                _ => SCROLL_HELP,
+            }),
+            ("Everywhere", GLOBAL_HELP),
+        ];
+        let sections = match self.current_screen {
+            // Every key but Ctrl-] and F1 goes to the shell
+            AppScreen::Terminal(_) => &sections[..1],
+            _ => &sections[..],
+        };
+        let mut lines = Vec::new();
+        for (title, bindings) in sections {
+            if !lines.is_empty() {
+                lines.push(Line::from(""));
+            }
+            lines.push(Line::from(*title).style(self.theme.header()));
+            for (keys, description) in bindings.iter() {
+                let keys: Vec<String> = keys.iter().map(|key| self.keymap.display(key)).collect();
+                lines.push(Line::from(vec![
+                    Span::styled(format!("  {:<22}", keys.join("/")), Style::default().fg(self.theme.input)),
+                    Span::raw(*description),
+                ]));
+            }
+        }
+
+        let width = 64.min(area.width);
+        let height = (lines.len() as u16 + 2).min(area.height);
+        let popup = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.y + (area.height - height) / 2,
+            width,
+            height,
+        };
+        let help = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(self.theme.accent)).title("Keys (any key closes)"));
+        frame.render_widget(Clear, popup);
+        frame.render_widget(help, popup);
+    }
+
+    fn draw_pager(&mut self, frame: &mut Frame, area: Rect) {
+        let Some(pager) = &mut self.pager else {
+            return;
+        };
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(area);
+
+        let header = Paragraph::new(pager.title.clone())
+            .style(self.theme.header())
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL));
+        frame.render_widget(header, chunks[0]);
+
+        pager.viewport_height = chunks[1].height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = pager.lines.iter().skip(pager.scroll_offset).take(pager.viewport_height).map(|line| Line::from(line.as_str())).collect();
+        let title = format!(
+            "Output - lines {}-{} of {}{}",
+            (pager.scroll_offset + 1).min(pager.lines.len()),
+            (pager.scroll_offset + pager.viewport_height).min(pager.lines.len()),
+            pager.lines.len(),
+            match (pager.wrap, pager.column) {
+                (true, _) => " [wrap]".to_string(),
+                (false, 0) => String::new(),
+                (false, column) => format!(" [from column {}]", column + 1),
+            }
+        );
+        let content = Paragraph::new(lines)
+            .style(Style::default().fg(self.theme.accent))
+            .block(Block::default().borders(Borders::ALL).title(title));
+        let content = match pager.wrap {
+            true => content.wrap(Wrap { trim: false }),
+            false => content.scroll((0, pager.column.min(u16::MAX as usize) as u16)),
+        };
+        frame.render_widget(content, chunks[1]);
+
+        let help = Paragraph::new("↑/↓,k/j: Scroll | g/G: Top/Bottom | Ctrl-U/D: Half page | ←/→,h/l: Scroll sideways | 0: First column | w: Toggle wrap | c/y: Copy Command/Output | Esc/q: Close")
+            .style(Style::default().fg(self.theme.hint))
+            .alignment(Alignment::Center);
+        frame.render_widget(help, chunks[2]);
+    }
+
+    /// Line above the screens with the server, the tenant of the credentials and whether
+    /// the server can be reached.
+    fn draw_status_bar(&self, frame: &mut Frame, area: Rect) {
+        let connection = &self.connection;
+        let (marker, state) = match &connection.state {
+            ConnectionState::Connecting => (
+                Span::styled("○ ", Style::default().fg(self.theme.hint)),
+                Span::styled("connecting", Style::default().fg(self.theme.hint)),
+            ),
+            ConnectionState::Connected(tenant) => (
+                Span::styled("● ", Style::default().fg(self.theme.success)),
+                Span::styled(format!("tenant {}", tenant), Style::default().fg(self.theme.hint)),
+            ),
+            ConnectionState::Down { error, retry_at } => (
+                Span::styled("● ", Style::default().fg(self.theme.error)),
+                Span::styled(
+                    format!("{} - retrying in {}s", error, retry_at.saturating_duration_since(Instant::now()).as_secs()),
+                    Style::default().fg(self.theme.error),
+                ),
+            ),
+        };
+        let line = Line::from(vec![
+            Span::raw(" "),
+            marker,
+            Span::styled(connection.server.clone(), Style::default().fg(self.theme.accent)),
+            Span::raw("  "),
+            state,
+        ]);
+        frame.render_widget(Paragraph::new(line), area);
+    }
+
+    /// Dialog asking whether to remove the sandbox being stopped.
+    fn draw_confirmation(&self, frame: &mut Frame, area: Rect, confirmation: &Confirmation) {
+        let sandbox_id = &confirmation.sandbox_id;
+        let key = Style::default().fg(self.theme.input).add_modifier(Modifier::BOLD);
+        let lines = vec![
+            Line::from(format!("Stop sandbox {}?", &sandbox_id[..8.min(sandbox_id.len())])),
+            Line::from(""),
+            Line::from(vec![Span::styled("y", key), Span::raw(": Stop and remove")]),
+            Line::from(vec![Span::styled("s", key), Span::raw(": Stop and keep")]),
+            Line::from(vec![Span::styled("n/Enter", key), Span::raw(": Cancel")]),
+        ];
+
+        let width = 48.min(area.width);
+        let height = (lines.len() as u16 + 2).min(area.height);
+        let popup = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.y + (area.height - height) / 2,
+            width,
+            height,
+        };
+        let dialog = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(self.theme.error)).title("Confirm"));
+        frame.render_widget(Clear, popup);
+        frame.render_widget(dialog, popup);
+    }
+
     fn draw_sandbox_list(&mut self, frame: &mut Frame, area: Rect) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
@@ -846,16 +3046,26 @@ impl App {
 
         // Header
         let header = Paragraph::new("SOS - Sandbox Manager")
-            .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+            .style(self.theme.header())
             .alignment(Alignment::Center)
             .block(Block::default().borders(Borders::ALL));
         frame.render_widget(header, chunks[0]);
 
         // Help text
-        let help_text = "↑/↓,k/j: Navigate | gg: Top | G: Bottom | Ctrl-U/D: Half page | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | Enter: View Details | n: New Sandbox | r: Refresh | q: Quit";
-        let help = Paragraph::new(help_text)
-            .style(Style::default().fg(Color::Gray))
-            .alignment(Alignment::Center);
+        let help = if self.list_filter.editing {
+            // The query being typed, in place of the help
+            Paragraph::new(format!("/{}█  (ID prefix, image, status or key=value label | Enter: Apply | Esc: Clear)", self.list_filter.query))
+                .style(Style::default().fg(self.theme.input))
+        } else if self.picking_split {
+            Paragraph::new("Pick the sandbox to open beside the session | ↑/↓,k/j: Navigate | /: Search | f: Status Filter | Enter: Open | Esc: Cancel")
+                .style(Style::default().fg(self.theme.input))
+                .alignment(Alignment::Center)
+        } else {
+            let help_text = "↑/↓,k/j: Navigate | gg: Top | G: Bottom | Ctrl-U/D: Half page | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | Enter: View Details | /: Search | f: Status Filter | Esc: Clear Filter | n: New Sandbox | e: Server Events | r: Refresh | ?: Help | q: Quit";
+            Paragraph::new(help_text)
+                .style(Style::default().fg(self.theme.hint))
+                .alignment(Alignment::Center)
+        };
         
         // Sandbox list
         let list_chunks = Layout::default()
@@ -864,8 +3074,13 @@ impl App {
             .split(chunks[1]);
 
         if self.sandbox_list.is_empty() {
-            let empty_msg = Paragraph::new("No sandboxes found. Press 'n' to create a new one.")
-                .style(Style::default().fg(Color::Gray))
+            let message = if self.list_filter.is_active() && !self.all_sandboxes.is_empty() {
+                format!("No sandboxes match {}. Press Esc to clear the filter.", self.list_filter.describe())
+            } else {
+                "No sandboxes found. Press 'n' to create a new one.".to_string()
+            };
+            let empty_msg = Paragraph::new(message)
+                .style(Style::default().fg(self.theme.hint))
                 .alignment(Alignment::Center)
                 .block(Block::default().borders(Borders::ALL).title("Sandboxes"));
             frame.render_widget(empty_msg, list_chunks[0]);
@@ -901,7 +3116,7 @@ impl App {
                         }
                     );
                     let style = if i == self.selected_sandbox {
-                        Style::default().bg(Color::Blue).fg(Color::White)
+                        self.theme.selected()
                     } else {
                         Style::default()
                     };
@@ -909,16 +3124,27 @@ impl App {
                 })
                 .collect();
 
-            let title = format!(
-                "Sandboxes ({}/{}) - gg:top G:bottom", 
-                self.selected_sandbox + 1, 
-                self.sandbox_list.len()
-            );
+            let title = if self.list_filter.is_active() {
+                format!(
+                    "Sandboxes ({}/{} of {}) [{}]",
+                    self.selected_sandbox + 1,
+                    self.sandbox_list.len(),
+                    self.all_sandboxes.len(),
+                    self.list_filter.describe()
+                )
+            } else {
+                format!(
+                    "Sandboxes ({}/{}) - gg:top G:bottom", 
+                    self.selected_sandbox + 1, 
+                    self.sandbox_list.len()
+                )
+            };
 
             let list = List::new(visible_items)
                 .block(Block::default().borders(Borders::ALL).title(title))
-                .highlight_style(Style::default().bg(Color::Blue));
+                .highlight_style(Style::default().bg(self.theme.selection_bg));
 
+            self.click_targets.list = Some((Block::default().borders(Borders::ALL).inner(list_chunks[0]), self.list_scroll_offset));
             frame.render_widget(list, list_chunks[0]);
         }
         
@@ -932,6 +3158,7 @@ impl App {
             .constraints([
                 Constraint::Length(3),
                 Constraint::Min(0),
+                Constraint::Length(6),
                 Constraint::Length(1),
             ].as_ref())
             .split(area);
@@ -939,39 +3166,337 @@ impl App {
         // Header
         let title = format!("Sandbox Details - {}", &sandbox_id[..8.min(sandbox_id.len())]);
         let header = Paragraph::new(title)
-            .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+            .style(self.theme.header())
             .alignment(Alignment::Center)
             .block(Block::default().borders(Borders::ALL));
         frame.render_widget(header, chunks[0]);
 
-        // Trajectory
+        // Trajectory, with the metadata beside it
+        let body = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(METADATA_WIDTH)].as_ref())
+            .split(chunks[1]);
         let trajectory_title = if self.detail_state.formatted {
             "Trajectory (Formatted)"
         } else {
             "Trajectory (Raw JSON)"
         };
         
+        let search = &self.detail_state.search;
         let lines: Vec<Line> = self.detail_state.trajectory
             .lines()
+            .enumerate()
             .skip(self.detail_state.scroll_offset)
-            .take(chunks[1].height.saturating_sub(2) as usize)
-            .map(|line| Self::colorize_trajectory_line(line))
+            .take(body[0].height.saturating_sub(2) as usize)
+            .map(|(i, line)| {
+                let colored = Self::colorize_trajectory_line(&self.theme, line);
+                // Matches of the search stand out, the one jumped to the most
+                if search.is_empty() || !line.to_lowercase().contains(search) {
+                    colored
+                } else if i == self.detail_state.scroll_offset {
+                    colored.patch_style(Style::default().bg(self.theme.match_bg).fg(self.theme.match_fg))
+                } else {
+                    colored.patch_style(Style::default().bg(self.theme.other_match_bg))
+                }
+            })
             .collect();
 
         let trajectory = Paragraph::new(lines)
             .block(Block::default().borders(Borders::ALL).title(trajectory_title))
             .wrap(Wrap { trim: false });
-        frame.render_widget(trajectory, chunks[1]);
+        frame.render_widget(trajectory, body[0]);
+        self.draw_metadata(frame, body[1]);
+
+        self.draw_resources(frame, chunks[2]);
 
         // Help
-        let help_text = "↑/↓,k/j: Scroll | gg: Top | G: Bottom | Ctrl-U/D: Half page | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | t: Toggle Format | s: Start Session | x: Stop & Remove | Esc: Back";
+        let help = match &self.detail_state.prompt {
+            Some(('e', path)) => Paragraph::new(format!(
+                "Export as {} to: {}█  (Tab: Format | Enter: Write | Esc: Cancel)",
+                self.detail_state.export_format.name(),
+                path
+            ))
+            .style(Style::default().fg(self.theme.input)),
+            Some((kind, input)) => Paragraph::new(format!("{}{}█", kind, input))
+                .style(Style::default().fg(self.theme.input)),
+            None => {
+                let help_text = "↑/↓,k/j: Scroll | gg: Top | G: Bottom | Ctrl-U/D: Half page | /: Search | n/N: Next/Previous Match | :N: Go to Step | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | t: Toggle Format | o: Open Output | c/y: Copy Command/Output | s: Start Session | a: Attach Terminal | f: Browse Files | e: Export | x: Stop | ?: Help | Esc: Back";
+                Paragraph::new(help_text)
+                    .style(Style::default().fg(self.theme.hint))
+                    .alignment(Alignment::Center)
+            }
+        };
+        frame.render_widget(help, chunks[3]);
+    }
+
+    /// Sidebar of the detail screen, with the image, status, container, uptime, limits,
+    /// labels and commands of the sandbox.
+    fn draw_metadata(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title("Sandbox");
+        let Some(metadata) = &self.detail_state.metadata else {
+            let unavailable = Paragraph::new("Details unavailable")
+                .style(Style::default().fg(self.theme.hint))
+                .block(block);
+            frame.render_widget(unavailable, area);
+            return;
+        };
+        let label = Style::default().fg(self.theme.accent).add_modifier(Modifier::BOLD);
+        let field = |name: &str, value: String| Line::from(vec![Span::styled(format!("{}: ", name), label), Span::raw(value)]);
+        let info = &metadata.info;
+        let none = || "-".to_string();
+
+        let mut lines = vec![
+            field("Image", info.image.clone()),
+            field("Status", info.status.clone()),
+            field("Container", metadata.container_id.as_deref().map_or_else(none, |id| id[..12.min(id.len())].to_string())),
+        ];
+        let uptime = metadata.started_at.filter(|_| info.status == "started").map(|started_at| format_uptime(chrono::Utc::now() - started_at));
+        lines.push(field("Uptime", uptime.unwrap_or_else(none)));
+        let mut limits = Vec::new();
+        if let Some(memory) = metadata.limits.memory_mb {
+            limits.push(format!("{} MiB", memory));
+        }
+        if let Some(cpus) = metadata.limits.cpus {
+            limits.push(format!("{} CPUs", cpus));
+        }
+        if let Some(pids) = metadata.limits.pids {
+            limits.push(format!("{} pids", pids));
+        }
+        lines.push(field("Limits", match limits.is_empty() {
+            true => none(),
+            false => limits.join(", "),
+        }));
+        lines.push(field("Time limit", metadata.time_limit_secs.map_or_else(none, |secs| format_uptime(chrono::TimeDelta::seconds(secs as i64)))));
+        lines.push(field("Commands", info.session_command_count.to_string()));
+        let exit_style = |code: Option<i64>| match code {
+            Some(0) => Style::default().fg(self.theme.success),
+            Some(_) => Style::default().fg(self.theme.error),
+            None => Style::default(),
+        };
+        lines.push(Line::from(vec![
+            Span::styled("Last exit: ", label),
+            Span::styled(metadata.last_exit_code.map_or_else(none, |code| code.to_string()), exit_style(metadata.last_exit_code)),
+        ]));
+        lines.push(Line::from(vec![
+            Span::styled("Standalone: ", label),
+            Span::styled(info.last_standalone_exit_code.map_or_else(none, |code| code.to_string()), exit_style(info.last_standalone_exit_code)),
+        ]));
+        lines.push(Line::from(Span::styled("Labels:", label)));
+        let mut labels: Vec<String> = info.labels.iter().map(|(key, value)| format!("  {}={}", key, value)).collect();
+        labels.sort();
+        if labels.is_empty() {
+            labels.push("  none".to_string());
+        }
+        lines.extend(labels.into_iter().map(Line::from));
+
+        let sidebar = Paragraph::new(lines).block(block).wrap(Wrap { trim: false });
+        frame.render_widget(sidebar, area);
+    }
+
+    fn draw_resources(&self, frame: &mut Frame, area: Rect) {
+        let (resources, last) = match &self.resources {
+            Some(resources @ ResourceMonitor { last: Some(last), .. }) => (resources, last),
+            _ => {
+                let waiting = Paragraph::new("Waiting for the resource usage of the sandbox...")
+                    .style(Style::default().fg(self.theme.hint))
+                    .block(Block::default().borders(Borders::ALL).title("Resources"));
+                frame.render_widget(waiting, area);
+                return;
+            }
+        };
+
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Ratio(1, 3); 3].as_ref())
+            .split(area);
+        let network = resources.network.back().copied().unwrap_or(0);
+        let panes = [
+            (
+                format!("CPU {:.1}%", last.cpu_percent),
+                &resources.cpu,
+                self.theme.success,
+            ),
+            (
+                format!(
+                    "Memory {} / {}",
+                    super::format_bytes(last.memory_usage_bytes),
+                    super::format_bytes(last.memory_limit_bytes)
+                ),
+                &resources.memory,
+                self.theme.emphasis,
+            ),
+            (
+                format!("Network {}/s", super::format_bytes(network / RESOURCE_INTERVAL.as_secs().max(1))),
+                &resources.network,
+                self.theme.accent,
+            ),
+        ];
+        for ((title, history, color), area) in panes.into_iter().zip(chunks.iter()) {
+            // The most recent samples that fit, right-aligned
+            let width = area.width.saturating_sub(2) as usize;
+            let data: Vec<u64> = history.iter().skip(history.len().saturating_sub(width)).copied().collect();
+            let sparkline = Sparkline::default()
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .data(&data)
+                .style(Style::default().fg(color));
+            frame.render_widget(sparkline, *area);
+        }
+    }
+
+    fn draw_file_browser(&mut self, frame: &mut Frame, area: Rect, sandbox_id: &str) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(area);
+
+        // Header
+        let title = format!("Files - {}", &sandbox_id[..8.min(sandbox_id.len())]);
+        let header = Paragraph::new(title)
+            .style(self.theme.header())
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL));
+        frame.render_widget(header, chunks[0]);
+
+        let viewport_height = chunks[1].height.saturating_sub(2) as usize;
+        let help_text = match &self.files.preview {
+            Some(preview) => {
+                let lines: Vec<Line> = preview.lines.iter().skip(preview.scroll_offset).take(viewport_height).cloned().collect();
+                let content = Paragraph::new(lines)
+                    .block(Block::default().borders(Borders::ALL).title(preview.path.clone()));
+                frame.render_widget(content, chunks[1]);
+                "↑/↓,k/j: Scroll | gg: Top | G: Bottom | Ctrl-U/D: Half page | Ctrl-C: Copy Content | d: Download | Esc/h: Back to Listing"
+            }
+            None => {
+                // Keep the selected entry in view
+                if self.files.selected < self.files.scroll_offset {
+                    self.files.scroll_offset = self.files.selected;
+                } else if viewport_height > 0 && self.files.selected >= self.files.scroll_offset + viewport_height {
+                    self.files.scroll_offset = self.files.selected + 1 - viewport_height;
+                }
+                let items: Vec<ListItem> = self.files.entries
+                    .iter()
+                    .enumerate()
+                    .skip(self.files.scroll_offset)
+                    .take(viewport_height)
+                    .map(|(i, entry)| {
+                        let (name, style) = if entry.dir {
+                            (format!("{}/", entry.name), Style::default().fg(self.theme.directory).add_modifier(Modifier::BOLD))
+                        } else {
+                            (entry.name.clone(), Style::default())
+                        };
+                        let style = if i == self.files.selected {
+                            self.theme.selected()
+                        } else {
+                            style
+                        };
+                        ListItem::new(name).style(style)
+                    })
+                    .collect();
+                let title = format!("{} ({} entries)", self.files.path, self.files.entries.iter().filter(|entry| entry.name != "..").count());
+                let list = List::new(items).block(Block::default().borders(Borders::ALL).title(title));
+                self.click_targets.list = Some((Block::default().borders(Borders::ALL).inner(chunks[1]), self.files.scroll_offset));
+                frame.render_widget(list, chunks[1]);
+                "↑/↓,k/j: Navigate | gg: Top | G: Bottom | Enter/l: Open | h/Backspace: Parent | d: Download | r: Refresh | Ctrl-C: Copy Content | Esc: Back"
+            }
+        };
+
+        let help = Paragraph::new(help_text)
+            .style(Style::default().fg(self.theme.hint))
+            .alignment(Alignment::Center);
+        frame.render_widget(help, chunks[2]);
+    }
+
+    fn draw_events(&self, frame: &mut Frame, area: Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(area);
+
+        // Header
+        let header = Paragraph::new("Server Events")
+            .style(self.theme.header())
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL));
+        frame.render_widget(header, chunks[0]);
+
+        let Some(event_log) = &self.event_log else {
+            return;
+        };
+        let viewport_height = chunks[1].height.saturating_sub(2) as usize;
+        let visible = event_log.visible();
+        let last = visible.len().saturating_sub(viewport_height);
+        let start = match event_log.follow {
+            true => last,
+            false => event_log.scroll_offset.min(last),
+        };
+        let mut lines: Vec<Line> = visible.iter().skip(start).take(viewport_height).map(|event| EventLog::event_line(&self.theme, event)).collect();
+        if let Some(error) = &event_log.error {
+            lines.push(Line::from(error.clone()).style(Style::default().fg(self.theme.error)));
+        }
+        let mut title = format!("Events ({})", visible.len());
+        if !event_log.filter.is_empty() {
+            title.push_str(&format!(" [sandbox {}]", event_log.filter));
+        }
+        title.push_str(match event_log.follow {
+            true => " - following",
+            false => " - paused, G to follow",
+        });
+        let events = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(events, chunks[1]);
+
+        let help = if event_log.editing {
+            Paragraph::new(format!("/{}█  (sandbox ID prefix | Enter: Apply | Esc: Clear)", event_log.filter))
+                .style(Style::default().fg(self.theme.input))
+        } else {
+            let help_text = "↑/↓,k/j: Scroll | gg: Top | G: Follow | Ctrl-U/D: Half page | /: Filter by Sandbox | c: Clear | Ctrl-C: Copy Content | ?: Help | Esc: Back";
+            Paragraph::new(help_text)
+                .style(Style::default().fg(self.theme.hint))
+                .alignment(Alignment::Center)
+        };
+        frame.render_widget(help, chunks[2]);
+    }
+
+    fn draw_terminal(&mut self, frame: &mut Frame, area: Rect, sandbox_id: &str) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(area);
+
+        // Header
+        let title = format!("Terminal - {}", &sandbox_id[..8.min(sandbox_id.len())]);
+        let header = Paragraph::new(title)
+            .style(self.theme.header())
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL));
+        frame.render_widget(header, chunks[0]);
+
+        if let Some(terminal) = &mut self.terminal {
+            let block = Block::default().borders(Borders::ALL);
+            let inner = block.inner(chunks[1]);
+            terminal.resize(inner.height, inner.width);
+            let screen = terminal.parser.screen();
+            let title = match screen.scrollback() {
+                0 => "Shell".to_string(),
+                lines => format!("Shell (scrolled back {} lines)", lines),
+            };
+            frame.render_widget(Paragraph::new(terminal.lines()).block(block.title(title)), chunks[1]);
+            if screen.scrollback() == 0 && !screen.hide_cursor() {
+                let (row, col) = screen.cursor_position();
+                frame.set_cursor_position((inner.x + col, inner.y + row));
+            }
+        }
+
+        let help_text = "Keys go to the shell | Ctrl-]: Detach | Mouse wheel: Scroll back | F1: Toggle Mouse/Selection";
         let help = Paragraph::new(help_text)
-            .style(Style::default().fg(Color::Gray))
+            .style(Style::default().fg(self.theme.hint))
             .alignment(Alignment::Center);
         frame.render_widget(help, chunks[2]);
     }
 
-    fn draw_new_sandbox(&self, frame: &mut Frame, area: Rect) {
+    fn draw_new_sandbox(&mut self, frame: &mut Frame, area: Rect) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .margin(1)
@@ -984,13 +3509,50 @@ impl App {
 
         // Header
         let header = Paragraph::new("New Sandbox")
-            .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+            .style(self.theme.header())
             .alignment(Alignment::Center)
             .block(Block::default().borders(Borders::ALL));
         frame.render_widget(header, chunks[0]);
 
         // Content based on step
         match &self.new_sandbox_state.step {
+            NewSandboxStep::PickTemplate => {
+                let form_chunks = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+                    .split(chunks[1]);
+                self.click_targets.list = Some((Block::default().borders(Borders::ALL).inner(form_chunks[0]), 0));
+
+                let state = &self.new_sandbox_state;
+                let names = std::iter::once("Manual entry (image and setup commands)".to_string())
+                    .chain(state.templates.iter().map(|template| template.name.clone()));
+                let items: Vec<ListItem> = names
+                    .enumerate()
+                    .map(|(i, name)| {
+                        let style = if i == state.selected_template {
+                            self.theme.selected()
+                        } else {
+                            Style::default()
+                        };
+                        ListItem::new(name).style(style)
+                    })
+                    .collect();
+                let list = List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title("Templates"));
+                frame.render_widget(list, form_chunks[0]);
+
+                let preview = match state.selected_template.checked_sub(1).and_then(|i| state.templates.get(i)) {
+                    Some(template) => Self::template_preview(&self.theme, template),
+                    None => vec![
+                        Line::from("Enter the image and setup commands of the sandbox."),
+                        Line::from(format!("Image: {}", state.image)),
+                    ],
+                };
+                let preview = Paragraph::new(preview)
+                    .block(Block::default().borders(Borders::ALL).title("Preview"))
+                    .wrap(Wrap { trim: false });
+                frame.render_widget(preview, form_chunks[1]);
+            }
             NewSandboxStep::EnterImage => {
                 let form_chunks = Layout::default()
                     .direction(Direction::Vertical)
@@ -1001,12 +3563,12 @@ impl App {
                     .split(chunks[1]);
 
                 let image_input = Paragraph::new(self.new_sandbox_state.image.as_str())
-                    .style(Style::default().fg(Color::Yellow))
+                    .style(Style::default().fg(self.theme.input))
                     .block(Block::default().borders(Borders::ALL).title("Docker Image"));
                 frame.render_widget(image_input, form_chunks[0]);
 
                 let instructions = Paragraph::new("Enter the Docker image name (e.g., ubuntu:latest, python:3.9)\nPress Enter to continue, Esc to cancel")
-                    .style(Style::default().fg(Color::Gray))
+                    .style(Style::default().fg(self.theme.hint))
                     .block(Block::default().borders(Borders::ALL).title("Instructions"));
                 frame.render_widget(instructions, form_chunks[1]);
             }
@@ -1029,18 +3591,18 @@ impl App {
 
                 // Current command input
                 let current_input = Paragraph::new(self.new_sandbox_state.current_command.as_str())
-                    .style(Style::default().fg(Color::Yellow))
+                    .style(Style::default().fg(self.theme.input))
                     .block(Block::default().borders(Borders::ALL).title("Add Command"));
                 frame.render_widget(current_input, form_chunks[1]);
 
                 let instructions = Paragraph::new("Enter setup commands one by one. Press Enter after each command.\nPress Enter on empty line to finish and create sandbox.\nEsc to cancel")
-                    .style(Style::default().fg(Color::Gray))
+                    .style(Style::default().fg(self.theme.hint))
                     .block(Block::default().borders(Borders::ALL).title("Instructions"));
                 frame.render_widget(instructions, form_chunks[2]);
             }
             NewSandboxStep::Creating => {
                 let creating = Paragraph::new("Creating sandbox...")
-                    .style(Style::default().fg(Color::Yellow))
+                    .style(Style::default().fg(self.theme.input))
                     .alignment(Alignment::Center)
                     .block(Block::default().borders(Borders::ALL));
                 frame.render_widget(creating, chunks[1]);
@@ -1051,14 +3613,52 @@ impl App {
         }
 
         // Help
-        let help_text = "Follow the prompts | Ctrl-C: Copy Content | Esc: Cancel and return to main menu";
+        let help_text = match self.new_sandbox_state.step {
+            NewSandboxStep::PickTemplate => "↑/↓,k/j: Navigate | Enter: Create from Template / Manual Entry | Esc: Cancel and return to main menu",
+            _ => "Follow the prompts | Ctrl-C: Copy Content | Esc: Cancel and return to main menu",
+        };
         let help = Paragraph::new(help_text)
-            .style(Style::default().fg(Color::Gray))
+            .style(Style::default().fg(self.theme.hint))
             .alignment(Alignment::Center);
         frame.render_widget(help, chunks[2]);
     }
 
-    fn draw_sandbox_session(&self, frame: &mut Frame, area: Rect, sandbox_id: &str) {
+    /// Image, setup commands, limits and labels of a template, for the picker.
+    fn template_preview(theme: &Theme, template: &Template) -> Vec<Line<'static>> {
+        let label = Style::default().fg(theme.accent).add_modifier(Modifier::BOLD);
+        let mut lines = vec![Line::from(vec![Span::styled("Image: ", label), Span::raw(template.image.clone())])];
+        lines.push(Line::from(Span::styled("Setup commands:", label)));
+        if template.setup_commands.is_empty() {
+            lines.push(Line::from("  none"));
+        }
+        for command in &template.setup_commands {
+            lines.push(Line::from(format!("  $ {}", command)).style(Style::default().fg(theme.command)));
+        }
+        if let Some(limits) = &template.limits {
+            let mut parts = Vec::new();
+            if let Some(memory) = limits.memory_mb {
+                parts.push(format!("{} MiB", memory));
+            }
+            if let Some(cpus) = limits.cpus {
+                parts.push(format!("{} CPUs", cpus));
+            }
+            if let Some(pids) = limits.pids {
+                parts.push(format!("{} processes", pids));
+            }
+            lines.push(Line::from(vec![Span::styled("Limits: ", label), Span::raw(parts.join(", "))]));
+        }
+        if !template.labels.is_empty() {
+            let mut labels: Vec<String> = template.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            labels.sort();
+            lines.push(Line::from(vec![Span::styled("Labels: ", label), Span::raw(labels.join(", "))]));
+        }
+        if let Some(verify) = &template.verify_command {
+            lines.push(Line::from(vec![Span::styled("Verify: ", label), Span::raw(verify.clone())]));
+        }
+        lines
+    }
+
+    fn draw_sandbox_session(&mut self, frame: &mut Frame, area: Rect, sandbox_id: &str) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .margin(1)
@@ -1070,51 +3670,106 @@ impl App {
             .split(area);
 
         // Header
-        let title = format!("Session - {}", &sandbox_id[..8.min(sandbox_id.len())]);
+        let title = match self.split.len() {
+            0 => format!("Session - {}", &sandbox_id[..8.min(sandbox_id.len())]),
+            n => format!("Sessions - {} panes, focused {}", n + 1, &sandbox_id[..8.min(sandbox_id.len())]),
+        };
         let header = Paragraph::new(title)
-            .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+            .style(self.theme.header())
             .alignment(Alignment::Center)
             .block(Block::default().borders(Borders::ALL));
         frame.render_widget(header, chunks[0]);
 
-        self.draw_session_content(frame, chunks[1]);
+        if self.split.is_empty() {
+            self.click_targets.panes = vec![chunks[1]];
+            self.draw_session_content(frame, chunks[1]);
+        } else {
+            // Side by side, in their order
+            let count = self.split.len() as u32 + 1;
+            let panes = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints(vec![Constraint::Ratio(1, count); count as usize])
+                .split(chunks[1]);
+            self.click_targets.panes = panes.to_vec();
+            let mut others = self.split.iter();
+            for (i, area) in panes.iter().enumerate() {
+                if i == self.focus_index {
+                    Self::draw_session_pane(frame, *area, &self.theme, sandbox_id, &self.session_state, self.running.as_ref(), true);
+                } else if let Some(pane) = others.next() {
+                    Self::draw_session_pane(frame, *area, &self.theme, &pane.sandbox_id, &pane.state, pane.running.as_ref(), false);
+                }
+            }
+        }
 
         // Help
-        let help_text = "Type commands and press Enter | ↑/↓,k/j: Scroll (when input empty) | gg: Top | G: Bottom | Ctrl-U/D: Half page | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | Esc: Exit session";
+        let help_text = "Type commands and press Enter | ↑/↓: Previous/Next Command | Ctrl-R: Search History | Ctrl-O: Open Output | Ctrl-U/D: Scroll | Ctrl-V: Open Session Beside | Tab: Switch Pane | Ctrl-W: Close Pane | Ctrl-T: Terminal | F1: Toggle Mouse/Selection | Ctrl-C: Copy Content | ?: Help | Esc: Exit session";
         let help = Paragraph::new(help_text)
-            .style(Style::default().fg(Color::Gray))
+            .style(Style::default().fg(self.theme.hint))
             .alignment(Alignment::Center);
         frame.render_widget(help, chunks[2]);
     }
 
     fn draw_session_content(&self, frame: &mut Frame, area: Rect) {
+        Self::draw_session_output(frame, area, &self.theme, "Output".to_string(), &self.session_state, self.running.as_ref(), Style::default());
+    }
+
+    /// Pane of the split view. The focused one has a border of the input color.
+    fn draw_session_pane(frame: &mut Frame, area: Rect, theme: &Theme, sandbox_id: &str, state: &SessionState, running: Option<&RunningCommand>, focused: bool) {
+        let border = match focused {
+            true => Style::default().fg(theme.input),
+            false => Style::default().fg(theme.muted),
+        };
+        let title = format!("Output - {}", &sandbox_id[..8.min(sandbox_id.len())]);
+        Self::draw_session_output(frame, area, theme, title, state, running, border);
+    }
+
+    fn draw_session_output(frame: &mut Frame, area: Rect, theme: &Theme, title: String, state: &SessionState, running: Option<&RunningCommand>, border: Style) {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
             .split(area);
 
-        // History
-        let history_lines: Vec<Line> = self.session_state.history
-            .iter()
-            .skip(self.session_state.scroll_offset)
-            .take(chunks[0].height.saturating_sub(2) as usize)
-            .map(|line| Self::colorize_session_line(line))
-            .collect();
+        // History, followed by the running command whose output is kept in view
+        let viewport_height = chunks[0].height.saturating_sub(2) as usize;
+        let history_lines: Vec<Line> = match running {
+            Some(running) => {
+                let mut lines: Vec<Line> = state.history.iter().map(|line| Self::colorize_session_line(theme, line)).collect();
+                lines.extend(running.display_lines(theme));
+                lines.split_off(lines.len().saturating_sub(viewport_height))
+            }
+            None => state.history
+                .iter()
+                .skip(state.scroll_offset)
+                .take(viewport_height)
+                .map(|line| Self::colorize_session_line(theme, line))
+                .collect(),
+        };
 
         let history = Paragraph::new(history_lines)
-            .block(Block::default().borders(Borders::ALL).title("Output"))
+            .block(Block::default().borders(Borders::ALL).border_style(border).title(title))
             .wrap(Wrap { trim: false });
         frame.render_widget(history, chunks[0]);
 
-        // Input
-        let input = Paragraph::new(self.session_state.current_input.as_str())
-            .style(Style::default().fg(Color::Yellow))
-            .block(Block::default().borders(Borders::ALL).title("Command"));
+        // Input, or the command found by the Ctrl-R search
+        let (text, title) = match &state.inputs.search {
+            Some((query, Some(index))) => (state.inputs.commands[*index].as_str(), format!("Search history: {}█ (Ctrl-R: Older | Enter: Use | Esc: Cancel)", query)),
+            Some((query, None)) => ("", format!("Search history: {}█ (no match)", query)),
+            None => (state.current_input.as_str(), "Command".to_string()),
+        };
+        let input = Paragraph::new(text)
+            .style(Style::default().fg(theme.input))
+            .block(Block::default().borders(Borders::ALL).border_style(border).title(title));
         frame.render_widget(input, chunks[1]);
     }
 }
 
-pub async fn run_tui(server_url: String) -> Result<()> {
+pub async fn run_tui(
+    client: SosClient,
+    default_image: String,
+    default_setup: Vec<String>,
+    keymap: Keymap,
+    theme: Theme,
+) -> Result<()> {
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -1123,13 +3778,22 @@ pub async fn run_tui(server_url: String) -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // Create app
-    let mut app = App::new(server_url);
+    let mut app = App::new(client, default_image, default_setup, keymap, theme);
     
     // Initial data load
     let _ = app.refresh_sandbox_list().await;
 
     // Main loop
     loop {
+        if let Some(resources) = &mut app.resources {
+            resources.tick();
+        }
+        app.tick_command();
+        app.tick_terminal();
+        app.tick_connection().await;
+        if let Some(event_log) = &mut app.event_log {
+            event_log.tick();
+        }
         terminal.draw(|f| app.draw(f))?;
 
         if event::poll(Duration::from_millis(100))? {
@@ -0,0 +1,746 @@
+//! Request and response types of the HTTP API.
+//!
+//! Shared by the server handlers and [`crate::client::SosClient`] so both sides agree
+//! on the shape of every payload.
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::policy::PolicyViolation;
+use crate::sandbox::{
+    CommandResult, CommandUsage, Mount, NetworkRequest, PullProgress, RepoSpec, ResourceLimits,
+    RestartPolicy, Shell, TerminalSize, Verification,
+};
+
+/// POST `/sandboxes` payload.
+///
+/// Includes the container image to use and the setup commands to run
+/// on container startup. Setup commands will be chained together with `&&`.
+/// When `template` is set, the template is merged into the payload first.
+/// `verify_command` is run standalone by `POST /sandboxes/{id}/verify`, and
+/// `time_limit_secs` overrides the server timeout after which the sandbox is stopped.
+/// `dns`, `dns_search` and `extra_hosts` (`host:ip` entries) set the name resolution of
+/// the container instead of the runtime's. `shell` picks the shell of the session,
+/// `bash` by default, and `shell_init` lines run in it once it is configured, after
+/// the server's. `tools` names the tool bundles of the server installed on start, and
+/// `repo` a git repository cloned before the setup commands run. `cols` and `rows` size
+/// the session terminal, 80x24 when only one is given and the runtime's default when none
+/// is. Commands matching a regular expression of `blocked_commands` are refused with
+/// `COMMAND_BLOCKED` and recorded in the trajectory without being run. Once
+/// `max_commands` commands are recorded, the sandbox is `exhausted` and refuses session
+/// commands with `BUDGET_EXHAUSTED`. A sandbox whose session shell or container died is
+/// `crashed` and fails with `SANDBOX_CRASHED`, unless `restart_policy` is `on_crash`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreatePayload {
+    #[serde(default)]
+    pub image: String,
+    #[serde(default)]
+    pub setup_commands: Vec<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<ResourceLimits>,
+    /// Host paths mounted into the container, set by the template alone: clients could
+    /// otherwise mount any path of the host
+    #[serde(skip)]
+    pub mounts: Vec<Mount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit_secs: Option<u64>,
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub dns_search: Vec<String>,
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Shell>,
+    #[serde(default)]
+    pub shell_init: Vec<String>,
+    #[serde(default)]
+    pub tools: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<RepoSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cols: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u16>,
+    #[serde(default)]
+    pub blocked_commands: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_commands: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+}
+
+impl CreatePayload {
+    /// Size of the session terminal, when `cols` or `rows` is given.
+    pub fn terminal_size(&self) -> Option<TerminalSize> {
+        if self.cols.is_none() && self.rows.is_none() {
+            return None;
+        }
+        let default = TerminalSize::default();
+        Some(TerminalSize {
+            cols: self.cols.unwrap_or(default.cols),
+            rows: self.rows.unwrap_or(default.rows),
+        })
+    }
+}
+
+/// Named sandbox template.
+///
+/// Bundles an image, setup commands, env, limits, mounts, a shell, a repository and a verify
+/// command so clients can create sandboxes with `template: "<name>"` instead of repeating them
+/// in every request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Template {
+    pub name: String,
+    pub image: String,
+    #[serde(default)]
+    pub setup_commands: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub limits: Option<ResourceLimits>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Shell>,
+    #[serde(default)]
+    pub shell_init: Vec<String>,
+    #[serde(default)]
+    pub tools: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<RepoSpec>,
+    #[serde(default)]
+    pub blocked_commands: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_commands: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+}
+
+impl Template {
+    /// Copy of the template with the token of its repository redacted, to be returned by
+    /// the API.
+    pub fn redacted(&self) -> Template {
+        Template {
+            repo: self.repo.as_ref().map(RepoSpec::redacted),
+            ..self.clone()
+        }
+    }
+
+    /// Merges the template into a create payload.
+    ///
+    /// Values given in the payload take precedence: the image, limits, shell, repository
+    /// and verify command replace the template's, env and labels are merged, and setup
+    /// commands, shell init lines, tools and blocked commands are appended after the
+    /// template's. Mounts and the restart policy set by the template are kept, and the
+    /// payload may only lower its command budget.
+    pub fn apply(&self, payload: CreatePayload) -> CreatePayload {
+        let image = match payload.image.is_empty() {
+            true => self.image.clone(),
+            false => payload.image,
+        };
+
+        let mut env = self.env.clone();
+        env.extend(payload.env);
+        let mut labels = self.labels.clone();
+        labels.extend(payload.labels);
+
+        CreatePayload {
+            image,
+            setup_commands: [self.setup_commands.clone(), payload.setup_commands].concat(),
+            labels,
+            env,
+            limits: payload.limits.or_else(|| self.limits.clone()),
+            mounts: self.mounts.clone(),
+            verify_command: payload
+                .verify_command
+                .or_else(|| self.verify_command.clone()),
+            shell: payload.shell.or(self.shell),
+            shell_init: [self.shell_init.clone(), payload.shell_init].concat(),
+            tools: [self.tools.clone(), payload.tools].concat(),
+            repo: payload.repo.or_else(|| self.repo.clone()),
+            blocked_commands: [self.blocked_commands.clone(), payload.blocked_commands].concat(),
+            max_commands: match (payload.max_commands, self.max_commands) {
+                (Some(requested), Some(max)) => Some(requested.min(max)),
+                (requested, max) => requested.or(max),
+            },
+            restart_policy: self.restart_policy.or(payload.restart_policy),
+            ..payload
+        }
+    }
+}
+
+/// POST `/sandboxes` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateResponse {
+    pub id: String,
+}
+
+/// POST `/tasks/{name}/instantiate` response.
+///
+/// The sandbox is already started, with the task files seeded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstantiateResponse {
+    pub id: String,
+    pub instructions: String,
+}
+
+/// POST `/sandboxes/{id}/exec` payload.
+///
+/// Includes the command to execute and whether it should be run in standalone
+/// mode. With `raw`, the response also has the output with its ANSI escape sequences,
+/// for clients rendering terminal colors. A command still running after `timeout_secs`
+/// fails with `COMMAND_TIMEOUT`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecPayload {
+    pub command: String,
+    pub standalone: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// POST `/sandboxes/{id}/exec` response.
+///
+/// `exited` is set when the command ended the session shell. `output` interleaves
+/// `stdout` and `stderr` line by line, in the order they were written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecResponse {
+    pub output: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    pub exit_code: i64,
+    pub exited: bool,
+    /// Part of the output was dropped, see [`CommandResult`]
+    #[serde(default)]
+    pub truncated: bool,
+    /// Output with its ANSI escape sequences, when asked for with `raw`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_output: Option<String>,
+}
+
+impl From<CommandResult> for ExecResponse {
+    fn from(result: CommandResult) -> Self {
+        ExecResponse {
+            output: result.output,
+            stdout: result.stdout,
+            stderr: result.stderr,
+            exit_code: result.exit_code,
+            exited: result.exited,
+            truncated: result.truncated,
+            raw_output: result.raw_output,
+        }
+    }
+}
+
+/// POST `/sandboxes/{id}/kernel/execute` payload.
+///
+/// Executes `code` in the Jupyter kernel of the sandbox. The kernel is interrupted
+/// when it has not replied after `timeout_secs`, 60 by default. The response is a
+/// [`KernelReply`](crate::sandbox::KernelReply).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KernelExecutePayload {
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Query of `GET /sandboxes/{id}/attach`, the initial size of the terminal, the runtime's
+/// default when not given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AttachQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cols: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u16>,
+}
+
+/// POST `/sandboxes/{id}/resize` payload, the new size of the session terminal.
+pub type ResizePayload = TerminalSize;
+
+/// Query of `GET` and `PUT /sandboxes/{id}/files`.
+///
+/// `GET` returns `path`, a file or a directory of the sandbox, as a tar archive. `PUT`
+/// extracts the tar archive of the body into the `path` directory, created if missing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilesQuery {
+    pub path: String,
+}
+
+/// POST `/sandboxes/{id}/copy` payload.
+///
+/// Copies `path`, a file or a directory of the sandbox, into the `destination_dir`
+/// directory of the `destination` sandbox, created if missing. The copy keeps the name of
+/// `path`, and lands at the same place when `destination_dir` is not given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CopyPayload {
+    pub path: String,
+    pub destination: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_dir: Option<String>,
+}
+
+/// Query of `GET /sandboxes/{id}/patch`.
+///
+/// `dir` is the git working tree to diff, the directory of the sandbox repository or
+/// `/workspace` when not given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+}
+
+/// GET `/sandboxes/{id}/patch` response.
+///
+/// `patch` is the unified diff of the changes against `HEAD`, untracked files included,
+/// binary files in git's binary format, and `files` the paths it changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchResponse {
+    pub patch: String,
+    pub files: Vec<String>,
+}
+
+/// POST `/sandboxes/{id}/patch` payload.
+///
+/// Applies `patch`, a unified diff as returned by `GET /sandboxes/{id}/patch`, with
+/// `git apply` in `dir`, defaulting as for [`PatchQuery`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApplyPatchPayload {
+    pub patch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+}
+
+/// Query of `GET /sandboxes/{id}/logs`.
+///
+/// `source` picks the logs returned, all of them by default: the output of the main
+/// process of the container, then the raw output of the session terminal, markers and
+/// echoed commands included, of which the last 256 KiB are kept. With `follow`, new output
+/// is streamed until the sandbox is removed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogsQuery {
+    #[serde(default)]
+    pub source: LogSource,
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// Logs returned by `GET /sandboxes/{id}/logs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSource {
+    #[default]
+    All,
+    Container,
+    Session,
+}
+
+impl std::str::FromStr for LogSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(LogSource::All),
+            "container" => Ok(LogSource::Container),
+            "session" => Ok(LogSource::Session),
+            _ => Err(format!(
+                "Unknown log source '{}', expected all, container or session",
+                s
+            )),
+        }
+    }
+}
+
+/// POST `/sandboxes/exec` payload.
+///
+/// Runs the same command in every sandbox listed in `ids` and in every sandbox
+/// whose labels include all of `labels`. At least one selector must be given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FanOutExecPayload {
+    pub command: String,
+    pub standalone: Option<bool>,
+    #[serde(default)]
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// Result of a fan-out exec in a single sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FanOutResult {
+    Ok(ExecResponse),
+    Err { error: String },
+}
+
+/// POST `/sandboxes/exec` response, keyed by sandbox ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutExecResponse {
+    pub results: HashMap<String, FanOutResult>,
+}
+
+/// POST `/images/pull` payload.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PullPayload {
+    pub images: Vec<String>,
+}
+
+/// Outcome of pulling a single image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PullResult {
+    /// The image was already present
+    Present,
+    Pulled,
+    Failed { error: String },
+}
+
+/// POST `/images/pull` response, keyed by image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullResponse {
+    pub results: HashMap<String, PullResult>,
+}
+
+/// POST `/sandboxes/{id}/stop` payload.
+///
+/// Includes a flag for whether to remove the sandbox after stopping it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopPayload {
+    pub remove: Option<bool>,
+}
+
+/// POST `/sandboxes/{id}/stop` response.
+///
+/// `archive_url` is set when the sandbox was archived to object storage.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_url: Option<String>,
+}
+
+/// Outcome of `POST /sandboxes/{id}/start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartStatus {
+    Started,
+    /// The server is at capacity, the sandbox starts once a slot frees up
+    Queued,
+}
+
+/// POST `/sandboxes/{id}/start` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartResponse {
+    pub status: StartStatus,
+}
+
+/// Type of a server event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerEventKind {
+    Created,
+    /// The sandbox is waiting for capacity to start
+    Queued,
+    Started,
+    /// A queued sandbox could not be started
+    StartFailed,
+    Stopped,
+    Removed,
+    Frozen,
+    Unfrozen,
+    /// A fresh session shell replaced the one that exited
+    SessionOpened,
+    ExecStarted,
+    ExecFinished,
+    /// The reaper is removing the sandbox after its time limit
+    TimedOut,
+    /// The image of a starting sandbox is being pulled
+    Pulling,
+}
+
+impl ServerEventKind {
+    /// Name of the event in the `GET /events` stream.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerEventKind::Created => "created",
+            ServerEventKind::Queued => "queued",
+            ServerEventKind::Started => "started",
+            ServerEventKind::StartFailed => "start_failed",
+            ServerEventKind::Stopped => "stopped",
+            ServerEventKind::Removed => "removed",
+            ServerEventKind::Frozen => "frozen",
+            ServerEventKind::Unfrozen => "unfrozen",
+            ServerEventKind::SessionOpened => "session_opened",
+            ServerEventKind::ExecStarted => "exec_started",
+            ServerEventKind::ExecFinished => "exec_finished",
+            ServerEventKind::TimedOut => "timed_out",
+            ServerEventKind::Pulling => "pulling",
+        }
+    }
+}
+
+/// Event of the `GET /events` stream.
+///
+/// `command` is set on exec events, `exit_code` on finished execs that succeeded,
+/// `progress` on pulling events and `error` on failed starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerEvent {
+    #[serde(rename = "type")]
+    pub kind: ServerEventKind,
+    pub sandbox_id: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<PullProgress>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ServerEvent {
+    pub fn new(kind: ServerEventKind, sandbox_id: impl Into<String>) -> Self {
+        ServerEvent {
+            kind,
+            sandbox_id: sandbox_id.into(),
+            timestamp: Utc::now(),
+            command: None,
+            exit_code: None,
+            progress: None,
+            error: None,
+        }
+    }
+}
+
+/// GET `/sandboxes/{id}/trajectory` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrajectoryResponse {
+    pub sandbox_id: String,
+    pub command_count: usize,
+    pub trajectory: Vec<TrajectoryEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub verifications: Vec<VerifyResponse>,
+    /// HTTP requests made through the recording proxy, when it is enabled
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requests: Vec<NetworkRequest>,
+}
+
+/// Line of a JSONL trajectory export, one per command.
+///
+/// Timestamps are RFC 3339 and the duration is in seconds. The output, exit code, end
+/// and resource usage of the command are missing while it is still running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrajectoryRecord {
+    pub sandbox_id: String,
+    pub index: usize,
+    pub command: String,
+    pub output: Option<String>,
+    pub exit_code: Option<i64>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CommandUsage>,
+}
+
+/// Chat trajectory export, in the `messages` shape of OpenAI and Hugging Face chat
+/// fine-tuning datasets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatExport {
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// Single command of a trajectory.
+///
+/// `timestamp` is the number of seconds since the sandbox started and `started_at`
+/// the wall-clock time of the command. `result` and `duration` (in seconds) are
+/// missing while the command is still running, and for a command refused by the
+/// `blocked` pattern of the sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrajectoryEntry {
+    pub index: usize,
+    pub command: String,
+    pub timestamp: f64,
+    pub started_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<TrajectoryResult>,
+    /// CPU time used while the command ran, and the peak memory of the sandbox so far
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CommandUsage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrajectoryResult {
+    pub output: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    pub exit_code: i64,
+}
+
+/// POST `/sandboxes/{id}/verify` response, also listed in the trajectory.
+///
+/// `step` is the number of session commands executed when the verify command ran.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyResponse {
+    pub score: f64,
+    pub passed: bool,
+    pub output: String,
+    pub exit_code: i64,
+    pub step: usize,
+}
+
+impl From<Verification> for VerifyResponse {
+    fn from(verification: Verification) -> Self {
+        VerifyResponse {
+            score: verification.score,
+            passed: verification.passed,
+            output: verification.output,
+            exit_code: verification.exit_code,
+            step: verification.after_step,
+        }
+    }
+}
+
+/// GET `/sandboxes` response struct.
+///
+/// Includes the ID, image, setup commands, and status of the sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SandboxInfo {
+    pub id: String,
+    pub image: String,
+    pub setup_commands: String,
+    pub status: String,
+    pub session_command_count: usize,
+    pub last_standalone_exit_code: Option<i64>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_url: Option<String>,
+}
+
+/// GET `/sandboxes/{id}` response struct.
+///
+/// The sandbox as listed, with its container, start time, limits and the exit code of
+/// the last command of its session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SandboxDetail {
+    #[serde(flatten)]
+    pub info: SandboxInfo,
+    /// ID of the container, while it runs
+    pub container_id: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Time after which the sandbox is stopped, when it has one
+    pub time_limit_secs: Option<u64>,
+    pub last_exit_code: Option<i64>,
+}
+
+/// GET `/whoami` response struct: the tenant the credentials of the request map to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WhoAmIResponse {
+    pub tenant: String,
+}
+
+/// POST `/admin/reload` response struct: what the reloaded configuration holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadResponse {
+    pub max_sandboxes: usize,
+    pub templates: usize,
+    pub tasks: usize,
+    pub tenants: usize,
+}
+
+/// Body of every error response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    /// Rule of the admission policy the request broke, for `POLICY_VIOLATION` errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violation: Option<PolicyViolation>,
+}
+
+/// POST `/envs` payload.
+///
+/// Describes an RL environment: the sandbox to create on every reset, the
+/// instruction returned as the initial observation, and how the episode is scored.
+/// When the episode ends, the `verify_command` of the sandbox runs and its score is
+/// the reward.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnvSpec {
+    #[serde(flatten)]
+    pub sandbox: CreatePayload,
+    /// Task instruction, returned as the initial observation on reset
+    #[serde(default)]
+    pub instruction: String,
+    /// Number of steps after which the episode is truncated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<usize>,
+}
+
+/// POST `/envs/{id}/reset` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetResponse {
+    pub observation: String,
+    pub info: StepInfo,
+}
+
+/// POST `/envs/{id}/step` payload.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StepPayload {
+    pub command: String,
+}
+
+/// POST `/envs/{id}/step` response.
+///
+/// `done` is set when the shell exited or the step budget ran out (`truncated`).
+/// The reward is only computed once the episode is done and is 0.0 before that.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResponse {
+    pub observation: String,
+    pub exit_code: i64,
+    pub reward: f64,
+    pub done: bool,
+    pub truncated: bool,
+    pub info: StepInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepInfo {
+    pub sandbox_id: String,
+    pub step: usize,
+}
@@ -0,0 +1,74 @@
+//! Archival of sandboxes to object storage.
+//!
+//! When a sandbox stops, its trajectory (as JSONL) and optionally a tar of its workspace
+//! are uploaded to `<url>/<tenant>/<sandbox id>/`, on any store supported by
+//! `object_store` (`s3://`, `gs://`, `az://`, `file://`, ...).
+use std::collections::HashMap;
+
+use anyhow::Context;
+use object_store::{ObjectStore, PutPayload, path::Path};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::export::{records, to_jsonl};
+use crate::sandbox::Sandbox;
+
+/// Archive section of the server configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Location archives are uploaded to, e.g. `s3://bucket/trajectories`
+    pub url: String,
+    /// Store options such as `aws_region` or `aws_endpoint` for S3-compatible stores.
+    /// Credentials are read from the environment when not given here.
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    /// Directory of the container uploaded as `workspace.tar`. Only the trajectory is
+    /// archived when unset.
+    #[serde(default)]
+    pub workspace: Option<String>,
+}
+
+/// Uploads sandbox archives to the configured store.
+pub struct Archiver {
+    store: Box<dyn ObjectStore>,
+    prefix: Path,
+    url: String,
+    workspace: Option<String>,
+}
+
+impl Archiver {
+    pub fn new(config: &ArchiveConfig) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url)
+            .with_context(|| format!("Invalid archive URL {}", config.url))?;
+        let (store, prefix) = object_store::parse_url_opts(&url, &config.options)
+            .with_context(|| format!("Unsupported archive URL {}", config.url))?;
+        Ok(Archiver {
+            store,
+            prefix,
+            url: config.url.trim_end_matches('/').to_string(),
+            workspace: config.workspace.clone(),
+        })
+    }
+
+    /// Uploads the trajectory and workspace of the sandbox, which must still be
+    /// running for the workspace to be exported. Returns the URL of the archive.
+    pub async fn archive(&self, sandbox: &Sandbox) -> anyhow::Result<String> {
+        let dir = self.prefix.child(sandbox.tenant.as_str()).child(sandbox.id.as_str());
+
+        let trajectory = to_jsonl(&records(&sandbox.snapshot()));
+        self.store
+            .put(&dir.child("trajectory.jsonl"), PutPayload::from(trajectory.into_bytes()))
+            .await
+            .context("Failed to upload trajectory")?;
+
+        if let Some(workspace) = &self.workspace {
+            let tar = sandbox.download(workspace, None).await?;
+            self.store
+                .put(&dir.child("workspace.tar"), PutPayload::from(tar))
+                .await
+                .context("Failed to upload workspace")?;
+        }
+
+        Ok(format!("{}/{}/{}", self.url, sandbox.tenant, sandbox.id))
+    }
+}
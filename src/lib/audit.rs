@@ -0,0 +1,212 @@
+//! Audit log of API calls.
+//!
+//! Every request is appended as a JSON line to the audit log file with who made it,
+//! the sandbox it targeted, the command it ran and how it ended. The log is written
+//! independently of trajectories, so it also covers rejected requests and sandboxes
+//! that were removed.
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex, time::Instant};
+use tracing::warn;
+
+use crate::http::{Caller, SoSState};
+use crate::tls::ClientIdentity;
+
+/// Most of a request or response body inspected, matching the default body limit of
+/// the JSON extractor. Larger bodies are forwarded whole, only their start is read.
+const MAX_AUDITED_BODY: usize = 2 * 1024 * 1024;
+
+/// Line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    /// Tenant of the caller, unset when the request was not authenticated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// Sandbox targeted by the request, or created by it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    /// Error code of failed requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Seconds taken to answer the request
+    pub duration: f64,
+}
+
+/// Append-only audit log file.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+
+    pub async fn append(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+}
+
+/// Sandbox ID in a `/sandboxes/{id}/...` path.
+fn path_sandbox_id(path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("sandboxes"), Some(id)) if !id.is_empty() && id != "exec" => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+fn json_field<'a>(json: &'a serde_json::Value, field: &str) -> Option<&'a serde_json::Value> {
+    json.as_object()?.get(field)
+}
+
+/// Audit logging middleware.
+///
+/// Reads the start of request and JSON response bodies to extract the command and its
+/// result, then forwards them whole. Streamed responses are passed through untouched.
+pub async fn audit(State(state): State<Arc<SoSState>>, request: Request, next: Next) -> Response {
+    let Some(log) = state.audit_log.clone() else {
+        return next.run(request).await;
+    };
+    let start = Instant::now();
+
+    let (mut parts, body) = request.into_parts();
+    let tenant = Caller::from_request_parts(&mut parts, &state)
+        .await
+        .ok()
+        .map(|Caller(tenant)| tenant.name.clone());
+    let mut record = AuditRecord {
+        timestamp: Utc::now(),
+        tenant,
+        client_cert: parts
+            .extensions
+            .get::<ClientIdentity>()
+            .map(|ClientIdentity(cn)| cn.clone()),
+        remote_addr: parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.to_string()),
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
+        sandbox_id: path_sandbox_id(parts.uri.path()),
+        command: None,
+        status: 0,
+        exit_code: None,
+        error: None,
+        duration: 0.0,
+    };
+
+    let (prefix, body) = peek(body).await;
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&prefix) {
+        record.command = json_field(&json, "command")
+            .and_then(|command| command.as_str())
+            .map(str::to_string);
+    }
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+
+    record.status = response.status().as_u16();
+    let response = match is_json {
+        true => {
+            let (parts, body) = response.into_parts();
+            let (prefix, body) = peek(body).await;
+            summarize(&mut record, &prefix);
+            Response::from_parts(parts, body)
+        }
+        false => response,
+    };
+
+    record.duration = start.elapsed().as_secs_f64();
+    if let Err(e) = log.append(&record).await {
+        warn!(error = %e, "Failed to write audit log");
+    }
+    response
+}
+
+/// Reads the start of a body, up to [`MAX_AUDITED_BODY`] bytes. Returns it along with
+/// the body to forward, unchanged: the chunks read are put back in front of the rest.
+async fn peek(body: Body) -> (Bytes, Body) {
+    let mut stream = body.into_data_stream();
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    while size <= MAX_AUDITED_BODY {
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                size += chunk.len();
+                chunks.push(chunk);
+            }
+            // Forwarded as is, for the handler to fail on
+            Some(Err(e)) => {
+                let prefix = Bytes::from(chunks.concat());
+                let read = chunks.into_iter().map(Ok).chain([Err(e)]);
+                return (prefix, Body::from_stream(stream::iter(read)));
+            }
+            None => {
+                let body = Bytes::from(chunks.concat());
+                return (body.clone(), Body::from(body));
+            }
+        }
+    }
+    let mut prefix = chunks.concat();
+    prefix.truncate(MAX_AUDITED_BODY);
+    let read = stream::iter(chunks.into_iter().map(Ok)).chain(stream);
+    (Bytes::from(prefix), Body::from_stream(read))
+}
+
+/// Fills in the result of the request from its JSON response.
+fn summarize(record: &mut AuditRecord, body: &Bytes) {
+    let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return;
+    };
+    record.exit_code = json_field(&json, "exit_code").and_then(|code| code.as_i64());
+    record.error = json_field(&json, "error")
+        .and_then(|error| json_field(error, "code"))
+        .and_then(|code| code.as_str())
+        .map(str::to_string);
+    // The ID of a created sandbox is only known once it is answered
+    if record.sandbox_id.is_none() && record.path == "/sandboxes" && record.method == "POST" {
+        record.sandbox_id = json_field(&json, "id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+    }
+}
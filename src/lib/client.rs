@@ -0,0 +1,693 @@
+//! Typed async client for the SoS HTTP API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), sos::client::ClientError> {
+//! use sos::api::CreatePayload;
+//! use sos::client::SosClient;
+//!
+//! let client = SosClient::new("http://localhost:3000");
+//! let id = client
+//!     .create(&CreatePayload {
+//!         image: "ubuntu:latest".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! client.start_and_wait(&id).await?;
+//! let result = client.exec(&id, "echo hello").await?;
+//! assert_eq!(result.output, "hello");
+//! client.stop(&id, true).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+
+use futures::{Stream, StreamExt, stream};
+use reqwest::{RequestBuilder, Response, header};
+use serde::de::DeserializeOwned;
+
+use crate::api::{
+    ApplyPatchPayload, ChatExport, CopyPayload, CreatePayload, CreateResponse, EnvSpec,
+    ErrorResponse, ExecPayload, ExecResponse, FanOutExecPayload, FanOutExecResponse,
+    FanOutResult, FilesQuery, InstantiateResponse, KernelExecutePayload, LogSource, LogsQuery,
+    PatchQuery, PatchResponse, PullPayload, PullResponse, ReloadResponse, ResetResponse,
+    SandboxDetail, SandboxInfo,
+    ServerEvent, ServerEventKind, StartResponse, StartStatus, StepPayload, StepResponse,
+    StopPayload, StopResponse, Template, TrajectoryEntry, TrajectoryResponse, VerifyResponse,
+    WhoAmIResponse,
+};
+use crate::policy::PolicyViolation;
+use crate::sandbox::{KernelReply, ResourceUsage, TerminalSize};
+use crate::swebench::SweBenchImport;
+use crate::task::Task;
+
+/// Errors returned by [`SosClient`].
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The request could not be sent or the response could not be decoded
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    /// A streamed event could not be decoded
+    #[error("Invalid event: {0}")]
+    Decode(#[from] serde_json::Error),
+    /// The API key cannot be sent as a header
+    #[error("Invalid API key: {0}")]
+    InvalidApiKey(#[from] header::InvalidHeaderValue),
+    /// A queued sandbox could not be started
+    #[error("Failed to start sandbox: {0}")]
+    StartFailed(String),
+    /// A WebSocket could not be opened
+    #[error("WebSocket failed: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    /// The server answered with an error response
+    #[error("{message}")]
+    Api {
+        status: u16,
+        code: String,
+        message: String,
+        /// Rule of the admission policy the request broke
+        violation: Option<PolicyViolation>,
+    },
+}
+
+impl ClientError {
+    /// Machine-readable error code of an API error, e.g. `SANDBOX_NOT_FOUND`.
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Api { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Whether the server could not be reached: the connection failed or timed out.
+    pub fn is_connect(&self) -> bool {
+        match self {
+            ClientError::Request(error) => error.is_connect() || error.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// WebSocket opened by [`SosClient::attach`].
+pub type WebSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Size of the chunks uploads are streamed in, each reporting progress.
+const UPLOAD_CHUNK_BYTES: usize = 256 * 1024;
+
+/// Client for a SoS server.
+#[derive(Debug, Clone)]
+pub struct SosClient {
+    http: reqwest::Client,
+    base_url: String,
+    /// `Authorization` header, also sent when opening WebSockets
+    authorization: Option<header::HeaderValue>,
+}
+
+impl SosClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        SosClient::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    /// Creates a client that authenticates every request with the API key.
+    pub fn with_api_key(base_url: impl Into<String>, api_key: &str) -> Result<Self> {
+        let mut value = header::HeaderValue::from_str(&format!("Bearer {}", api_key))?;
+        value.set_sensitive(true);
+        let headers = header::HeaderMap::from_iter([(header::AUTHORIZATION, value.clone())]);
+        let http = reqwest::Client::builder().default_headers(headers).build()?;
+        Ok(SosClient {
+            authorization: Some(value),
+            ..SosClient::with_http_client(http, base_url)
+        })
+    }
+
+    /// Creates a client on top of a preconfigured `reqwest` client, e.g. one with
+    /// custom root certificates or a client identity for mutual TLS.
+    pub fn with_http_client(http: reqwest::Client, base_url: impl Into<String>) -> Self {
+        SosClient {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            authorization: None,
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Sends the request and turns error responses into [`ClientError::Api`].
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request.send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status().as_u16();
+        let text = response.text().await.unwrap_or_default();
+        Err(api_error(status, text))
+    }
+
+    async fn send_json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// Creates a sandbox and returns its ID. Does not start it.
+    pub async fn create(&self, payload: &CreatePayload) -> Result<String> {
+        let request = self.http.post(self.url("/sandboxes")).json(payload);
+        let CreateResponse { id } = self.send_json(request).await?;
+        Ok(id)
+    }
+
+    /// Lists the sandboxes visible to the client.
+    pub async fn list(&self) -> Result<Vec<SandboxInfo>> {
+        self.send_json(self.http.get(self.url("/sandboxes"))).await
+    }
+
+    /// Details of a sandbox: its container, start time, limits and last exit code.
+    pub async fn sandbox(&self, id: &str) -> Result<SandboxDetail> {
+        let request = self.http.get(self.url(&format!("/sandboxes/{}", id)));
+        self.send_json(request).await
+    }
+
+    /// Starts a sandbox, waiting until its setup commands finished.
+    pub async fn start(&self, id: &str) -> Result<StartResponse> {
+        let request = self.http.post(self.url(&format!("/sandboxes/{}/start", id)));
+        self.send_json(request).await
+    }
+
+    /// Starts the sandbox and, if the server queued it, waits until it has started.
+    pub async fn start_and_wait(&self, id: &str) -> Result<()> {
+        // Subscribed first so the outcome of a queued start is not missed
+        let events = self.events().await?;
+        if self.start(id).await?.status == StartStatus::Started {
+            return Ok(());
+        }
+
+        let mut events = Box::pin(events);
+        while let Some(event) = events.next().await {
+            let event = event?;
+            if event.sandbox_id != id {
+                continue;
+            }
+            match event.kind {
+                ServerEventKind::Started => return Ok(()),
+                ServerEventKind::StartFailed => {
+                    return Err(ClientError::StartFailed(event.error.unwrap_or_default()));
+                }
+                _ => {}
+            }
+        }
+        Err(ClientError::StartFailed(
+            "Events stream closed before the sandbox started".to_string(),
+        ))
+    }
+
+    /// Runs a command in the sandbox session.
+    pub async fn exec(&self, id: &str, command: &str) -> Result<ExecResponse> {
+        self.exec_with(
+            id,
+            &ExecPayload {
+                command: command.to_string(),
+                standalone: None,
+                raw: None,
+                timeout_secs: None,
+            },
+        )
+        .await
+    }
+
+    /// Runs a command in a new process, outside of the session.
+    pub async fn exec_standalone(&self, id: &str, command: &str) -> Result<ExecResponse> {
+        self.exec_with(
+            id,
+            &ExecPayload {
+                command: command.to_string(),
+                standalone: Some(true),
+                raw: None,
+                timeout_secs: None,
+            },
+        )
+        .await
+    }
+
+    pub async fn exec_with(&self, id: &str, payload: &ExecPayload) -> Result<ExecResponse> {
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/exec", id)))
+            .json(payload);
+        self.send_json(request).await
+    }
+
+    /// Executes code in the Jupyter kernel of the sandbox, started on the first call.
+    pub async fn exec_kernel(&self, id: &str, code: &str) -> Result<KernelReply> {
+        let payload = KernelExecutePayload {
+            code: code.to_string(),
+            timeout_secs: None,
+        };
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/kernel/execute", id)))
+            .json(&payload);
+        self.send_json(request).await
+    }
+
+    /// Runs a command in every selected sandbox. Returns the result per sandbox ID.
+    pub async fn exec_many(
+        &self,
+        payload: &FanOutExecPayload,
+    ) -> Result<HashMap<String, FanOutResult>> {
+        let request = self.http.post(self.url("/sandboxes/exec")).json(payload);
+        let FanOutExecResponse { results } = self.send_json(request).await?;
+        Ok(results)
+    }
+
+    /// Stops a sandbox, removing it from the server when `remove` is set.
+    pub async fn stop(&self, id: &str, remove: bool) -> Result<StopResponse> {
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/stop", id)))
+            .json(&StopPayload {
+                remove: Some(remove),
+            });
+        self.send_json(request).await
+    }
+
+    pub async fn freeze(&self, id: &str) -> Result<()> {
+        let request = self.http.post(self.url(&format!("/sandboxes/{}/freeze", id)));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Resizes the session terminal of the sandbox.
+    pub async fn resize(&self, id: &str, cols: u16, rows: u16) -> Result<()> {
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/resize", id)))
+            .json(&TerminalSize { cols, rows });
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Opens an interactive shell in a terminal of a sandbox, sized `size` or the
+    /// runtime's default, as a WebSocket described by `GET /sandboxes/{id}/attach`. The
+    /// socket does not use the TLS settings of a client built with
+    /// [`SosClient::with_http_client`].
+    pub async fn attach(&self, id: &str, size: Option<TerminalSize>) -> Result<WebSocket> {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let url = self.url(&format!("/sandboxes/{}/attach", id));
+        // http:// becomes ws:// and https:// wss://
+        let mut url = match url.strip_prefix("http") {
+            Some(rest) => format!("ws{}", rest),
+            None => url,
+        };
+        if let Some(size) = size {
+            url.push_str(&format!("?cols={}&rows={}", size.cols, size.rows));
+        }
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .map_err(|e| ClientError::WebSocket(Box::new(e)))?;
+        if let Some(authorization) = &self.authorization {
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, authorization.clone());
+        }
+        if url.starts_with("wss://") {
+            let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .map_err(|e| match e {
+                // The server refused the upgrade with an error response
+                tokio_tungstenite::tungstenite::Error::Http(response) => {
+                    let body = response.body().clone().unwrap_or_default();
+                    api_error(
+                        response.status().as_u16(),
+                        String::from_utf8_lossy(&body).into_owned(),
+                    )
+                }
+                e => ClientError::WebSocket(Box::new(e)),
+            })?;
+        Ok(socket)
+    }
+
+    /// Opens a fresh session in a sandbox whose session exited.
+    pub async fn reopen_session(&self, id: &str) -> Result<()> {
+        let request = self.http.post(self.url(&format!("/sandboxes/{}/session", id)));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Downloads a file or a directory of a sandbox as a tar archive. `on_progress` is
+    /// called with the bytes received so far and the size of the archive, when known.
+    pub async fn download(
+        &self,
+        id: &str,
+        path: &str,
+        mut on_progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Vec<u8>> {
+        let query = FilesQuery {
+            path: path.to_string(),
+        };
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/files", id)))
+            .query(&query);
+        let response = self.send(request).await?;
+        let total = response.content_length();
+        let mut archive = Vec::new();
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            archive.extend_from_slice(&chunk?);
+            on_progress(archive.len() as u64, total);
+        }
+        Ok(archive)
+    }
+
+    /// Extracts a tar archive into a directory of a sandbox, created if missing.
+    /// `on_progress` is called with the bytes sent so far and the size of the archive.
+    pub async fn upload(
+        &self,
+        id: &str,
+        dir: &str,
+        archive: Vec<u8>,
+        mut on_progress: impl FnMut(u64, u64) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let query = FilesQuery {
+            path: dir.to_string(),
+        };
+        let total = archive.len() as u64;
+        let archive = bytes::Bytes::from(archive);
+        let chunks: Vec<bytes::Bytes> = (0..archive.len())
+            .step_by(UPLOAD_CHUNK_BYTES)
+            .map(|start| archive.slice(start..archive.len().min(start + UPLOAD_CHUNK_BYTES)))
+            .collect();
+        let mut sent = 0;
+        let body = stream::iter(chunks).map(move |chunk| {
+            sent += chunk.len() as u64;
+            on_progress(sent, total);
+            Ok::<_, std::io::Error>(chunk)
+        });
+        let request = self
+            .http
+            .put(self.url(&format!("/sandboxes/{}/files", id)))
+            .query(&query)
+            .header(header::CONTENT_LENGTH, total)
+            .body(reqwest::Body::wrap_stream(body));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Copies files of a sandbox into another, as described by [`CopyPayload`].
+    pub async fn copy(&self, id: &str, payload: &CopyPayload) -> Result<()> {
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/copy", id)))
+            .json(payload);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Diff of the changes in the git workspace of a sandbox, or of `dir` when given.
+    pub async fn patch(&self, id: &str, dir: Option<&str>) -> Result<PatchResponse> {
+        let query = PatchQuery {
+            dir: dir.map(str::to_string),
+        };
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/patch", id)))
+            .query(&query);
+        self.send_json(request).await
+    }
+
+    /// Applies a diff to the git workspace of a sandbox, or to `dir` when given.
+    pub async fn apply_patch(&self, id: &str, patch: &str, dir: Option<&str>) -> Result<()> {
+        let payload = ApplyPatchPayload {
+            patch: patch.to_string(),
+            dir: dir.map(str::to_string),
+        };
+        let request = self
+            .http
+            .post(self.url(&format!("/sandboxes/{}/patch", id)))
+            .json(&payload);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn unfreeze(&self, id: &str) -> Result<()> {
+        let request = self.http.post(self.url(&format!("/sandboxes/{}/unfreeze", id)));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn trajectory(&self, id: &str) -> Result<TrajectoryResponse> {
+        let request = self.http.get(self.url(&format!("/sandboxes/{}/trajectory", id)));
+        self.send_json(request).await
+    }
+
+    /// Streams the trajectory: the commands executed so far, then every new command as
+    /// it completes. The stream ends when the sandbox is removed.
+    pub async fn stream_trajectory(
+        &self,
+        id: &str,
+    ) -> Result<impl Stream<Item = Result<TrajectoryEntry>> + use<>> {
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/trajectory/stream", id)));
+        let response = self.send(request).await?;
+        Ok(sse_data(response.bytes_stream())
+            .map(|data| Ok(serde_json::from_str::<TrajectoryEntry>(&data?)?)))
+    }
+
+    /// Pulls the images the server does not have yet.
+    pub async fn pull_images(&self, images: &[String]) -> Result<PullResponse> {
+        let request = self.http.post(self.url("/images/pull")).json(&PullPayload {
+            images: images.to_vec(),
+        });
+        self.send_json(request).await
+    }
+
+    /// Streams the logs of a sandbox, as described by [`LogsQuery`]. Without `follow`, the
+    /// stream ends after the logs so far.
+    pub async fn logs(
+        &self,
+        id: &str,
+        source: LogSource,
+        follow: bool,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>> + use<>> {
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/logs", id)))
+            .query(&LogsQuery { source, follow });
+        let response = self.send(request).await?;
+        Ok(response
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(ClientError::from)))
+    }
+
+    /// Tenant the credentials of the client map to, `default` on servers without tenants.
+    pub async fn whoami(&self) -> Result<String> {
+        let WhoAmIResponse { tenant } = self.send_json(self.http.get(self.url("/whoami"))).await?;
+        Ok(tenant)
+    }
+
+    /// Samples the resource usage of a sandbox container.
+    pub async fn stats(&self, id: &str) -> Result<ResourceUsage> {
+        let request = self.http.get(self.url(&format!("/sandboxes/{}/stats", id)));
+        self.send_json(request).await
+    }
+
+    /// Streams the server events of the caller's sandboxes as they happen.
+    pub async fn events(&self) -> Result<impl Stream<Item = Result<ServerEvent>> + use<>> {
+        let request = self.http.get(self.url("/events"));
+        let response = self.send(request).await?;
+        Ok(sse_data(response.bytes_stream())
+            .map(|data| Ok(serde_json::from_str::<ServerEvent>(&data?)?)))
+    }
+
+    /// Runs the verify command of the sandbox and returns its verdict.
+    pub async fn verify(&self, id: &str) -> Result<VerifyResponse> {
+        let request = self.http.post(self.url(&format!("/sandboxes/{}/verify", id)));
+        self.send_json(request).await
+    }
+
+    /// Human-readable rendering of the trajectory.
+    pub async fn trajectory_formatted(&self, id: &str) -> Result<String> {
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/trajectory/formatted", id)));
+        Ok(self.send(request).await?.text().await?)
+    }
+
+    /// Exports the trajectory as role-tagged chat messages, with the command outputs
+    /// in `tool` messages.
+    pub async fn export_chat(&self, id: &str) -> Result<ChatExport> {
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/trajectory/export", id)))
+            .query(&[("format", "chat")]);
+        self.send_json(request).await
+    }
+
+    /// Exports the trajectory in the given format, e.g. `jsonl`.
+    pub async fn export_trajectory(&self, id: &str, format: &str) -> Result<String> {
+        let request = self
+            .http
+            .get(self.url(&format!("/sandboxes/{}/trajectory/export", id)))
+            .query(&[("format", format)]);
+        Ok(self.send(request).await?.text().await?)
+    }
+
+    /// Registers an environment and returns its ID.
+    pub async fn create_env(&self, spec: &EnvSpec) -> Result<String> {
+        let request = self.http.post(self.url("/envs")).json(spec);
+        let CreateResponse { id } = self.send_json(request).await?;
+        Ok(id)
+    }
+
+    /// Starts a new episode in a fresh sandbox.
+    pub async fn reset(&self, env_id: &str) -> Result<ResetResponse> {
+        let request = self.http.post(self.url(&format!("/envs/{}/reset", env_id)));
+        self.send_json(request).await
+    }
+
+    pub async fn step(&self, env_id: &str, command: &str) -> Result<StepResponse> {
+        let request = self
+            .http
+            .post(self.url(&format!("/envs/{}/step", env_id)))
+            .json(&StepPayload {
+                command: command.to_string(),
+            });
+        self.send_json(request).await
+    }
+
+    /// Removes the environment and its sandbox.
+    pub async fn delete_env(&self, env_id: &str) -> Result<()> {
+        let request = self.http.delete(self.url(&format!("/envs/{}", env_id)));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn templates(&self) -> Result<Vec<Template>> {
+        self.send_json(self.http.get(self.url("/templates"))).await
+    }
+
+    pub async fn template(&self, name: &str) -> Result<Template> {
+        self.send_json(self.http.get(self.url(&format!("/templates/{}", name))))
+            .await
+    }
+
+    /// Registers a template, replacing any existing one with the same name.
+    pub async fn create_template(&self, template: &Template) -> Result<()> {
+        let request = self.http.post(self.url("/templates")).json(template);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn tasks(&self) -> Result<Vec<Task>> {
+        self.send_json(self.http.get(self.url("/tasks"))).await
+    }
+
+    pub async fn task(&self, name: &str) -> Result<Task> {
+        self.send_json(self.http.get(self.url(&format!("/tasks/{}", name))))
+            .await
+    }
+
+    /// Registers a task, replacing any existing one with the same name.
+    pub async fn create_task(&self, task: &Task) -> Result<()> {
+        let request = self.http.post(self.url("/tasks")).json(task);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Registers a task per SWE-bench instance and returns the task names.
+    pub async fn import_swebench(&self, import: &SweBenchImport) -> Result<Vec<String>> {
+        let request = self.http.post(self.url("/tasks/import/swebench")).json(import);
+        self.send_json(request).await
+    }
+
+    /// Creates and starts a sandbox for the task.
+    pub async fn instantiate(&self, name: &str) -> Result<InstantiateResponse> {
+        let request = self
+            .http
+            .post(self.url(&format!("/tasks/{}/instantiate", name)));
+        self.send_json(request).await
+    }
+
+    /// Creates and starts a sandbox for a task the server does not know, as the tenant of
+    /// the client, unlike [`SosClient::instantiate`] which needs the task registered by
+    /// an admin tenant. The sandbox is removed when it fails to start.
+    pub async fn instantiate_task(&self, task: &Task) -> Result<InstantiateResponse> {
+        let id = self.create(&task.to_payload()).await?;
+        if let Err(e) = self.start_and_wait(&id).await {
+            let _ = self.stop(&id, true).await;
+            return Err(e);
+        }
+        Ok(InstantiateResponse {
+            id,
+            instructions: task.instructions.clone(),
+        })
+    }
+
+    /// Makes the server reload the policy of its configuration file. Needs an admin
+    /// tenant on servers with tenants.
+    pub async fn reload(&self) -> Result<ReloadResponse> {
+        self.send_json(self.http.post(self.url("/admin/reload"))).await
+    }
+}
+
+/// Error response of the server, with the [`ErrorResponse`] of its body when it has one.
+fn api_error(status: u16, text: String) -> ClientError {
+    match serde_json::from_str::<ErrorResponse>(&text) {
+        Ok(ErrorResponse { error }) => ClientError::Api {
+            status,
+            code: error.code,
+            message: error.message,
+            violation: error.violation,
+        },
+        Err(_) => ClientError::Api {
+            status,
+            code: "UNKNOWN".to_string(),
+            message: text,
+            violation: None,
+        },
+    }
+}
+
+/// Parses a server-sent events body into the data of its events. Comments, such as
+/// keep-alives, are skipped.
+fn sse_data(
+    body: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Send + 'static,
+) -> impl Stream<Item = Result<String>> {
+    stream::unfold(
+        (Box::pin(body), Vec::new()),
+        |(mut body, mut buffer)| async move {
+            loop {
+                if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                    let event: Vec<u8> = buffer.drain(..end + 2).collect();
+                    let event = String::from_utf8_lossy(&event);
+                    let data: Vec<&str> = event
+                        .lines()
+                        .filter_map(|line| line.strip_prefix("data:"))
+                        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                        .collect();
+                    if data.is_empty() {
+                        continue;
+                    }
+                    return Some((Ok(data.join("\n")), (body, buffer)));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e.into()), (body, buffer))),
+                    None => return None,
+                }
+            }
+        },
+    )
+}
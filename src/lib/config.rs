@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use axum::http::{HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::warn;
+
+use crate::archive::{ArchiveConfig, Archiver};
+use crate::audit::AuditLog;
+use crate::http::validate_image;
+use crate::listen::BindAddress;
+use crate::policy::{Policy, PolicyConfig};
+use crate::pool::PoolConfig;
+use crate::proxy::ProxyConfig;
+use crate::sandbox::{HooksConfig, OutputFilters};
+use crate::rate_limit::RateLimitConfig;
+use crate::runtime::RuntimeConfig;
+use crate::task::Task;
+use crate::tenant::TenantConfig;
+use crate::tls::TlsConfig;
+use crate::tools::ToolBundleConfig;
+
+pub use crate::api::Template;
+
+/// Server configuration, loaded from a TOML file with `sos serve --config`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// File the configuration was loaded from, read again on reload
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Container runtime sandboxes run on, Docker unless set
+    pub runtime: RuntimeConfig,
+    /// Interface or Unix socket the API listens on, the loopback interface unless set
+    pub bind: BindAddress,
+    /// Permissions of the Unix socket of `bind`, e.g. `0o660`. Left to the umask when unset.
+    pub socket_mode: Option<u32>,
+    /// File the pid of the server is written to while it runs
+    pub pid_file: Option<PathBuf>,
+    /// Maximum number of concurrent sandboxes
+    pub max_sandboxes: usize,
+    /// Sandbox templates available at startup
+    pub templates: Vec<Template>,
+    /// Tasks available at startup
+    pub tasks: Vec<Task>,
+    /// Directory of YAML or JSON task files, added to `tasks` on load
+    pub task_dir: Option<PathBuf>,
+    /// Admission policy checked before sandboxes are created or started and before
+    /// commands run, see [`crate::policy`]. Tenants override it rule by rule.
+    pub policy: PolicyConfig,
+    /// Tenants allowed to use the server. When empty, the server is open and
+    /// every request belongs to the default namespace.
+    pub tenants: Vec<TenantConfig>,
+    /// Serve HTTPS with the given certificate and key
+    pub tls: Option<TlsConfig>,
+    /// Per-client rate limits. Disabled when unset.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Cross-origin requests allowed from browser clients. Disabled when unset.
+    pub cors: Option<CorsConfig>,
+    /// Directory trajectories are persisted to, so they outlive their sandboxes and
+    /// the server. Trajectories are only kept in memory when unset.
+    pub trajectory_dir: Option<PathBuf>,
+    /// Object storage sandboxes are archived to when they stop. Disabled when unset.
+    pub archive: Option<ArchiveConfig>,
+    /// JSONL file every API call is appended to. Disabled when unset.
+    pub audit_log: Option<PathBuf>,
+    /// Images to keep warm containers of, so sandboxes start without waiting for one
+    pub pool: Vec<PoolConfig>,
+    /// Images pulled in the background at startup, so the first sandboxes using them
+    /// do not wait for the pull
+    pub prefetch_images: Vec<String>,
+    /// Recording proxy sandboxes send their HTTP(S) requests through, so they are
+    /// added to their trajectories. Disabled when unset.
+    pub proxy: Option<ProxyConfig>,
+    /// Lines run by the session shell of every sandbox once it is configured, before
+    /// those of the sandbox: aliases, helper functions, PATH additions or hardening
+    pub shell_init: Vec<String>,
+    /// Bundles of scripts or binaries sandboxes can have installed with `tools`
+    pub tool_bundles: Vec<ToolBundleConfig>,
+    /// Commands and webhook run at the start, around the commands and at the stop of
+    /// every sandbox
+    pub hooks: HooksConfig,
+    /// Filters scrubbing the output of commands before it is returned and recorded
+    pub output_filters: OutputFilters,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            runtime: RuntimeConfig::default(),
+            bind: BindAddress::default(),
+            socket_mode: None,
+            pid_file: None,
+            max_sandboxes: 10,
+            templates: Vec::new(),
+            tasks: Vec::new(),
+            task_dir: None,
+            policy: PolicyConfig::default(),
+            tenants: Vec::new(),
+            tls: None,
+            rate_limit: None,
+            cors: None,
+            trajectory_dir: None,
+            archive: None,
+            audit_log: None,
+            pool: Vec::new(),
+            prefetch_images: Vec::new(),
+            proxy: None,
+            shell_init: Vec::new(),
+            tool_bundles: Vec::new(),
+            hooks: HooksConfig::default(),
+            output_filters: OutputFilters::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the configuration from a TOML file, along with the tasks of `task_dir`.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(&path)?;
+        let mut config: ServerConfig = toml::from_str(&text)?;
+        config.path = Some(path.as_ref().to_path_buf());
+        if let Some(dir) = &config.task_dir {
+            config.tasks.extend(Task::load_dir(dir)?);
+        }
+        if let Some(archive) = &config.archive {
+            // Fail on startup rather than when the first sandbox stops
+            Archiver::new(archive)?;
+        }
+        if let Some(image) = config
+            .prefetch_images
+            .iter()
+            .find(|image| validate_image(image).is_err())
+        {
+            anyhow::bail!("Invalid image name in prefetch_images: {}", image);
+        }
+        config.runtime.validate()?;
+        Policy::new(&config.policy)?;
+        for tenant in &config.tenants {
+            Policy::new(&tenant.policy)
+                .with_context(|| format!("Invalid policy of tenant {}", tenant.name))?;
+        }
+        for bundle in &config.tool_bundles {
+            bundle.validate()?;
+        }
+        config.output_filters.validate()?;
+        if let Some(path) = &config.audit_log {
+            AuditLog::open(path)
+                .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        }
+        Ok(config)
+    }
+}
+
+/// CORS section of the server configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://dashboard.example.com`.
+    /// `"*"` allows any origin.
+    pub allowed_origins: Vec<String>,
+    /// Allowed methods. Defaults to GET and POST.
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+impl CorsConfig {
+    /// Builds the CORS layer. Invalid origins and methods are skipped with a warning.
+    pub fn layer(&self) -> CorsLayer {
+        let origins = match self.allowed_origins.iter().any(|o| o == "*") {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(self.allowed_origins.iter().filter_map(|origin| {
+                HeaderValue::from_str(origin)
+                    .inspect_err(|_| warn!(origin = %origin, "Ignoring invalid CORS origin"))
+                    .ok()
+            })),
+        };
+        let methods: Vec<Method> = self
+            .allowed_methods
+            .iter()
+            .filter_map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .inspect_err(|_| warn!(method = %method, "Ignoring invalid CORS method"))
+                    .ok()
+            })
+            .collect();
+
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(Any)
+    }
+}
@@ -0,0 +1,144 @@
+//! Integration of `sos serve` with service managers: systemd socket activation and
+//! readiness notification, pid files, and shutdown and reload on signals.
+//!
+//! The systemd protocols are implemented directly from the environment it sets, so the
+//! server behaves the same outside of systemd, where the variables are missing.
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::sys::socket::{AddressFamily, SockaddrLike, SockaddrStorage, getsockname};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{debug, info, warn};
+
+use crate::http::SoSState;
+use crate::listen::Listener;
+
+/// First file descriptor passed by socket activation, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening socket passed by systemd socket activation, if any.
+///
+/// The socket is the first one of the `.socket` unit, TCP or Unix. It is used as is: its
+/// address and permissions are those of the unit, not of `bind` and `socket_mode`.
+/// Must be called from within the Tokio runtime.
+pub fn inherited_listener() -> Result<Option<Listener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !for_us || fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(fds = fds, "Socket activation passed several sockets, using the first one");
+    }
+
+    let fd = LISTEN_FDS_START;
+    // Inherited without close-on-exec, which would leak it into the processes the
+    // local runtime spawns
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .context("Invalid socket passed by socket activation")?;
+    let family = getsockname::<SockaddrStorage>(fd)
+        .context("Invalid socket passed by socket activation")?
+        .family();
+    // SAFETY: the descriptor was passed to this process for it to own, and nothing
+    // else in the process uses it
+    let listener = match family {
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        }
+        Some(AddressFamily::Unix) => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(listener)?)
+        }
+        family => anyhow::bail!(
+            "Unsupported socket passed by socket activation: {:?}",
+            family
+        ),
+    };
+    Ok(Some(listener))
+}
+
+/// Sends a state to the service manager, e.g. `READY=1` once the server accepts
+/// connections or `STOPPING=1` when it shuts down. Does nothing unless the service is
+/// run with `Type=notify`, i.e. `NOTIFY_SOCKET` is set.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        match path.as_encoded_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+    match sent {
+        Ok(_) => debug!(state = %state, "Notified the service manager"),
+        Err(e) => warn!(error = %e, "Failed to notify the service manager"),
+    }
+}
+
+/// Pid file of the server, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the pid of the process to `path`.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pid file {}", path.display()))?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "Failed to remove pid file");
+        }
+    }
+}
+
+/// Resolves on `SIGTERM`, sent by service managers to stop the server, or `SIGINT`.
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Reloads the configuration of the server on `SIGHUP`, see [`SoSState::reload`].
+pub fn spawn_reload_on_hangup(state: Arc<SoSState>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Reloading the configuration");
+            notify("RELOADING=1");
+            if let Err(e) = state.reload().await {
+                warn!(error = %e, "Failed to reload the configuration");
+            }
+            notify("READY=1");
+        }
+    });
+    Ok(())
+}
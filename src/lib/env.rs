@@ -0,0 +1,183 @@
+//! Gym-style environment API on top of sandboxes.
+//!
+//! An environment owns at most one sandbox at a time. `reset` replaces it with a fresh
+//! one built from the [`EnvSpec`], and `step` runs a command in its session, recording
+//! it in the sandbox trajectory like any other exec.
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::api::{CreateResponse, EnvSpec, ResetResponse, StepInfo, StepPayload, StepResponse};
+use crate::http::{ApiError, ApiJson, Caller, SoSState};
+use crate::sandbox::SandboxStatus;
+use crate::tenant::Tenant;
+
+/// Environment registered with `POST /envs`.
+#[derive(Debug)]
+pub struct Env {
+    pub id: String,
+    pub tenant: String,
+    pub spec: EnvSpec,
+    pub sandbox_id: Option<String>,
+    pub steps: usize,
+    pub done: bool,
+}
+
+impl Env {
+    pub fn new(spec: EnvSpec, tenant: String) -> Self {
+        Env {
+            id: uuid::Uuid::new_v4().to_string(),
+            tenant,
+            spec,
+            sandbox_id: None,
+            steps: 0,
+            done: false,
+        }
+    }
+}
+
+async fn get_env(state: &SoSState, tenant: &Tenant, id: &str) -> Result<Arc<Mutex<Env>>, ApiError> {
+    let not_found = || {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "ENV_NOT_FOUND",
+            format!("Environment {} not found", id),
+        )
+    };
+    let env_arc = state
+        .envs
+        .get(id)
+        .map(|entry| entry.value().clone())
+        .ok_or_else(not_found)?;
+    if env_arc.lock().await.tenant != tenant.name {
+        return Err(not_found());
+    }
+    Ok(env_arc)
+}
+
+/// POST `/envs` handler.
+///
+/// Registers an environment and returns its ID. No sandbox is created until the
+/// first reset.
+pub async fn create_env(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(spec): ApiJson<EnvSpec>,
+) -> Result<Json<CreateResponse>, ApiError> {
+    let env = Env::new(spec, tenant.name.clone());
+    let id = env.id.clone();
+    state.envs.insert(id.clone(), Arc::new(Mutex::new(env)));
+    Ok(Json(CreateResponse { id }))
+}
+
+/// POST `/envs/{id}/reset` handler.
+///
+/// Removes the sandbox of the previous episode, if any, then creates and starts a
+/// new one. Returns the task instruction as the initial observation.
+pub async fn reset_env(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<ResetResponse>, ApiError> {
+    let env_arc = get_env(&state, &tenant, &id).await?;
+    let mut env = env_arc.lock().await;
+
+    if let Some(sandbox_id) = env.sandbox_id.take()
+        && let Err(e) = state.stop_sandbox(&tenant, &sandbox_id, true).await
+    {
+        warn!(env_id = %id, sandbox_id = %sandbox_id, error = %e.message, "Failed to stop previous episode sandbox");
+    }
+
+    let sandbox_id = state.create_sandbox(&tenant, env.spec.sandbox.clone()).await?;
+    if let Err(e) = state.start_sandbox(&tenant, &sandbox_id).await {
+        // Also removes the container a failed setup command leaves behind
+        let _ = state.stop_sandbox(&tenant, &sandbox_id, true).await;
+        return Err(e);
+    }
+    env.sandbox_id = Some(sandbox_id.clone());
+    env.steps = 0;
+    env.done = false;
+
+    Ok(Json(ResetResponse {
+        observation: env.spec.instruction.clone(),
+        info: StepInfo {
+            sandbox_id,
+            step: 0,
+        },
+    }))
+}
+
+/// POST `/envs/{id}/step` handler.
+///
+/// Runs the command in the session shell and returns its output as the observation.
+/// When the episode ends, the verify command of the sandbox scores it.
+pub async fn step_env(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<StepPayload>,
+) -> Result<Json<StepResponse>, ApiError> {
+    let env_arc = get_env(&state, &tenant, &id).await?;
+    let mut env = env_arc.lock().await;
+
+    let sandbox_id = match (&env.sandbox_id, env.done) {
+        (Some(sandbox_id), false) => sandbox_id.clone(),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "ENV_NOT_RESET",
+                "The environment must be reset before stepping",
+            ));
+        }
+    };
+    let sandbox_arc = state.get_sandbox(&tenant, &sandbox_id).await?;
+    state.policy(&tenant).check_command(&payload.command)?;
+    let mut sandbox = sandbox_arc.lock().await;
+
+    let result = state.exec(&mut sandbox, payload.command, false, None).await?;
+    env.steps += 1;
+    let truncated = env.spec.max_steps.is_some_and(|max| env.steps >= max)
+        || matches!(sandbox.get_status(), SandboxStatus::Exhausted(_));
+    env.done = result.exited || truncated;
+
+    let reward = match (&sandbox.verify_command, env.done) {
+        (Some(_), true) => sandbox.verify().await?.score,
+        _ => 0.0,
+    };
+
+    Ok(Json(StepResponse {
+        observation: result.output,
+        exit_code: result.exit_code,
+        reward,
+        done: env.done,
+        truncated: truncated && !result.exited,
+        info: StepInfo {
+            sandbox_id,
+            step: env.steps,
+        },
+    }))
+}
+
+/// DELETE `/envs/{id}` handler.
+///
+/// Stops the current sandbox and removes the environment.
+pub async fn delete_env(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<(), ApiError> {
+    let env_arc = get_env(&state, &tenant, &id).await?;
+    state.envs.remove(&id);
+
+    if let Some(sandbox_id) = env_arc.lock().await.sandbox_id.take() {
+        // The sandbox may never have started, nothing to stop then
+        let _ = state.stop_sandbox(&tenant, &sandbox_id, true).await;
+    }
+    Ok(())
+}
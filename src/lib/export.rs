@@ -0,0 +1,168 @@
+//! Trajectory export formats for data pipelines.
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::api::{ChatExport, ChatMessage, TrajectoryRecord};
+use crate::http::{ApiError, Caller, SoSState};
+use crate::sandbox::Trajectory;
+
+/// One record per command of the trajectory.
+pub fn records(trajectory: &Trajectory) -> Vec<TrajectoryRecord> {
+    trajectory
+        .commands
+        .iter()
+        .enumerate()
+        .map(|(i, cmd)| TrajectoryRecord {
+            sandbox_id: trajectory.sandbox_id.clone(),
+            index: i,
+            command: cmd.command.clone(),
+            output: cmd.result.as_ref().map(|r| r.output.clone()),
+            exit_code: cmd.result.as_ref().map(|r| r.exit_code),
+            started_at: cmd.timestamp,
+            finished_at: cmd.duration.map(|d| cmd.timestamp + d),
+            duration: cmd.duration.map(|d| d.as_secs_f64()),
+            usage: cmd.usage,
+        })
+        .collect()
+}
+
+/// Serializes the records as JSON Lines.
+pub fn to_jsonl(records: &[TrajectoryRecord]) -> String {
+    records
+        .iter()
+        .map(|record| serde_json::to_string(record).expect("Trajectory records serialize") + "\n")
+        .collect()
+}
+
+/// Renders the trajectory as a conversation: every command is an `assistant` message
+/// followed by its output in a message with `output_role` (`tool` or `user`). Failing
+/// commands have their exit code appended to the output.
+pub fn to_chat(trajectory: &Trajectory, system: Option<&str>, output_role: &str) -> ChatExport {
+    let mut messages: Vec<ChatMessage> = system
+        .map(|content| ChatMessage {
+            role: "system".to_string(),
+            content: content.to_string(),
+        })
+        .into_iter()
+        .collect();
+
+    for cmd in &trajectory.commands {
+        messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: cmd.command.clone(),
+        });
+        if let Some(result) = &cmd.result {
+            let content = match result.exit_code {
+                0 => result.output.clone(),
+                code => format!("{}\n[exit code: {}]", result.output, code)
+                    .trim_start()
+                    .to_string(),
+            };
+            messages.push(ChatMessage {
+                role: output_role.to_string(),
+                content,
+            });
+        }
+    }
+    ChatExport { messages }
+}
+
+/// Terminal size of asciicast recordings.
+const CAST_WIDTH: u16 = 120;
+const CAST_HEIGHT: u16 = 40;
+
+/// Renders the trajectory as an asciicast v2 recording, for `asciinema play`: each command
+/// is typed at a prompt when it was sent, and its output printed when it finished.
+pub fn to_cast(trajectory: &Trajectory) -> String {
+    let mut header = serde_json::json!({
+        "version": 2,
+        "width": CAST_WIDTH,
+        "height": CAST_HEIGHT,
+    });
+    let started_at = trajectory.started_at.or(trajectory.commands.first().map(|cmd| cmd.timestamp));
+    if let Some(started_at) = started_at {
+        header["timestamp"] = started_at.timestamp().into();
+    }
+    let mut cast = header.to_string() + "\n";
+    // Event times never go back, even if the clock did
+    let mut time = 0.0_f64;
+    let mut push = |at: f64, text: String| {
+        time = time.max(at);
+        cast.push_str(&serde_json::json!([time, "o", text]).to_string());
+        cast.push('\n');
+    };
+    for cmd in &trajectory.commands {
+        let offset = trajectory.offset(cmd);
+        push(offset, format!("$ {}\r\n", cmd.command));
+        if let Some(result) = cmd.result.as_ref().filter(|result| !result.output.is_empty()) {
+            let end = offset + cmd.duration.map_or(0.0, |duration| duration.as_secs_f64());
+            push(end, format!("{}\r\n", result.output.replace('\n', "\r\n")));
+        }
+    }
+    cast
+}
+
+/// Query of `GET /sandboxes/{id}/trajectory/export`.
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// Role of the command output messages in the chat format, `tool` or `user`
+    #[serde(default = "default_output_role")]
+    pub output_role: String,
+    /// System prompt opening the conversation in the chat format
+    pub system: Option<String>,
+}
+
+fn default_format() -> String {
+    "jsonl".to_string()
+}
+
+fn default_output_role() -> String {
+    "tool".to_string()
+}
+
+/// GET `/sandboxes/{id}/trajectory/export` handler.
+///
+/// Returns the trajectory in the requested `format`. `jsonl` (the default) produces
+/// one JSON object per command, `chat` a list of role-tagged messages and `cast` an
+/// asciicast recording.
+pub async fn export_trajectory(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let trajectory = state.trajectory(&tenant, &id).await?;
+
+    match query.format.as_str() {
+        "jsonl" => Ok((
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            to_jsonl(&records(&trajectory)),
+        )
+            .into_response()),
+        "chat" => {
+            if !matches!(query.output_role.as_str(), "tool" | "user") {
+                return Err(ApiError::invalid("The output role must be tool or user"));
+            }
+            let chat = to_chat(&trajectory, query.system.as_deref(), &query.output_role);
+            Ok(Json(chat).into_response())
+        }
+        "cast" => Ok((
+            [(header::CONTENT_TYPE, "application/x-asciicast")],
+            to_cast(&trajectory),
+        )
+            .into_response()),
+        format => Err(ApiError::invalid(format!(
+            "Unknown export format {}, expected jsonl, chat or cast",
+            format
+        ))),
+    }
+}
@@ -1,66 +1,549 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{
+        DefaultBodyLimit, FromRequest, FromRequestParts, Path, Query, State,
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{Extensions, HeaderMap, StatusCode, header, request::Parts},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::post,
 };
-use bollard::Docker;
-use futures::future::join_all;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::{
-    sync::{Mutex, Semaphore},
-    time::Instant,
+use dashmap::DashMap;
+use futures::{SinkExt, Stream, StreamExt, future::join_all, stream};
+use tokio::sync::{
+    Mutex, RwLock,
+    broadcast::{self, error::RecvError},
 };
+use tracing::{error, info, warn};
 
+pub use crate::api::{
+    ApplyPatchPayload, AttachQuery, CopyPayload, CreatePayload, ExecPayload, FanOutExecPayload, FilesQuery,
+    KernelExecutePayload, LogSource, LogsQuery, PatchQuery, PullPayload, ResizePayload, SandboxDetail, SandboxInfo, StopPayload, WhoAmIResponse,
+};
+use crate::api::{
+    CreateResponse, ErrorBody, ErrorResponse, ExecResponse, FanOutExecResponse, FanOutResult,
+    PatchResponse, PullResponse, PullResult, ReloadResponse, ServerEvent, ServerEventKind, StartResponse, StartStatus, StopResponse,
+    TrajectoryEntry, TrajectoryResponse, TrajectoryResult, VerifyResponse,
+};
+use crate::audit::{AuditLog, audit};
+use crate::config::{CorsConfig, ServerConfig, Template};
+use crate::export::export_trajectory;
+use crate::manager::Manager;
+use crate::policy::PolicyViolation;
+use crate::env::{Env, create_env, delete_env, reset_env, step_env};
+use crate::swebench::import_swebench;
+use crate::task::{Task, create_task, get_task, instantiate_task, list_tasks};
+use crate::rate_limit::{RateLimiter, rate_limit};
+use crate::runtime::{Attached, ContainerRuntime};
 use crate::sandbox::*;
+use crate::tenant::{Tenant, Tenants};
+use crate::tls::ClientIdentity;
+
+/// Largest command accepted by the exec endpoints, in bytes.
+pub const MAX_COMMAND_BYTES: usize = 64 * 1024;
+
+/// Time the Jupyter kernel gets to reply when the payload sets none.
+const DEFAULT_KERNEL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest archive accepted by `PUT /sandboxes/{id}/files`.
+const MAX_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    // Docker image reference: [registry[:port]/]name[:tag][@digest]
+    static ref IMAGE_REFERENCE: regex::Regex = regex::Regex::new(
+        r"^([a-zA-Z0-9.-]+(:[0-9]+)?/)?[a-z0-9]+([._-]{1,2}[a-z0-9]+)*(/[a-z0-9]+([._-]{1,2}[a-z0-9]+)*)*(:[\w][\w.-]{0,127})?(@sha256:[a-f0-9]{64})?$"
+    )
+    .unwrap();
+}
+
+/// Error returned by the HTTP handlers.
+///
+/// Serialized as `{"error": {"code": "SANDBOX_NOT_FOUND", "message": "..."}}` so clients
+/// can branch on the machine-readable code instead of the message. Policy violations
+/// also carry the rule broken, as `violation`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub violation: Option<PolicyViolation>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            violation: None,
+        }
+    }
+
+    /// 400 error for a request that failed validation.
+    pub fn invalid(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "INVALID_REQUEST", message)
+    }
+
+    pub fn sandbox_not_found(id: &str) -> Self {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "SANDBOX_NOT_FOUND",
+            format!("Sandbox {} not found", id),
+        )
+    }
+
+    pub fn template_not_found(name: &str) -> Self {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "TEMPLATE_NOT_FOUND",
+            format!("Template {} not found", name),
+        )
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", message)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
 
-impl From<SandboxError> for (StatusCode, String) {
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: ErrorBody {
+                code: self.code.to_string(),
+                message: self.message,
+                violation: self.violation,
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<SandboxError> for ApiError {
     fn from(err: SandboxError) -> Self {
-        (err.to_status_code(), err.to_string())
+        ApiError::new(err.to_status_code(), err.error_code(), err.to_string())
+    }
+}
+
+impl From<PolicyViolation> for ApiError {
+    fn from(violation: PolicyViolation) -> Self {
+        ApiError {
+            violation: Some(violation.clone()),
+            ..ApiError::new(StatusCode::FORBIDDEN, "POLICY_VIOLATION", violation.to_string())
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(rejection.status(), "INVALID_REQUEST", rejection.body_text())
     }
 }
 
+/// JSON body extractor that rejects malformed payloads with an [`ApiError`].
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
 impl SandboxError {
     fn to_status_code(&self) -> StatusCode {
         match self {
             SandboxError::NotStarted => StatusCode::BAD_REQUEST,
             SandboxError::AlreadyStarted => StatusCode::BAD_REQUEST,
             SandboxError::AlreadyExited => StatusCode::BAD_REQUEST,
-            SandboxError::SetupCommandsFailed(_) => StatusCode::BAD_REQUEST,
+            SandboxError::SessionNotExited => StatusCode::BAD_REQUEST,
+            SandboxError::Frozen => StatusCode::BAD_REQUEST,
+            SandboxError::NotFrozen => StatusCode::BAD_REQUEST,
+            SandboxError::NoVerifier => StatusCode::BAD_REQUEST,
+            SandboxError::SetupCommandsFailed { .. } => StatusCode::BAD_REQUEST,
+            SandboxError::ToolInstallFailed(..) => StatusCode::BAD_REQUEST,
+            SandboxError::HookFailed(_) => StatusCode::BAD_REQUEST,
+            SandboxError::RepoCloneFailed(..) => StatusCode::BAD_REQUEST,
+            SandboxError::PatchFailed(_) => StatusCode::BAD_REQUEST,
             SandboxError::PullImageFailed { .. } => StatusCode::BAD_REQUEST,
             SandboxError::StopContainerFailed(_) => StatusCode::BAD_REQUEST,
             SandboxError::StartContainerFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            SandboxError::PauseFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SandboxError::ContainerWriteFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SandboxError::ContainerReadFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            SandboxError::ArchiveTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             SandboxError::ExecFailed(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
             SandboxError::CreateExecFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SandboxError::TimeoutWaitingForMarker(_) => StatusCode::GATEWAY_TIMEOUT,
+            SandboxError::CommandTimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            SandboxError::CommandBlocked(_) => StatusCode::FORBIDDEN,
+            SandboxError::BudgetExhausted(_) => StatusCode::BAD_REQUEST,
+            SandboxError::Crashed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            SandboxError::KernelFailed(_) => StatusCode::BAD_REQUEST,
+            SandboxError::MissingSetting(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            SandboxError::NotStarted => "SANDBOX_NOT_STARTED",
+            SandboxError::AlreadyStarted => "SANDBOX_ALREADY_STARTED",
+            SandboxError::AlreadyExited => "SANDBOX_EXITED",
+            SandboxError::SessionNotExited => "SESSION_NOT_EXITED",
+            SandboxError::Frozen => "SANDBOX_FROZEN",
+            SandboxError::NotFrozen => "SANDBOX_NOT_FROZEN",
+            SandboxError::NoVerifier => "NO_VERIFIER",
+            SandboxError::SetupCommandsFailed { .. } => "SETUP_COMMANDS_FAILED",
+            SandboxError::ToolInstallFailed(..) => "TOOL_INSTALL_FAILED",
+            SandboxError::HookFailed(_) => "HOOK_FAILED",
+            SandboxError::RepoCloneFailed(..) => "REPO_CLONE_FAILED",
+            SandboxError::PatchFailed(_) => "PATCH_FAILED",
+            SandboxError::PullImageFailed { .. } => "IMAGE_PULL_FAILED",
+            SandboxError::StopContainerFailed(_) => "STOP_FAILED",
+            SandboxError::StartContainerFailed { .. } => "START_FAILED",
+            SandboxError::PauseFailed(_) => "PAUSE_FAILED",
+            SandboxError::ContainerWriteFailed(_) => "CONTAINER_IO_FAILED",
+            SandboxError::ContainerReadFailed(_) => "CONTAINER_IO_FAILED",
+            SandboxError::ArchiveTooLarge(_) => "ARCHIVE_TOO_LARGE",
+            SandboxError::ExecFailed(_, _) => "EXEC_FAILED",
+            SandboxError::CreateExecFailed(_) => "EXEC_FAILED",
+            SandboxError::TimeoutWaitingForMarker(_) => "COMMAND_TIMEOUT",
+            SandboxError::CommandTimedOut(_) => "COMMAND_TIMEOUT",
+            SandboxError::CommandBlocked(_) => "COMMAND_BLOCKED",
+            SandboxError::BudgetExhausted(_) => "BUDGET_EXHAUSTED",
+            SandboxError::Crashed { .. } => "SANDBOX_CRASHED",
+            SandboxError::KernelFailed(_) => "KERNEL_FAILED",
+            SandboxError::MissingSetting(_) => "INVALID_SANDBOX",
         }
     }
 }
 
-/// Shared state for the SoS server.
-/// Includes the docker client, the sandboxes map, and the semaphore.
+/// Shared state for the SoS server: the [`Manager`] of the sandboxes, which it derefs to,
+/// and what only the HTTP API needs. Includes the environments map, the task registry,
+/// the tenants, the rate limiter, the CORS settings and the audit log.
+///
+/// The tenants and the rate limiter are swapped by [`SoSState::reload`], so requests
+/// read them through [`SoSState::tenants`] and [`SoSState::rate_limiter`].
 #[derive(Clone)]
 pub struct SoSState {
-    pub docker: Arc<Docker>,
-    pub sandboxes: Arc<Mutex<HashMap<String, Arc<Mutex<Sandbox>>>>>,
-    pub semaphore: Arc<Semaphore>,
+    pub manager: Arc<Manager>,
+    pub envs: Arc<DashMap<String, Arc<Mutex<Env>>>>,
+    pub tasks: Arc<RwLock<HashMap<String, Task>>>,
+    tenants: Arc<std::sync::RwLock<Arc<Tenants>>>,
+    rate_limiter: Arc<std::sync::RwLock<Option<Arc<RateLimiter>>>>,
+    pub cors: Option<CorsConfig>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Configuration file reloaded by [`SoSState::reload`]
+    pub config_path: Option<PathBuf>,
+}
+
+impl SoSState {
+    /// Builds the server state from a container runtime and the server configuration.
+    pub fn new(runtime: Arc<dyn ContainerRuntime>, mut config: ServerConfig) -> Self {
+        let tasks = std::mem::take(&mut config.tasks)
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect();
+        let tenants = Tenants::new(&config.tenants, &Tenants::default());
+        SoSState {
+            envs: Arc::new(DashMap::new()),
+            tasks: Arc::new(RwLock::new(tasks)),
+            tenants: Arc::new(std::sync::RwLock::new(Arc::new(tenants))),
+            rate_limiter: Arc::new(std::sync::RwLock::new(
+                config.rate_limit.take().map(|c| Arc::new(RateLimiter::new(c))),
+            )),
+            cors: config.cors.take(),
+            audit_log: config.audit_log.as_ref().and_then(|path| {
+                AuditLog::open(path)
+                    .inspect_err(|e| error!(error = %e, "Audit log disabled"))
+                    .ok()
+                    .map(Arc::new)
+            }),
+            config_path: config.path.clone(),
+            manager: Arc::new(Manager::new(runtime, config)),
+        }
+    }
+
+    /// Current tenants of the server.
+    pub fn tenants(&self) -> Arc<Tenants> {
+        self.tenants.read().unwrap().clone()
+    }
+
+    /// Resolves the tenant of a request, see [`Caller`].
+    pub(crate) fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        extensions: &Extensions,
+    ) -> Result<Arc<Tenant>, ApiError> {
+        let tenants = self.tenants();
+        if tenants.is_empty() {
+            return Ok(self.default_tenant.clone());
+        }
+
+        let by_cert = extensions
+            .get::<ClientIdentity>()
+            .and_then(|ClientIdentity(cn)| tenants.by_cert.get(cn));
+        if let Some(tenant) = by_cert {
+            return Ok(tenant.clone());
+        }
+
+        let api_key = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("Missing API key"))?;
+
+        tenants
+            .by_api_key
+            .get(api_key.trim())
+            .cloned()
+            .ok_or_else(|| unauthorized("Invalid API key"))
+    }
+
+    /// Current rate limiter of the server, `None` without rate limits.
+    pub fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.rate_limiter.read().unwrap().clone()
+    }
+
+    /// Reads the configuration file again and applies its policy without touching the
+    /// running sandboxes: the sandbox limits, admission policy, templates, tasks, tenants
+    /// and rate limits.
+    ///
+    /// Templates and tasks of the file replace those with the same name, tenants keep
+    /// their running sandboxes, and clients keep their rate limit buckets unless the
+    /// limits changed. The rest of the configuration, such as the runtime, TLS or the
+    /// warm pool, needs a restart.
+    ///
+    /// An unreadable or invalid file is rejected as a whole, and the server keeps the
+    /// policy it had.
+    pub async fn reload(&self) -> Result<ReloadResponse, ApiError> {
+        let path = self.config_path.as_ref().ok_or_else(|| {
+            ApiError::new(
+                StatusCode::CONFLICT,
+                "NO_CONFIG_FILE",
+                "The server was not started with a configuration file",
+            )
+        })?;
+        let invalid = |e: anyhow::Error| {
+            ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "INVALID_CONFIG",
+                format!("Failed to load {}: {:#}", path.display(), e),
+            )
+        };
+        let config = ServerConfig::load(path).map_err(invalid)?;
+
+        // Applied first, as it is the only part that can still fail
+        self.manager.reload(&config).await.map_err(invalid)?;
+        let mut tasks = self.tasks.write().await;
+        for task in &config.tasks {
+            tasks.insert(task.name.clone(), task.clone());
+        }
+        drop(tasks);
+        let tenants = Tenants::new(&config.tenants, &self.tenants());
+        *self.tenants.write().unwrap() = Arc::new(tenants);
+        let mut rate_limiter = self.rate_limiter.write().unwrap();
+        if rate_limiter.as_ref().map(|limiter| limiter.config()) != config.rate_limit.as_ref() {
+            *rate_limiter = config.rate_limit.clone().map(|c| Arc::new(RateLimiter::new(c)));
+        }
+        drop(rate_limiter);
+
+        info!(path = %path.display(), "Configuration reloaded");
+        Ok(ReloadResponse {
+            max_sandboxes: config.max_sandboxes,
+            templates: config.templates.len(),
+            tasks: config.tasks.len(),
+            tenants: config.tenants.len(),
+        })
+    }
 }
 
-/// POST `/sandboxes` payload.
+impl std::ops::Deref for SoSState {
+    type Target = Manager;
+
+    fn deref(&self) -> &Manager {
+        &self.manager
+    }
+}
+
+/// Tenant making the request.
 ///
-/// Includes the container image to use and the setup commands to run
-/// on container startup. Setup commands will be chained together with `&&`.
-#[derive(Deserialize, serde::Serialize)]
-pub struct CreatePayload {
-    pub image: String,
-    pub setup_commands: Vec<String>,
+/// Resolved from the client certificate of the connection when it maps to a tenant,
+/// and from the `Authorization: Bearer <api_key>` header otherwise. When no tenants
+/// are configured every request belongs to the default, unrestricted tenant.
+pub struct Caller(pub Arc<Tenant>);
+
+impl FromRequestParts<Arc<SoSState>> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SoSState>,
+    ) -> Result<Self, Self::Rejection> {
+        state
+            .authenticate(&parts.headers, &parts.extensions)
+            .map(Caller)
+    }
+}
+
+fn unauthorized(message: &str) -> ApiError {
+    ApiError::new(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", message)
+}
+
+/// Refuses callers that are not admin tenants. `action` is what they tried, e.g.
+/// `Registering templates`. On servers without tenants, every caller is the default
+/// tenant, an admin.
+pub(crate) fn require_admin(tenant: &Tenant, action: &str) -> Result<(), ApiError> {
+    match tenant.admin {
+        true => Ok(()),
+        false => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN",
+            format!("{} needs an admin tenant", action),
+        )),
+    }
+}
+
+impl CreatePayload {
+    /// Checks the payload once the template has been applied.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.image.is_empty() {
+            return Err(ApiError::invalid("An image or a template is required"));
+        }
+        validate_image(&self.image)?;
+        for command in self
+            .setup_commands
+            .iter()
+            .chain(&self.verify_command)
+            .chain(&self.shell_init)
+        {
+            validate_command(command)?;
+        }
+        self.blocked_command_patterns()?;
+        if self.labels.keys().any(|key| key.is_empty()) {
+            return Err(ApiError::invalid("Label keys cannot be empty"));
+        }
+        if self.env.keys().any(|key| key.is_empty() || key.contains('=')) {
+            return Err(ApiError::invalid(
+                "Environment variable names cannot be empty or contain '='",
+            ));
+        }
+        for mount in &self.mounts {
+            if mount.source.is_empty() || !mount.target.starts_with('/') {
+                return Err(ApiError::invalid(format!(
+                    "Invalid mount {}:{}, the target must be an absolute path",
+                    mount.source, mount.target
+                )));
+            }
+        }
+        if let Some(repo) = &self.repo {
+            if repo.url.is_empty() || repo.url.starts_with('-') {
+                return Err(ApiError::invalid("Invalid repository URL"));
+            }
+            if repo.reference.as_ref().is_some_and(|r| r.is_empty() || r.starts_with('-')) {
+                return Err(ApiError::invalid("Invalid repository ref"));
+            }
+            if repo.depth == Some(0) {
+                return Err(ApiError::invalid("The clone depth must be positive"));
+            }
+            if !repo.dir().starts_with('/') {
+                return Err(ApiError::invalid(
+                    "The repository directory must be an absolute path",
+                ));
+            }
+        }
+        if let Some(limits) = &self.limits {
+            let positive = limits.memory_mb.is_none_or(|m| m > 0)
+                && limits.cpus.is_none_or(|c| c > 0.0)
+                && limits.pids.is_none_or(|p| p > 0);
+            if !positive {
+                return Err(ApiError::invalid("Resource limits must be positive"));
+            }
+        }
+        if self.time_limit_secs == Some(0) {
+            return Err(ApiError::invalid("The time limit must be positive"));
+        }
+        if self.max_commands == Some(0) {
+            return Err(ApiError::invalid("The command budget must be positive"));
+        }
+        if self.terminal_size().is_some_and(|size| size.cols == 0 || size.rows == 0) {
+            return Err(ApiError::invalid("The terminal size must be positive"));
+        }
+        if let Some(server) = self.dns.iter().find(|server| server.parse::<IpAddr>().is_err()) {
+            return Err(ApiError::invalid(format!(
+                "Invalid DNS server {}, expected an IP address",
+                server
+            )));
+        }
+        if let Some(domain) = self
+            .dns_search
+            .iter()
+            .find(|domain| domain.is_empty() || domain.contains(char::is_whitespace))
+        {
+            return Err(ApiError::invalid(format!("Invalid DNS search domain '{}'", domain)));
+        }
+        for entry in &self.extra_hosts {
+            let valid = entry.split_once(':').is_some_and(|(host, ip)| {
+                !host.is_empty()
+                    && !host.contains(char::is_whitespace)
+                    && (ip == "host-gateway" || ip.parse::<IpAddr>().is_ok())
+            });
+            if !valid {
+                return Err(ApiError::invalid(format!(
+                    "Invalid extra host {}, expected host:ip",
+                    entry
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the `blocked_commands` of the payload.
+    pub fn blocked_command_patterns(&self) -> Result<Vec<regex::Regex>, ApiError> {
+        self.blocked_commands
+            .iter()
+            .map(|pattern| {
+                regex::Regex::new(pattern).map_err(|e| {
+                    ApiError::invalid(format!("Invalid blocked command {}: {}", pattern, e))
+                })
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn validate_image(image: &str) -> Result<(), ApiError> {
+    match IMAGE_REFERENCE.is_match(image) {
+        true => Ok(()),
+        false => Err(ApiError::invalid(format!("Invalid image name: {}", image))),
+    }
+}
+
+fn validate_command(command: &str) -> Result<(), ApiError> {
+    if command.len() > MAX_COMMAND_BYTES {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "COMMAND_TOO_LARGE",
+            format!(
+                "Command is {} bytes, the maximum is {}",
+                command.len(),
+                MAX_COMMAND_BYTES
+            ),
+        ));
+    }
+    Ok(())
 }
 
 /// POST `/sandboxes` handler.
@@ -69,21 +552,11 @@ pub struct CreatePayload {
 /// Assigns a new UUID to the sandbox, and returns it. Does NOT start a container.
 pub async fn create_sandbox(
     State(state): State<Arc<SoSState>>,
-    Json(payload): Json<CreatePayload>,
-) -> Result<Json<Value>, (StatusCode, String)> {
-    let setup = if !payload.setup_commands.is_empty() {
-        payload.setup_commands.join(" && ")
-    } else {
-        String::new()
-    };
-    let sandbox = Sandbox::new(payload.image, setup, state.docker.clone());
-    let id = sandbox.id.clone();
-    state
-        .sandboxes
-        .lock()
-        .await
-        .insert(id.clone(), Arc::new(Mutex::new(sandbox)));
-    Ok(Json(serde_json::json!({ "id": id })))
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<CreatePayload>,
+) -> Result<Json<CreateResponse>, ApiError> {
+    let id = state.create_sandbox(&tenant, payload).await?;
+    Ok(Json(CreateResponse { id }))
 }
 
 // TODO: we could read from /etc/motd to get a first message after the task.
@@ -92,43 +565,21 @@ pub async fn create_sandbox(
 /// POST `/sandboxes/{id}/start` handler.
 ///
 /// Starts a sandbox with the given ID and runs the setup commands.
-/// Acquires a permit from the semaphore, and locks the sandbox until the
-/// sandbox is stopped. If no permits are available, it blocks until one is.
+/// Acquires a permit from the tenant semaphore and then from the global one, and
+/// holds both until the sandbox is stopped. If no permits are available, the sandbox
+/// is queued and the request answers `202 Accepted` right away: clients poll
+/// `GET /sandboxes` or watch `GET /events` to know when it has started.
 pub async fn start_sandbox(
     Path(id): Path<String>,
     State(state): State<Arc<SoSState>>,
-) -> Result<(), (StatusCode, String)> {
-    let permit = state
-        .semaphore
-        .clone()
-        .acquire_owned()
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    let sandbox_arc = {
-        let sandboxes = state.sandboxes.lock().await;
-        sandboxes
-            .get(&id)
-            .cloned()
-            .ok_or((StatusCode::NOT_FOUND, "Sandbox not found".to_string()))?
+    Caller(tenant): Caller,
+) -> Result<(StatusCode, Json<StartResponse>), ApiError> {
+    let status = state.manager.start_or_queue(tenant, &id).await?;
+    let code = match status {
+        StartStatus::Started => StatusCode::OK,
+        StartStatus::Queued => StatusCode::ACCEPTED,
     };
-
-    // Now lock the individual sandbox and do long work
-    let mut sandbox_guard = sandbox_arc.lock().await;
-
-    sandbox_guard.start(permit).await?;
-
-    Ok(())
-}
-
-/// POST `/sandboxes/{id}/exec` payload.
-///
-/// Includes the command to execute and whether it should be run in standalone
-/// mode.
-#[derive(Deserialize, serde::Serialize)]
-pub struct ExecPayload {
-    pub command: String,
-    pub standalone: Option<bool>,
+    Ok((code, Json(StartResponse { status })))
 }
 
 /// POST `/sandboxes/{id}/exec` handler.
@@ -140,39 +591,111 @@ pub struct ExecPayload {
 pub async fn exec_cmd(
     Path(id): Path<String>,
     State(state): State<Arc<SoSState>>,
-    Json(payload): Json<ExecPayload>,
-) -> Result<Json<Value>, (StatusCode, String)> {
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<ExecPayload>,
+) -> Result<Json<ExecResponse>, ApiError> {
     let command = payload.command;
+    validate_command(&command)?;
+    if payload.timeout_secs == Some(0) {
+        return Err(ApiError::invalid("timeout_secs must be greater than 0"));
+    }
 
-    let sandbox_arc = {
-        let sandboxes = state.sandboxes.lock().await;
-        sandboxes
-            .get(&id)
-            .cloned()
-            .ok_or((StatusCode::NOT_FOUND, "Sandbox not found".to_string()))?
+    let standalone = payload.standalone.unwrap_or(false);
+    let timeout = payload.timeout_secs.map(Duration::from_secs);
+    let mut result = state
+        .exec_in(&tenant, &id, command, standalone, timeout)
+        .await?;
+    // Standalone output is never stripped
+    result.raw_output = match payload.raw.unwrap_or(false) {
+        true => Some(result.raw_output.unwrap_or_else(|| result.output.clone())),
+        false => None,
     };
 
-    let mut sandbox_guard = sandbox_arc.lock().await;
-    let standalone = payload.standalone.unwrap_or(false);
+    Ok(Json(result.into()))
+}
+
+/// POST `/sandboxes/{id}/kernel/execute` handler.
+///
+/// Executes code in the Jupyter kernel of the sandbox, started on the first execution.
+/// Returns the status of the execution and the outputs of the kernel, rich outputs
+/// included. Emits the same events as `/sandboxes/{id}/exec`.
+pub async fn exec_kernel(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<KernelExecutePayload>,
+) -> Result<Json<KernelReply>, ApiError> {
+    validate_command(&payload.code)?;
+    state.policy(&tenant).check_command(&payload.code)?;
+    let timeout = payload
+        .timeout_secs
+        .map_or(DEFAULT_KERNEL_TIMEOUT, Duration::from_secs);
+
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+    let mut sandbox = sandbox_arc.lock().await;
 
-    let CommandResult { output, exit_code, exited } = match standalone {
-        true => sandbox_guard.exec_standalone_cmd(command).await?,
-        false => sandbox_guard.exec_session_cmd(command).await?,
+    let event = |kind| ServerEvent {
+        command: Some(payload.code.clone()),
+        ..ServerEvent::new(kind, &id)
     };
+    let mut finished = event(ServerEventKind::ExecFinished);
+    state.emit(&tenant.name, event(ServerEventKind::ExecStarted));
+    let reply = sandbox.exec_kernel(payload.code.clone(), timeout).await;
+    finished.timestamp = chrono::Utc::now();
+    finished.exit_code = reply.as_ref().ok().map(|reply| reply.to_result().exit_code);
+    state.emit(&tenant.name, finished);
 
-    Ok(Json(serde_json::json!({
-        "output": output,
-        "exit_code": exit_code,
-        "exited": exited
-    })))
+    Ok(Json(reply?))
 }
 
-/// POST `/sandboxes/{id}/stop` payload.
+/// POST `/sandboxes/exec` handler.
 ///
-/// Includes a flag for whether to remove the sandbox after stopping it.
-#[derive(Deserialize, serde::Serialize)]
-pub struct StopPayload {
-    pub remove: Option<bool>,
+/// Executes a command concurrently in the selected sandboxes.
+/// Returns a map of sandbox ID to either the command result or the error it produced.
+pub async fn fan_out_exec(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<FanOutExecPayload>,
+) -> Result<Json<FanOutExecResponse>, ApiError> {
+    if payload.ids.is_empty() && payload.labels.is_empty() {
+        return Err(ApiError::invalid("Either ids or labels must be provided"));
+    }
+    validate_command(&payload.command)?;
+    state.policy(&tenant).check_command(&payload.command)?;
+
+    let selected = state.sandbox_list().into_iter().filter(|entry| {
+        let by_id = payload.ids.contains(&entry.id);
+        let by_labels = !payload.labels.is_empty()
+            && payload
+                .labels
+                .iter()
+                .all(|(k, v)| entry.labels.get(k) == Some(v));
+        entry.tenant == tenant.name && (by_id || by_labels)
+    });
+
+    let standalone = payload.standalone.unwrap_or(false);
+    let state = &state;
+    let futures: Vec<_> = selected
+        .map(|entry| {
+            let command = payload.command.clone();
+            async move {
+                let mut sandbox = entry.sandbox.lock().await;
+                let result = match state.exec(&mut sandbox, command, standalone, None).await {
+                    Ok(result) => FanOutResult::Ok(ExecResponse {
+                        raw_output: None,
+                        ..result.into()
+                    }),
+                    Err(e) => FanOutResult::Err {
+                        error: e.to_string(),
+                    },
+                };
+                (entry.id.clone(), result)
+            }
+        })
+        .collect();
+
+    let results = join_all(futures).await.into_iter().collect();
+    Ok(Json(FanOutExecResponse { results }))
 }
 
 /// POST `/sandboxes/{id}/stop` handler.
@@ -183,73 +706,522 @@ pub struct StopPayload {
 pub async fn stop_sandbox(
     Path(id): Path<String>,
     State(state): State<Arc<SoSState>>,
-    Json(payload): Json<StopPayload>,
-) -> Result<(), (StatusCode, String)> {
-    let sandbox_arc = {
-        let remove = payload.remove.unwrap_or(false);
-        let mut sandboxes = state.sandboxes.lock().await;
-        let opt = match remove {
-            true => sandboxes.remove(&id),
-            false => sandboxes.get(&id).cloned(),
-        };
-        opt.ok_or((StatusCode::NOT_FOUND, format!("Sandbox {} not found", id)))?
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<StopPayload>,
+) -> Result<Json<StopResponse>, ApiError> {
+    state
+        .stop_sandbox(&tenant, &id, payload.remove.unwrap_or(false))
+        .await
+        .map(Json)
+}
+
+/// POST `/sandboxes/{id}/freeze` handler.
+///
+/// Freezes the session shell of the sandbox and every process it spawned, so graders can
+/// inspect the filesystem without the agent's background processes mutating it.
+/// Standalone commands still run while the sandbox is frozen, unless the container cannot
+/// manage its own cgroups and the runtime paused it whole, see [`Sandbox::freeze`].
+pub async fn freeze_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<(), ApiError> {
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    sandbox_arc.lock().await.freeze().await?;
+    state.emit(&tenant.name, ServerEvent::new(ServerEventKind::Frozen, &id));
+
+    Ok(())
+}
+
+/// POST `/sandboxes/{id}/session` handler.
+///
+/// Opens a fresh session shell in the container of a sandbox whose session exited, so
+/// commands can run in a session again. The previous session is hung up, like a closed
+/// terminal: its processes get `SIGHUP`.
+pub async fn reopen_session(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<(), ApiError> {
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    sandbox_arc.lock().await.reopen_session().await?;
+    state.emit(
+        &tenant.name,
+        ServerEvent::new(ServerEventKind::SessionOpened, &id),
+    );
+
+    Ok(())
+}
+
+/// GET `/sandboxes/{id}/files` handler.
+///
+/// Returns a file or a directory of the sandbox as a tar archive.
+pub async fn download_files(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<FilesQuery>,
+) -> Result<Response, ApiError> {
+    let path = query.path.trim_end_matches('/');
+    if path.is_empty() {
+        return Err(ApiError::invalid("The path must not be empty"));
+    }
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    let archive = sandbox_arc.lock().await.download(path, None).await?;
+
+    Ok(([(header::CONTENT_TYPE, "application/x-tar")], archive).into_response())
+}
+
+/// PUT `/sandboxes/{id}/files` handler.
+///
+/// Extracts the tar archive of the body into a directory of the sandbox.
+pub async fn upload_files(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<FilesQuery>,
+    archive: bytes::Bytes,
+) -> Result<(), ApiError> {
+    if query.path.is_empty() {
+        return Err(ApiError::invalid("The path must not be empty"));
+    }
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    sandbox_arc.lock().await.upload(&query.path, &archive).await?;
+
+    Ok(())
+}
+
+/// POST `/sandboxes/{id}/copy` handler.
+///
+/// Copies files of the sandbox into another sandbox of the caller, through the server.
+/// Each sandbox is locked only while its side of the copy runs. The archive is held in
+/// memory, so copies larger than an upload fail with `ARCHIVE_TOO_LARGE`.
+pub async fn copy_files(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<CopyPayload>,
+) -> Result<(), ApiError> {
+    let path = payload.path.trim_end_matches('/');
+    if path.is_empty() {
+        return Err(ApiError::invalid("The path to copy must not be empty"));
+    }
+    let destination_dir = match &payload.destination_dir {
+        Some(dir) => dir.clone(),
+        None => std::path::Path::new(path)
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| ".".to_string()),
+    };
+    let source_arc = state.get_sandbox(&tenant, &id).await?;
+    let destination_arc = state.get_sandbox(&tenant, &payload.destination).await?;
+
+    let archive = source_arc
+        .lock()
+        .await
+        .download(path, Some(MAX_UPLOAD_BYTES))
+        .await?;
+    destination_arc
+        .lock()
+        .await
+        .upload(&destination_dir, &archive)
+        .await?;
+
+    Ok(())
+}
+
+/// GET `/sandboxes/{id}/patch` handler.
+///
+/// Returns the diff of the changes made in the git workspace of the sandbox, for
+/// scoring or replaying them elsewhere.
+pub async fn get_patch(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<PatchQuery>,
+) -> Result<Json<PatchResponse>, ApiError> {
+    validate_patch_dir(query.dir.as_deref())?;
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    let mut sandbox = sandbox_arc.lock().await;
+    let dir = query.dir.unwrap_or_else(|| sandbox.workspace().to_string());
+    let patch = sandbox.diff(&dir).await?;
+
+    Ok(Json(PatchResponse {
+        files: changed_files(&patch),
+        patch,
+    }))
+}
+
+/// POST `/sandboxes/{id}/patch` handler.
+///
+/// Applies a diff to the git workspace of the sandbox. Nothing is changed when it does
+/// not apply cleanly.
+pub async fn apply_patch(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<ApplyPatchPayload>,
+) -> Result<(), ApiError> {
+    validate_patch_dir(payload.dir.as_deref())?;
+    if payload.patch.trim().is_empty() {
+        return Err(ApiError::invalid("The patch must not be empty"));
+    }
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    let mut sandbox = sandbox_arc.lock().await;
+    let dir = payload.dir.unwrap_or_else(|| sandbox.workspace().to_string());
+    sandbox.apply_patch(&dir, &payload.patch).await?;
+
+    Ok(())
+}
+
+fn validate_patch_dir(dir: Option<&str>) -> Result<(), ApiError> {
+    match dir {
+        Some(dir) if !dir.starts_with('/') => Err(ApiError::invalid(
+            "The workspace directory must be an absolute path",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Command of the interactive shell of `GET /sandboxes/{id}/attach`.
+const ATTACH_SHELL: &str =
+    "export TERM=xterm-256color; command -v bash >/dev/null && exec bash -l || exec sh -l";
+
+/// GET `/sandboxes/{id}/attach` handler.
+///
+/// Upgrades to a WebSocket bridged to an interactive shell in a terminal of the container.
+/// The shell runs next to the session, so programs such as editors or REPLs can be used
+/// without disturbing the agent's session. Binary messages carry the terminal input and
+/// output, and a text message `{"cols": 120, "rows": 40}` resizes the terminal. The socket
+/// closes when the shell exits, and closing it ends the input of the shell.
+///
+/// What is typed in the shell escapes the forbidden commands of the policy, so the policy
+/// must allow it, see [`crate::policy::PolicyConfig::allow_attach`]. Sandboxes with blocked
+/// commands or without budget left refuse it, see
+/// [`crate::manager::SandboxEntry::check_attach`]. Once detached, the shell is recorded in
+/// the trajectory without its input and output, and counts as a command toward the budget.
+pub async fn attach_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<AttachQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let entry = state.get_entry(&tenant, &id)?;
+    state.policy(&tenant).check_attach(&id)?;
+    entry.check_attach()?;
+    let cid = entry.view.container_id().ok_or(SandboxError::NotStarted)?;
+
+    let cmd = ["sh", "-c", ATTACH_SHELL].map(str::to_string).to_vec();
+    let attached = state.runtime.attach(&cid, cmd).await?;
+    if let (Some(cols), Some(rows)) = (query.cols, query.rows) {
+        (attached.resize)(TerminalSize { cols, rows }).await?;
+    }
+
+    Ok(upgrade.on_upgrade(move |socket| async move {
+        let timestamp = chrono::Utc::now();
+        let started = Instant::now();
+        bridge_terminal(socket, attached).await;
+        entry
+            .sandbox
+            .lock()
+            .await
+            .record_attach(ATTACH_SHELL, timestamp, started.elapsed())
+            .await;
+    }))
+}
+
+/// Forwards the terminal output to the socket and the socket input to the terminal,
+/// until either ends.
+async fn bridge_terminal(socket: WebSocket, attached: Attached) {
+    use tokio::io::AsyncWriteExt;
+
+    let Attached {
+        mut input,
+        mut output,
+        resize,
+    } = attached;
+    let (mut sender, mut receiver) = socket.split();
+    let to_socket = async {
+        while let Some(bytes) = output.next().await {
+            if sender.send(Message::Binary(bytes)).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(Message::Close(None)).await;
     };
+    let to_terminal = async {
+        while let Some(Ok(message)) = receiver.next().await {
+            match message {
+                Message::Binary(bytes)
+                    if input.write_all(&bytes).await.is_err() || input.flush().await.is_err() =>
+                {
+                    return;
+                }
+                Message::Text(text) => match serde_json::from_str::<TerminalSize>(&text) {
+                    Ok(size) => {
+                        if let Err(e) = resize(size).await {
+                            warn!("Failed to resize the attached terminal: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Invalid attach message: {}", e),
+                },
+                Message::Close(_) => return,
+                _ => {}
+            }
+        }
+    };
+    tokio::select! {
+        _ = to_socket => {}
+        _ = to_terminal => {}
+    }
+}
+
+/// POST `/sandboxes/{id}/resize` handler.
+///
+/// Resizes the session terminal, so commands formatting their output for the terminal
+/// (`ls`, `git log`, ...) use the new width.
+pub async fn resize_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<ResizePayload>,
+) -> Result<(), ApiError> {
+    if payload.cols == 0 || payload.rows == 0 {
+        return Err(ApiError::invalid("The terminal size must be positive"));
+    }
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    sandbox_arc.lock().await.resize(payload).await?;
 
-    // Permit is released here
-    sandbox_arc.lock().await.stop().await?;
+    Ok(())
+}
+
+/// POST `/sandboxes/{id}/unfreeze` handler.
+///
+/// Resumes the processes frozen by `/sandboxes/{id}/freeze`.
+pub async fn unfreeze_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<(), ApiError> {
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
+
+    sandbox_arc.lock().await.unfreeze().await?;
+    state.emit(
+        &tenant.name,
+        ServerEvent::new(ServerEventKind::Unfrozen, &id),
+    );
 
     Ok(())
 }
 
+/// GET `/whoami` handler.
+///
+/// Returns the tenant of the caller, `default` on servers without tenants. Clients use it
+/// to check their credentials and that the server is up.
+pub async fn whoami(Caller(tenant): Caller) -> Json<WhoAmIResponse> {
+    Json(WhoAmIResponse {
+        tenant: tenant.name.clone(),
+    })
+}
+
+/// GET `/sandboxes/{id}/stats` handler.
+///
+/// Returns the CPU, memory, network and block I/O usage of the sandbox container. Reads
+/// without locking the sandbox, so it can be polled while a command runs.
+pub async fn get_stats(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<ResourceUsage>, ApiError> {
+    let entry = state.get_entry(&tenant, &id)?;
+    let cid = entry.view.container_id().ok_or(SandboxError::NotStarted)?;
+
+    let usage = state.runtime.stats(&cid).await?;
+
+    Ok(Json(usage))
+}
+
 /// GET `/sandboxes/{id}/trajectory` handler.
 ///
 /// Returns the trajectory of the sandbox.
 /// The trajectory is a list of commands that have been executed in the sandbox.
 /// Each command has a timestamp, a command string, and a result.
 /// The result is the stdout, stderr, and exit code of the command.
+/// Trajectories of removed sandboxes are read from the trajectory store, if enabled.
 pub async fn get_trajectory(
     Path(id): Path<String>,
     State(state): State<Arc<SoSState>>,
-) -> Result<Json<Value>, (StatusCode, String)> {
-    let sandbox_arc = {
-        let sandboxes = state.sandboxes.lock().await;
-        sandboxes
-            .get(&id)
-            .cloned()
-            .ok_or((StatusCode::NOT_FOUND, format!("Sandbox {} not found", id)))?
-    };
-
-    let sandbox = sandbox_arc.lock().await;
-    let trajectory = sandbox.get_trajectory();
+    Caller(tenant): Caller,
+) -> Result<Json<TrajectoryResponse>, ApiError> {
+    let trajectory = state.trajectory(&tenant, &id).await?;
 
-    let start_time = sandbox.start_time.unwrap_or(Instant::now());
-    let trajectory_json: Vec<Value> = trajectory
+    let entries = trajectory
+        .commands
         .iter()
         .enumerate()
-        .map(|(i, cmd)| {
-            let timestamp = (cmd.timestamp - start_time).as_secs_f64();
-            let mut cmd_json = serde_json::json!({
-                "index": i,
-                "command": cmd.command,
-                "timestamp": timestamp,
-            });
+        .map(|(i, cmd)| trajectory_entry(&trajectory, i, cmd))
+        .collect();
 
-            if let Some(result) = &cmd.result {
-                cmd_json["result"] = serde_json::json!({
-                    "output": result.output,
-                    "exit_code": result.exit_code,
-                });
+    Ok(Json(TrajectoryResponse {
+        sandbox_id: id,
+        command_count: trajectory.commands.len(),
+        trajectory: entries,
+        verifications: trajectory
+            .verifications
+            .into_iter()
+            .map(VerifyResponse::from)
+            .collect(),
+        requests: trajectory.requests,
+    }))
+}
+
+fn trajectory_entry(
+    trajectory: &Trajectory,
+    index: usize,
+    cmd: &CommandExecution,
+) -> TrajectoryEntry {
+    TrajectoryEntry {
+        index,
+        command: cmd.command.clone(),
+        timestamp: trajectory.offset(cmd),
+        started_at: cmd.timestamp,
+        duration: cmd.duration.map(|d| d.as_secs_f64()),
+        result: cmd.result.as_ref().map(|result| TrajectoryResult {
+            output: result.output.clone(),
+            stdout: result.stdout.clone(),
+            stderr: result.stderr.clone(),
+            exit_code: result.exit_code,
+        }),
+        usage: cmd.usage,
+        blocked: cmd.blocked.clone(),
+    }
+}
+
+/// GET `/sandboxes/{id}/trajectory/stream` handler.
+///
+/// Server-sent events stream of the trajectory. Replays the commands executed so far,
+/// then pushes every new command as it completes, each as a `command` event with a
+/// trajectory entry as data. The stream ends when the sandbox is removed.
+pub async fn stream_trajectory(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let (trajectory, receiver) = state.get_entry(&tenant, &id)?.view.subscribe();
+
+    let replay: Vec<_> = trajectory.commands.iter().cloned().enumerate().collect();
+    let events = stream::iter(replay)
+        .chain(broadcast_stream(receiver))
+        .map(move |(index, cmd)| {
+            Event::default()
+                .event("command")
+                .json_data(trajectory_entry(&trajectory, index, &cmd))
+        });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// GET `/sandboxes/{id}/logs` handler.
+///
+/// Returns the logs of the sandbox as plain text, streamed as they come with `follow`.
+/// They are read without locking the sandbox, so they show while a command runs.
+pub async fn get_logs(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    Query(query): Query<LogsQuery>,
+) -> Result<Response, ApiError> {
+    let entry = state.get_entry(&tenant, &id)?;
+
+    let container = match (query.source, entry.view.container_id()) {
+        (LogSource::Session, _) | (_, None) => stream::empty().boxed(),
+        (_, Some(cid)) => state.runtime.logs(&cid, query.follow).await?,
+    };
+    let session = match query.source {
+        LogSource::Container => stream::empty().boxed(),
+        _ => {
+            let (log, receiver) = entry.view.subscribe_session();
+            let backlog = stream::once(futures::future::ready(Ok(bytes::Bytes::from(log))));
+            match query.follow {
+                true => backlog.chain(broadcast_stream(receiver).map(Ok)).boxed(),
+                false => backlog.boxed(),
             }
+        }
+    };
+    let logs = match query.follow {
+        // Both go on, interleaved as output comes
+        true => stream::select(container, session).boxed(),
+        false => container.chain(session).boxed(),
+    };
 
-            cmd_json
-        })
-        .collect();
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        axum::body::Body::from_stream(logs),
+    )
+        .into_response())
+}
+
+/// Items of a broadcast channel as a stream, ending when the channel closes.
+fn broadcast_stream<T: Clone + Send + 'static>(
+    receiver: broadcast::Receiver<T>,
+) -> impl Stream<Item = T> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(item) => return Some((item, receiver)),
+                // Slow client, skip the items it missed
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// GET `/events` handler.
+///
+/// Server-sent events stream of what happens to the caller's sandboxes: lifecycle
+/// changes, commands starting and finishing, and sandboxes timed out by the reaper.
+/// Each event is named after its type and carries a server event as data. Past events
+/// are not replayed.
+pub async fn stream_events(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = broadcast_stream(state.events.subscribe()).filter_map(move |(owner, event)| {
+        let event = (owner == tenant.name).then(|| {
+            Event::default()
+                .event(event.kind.as_str())
+                .json_data(&event)
+        });
+        async move { event }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// POST `/sandboxes/{id}/verify` handler.
+///
+/// Runs the verify command of the sandbox standalone and returns its verdict. The
+/// verdict is recorded alongside the trajectory.
+pub async fn verify_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<VerifyResponse>, ApiError> {
+    let sandbox_arc = state.get_sandbox(&tenant, &id).await?;
 
-    Ok(Json(serde_json::json!({
-        "sandbox_id": id,
-        "command_count": sandbox.command_count(),
-        "trajectory": trajectory_json
-    })))
+    let verification = sandbox_arc.lock().await.verify().await?;
+
+    Ok(Json(verification.into()))
 }
 
 /// GET `/sandboxes/{id}/trajectory/formatted` handler.
@@ -260,72 +1232,164 @@ pub async fn get_trajectory(
 pub async fn get_trajectory_formatted(
     Path(id): Path<String>,
     State(state): State<Arc<SoSState>>,
-) -> Result<String, (StatusCode, String)> {
-    let sandbox_arc = {
-        let sandboxes = state.sandboxes.lock().await;
-        sandboxes
-            .get(&id)
-            .cloned()
-            .ok_or((StatusCode::NOT_FOUND, format!("Sandbox {} not found", id)))?
-    };
+    Caller(tenant): Caller,
+) -> Result<String, ApiError> {
+    Ok(state.trajectory(&tenant, &id).await?.format())
+}
+
+/// GET `/sandboxes` handler.
+///
+/// Returns a list of all sandboxes owned by the caller's tenant.
+/// Each sandbox has an ID, image, setup commands, and status.
+pub async fn list_sandboxes(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<Vec<SandboxInfo>>, ApiError> {
+    // Read from the views, so sandboxes running a command are listed right away
+    let sandbox_list = state
+        .sandbox_list()
+        .into_iter()
+        .filter(|entry| entry.tenant == tenant.name)
+        .map(|entry| entry.info())
+        .collect();
+    Ok(Json(sandbox_list))
+}
 
-    let sandbox = sandbox_arc.lock().await;
-    Ok(sandbox.format_trajectory())
+/// GET `/sandboxes/{id}` handler.
+///
+/// Returns the sandbox as listed, with its container, start time, limits and the exit
+/// code of its last command. Reads from the view, without waiting for a running command.
+pub async fn get_sandbox(
+    Path(id): Path<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<SandboxDetail>, ApiError> {
+    let entry = state.get_entry(&tenant, &id)?;
+    Ok(Json(SandboxDetail {
+        info: entry.info(),
+        container_id: entry.view.container_id(),
+        started_at: entry.view.started_at(),
+        limits: entry.limits.clone(),
+        time_limit_secs: entry.time_limit.map(|limit| limit.as_secs()),
+        last_exit_code: entry.view.last_exit_code(),
+    }))
 }
 
-/// GET `/sandboxes` response struct.
+/// GET `/templates` handler.
 ///
-/// Includes the ID, image, setup commands, and status of the sandbox.
-#[derive(Serialize, Deserialize)]
-pub struct SandboxInfo {
-    pub id: String,
-    pub image: String,
-    pub setup_commands: String,
-    pub status: String,
-    pub session_command_count: usize,
-    pub last_standalone_exit_code: Option<i64>,
+/// Returns all the registered sandbox templates, shared by the tenants.
+pub async fn list_templates(
+    State(state): State<Arc<SoSState>>,
+    Caller(_): Caller,
+) -> Result<Json<Vec<Template>>, ApiError> {
+    let templates = state.templates.read().await;
+    let mut list: Vec<Template> = templates.values().map(Template::redacted).collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(list))
 }
 
-/// GET `/sandboxes` handler.
+/// GET `/templates/{name}` handler.
 ///
-/// Returns a list of all sandboxes.
-/// Each sandbox has an ID, image, setup commands, and status.
-pub async fn list_sandboxes(
+/// Returns a single sandbox template.
+pub async fn get_template(
+    Path(name): Path<String>,
     State(state): State<Arc<SoSState>>,
-) -> Result<Json<Vec<SandboxInfo>>, (StatusCode, String)> {
-    // Brief global lock to clone all Arcs
-    let sandbox_arcs = {
-        let sandboxes = state.sandboxes.lock().await;
-        sandboxes.values().cloned().collect::<Vec<_>>()
-    };
+    Caller(_): Caller,
+) -> Result<Json<Template>, ApiError> {
+    let templates = state.templates.read().await;
+    templates
+        .get(&name)
+        .map(|template| Json(template.redacted()))
+        .ok_or_else(|| ApiError::template_not_found(&name))
+}
 
-    // Now process concurrently without holding global
-    let futures: Vec<_> = sandbox_arcs
-        .iter()
-        .map(|sandbox_arc| async {
-            let sandbox = sandbox_arc.lock().await;
-            let status = sandbox.get_status();
-            SandboxInfo {
-                id: sandbox.id.clone(),
-                image: sandbox.image.clone(),
-                setup_commands: sandbox.setup_commands.clone(),
-                status: status.to_string(),
-                session_command_count: sandbox.command_count(),
-                last_standalone_exit_code: sandbox.get_last_standalone_exit_code(),
-            }
+/// POST `/templates` handler.
+///
+/// Registers a sandbox template, replacing any existing template with the same name.
+/// Templates are shared by the tenants and may mount host paths, so only admin tenants
+/// register them.
+pub async fn create_template(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(template): ApiJson<Template>,
+) -> Result<(), ApiError> {
+    require_admin(&tenant, "Registering templates")?;
+    if template.name.is_empty() || template.image.is_empty() {
+        return Err(ApiError::invalid("Templates need a name and an image"));
+    }
+    validate_image(&template.image)?;
+    state
+        .templates
+        .write()
+        .await
+        .insert(template.name.clone(), template);
+    Ok(())
+}
+
+/// POST `/images/pull` handler.
+///
+/// Pulls the images that are not present yet, concurrently, and reports the outcome
+/// for each of them. Answers once every pull has finished. Only admin tenants pull.
+pub async fn pull_images(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(payload): ApiJson<PullPayload>,
+) -> Result<Json<PullResponse>, ApiError> {
+    require_admin(&tenant, "Pulling images")?;
+    if payload.images.is_empty() {
+        return Err(ApiError::invalid("At least one image is required"));
+    }
+    let policy = state.policy(&tenant);
+    for image in &payload.images {
+        validate_image(image)?;
+        policy.check_image(image)?;
+    }
+
+    let runtime = &state.runtime;
+    let futures: Vec<_> = payload
+        .images
+        .into_iter()
+        .map(|image| async move {
+            let result = match runtime.pull(&image, &mut |_| {}).await {
+                Ok(true) => PullResult::Pulled,
+                Ok(false) => PullResult::Present,
+                Err(SandboxError::PullImageFailed { source }) => PullResult::Failed {
+                    error: source.to_string(),
+                },
+                Err(e) => PullResult::Failed {
+                    error: e.to_string(),
+                },
+            };
+            (image, result)
         })
         .collect();
 
-    let sandbox_list = join_all(futures).await;
-    Ok(Json(sandbox_list))
+    let results = join_all(futures).await.into_iter().collect();
+    Ok(Json(PullResponse { results }))
+}
+
+/// POST `/admin/reload` handler.
+///
+/// Reloads the policy of the configuration file, see [`SoSState::reload`]. Only admin
+/// tenants may call it on servers with tenants.
+pub async fn reload_config(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<ReloadResponse>, ApiError> {
+    require_admin(&tenant, "Reloading the configuration")?;
+    Ok(Json(state.reload().await?))
 }
 
 /// Creates a new router for the SoS server.
 pub fn create_app(state: Arc<SoSState>) -> Router {
-    Router::new()
+    let cors = state.cors.as_ref().map(CorsConfig::layer);
+    let router = Router::new()
         .route("/sandboxes", post(create_sandbox).get(list_sandboxes))
+        .route("/sandboxes/exec", post(fan_out_exec))
+        .route("/sandboxes/{id}", axum::routing::get(get_sandbox))
         .route("/sandboxes/{id}/start", post(start_sandbox))
         .route("/sandboxes/{id}/exec", post(exec_cmd))
+        .route("/sandboxes/{id}/kernel/execute", post(exec_kernel))
         .route(
             "/sandboxes/{id}/trajectory",
             axum::routing::get(get_trajectory),
@@ -334,6 +1398,56 @@ pub fn create_app(state: Arc<SoSState>) -> Router {
             "/sandboxes/{id}/trajectory/formatted",
             axum::routing::get(get_trajectory_formatted),
         )
+        .route(
+            "/sandboxes/{id}/trajectory/stream",
+            axum::routing::get(stream_trajectory),
+        )
+        .route(
+            "/sandboxes/{id}/trajectory/export",
+            axum::routing::get(export_trajectory),
+        )
         .route("/sandboxes/{id}/stop", post(stop_sandbox))
-        .with_state(state)
+        .route("/sandboxes/{id}/stats", axum::routing::get(get_stats))
+        .route("/sandboxes/{id}/logs", axum::routing::get(get_logs))
+        .route("/events", axum::routing::get(stream_events))
+        .route("/whoami", axum::routing::get(whoami))
+        .route("/sandboxes/{id}/verify", post(verify_sandbox))
+        .route("/images/pull", post(pull_images))
+        .route("/templates", post(create_template).get(list_templates))
+        .route("/templates/{name}", axum::routing::get(get_template))
+        .route("/sandboxes/{id}/freeze", post(freeze_sandbox))
+        .route("/sandboxes/{id}/unfreeze", post(unfreeze_sandbox))
+        .route("/sandboxes/{id}/resize", post(resize_sandbox))
+        .route("/sandboxes/{id}/session", post(reopen_session))
+        .route("/sandboxes/{id}/attach", axum::routing::get(attach_sandbox))
+        .route(
+            "/sandboxes/{id}/files",
+            axum::routing::get(download_files)
+                .put(upload_files)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/sandboxes/{id}/copy", post(copy_files))
+        .route(
+            "/sandboxes/{id}/patch",
+            axum::routing::get(get_patch).post(apply_patch),
+        )
+        .route("/tasks", post(create_task).get(list_tasks))
+        .route("/tasks/import/swebench", post(import_swebench))
+        .route("/tasks/{name}", axum::routing::get(get_task))
+        .route("/tasks/{name}/instantiate", post(instantiate_task))
+        .route("/envs", post(create_env))
+        .route("/envs/{id}", axum::routing::delete(delete_env))
+        .route("/envs/{id}/reset", post(reset_env))
+        .route("/envs/{id}/step", post(step_env))
+        .route("/admin/reload", post(reload_config))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), rate_limit))
+        // Audited outside the rate limiter so rejected requests are logged too
+        .layer(axum::middleware::from_fn_with_state(state.clone(), audit))
+        .with_state(state);
+
+    // CORS goes outermost so preflight requests are answered before rate limiting
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
 }
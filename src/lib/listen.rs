@@ -0,0 +1,168 @@
+//! Address `sos serve` listens on: an interface of the host, on the `--port`, or a Unix
+//! socket.
+//!
+//! The API runs commands in containers for whoever can reach it, so the server binds the
+//! loopback interface unless told otherwise. A Unix socket leaves access control to its
+//! file permissions.
+use std::fmt;
+use std::fs::Permissions;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use axum::Router;
+use nix::sys::stat::{Mode, umask};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::rustls;
+
+/// Scheme of [`BindAddress::Unix`] addresses.
+const UNIX_SCHEME: &str = "unix://";
+
+/// Address the server listens on, `127.0.0.1` unless set.
+///
+/// Written as an IP address, e.g. `0.0.0.0` or `::1`, or as a Unix socket path prefixed
+/// with `unix://`, e.g. `unix:///run/sos.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddress {
+    /// Interface of the host, listened on at the port of the server
+    Ip(IpAddr),
+    /// Path of a Unix socket
+    Unix(PathBuf),
+}
+
+impl Default for BindAddress {
+    fn default() -> Self {
+        BindAddress::Ip(Ipv4Addr::LOCALHOST.into())
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Ip(ip) => write!(f, "{}", ip),
+            BindAddress::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_SCHEME) {
+            if path.is_empty() {
+                return Err("Missing Unix socket path, expected unix:///path/to/sos.sock".into());
+            }
+            return Ok(BindAddress::Unix(PathBuf::from(path)));
+        }
+        let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        ip.parse().map(BindAddress::Ip).map_err(|_| {
+            format!(
+                "Invalid bind address '{}', expected an IP address or unix:///path/to/sos.sock",
+                s
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BindAddress> for String {
+    fn from(address: BindAddress) -> Self {
+        address.to_string()
+    }
+}
+
+/// Bound socket of the server, see [`BindAddress::bind`].
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl BindAddress {
+    /// Binds the address, at `port` for an IP address.
+    ///
+    /// A Unix socket gets the permissions of `socket_mode` when given, e.g. `0o660` for
+    /// the owner and group of the server alone. It is created with them, so it is never
+    /// reachable with wider ones. A socket file left behind by a server that is no longer
+    /// running is replaced.
+    pub async fn bind(&self, port: u16, socket_mode: Option<u32>) -> Result<Listener> {
+        match self {
+            BindAddress::Ip(ip) => {
+                let addr = SocketAddr::new(*ip, port);
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to bind {}", addr))?;
+                Ok(Listener::Tcp(listener))
+            }
+            BindAddress::Unix(path) => {
+                remove_stale_socket(path)?;
+                // The umask is the process's, but nothing else creates files while binding
+                let previous =
+                    socket_mode.map(|mode| umask(Mode::from_bits_truncate((!mode & 0o777) as _)));
+                let listener = UnixListener::bind(path);
+                if let Some(previous) = previous {
+                    umask(previous);
+                }
+                let listener =
+                    listener.with_context(|| format!("Failed to bind {}", path.display()))?;
+                if let Some(mode) = socket_mode {
+                    std::fs::set_permissions(path, Permissions::from_mode(mode)).with_context(
+                        || format!("Failed to set the permissions of {}", path.display()),
+                    )?;
+                }
+                Ok(Listener::Unix(listener))
+            }
+        }
+    }
+}
+
+/// Removes the socket file at `path` unless a server still accepts connections on it.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        anyhow::bail!("{} is in use by another server", path.display());
+    }
+    std::fs::remove_file(path)
+        .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+}
+
+/// Serves the app on the listener, over HTTPS with `tls`.
+///
+/// Requests over TCP carry the peer address as [`axum::extract::ConnectInfo`]. Those over
+/// a Unix socket carry none, so rate limits apply to them per tenant or all together.
+/// TLS is only served over TCP.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    tls: Option<rustls::ServerConfig>,
+) -> Result<()> {
+    match (listener, tls) {
+        (Listener::Tcp(listener), Some(tls)) => crate::tls::serve(listener, app, tls).await?,
+        (Listener::Tcp(listener), None) => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await?
+        }
+        (Listener::Unix(_), Some(_)) => anyhow::bail!("TLS is not supported on a Unix socket"),
+        (Listener::Unix(listener), None) => axum::serve(listener, app.into_make_service()).await?,
+    }
+    Ok(())
+}
@@ -0,0 +1,683 @@
+//! In-process sandbox management, the core of the HTTP server.
+//!
+//! A [`Manager`] creates, starts, runs commands in and stops sandboxes of tenants, with the
+//! concurrency limits, templates, warm pool, archival and events of the server. Applications
+//! embed it instead of running `sos serve` and talking to it over HTTP:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use std::{sync::Arc, time::Duration};
+//!
+//! use sos::Manager;
+//! use sos::api::CreatePayload;
+//! use sos::config::ServerConfig;
+//!
+//! let config = ServerConfig::default();
+//! let runtime = config.runtime.connect().await?;
+//! let manager = Arc::new(Manager::new(runtime, config));
+//! manager.spawn_reaper(Duration::from_secs(600));
+//!
+//! let tenant = manager.default_tenant.clone();
+//! let payload = CreatePayload { image: "ubuntu:latest".into(), ..Default::default() };
+//! let id = manager.create_sandbox(&tenant, payload).await?;
+//! manager.start_sandbox(&tenant, &id).await?;
+//! let result = manager.exec_in(&tenant, &id, "ls /".into(), false, None).await?;
+//! println!("{}", result.output);
+//! println!("{:?}", manager.trajectory(&tenant, &id).await?.commands);
+//! manager.stop_sandbox(&tenant, &id, true).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore, broadcast};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::api::{CreatePayload, SandboxInfo, ServerEvent, ServerEventKind, StartStatus, StopResponse};
+use crate::archive::Archiver;
+use crate::config::{ServerConfig, Template};
+use crate::http::ApiError;
+use crate::policy::Policy;
+use crate::pool::WarmPool;
+use crate::proxy::{Proxy, Registration};
+use crate::runtime::ContainerRuntime;
+use crate::sandbox::{
+    CommandResult, Hook, Observer, OutputFilters, PullProgress, ResourceLimits, Sandbox,
+    SandboxError, SandboxStatus, SandboxView, Trajectory,
+};
+use crate::store::TrajectoryStore;
+use crate::tenant::{Tenant, resize_semaphore};
+use crate::tools::ToolRegistry;
+
+/// Server events buffered for slow subscribers.
+const EVENT_CAPACITY: usize = 256;
+
+/// Interval between two passes of the reaper.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sandbox registered with the manager.
+///
+/// Holds what requests read without locking the sandbox, so they do not wait behind a
+/// running command: the immutable metadata and the view of its status and trajectory.
+pub struct SandboxEntry {
+    pub id: String,
+    pub tenant: String,
+    pub image: String,
+    pub setup_commands: String,
+    pub labels: HashMap<String, String>,
+    pub limits: ResourceLimits,
+    pub time_limit: Option<Duration>,
+    /// Blocked command patterns of the sandbox, see [`SandboxEntry::check_attach`]
+    pub blocked_commands: Vec<String>,
+    pub max_commands: Option<usize>,
+    pub view: SandboxView,
+    pub sandbox: Arc<Mutex<Sandbox>>,
+    /// Proxy token of the sandbox, revoked once the sandbox is gone
+    _proxy: Option<Registration>,
+}
+
+impl SandboxEntry {
+    pub(crate) fn new(sandbox: Sandbox) -> Self {
+        SandboxEntry {
+            id: sandbox.id.clone(),
+            tenant: sandbox.tenant.clone(),
+            image: sandbox.image.clone(),
+            setup_commands: sandbox.setup_commands.join(" && "),
+            labels: sandbox.labels.clone(),
+            limits: sandbox.limits.clone(),
+            time_limit: sandbox.time_limit,
+            blocked_commands: sandbox.blocked_commands.iter().map(|p| p.to_string()).collect(),
+            max_commands: sandbox.max_commands,
+            view: sandbox.view().clone(),
+            sandbox: Arc::new(Mutex::new(sandbox)),
+            _proxy: None,
+        }
+    }
+
+    /// Checks an interactive shell about to be attached, without locking the sandbox so
+    /// that it can run next to a command. What is typed in the shell cannot be checked, so
+    /// sandboxes with blocked commands refuse it, as do those that used up their budget.
+    pub fn check_attach(&self) -> Result<(), SandboxError> {
+        if let Some(pattern) = self.blocked_commands.first() {
+            return Err(SandboxError::CommandBlocked(pattern.clone()));
+        }
+        match self.view.status().as_str() {
+            "exhausted" => Err(SandboxError::BudgetExhausted(
+                self.max_commands.unwrap_or_default(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The sandbox as listed by `GET /sandboxes`.
+    pub fn info(&self) -> SandboxInfo {
+        SandboxInfo {
+            id: self.id.clone(),
+            image: self.image.clone(),
+            setup_commands: self.setup_commands.clone(),
+            status: self.view.status(),
+            session_command_count: self.view.command_count(),
+            last_standalone_exit_code: self.view.last_standalone_exit_code(),
+            labels: self.labels.clone(),
+            archive_url: self.view.archive_url(),
+        }
+    }
+}
+
+/// Sandboxes of every tenant and what they run on.
+///
+/// Includes the container runtime, the sandboxes, the global semaphore, the templates,
+/// the trajectory store, the archiver, the warm container pool, the recording proxy and
+/// the events channel, whose messages are tagged with the tenant of the sandbox. Errors
+/// are [`ApiError`]s, with the code the HTTP API answers.
+pub struct Manager {
+    pub runtime: Arc<dyn ContainerRuntime>,
+    /// Sharded so requests on different sandboxes do not contend. Entries are cloned
+    /// out before locking a sandbox, never held across an await.
+    pub sandboxes: DashMap<String, Arc<SandboxEntry>>,
+    pub semaphore: Arc<Semaphore>,
+    /// Permits of `semaphore`, see [`Manager::set_max_sandboxes`]
+    max_sandboxes: AtomicUsize,
+    pub templates: RwLock<HashMap<String, Template>>,
+    /// Admission policy of the server, see [`Manager::policy`]
+    policy: std::sync::RwLock<Arc<Policy>>,
+    /// Tenant of every sandbox when no tenants are configured, unrestricted
+    pub default_tenant: Arc<Tenant>,
+    pub trajectory_store: Option<Arc<TrajectoryStore>>,
+    pub archiver: Option<Arc<Archiver>>,
+    pub pool: Option<Arc<WarmPool>>,
+    pub proxy: Option<Arc<Proxy>>,
+    /// Lines run by every session shell before those of the sandbox
+    pub shell_init: Vec<String>,
+    pub tools: ToolRegistry,
+    /// Hooks every sandbox runs, from the configuration unless replaced
+    pub hooks: Vec<Arc<dyn Hook>>,
+    /// Observers every sandbox has, none unless added
+    pub observers: Vec<Arc<dyn Observer>>,
+    /// Filters scrubbing the output of the commands of every sandbox, see
+    /// [`Manager::output_filters`]
+    output_filters: std::sync::RwLock<Arc<OutputFilters>>,
+    pub events: broadcast::Sender<(String, ServerEvent)>,
+}
+
+impl Manager {
+    /// Builds the manager from a container runtime and the server configuration, and
+    /// starts prefetching its images and filling its warm pool.
+    pub fn new(runtime: Arc<dyn ContainerRuntime>, config: ServerConfig) -> Self {
+        let policy = server_policy(&config);
+        let templates = config
+            .templates
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect();
+        if !config.prefetch_images.is_empty() {
+            let runtime = runtime.clone();
+            let images = config.prefetch_images.clone();
+            tokio::spawn(async move {
+                for image in images {
+                    if let Err(e) = runtime.pull(&image, &mut |_| {}).await {
+                        warn!(image = %image, error = %e, "Failed to prefetch image");
+                    }
+                }
+            });
+        }
+        let pool = (!config.pool.is_empty()).then(|| {
+            let pool = Arc::new(WarmPool::new(
+                runtime.clone(),
+                &config.pool,
+                config.shell_init.clone(),
+            ));
+            pool.fill();
+            pool
+        });
+        Manager {
+            runtime,
+            sandboxes: DashMap::new(),
+            semaphore: Arc::new(Semaphore::new(config.max_sandboxes)),
+            max_sandboxes: AtomicUsize::new(config.max_sandboxes),
+            templates: RwLock::new(templates),
+            policy: std::sync::RwLock::new(Arc::new(policy)),
+            default_tenant: Arc::new(Tenant::unrestricted()),
+            trajectory_store: config
+                .trajectory_dir
+                .map(|dir| Arc::new(TrajectoryStore::new(dir))),
+            archiver: config.archive.as_ref().and_then(|archive| {
+                Archiver::new(archive)
+                    .inspect_err(|e| error!(error = %format!("{:#}", e), "Archival disabled"))
+                    .ok()
+                    .map(Arc::new)
+            }),
+            pool,
+            proxy: config.proxy.as_ref().and_then(|proxy| {
+                Proxy::start(proxy)
+                    .inspect_err(|e| error!(error = %format!("{:#}", e), "Recording proxy disabled"))
+                    .ok()
+            }),
+            shell_init: config.shell_init,
+            tools: ToolRegistry::new(&config.tool_bundles),
+            hooks: config.hooks.hooks(),
+            observers: Vec::new(),
+            output_filters: std::sync::RwLock::new(Arc::new(config.output_filters)),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Changes the maximum number of concurrent sandboxes, without stopping running ones.
+    /// When lowered, sandboxes start again once enough of them have stopped.
+    pub fn set_max_sandboxes(&self, max_sandboxes: usize) {
+        let previous = self.max_sandboxes.swap(max_sandboxes, Ordering::SeqCst);
+        resize_semaphore(&self.semaphore, previous, max_sandboxes);
+    }
+
+    /// Admission policy of the server for `tenant`, with the rules of the tenant in place
+    /// of the server's.
+    pub fn policy(&self, tenant: &Tenant) -> Policy {
+        self.policy.read().unwrap().overridden_by(&tenant.policy)
+    }
+
+    /// Output filters given to new sandboxes.
+    pub fn output_filters(&self) -> Arc<OutputFilters> {
+        self.output_filters.read().unwrap().clone()
+    }
+
+    /// Applies the limits, policy, output filters and templates of a new configuration.
+    /// Templates of the configuration replace those with the same name, others are kept.
+    /// Sandboxes already created keep the output filters they were created with.
+    ///
+    /// Fails without applying anything when the policy does not compile, so the previous
+    /// one stays in force.
+    pub async fn reload(&self, config: &ServerConfig) -> anyhow::Result<()> {
+        let policy = Policy::new(&config.policy)?;
+        self.set_max_sandboxes(config.max_sandboxes);
+        *self.policy.write().unwrap() = Arc::new(policy);
+        *self.output_filters.write().unwrap() = Arc::new(config.output_filters.clone());
+        let mut templates = self.templates.write().await;
+        for template in &config.templates {
+            templates.insert(template.name.clone(), template.clone());
+        }
+        Ok(())
+    }
+
+    /// Publishes an event about a sandbox of `tenant` to the subscribers of [`Manager::events`].
+    pub fn emit(&self, tenant: &str, event: ServerEvent) {
+        // Sending only fails when nobody is listening
+        let _ = self.events.send((tenant.to_string(), event));
+    }
+
+    /// Runs a command in the sandbox, publishing when it starts and finishes.
+    pub async fn exec(
+        &self,
+        sandbox: &mut Sandbox,
+        command: String,
+        standalone: bool,
+        timeout: Option<Duration>,
+    ) -> Result<CommandResult, SandboxError> {
+        let event = |kind| ServerEvent {
+            command: Some(command.clone()),
+            ..ServerEvent::new(kind, &sandbox.id)
+        };
+        let started = event(ServerEventKind::ExecStarted);
+        let mut finished = event(ServerEventKind::ExecFinished);
+        self.emit(&sandbox.tenant, started);
+
+        let result = sandbox.exec(command, standalone, timeout).await;
+
+        finished.timestamp = chrono::Utc::now();
+        finished.exit_code = result.as_ref().ok().map(|result| result.exit_code);
+        self.emit(&sandbox.tenant, finished);
+        result
+    }
+
+    /// Registers a new sandbox owned by `tenant` and returns its ID.
+    /// The template, if any, is applied before validating the payload.
+    pub async fn create_sandbox(
+        &self,
+        tenant: &Tenant,
+        payload: CreatePayload,
+    ) -> Result<String, ApiError> {
+        let payload = match payload.template.clone() {
+            Some(name) => {
+                let templates = self.templates.read().await;
+                let template = templates
+                    .get(&name)
+                    .ok_or_else(|| ApiError::template_not_found(&name))?;
+                template.apply(payload)
+            }
+            None => payload,
+        };
+        payload.validate()?;
+        self.policy(tenant).check_create(&payload)?;
+
+        let mut tools = Vec::new();
+        for name in &payload.tools {
+            tools.push(self.tools.get(name).await?);
+        }
+        let terminal_size = payload.terminal_size();
+        let blocked_commands = payload.blocked_command_patterns()?;
+        let mut sandbox = Sandbox::builder()
+            .image(payload.image)
+            .setup(payload.setup_commands)
+            .tenant(&tenant.name)
+            .labels(payload.labels)
+            .env(payload.env)
+            .limits(payload.limits.unwrap_or_default())
+            .mounts(payload.mounts)
+            .dns(payload.dns)
+            .dns_search(payload.dns_search)
+            .extra_hosts(payload.extra_hosts)
+            .shell(payload.shell.unwrap_or_default())
+            .shell_init(self.shell_init.iter().cloned())
+            .shell_init(payload.shell_init)
+            .tools(tools)
+            .repo(payload.repo)
+            .hooks(self.hooks.iter().cloned())
+            .observers(self.observers.iter().cloned())
+            .output_filters(self.output_filters().as_ref().clone())
+            .terminal_size(terminal_size)
+            .verify_command(payload.verify_command)
+            .max_commands(payload.max_commands)
+            .restart_policy(payload.restart_policy.unwrap_or_default())
+            .blocked_commands(blocked_commands)
+            .time_limit(payload.time_limit_secs.map(Duration::from_secs))
+            .store(self.trajectory_store.clone())
+            .runtime(self.runtime.clone())
+            .build()?;
+        let registration = self.proxy.as_ref().map(|proxy| proxy.register(&mut sandbox));
+        let id = sandbox.id.clone();
+        let entry = SandboxEntry {
+            _proxy: registration,
+            ..SandboxEntry::new(sandbox)
+        };
+        self.sandboxes.insert(id.clone(), Arc::new(entry));
+        self.emit(
+            &tenant.name,
+            ServerEvent::new(ServerEventKind::Created, &id),
+        );
+        Ok(id)
+    }
+
+    /// Starts a sandbox owned by `tenant`, waiting for capacity if the server is full.
+    /// Acquires a permit from the tenant semaphore and then from the global one, and
+    /// holds both until the sandbox is stopped.
+    pub async fn start_sandbox(&self, tenant: &Tenant, id: &str) -> Result<(), ApiError> {
+        let entry = self.get_entry(tenant, id)?;
+        self.policy(tenant).check_sandbox(&entry.image, &entry.labels)?;
+        let permits = self.acquire_permits(tenant).await?;
+        self.start_entry(tenant, &entry, permits).await
+    }
+
+    /// Starts a sandbox owned by `tenant` if there is capacity for it. Otherwise it is
+    /// queued and started in the background once permits free up, with a `started` or
+    /// `start_failed` event.
+    pub async fn start_or_queue(
+        self: &Arc<Self>,
+        tenant: Arc<Tenant>,
+        id: &str,
+    ) -> Result<StartStatus, ApiError> {
+        let entry = self.get_entry(&tenant, id)?;
+        self.policy(&tenant).check_sandbox(&entry.image, &entry.labels)?;
+        if let (Ok(tenant_permit), Ok(permit)) = (
+            tenant.semaphore.clone().try_acquire_owned(),
+            self.semaphore.clone().try_acquire_owned(),
+        ) {
+            self.start_entry(&tenant, &entry, vec![tenant_permit, permit])
+                .await?;
+            return Ok(StartStatus::Started);
+        }
+
+        if !entry.sandbox.lock().await.queue()? {
+            return Ok(StartStatus::Queued);
+        }
+        self.emit(&tenant.name, ServerEvent::new(ServerEventKind::Queued, id));
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            let result = match state.acquire_permits(&tenant).await {
+                Ok(permits) => {
+                    let sandbox = entry.sandbox.lock().await;
+                    let queued = matches!(sandbox.get_status(), SandboxStatus::Queued);
+                    drop(sandbox);
+                    // Stopped while queued
+                    if !queued {
+                        return;
+                    }
+                    state.start_entry(&tenant, &entry, permits).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!(sandbox_id = %entry.id, error = %e.message, "Queued sandbox failed to start");
+                entry.sandbox.lock().await.dequeue();
+                let event = ServerEvent {
+                    error: Some(e.message),
+                    ..ServerEvent::new(ServerEventKind::StartFailed, &entry.id)
+                };
+                state.emit(&tenant.name, event);
+            }
+        });
+        Ok(StartStatus::Queued)
+    }
+
+    /// Waits for a permit from the tenant semaphore and then from the global one.
+    async fn acquire_permits(&self, tenant: &Tenant) -> Result<Vec<OwnedSemaphorePermit>, ApiError> {
+        let tenant_permit = tenant
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        Ok(vec![tenant_permit, permit])
+    }
+
+    /// Starts the sandbox of an entry. When it fails to start, its container and network
+    /// are removed and the entry is dropped from the registry, so nothing is left behind.
+    async fn start_entry(
+        &self,
+        tenant: &Tenant,
+        entry: &SandboxEntry,
+        permits: Vec<OwnedSemaphorePermit>,
+    ) -> Result<(), ApiError> {
+        let id = entry.id.as_str();
+
+        // Now lock the individual sandbox and do long work
+        let mut sandbox_guard = entry.sandbox.lock().await;
+        sandbox_guard.dequeue();
+        if !matches!(sandbox_guard.get_status(), SandboxStatus::Created) {
+            return Err(SandboxError::AlreadyStarted.into());
+        }
+
+        let started = async {
+            // Pulled here rather than by the sandbox so the progress reaches the events
+            // stream
+            let mut on_progress = |progress: &PullProgress| {
+                let event = ServerEvent {
+                    progress: Some(progress.clone()),
+                    ..ServerEvent::new(ServerEventKind::Pulling, id)
+                };
+                self.emit(&tenant.name, event);
+            };
+            self.runtime
+                .pull(&sandbox_guard.image, &mut on_progress)
+                .await?;
+
+            let warm = match &self.pool {
+                Some(pool) => pool.claim(&sandbox_guard).await,
+                None => None,
+            };
+            match warm {
+                Some(warm) => sandbox_guard.start_warm(permits, warm).await,
+                None => sandbox_guard.start(permits).await,
+            }
+        }
+        .await;
+        if let Err(e) = started {
+            sandbox_guard.abort_start().await;
+            self.sandboxes.remove(id);
+            self.emit(&tenant.name, ServerEvent::new(ServerEventKind::Removed, id));
+            return Err(e.into());
+        }
+        self.emit(&tenant.name, ServerEvent::new(ServerEventKind::Started, id));
+
+        Ok(())
+    }
+
+    /// Stops a sandbox owned by `tenant`, removing it from the registry when `remove` is set.
+    pub async fn stop_sandbox(
+        &self,
+        tenant: &Tenant,
+        id: &str,
+        remove: bool,
+    ) -> Result<StopResponse, ApiError> {
+        let sandbox_arc = self.get_sandbox(tenant, id).await?;
+        if remove {
+            self.sandboxes.remove(id);
+        }
+
+        let mut sandbox = sandbox_arc.lock().await;
+        self.archive_sandbox(&mut sandbox).await;
+        // Permit is released here
+        sandbox.stop().await?;
+        self.emit(&tenant.name, ServerEvent::new(ServerEventKind::Stopped, id));
+        if remove {
+            self.emit(&tenant.name, ServerEvent::new(ServerEventKind::Removed, id));
+        }
+        Ok(StopResponse {
+            archive_url: sandbox.view().archive_url(),
+        })
+    }
+
+    /// Uploads the sandbox to object storage, if archival is enabled and the sandbox
+    /// is running. Failures are logged and do not prevent the sandbox from stopping.
+    pub async fn archive_sandbox(&self, sandbox: &mut Sandbox) {
+        let Some(archiver) = &self.archiver else {
+            return;
+        };
+        if !matches!(
+            sandbox.get_status(),
+            SandboxStatus::Started(_)
+                | SandboxStatus::Exited(..)
+                | SandboxStatus::Frozen(_)
+                | SandboxStatus::Exhausted(_)
+                | SandboxStatus::Crashed(..)
+        ) {
+            return;
+        }
+        match archiver.archive(sandbox).await {
+            Ok(url) => {
+                info!(sandbox_id = %sandbox.id, url = %url, "Sandbox archived");
+                sandbox.view().set_archive_url(url);
+            }
+            Err(e) => {
+                warn!(sandbox_id = %sandbox.id, error = %format!("{:#}", e), "Failed to archive sandbox")
+            }
+        }
+    }
+
+    /// Looks up a sandbox owned by `tenant`.
+    /// Sandboxes of other tenants are reported as not found.
+    pub async fn get_sandbox(
+        &self,
+        tenant: &Tenant,
+        id: &str,
+    ) -> Result<Arc<Mutex<Sandbox>>, ApiError> {
+        Ok(self.get_entry(tenant, id)?.sandbox.clone())
+    }
+
+    /// Looks up the registry entry of a sandbox owned by `tenant`, without locking the
+    /// sandbox. Sandboxes of other tenants are reported as not found.
+    pub fn get_entry(&self, tenant: &Tenant, id: &str) -> Result<Arc<SandboxEntry>, ApiError> {
+        self.sandboxes
+            .get(id)
+            .map(|entry| entry.value().clone())
+            .filter(|entry| entry.tenant == tenant.name)
+            .ok_or_else(|| ApiError::sandbox_not_found(id))
+    }
+
+    /// Snapshot of the registry entries.
+    pub fn sandbox_list(&self) -> Vec<Arc<SandboxEntry>> {
+        self.sandboxes
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// Trajectory of a sandbox owned by `tenant`. Falls back to the trajectory store
+    /// once the sandbox has been removed.
+    pub async fn trajectory(&self, tenant: &Tenant, id: &str) -> Result<Trajectory, ApiError> {
+        let not_found = match self.get_entry(tenant, id) {
+            Ok(entry) => return Ok(entry.view.snapshot()),
+            Err(e) => e,
+        };
+        let Some(store) = &self.trajectory_store else {
+            return Err(not_found);
+        };
+        store
+            .load(&tenant.name, id)
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?
+            .ok_or(not_found)
+    }
+
+    /// Runs a command in the sandbox `id` of `tenant`, waiting for the sandbox if another
+    /// command is running in it. The command is checked against the admission policy first.
+    pub async fn exec_in(
+        &self,
+        tenant: &Tenant,
+        id: &str,
+        command: String,
+        standalone: bool,
+        timeout: Option<Duration>,
+    ) -> Result<CommandResult, ApiError> {
+        let sandbox = self.get_sandbox(tenant, id).await?;
+        self.policy(tenant).check_command(&command)?;
+        let mut sandbox = sandbox.lock().await;
+        Ok(self.exec(&mut sandbox, command, standalone, timeout).await?)
+    }
+
+    /// Stops and removes the sandboxes that outlived their time limit, or `timeout` for
+    /// those without one, with a `timed_out` event.
+    pub async fn reap(&self, timeout: Duration) {
+        let mut sandboxes_to_remove = Vec::new();
+        // Read from the views, sandboxes running a command are not waited for
+        for entry in self.sandbox_list() {
+            if let Some(started_at) = entry.view.started_at() {
+                let elapsed = (Utc::now() - started_at).to_std().unwrap_or_default();
+                if elapsed > entry.time_limit.unwrap_or(timeout) {
+                    warn!(sandbox_id = %entry.id, elapsed_seconds = elapsed.as_secs(), "Sandbox timed out, removing");
+                    sandboxes_to_remove.push(entry.id.clone());
+                }
+            }
+        }
+
+        for id in sandboxes_to_remove {
+            // This is a simplified version of the stop_sandbox logic
+            let entry = self.sandboxes.remove(&id);
+
+            if let Some((_, entry)) = entry {
+                let tenant = entry.tenant.clone();
+                self.emit(&tenant, ServerEvent::new(ServerEventKind::TimedOut, &id));
+                let mut sandbox = entry.sandbox.lock().await;
+                if let SandboxStatus::Started(_)
+                | SandboxStatus::Frozen(_)
+                | SandboxStatus::Exhausted(_)
+                | SandboxStatus::Exited(..)
+                | SandboxStatus::Crashed(..) = sandbox.get_status()
+                {
+                    self.archive_sandbox(&mut sandbox).await;
+                    let _ = sandbox.stop().await;
+                }
+                self.emit(&tenant, ServerEvent::new(ServerEventKind::Removed, &id));
+            }
+        }
+    }
+
+    /// Looks for idle sandboxes whose session or container died, which are marked crashed
+    /// and restarted per their restart policy. Sandboxes running a command notice it
+    /// themselves and are skipped. Each sandbox is checked in its own task, as restarting
+    /// one runs its setup again.
+    pub fn check_sessions(&self) {
+        for entry in self.sandbox_list() {
+            let Ok(mut sandbox) = entry.sandbox.clone().try_lock_owned() else {
+                continue;
+            };
+            tokio::spawn(async move {
+                // The crash is logged by the sandbox
+                let _ = sandbox.check_session().await;
+            });
+        }
+    }
+
+    /// Reaps timed out sandboxes and checks the sessions of the others every minute,
+    /// until the task is aborted.
+    pub fn spawn_reaper(self: &Arc<Self>, timeout: Duration) -> JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(REAP_INTERVAL).await;
+                manager.reap(timeout).await;
+                manager.check_sessions();
+            }
+        })
+    }
+}
+
+/// Compiles the admission policy of the configuration. It was checked on load, so it only
+/// fails for configurations built by hand, which then refuse every sandbox.
+fn server_policy(config: &ServerConfig) -> Policy {
+    Policy::new(&config.policy).unwrap_or_else(|e| {
+        error!(error = %format!("{:#}", e), "Invalid admission policy, denying all");
+        Policy::deny_all()
+    })
+}
@@ -1,2 +1,55 @@
+//! Sandboxes of shell sessions in containers, recording the trajectory of the commands run in
+//! them.
+//!
+//! The [`sandbox`] and [`runtime`] modules are always built. Cargo features add the rest:
+//!
+//! - `server`: the [`Manager`] of sandboxes embedded in-process, the HTTP API of `sos serve`
+//!   over it in [`http`], and the modules behind them
+//! - `client`: [`client::SosClient`], the typed client of the HTTP API
+//! - `tui`: the terminal interface of the `sos` binary
+//! - `local`: [`runtime::Local`], running sandboxes as host processes
+//! - `cli`: the `sos` binary, which needs all of the above
+//!
+//! All of them are enabled by default. Embedding only the client or the [`sandbox::Sandbox`]
+//! type, use `default-features = false` with the ones needed to leave out axum, ratatui,
+//! crossterm, clap and reqwest.
 pub mod sandbox;
+pub mod api;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "server")]
 pub mod http;
+#[cfg(feature = "server")]
+pub mod manager;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod daemon;
+#[cfg(feature = "server")]
+pub mod env;
+#[cfg(feature = "server")]
+pub mod export;
+#[cfg(feature = "server")]
+pub mod listen;
+pub mod store;
+#[cfg(feature = "server")]
+pub mod archive;
+#[cfg(feature = "server")]
+pub mod audit;
+pub mod policy;
+pub mod pool;
+#[cfg(feature = "server")]
+pub mod proxy;
+pub mod task;
+pub mod swebench;
+pub mod tenant;
+#[cfg(feature = "server")]
+pub mod tls;
+#[cfg(feature = "server")]
+pub mod tools;
+#[cfg(feature = "server")]
+pub mod rate_limit;
+pub mod runtime;
+
+#[cfg(feature = "server")]
+pub use manager::Manager;
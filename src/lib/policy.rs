@@ -0,0 +1,272 @@
+//! Admission policy of the server, checked before sandboxes are created or started and
+//! before commands run in them.
+//!
+//! The `[policy]` section of the configuration applies to every tenant. The `policy` of a
+//! tenant overrides it rule by rule: a rule the tenant sets replaces the server's, the
+//! others still apply.
+//!
+//! ```toml
+//! [policy]
+//! allowed_images = ["python:*", "ubuntu:*", "registry.internal/*"]
+//! denied_images = ["*:latest"]
+//! forbidden_commands = ['rm\s+-rf\s+/(\s|$)', 'curl[^|]*\|\s*(ba)?sh']
+//! max_setup_command_bytes = 4096
+//! required_labels = ["experiment"]
+//! allowed_mounts = ["/data/*"]
+//!
+//! [[tenants]]
+//! name = "red-team"
+//! api_key = "..."
+//! max_sandboxes = 2
+//! policy = { forbidden_commands = [], allow_attach = true }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Context;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::api::CreatePayload;
+
+/// Policy section of the server configuration, and of a tenant. Unset rules do not
+/// restrict anything, or fall back to the server's for a tenant.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Images sandboxes may use, as patterns where `*` matches anything. Any image when
+    /// unset.
+    pub allowed_images: Option<Vec<String>>,
+    /// Images sandboxes may not use, as patterns where `*` matches anything
+    pub denied_images: Option<Vec<String>>,
+    /// Regular expressions that commands, setup commands and `shell_init` lines may not
+    /// match
+    pub forbidden_commands: Option<Vec<String>>,
+    /// Size of the longest setup command, in bytes
+    pub max_setup_command_bytes: Option<usize>,
+    /// Labels every sandbox must have
+    pub required_labels: Option<Vec<String>>,
+    /// Host paths templates may mount, as patterns where `*` matches anything. Any path
+    /// when unset.
+    pub allowed_mounts: Option<Vec<String>>,
+    /// Allows `GET /sandboxes/{id}/attach`. What is typed in its shell is not recorded and
+    /// escapes the forbidden commands, so it is refused when unset.
+    pub allow_attach: Option<bool>,
+}
+
+/// Rule of the policy a request broke, returned in the `violation` of the error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyViolation {
+    /// Name of the rule, as in the configuration, e.g. `denied_images`
+    pub rule: String,
+    /// What broke it: the image, the command, the size of the setup command, the
+    /// missing label, the mounted host path or the sandbox attached to
+    pub value: String,
+}
+
+impl PolicyViolation {
+    fn new(rule: &str, value: impl Into<String>) -> Self {
+        PolicyViolation {
+            rule: rule.to_string(),
+            value: value.into(),
+        }
+    }
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule.as_str() {
+            "allowed_images" => write!(f, "Image {} is not allowed", self.value),
+            "denied_images" => write!(f, "Image {} is denied", self.value),
+            "forbidden_commands" => write!(f, "Command is forbidden: {}", self.value),
+            "max_setup_command_bytes" => {
+                write!(f, "Setup command of {} bytes is too long", self.value)
+            }
+            "required_labels" => write!(f, "Missing required label {}", self.value),
+            "allowed_mounts" => write!(f, "Mount of {} is not allowed", self.value),
+            "allow_attach" => write!(f, "Attaching to sandbox {} is not allowed", self.value),
+            rule => write!(f, "{} violates the {} policy", self.value, rule),
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+/// Compiled [`PolicyConfig`].
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    allowed_images: Option<Vec<Regex>>,
+    denied_images: Option<Vec<Regex>>,
+    forbidden_commands: Option<Vec<Regex>>,
+    max_setup_command_bytes: Option<usize>,
+    required_labels: Option<Vec<String>>,
+    allowed_mounts: Option<Vec<Regex>>,
+    allow_attach: Option<bool>,
+}
+
+impl Policy {
+    /// Compiles the patterns of the configuration, failing on invalid ones.
+    pub fn new(config: &PolicyConfig) -> anyhow::Result<Self> {
+        let images = |patterns: &Option<Vec<String>>| -> anyhow::Result<Option<Vec<Regex>>> {
+            patterns
+                .as_ref()
+                .map(|patterns| patterns.iter().map(|p| image_pattern(p)).collect())
+                .transpose()
+        };
+        let forbidden_commands = config
+            .forbidden_commands
+            .as_ref()
+            .map(|patterns| {
+                patterns
+                    .iter()
+                    .map(|p| {
+                        Regex::new(p).with_context(|| format!("Invalid forbidden command {}", p))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        Ok(Policy {
+            allowed_images: images(&config.allowed_images)?,
+            denied_images: images(&config.denied_images)?,
+            forbidden_commands,
+            max_setup_command_bytes: config.max_setup_command_bytes,
+            required_labels: config.required_labels.clone(),
+            allowed_mounts: images(&config.allowed_mounts)?,
+            allow_attach: config.allow_attach,
+        })
+    }
+
+    /// Policy refusing every image, mount, command and attach, used in place of one that
+    /// does not compile so that a broken policy fails closed.
+    pub fn deny_all() -> Self {
+        Policy {
+            allowed_images: Some(Vec::new()),
+            denied_images: None,
+            forbidden_commands: Some(vec![Regex::new("").expect("Empty regex is valid")]),
+            max_setup_command_bytes: Some(0),
+            required_labels: None,
+            allowed_mounts: Some(Vec::new()),
+            allow_attach: Some(false),
+        }
+    }
+
+    /// This policy with the rules `overrides` sets replaced by those.
+    pub fn overridden_by(&self, overrides: &Policy) -> Policy {
+        Policy {
+            allowed_images: overrides
+                .allowed_images
+                .clone()
+                .or_else(|| self.allowed_images.clone()),
+            denied_images: overrides
+                .denied_images
+                .clone()
+                .or_else(|| self.denied_images.clone()),
+            forbidden_commands: overrides
+                .forbidden_commands
+                .clone()
+                .or_else(|| self.forbidden_commands.clone()),
+            max_setup_command_bytes: overrides
+                .max_setup_command_bytes
+                .or(self.max_setup_command_bytes),
+            required_labels: overrides
+                .required_labels
+                .clone()
+                .or_else(|| self.required_labels.clone()),
+            allowed_mounts: overrides
+                .allowed_mounts
+                .clone()
+                .or_else(|| self.allowed_mounts.clone()),
+            allow_attach: overrides.allow_attach.or(self.allow_attach),
+        }
+    }
+
+    /// Checks a sandbox about to be created, once its template has been applied.
+    pub fn check_create(&self, payload: &CreatePayload) -> Result<(), PolicyViolation> {
+        self.check_sandbox(&payload.image, &payload.labels)?;
+        let max = self.max_setup_command_bytes.unwrap_or(usize::MAX);
+        if let Some(command) = payload.setup_commands.iter().find(|c| c.len() > max) {
+            return Err(PolicyViolation::new(
+                "max_setup_command_bytes",
+                command.len().to_string(),
+            ));
+        }
+        for command in payload
+            .setup_commands
+            .iter()
+            .chain(&payload.verify_command)
+            .chain(&payload.shell_init)
+        {
+            self.check_command(command)?;
+        }
+        let allowed = |source: &str| {
+            self.allowed_mounts.as_ref().is_none_or(|allowed| {
+                allowed.iter().any(|pattern| pattern.is_match(source))
+            })
+        };
+        match payload.mounts.iter().find(|mount| !allowed(&mount.source)) {
+            Some(mount) => Err(PolicyViolation::new("allowed_mounts", mount.source.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the image and labels of a sandbox, when it is created and again when it is
+    /// started, in case the policy changed in between.
+    pub fn check_sandbox(
+        &self,
+        image: &str,
+        labels: &HashMap<String, String>,
+    ) -> Result<(), PolicyViolation> {
+        self.check_image(image)?;
+        if let Some(label) = self
+            .required_labels
+            .iter()
+            .flatten()
+            .find(|label| !labels.contains_key(*label))
+        {
+            return Err(PolicyViolation::new("required_labels", label.clone()));
+        }
+        Ok(())
+    }
+
+    /// Checks an image against the allowed and denied images, e.g. before pulling it.
+    pub fn check_image(&self, image: &str) -> Result<(), PolicyViolation> {
+        if self.denied_images.iter().flatten().any(|pattern| pattern.is_match(image)) {
+            return Err(PolicyViolation::new("denied_images", image));
+        }
+        let allowed = self.allowed_images.as_ref().is_none_or(|allowed| {
+            allowed.iter().any(|pattern| pattern.is_match(image))
+        });
+        match allowed {
+            true => Ok(()),
+            false => Err(PolicyViolation::new("allowed_images", image)),
+        }
+    }
+
+    /// Checks an interactive shell about to be attached to a sandbox.
+    pub fn check_attach(&self, sandbox_id: &str) -> Result<(), PolicyViolation> {
+        match self.allow_attach.unwrap_or(false) {
+            true => Ok(()),
+            false => Err(PolicyViolation::new("allow_attach", sandbox_id)),
+        }
+    }
+
+    /// Checks a command about to run in a sandbox.
+    pub fn check_command(&self, command: &str) -> Result<(), PolicyViolation> {
+        match self
+            .forbidden_commands
+            .iter()
+            .flatten()
+            .any(|pattern| pattern.is_match(command))
+        {
+            true => Err(PolicyViolation::new("forbidden_commands", command)),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Compiles an image pattern, where `*` matches anything, into a regex matching whole
+/// image names.
+fn image_pattern(pattern: &str) -> anyhow::Result<Regex> {
+    let regex = format!("^{}$", regex::escape(pattern).replace(r"\*", ".*"));
+    Regex::new(&regex).with_context(|| format!("Invalid image pattern {}", pattern))
+}
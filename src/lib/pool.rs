@@ -0,0 +1,158 @@
+//! Warm container pool.
+//!
+//! Keeps idle containers running for the configured images, with their session shell
+//! already attached and configured. Starting a sandbox claims one of them when it can,
+//! so only its setup commands run at start, and the pool is refilled in the background.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::runtime::ContainerRuntime;
+use crate::sandbox::{Sandbox, SandboxStatus, Shell};
+
+/// Pool section of the server configuration, one per image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolConfig {
+    pub image: String,
+    /// Number of idle containers kept ready
+    #[serde(default = "default_pool_size")]
+    pub size: usize,
+}
+
+fn default_pool_size() -> usize {
+    2
+}
+
+/// Idle containers of an image.
+struct Slot {
+    size: usize,
+    idle: Vec<Sandbox>,
+    /// Containers being warmed up
+    pending: usize,
+}
+
+/// Pool of warm sandboxes per image.
+pub struct WarmPool {
+    runtime: Arc<dyn ContainerRuntime>,
+    /// Lines the session shells of the server run, so warm ones do as well
+    shell_init: Vec<String>,
+    images: Vec<String>,
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl WarmPool {
+    pub fn new(
+        runtime: Arc<dyn ContainerRuntime>,
+        config: &[PoolConfig],
+        shell_init: Vec<String>,
+    ) -> Self {
+        let slots = config
+            .iter()
+            .map(|pool| {
+                let slot = Slot {
+                    size: pool.size,
+                    idle: Vec::new(),
+                    pending: 0,
+                };
+                (pool.image.clone(), slot)
+            })
+            .collect();
+        WarmPool {
+            runtime,
+            shell_init,
+            images: config.iter().map(|pool| pool.image.clone()).collect(),
+            slots: Mutex::new(slots),
+        }
+    }
+
+    /// Starts filling the pool in the background.
+    pub fn fill(self: &Arc<Self>) {
+        for image in &self.images {
+            tokio::spawn(self.clone().refill(image.clone()));
+        }
+    }
+
+    /// Number of idle containers ready for the image.
+    pub async fn idle(&self, image: &str) -> usize {
+        self.slots
+            .lock()
+            .await
+            .get(image)
+            .map_or(0, |slot| slot.idle.len())
+    }
+
+    /// Takes a warm sandbox to start `sandbox` in, if one is ready.
+    ///
+    /// Only sandboxes that have not been started and need a plain container qualify:
+    /// environment variables, resource limits and mounts are set when a container is
+    /// created, so sandboxes using them always get a fresh one. Pooled containers run
+    /// a bash session, or sh when their image has no bash, initialized with the server's
+    /// shell init lines only.
+    pub async fn claim(self: &Arc<Self>, sandbox: &Sandbox) -> Option<Sandbox> {
+        let plain = sandbox.env.is_empty()
+            && sandbox.mounts.is_empty()
+            && sandbox.dns.is_empty()
+            && sandbox.dns_search.is_empty()
+            && sandbox.extra_hosts.is_empty()
+            && sandbox.shell == Shell::Bash
+            && sandbox.shell_init == self.shell_init
+            && sandbox.tools.is_empty()
+            && sandbox.limits.memory_mb.is_none()
+            && sandbox.limits.cpus.is_none()
+            && sandbox.limits.pids.is_none();
+        if !plain || !matches!(sandbox.get_status(), SandboxStatus::Created) {
+            return None;
+        }
+
+        let warm = self
+            .slots
+            .lock()
+            .await
+            .get_mut(&sandbox.image)?
+            .idle
+            .pop()?;
+        tokio::spawn(self.clone().refill(sandbox.image.clone()));
+        Some(warm)
+    }
+
+    /// Warms up containers for the image until the pool is full again.
+    async fn refill(self: Arc<Self>, image: String) {
+        loop {
+            {
+                let mut slots = self.slots.lock().await;
+                let Some(slot) = slots.get_mut(&image) else {
+                    return;
+                };
+                if slot.idle.len() + slot.pending >= slot.size {
+                    return;
+                }
+                slot.pending += 1;
+            }
+
+            let mut sandbox = Sandbox::builder()
+                .image(&image)
+                .shell_init(self.shell_init.iter().cloned())
+                .runtime(self.runtime.clone())
+                .build()
+                .expect("Pool sandboxes have an image and a runtime");
+            let result = sandbox.prepare().await;
+
+            let mut slots = self.slots.lock().await;
+            let slot = slots.get_mut(&image).expect("Pool images never change");
+            slot.pending -= 1;
+            match result {
+                Ok(()) => slot.idle.push(sandbox),
+                Err(e) => {
+                    drop(slots);
+                    warn!(image = %image, error = %e, "Failed to warm up container");
+                    // Retrying right away would most likely fail again
+                    let _ = sandbox.stop().await;
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Recording HTTP proxy.
+//!
+//! When enabled, every sandbox gets `HTTP_PROXY` and `HTTPS_PROXY` pointing at a proxy
+//! run by the server, with a token of its own as the proxy user. Each request going
+//! through the proxy is added to the trajectory of the sandbox that made it. Plain HTTP
+//! requests are forwarded by the proxy, so their URL and status are known. HTTPS
+//! requests are tunneled with `CONNECT` and not decrypted, so only their host is.
+//! Programs ignoring the proxy variables bypass it.
+//!
+//! The proxy only reaches public addresses: loopback, link-local, private and other
+//! special addresses, such as the server itself or the metadata service of a cloud
+//! provider, are refused unless their host is listed in `allowed_hosts`.
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::Utc;
+use dashmap::DashMap;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::sandbox::{NetworkRequest, Sandbox, SandboxView};
+use crate::store::{TrajectoryEvent, TrajectoryStore};
+
+/// Host of the server as seen from Docker containers, once mapped to their gateway.
+const DOCKER_HOST_GATEWAY: &str = "host.docker.internal";
+
+/// Address of the host on the default bridge network of Docker.
+const DOCKER_BRIDGE_GATEWAY: [u8; 4] = [172, 17, 0, 1];
+
+/// Headers describing a single hop, not forwarded by the proxy.
+const HOP_BY_HOP: [header::HeaderName; 6] = [
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Proxy section of the server configuration.
+///
+/// ```toml
+/// [proxy]
+/// listen = "172.17.0.1:3128"
+/// allowed_hosts = ["pypi.internal"]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// Address the proxy listens on, the host on the Docker bridge by default. Containers
+    /// reach it through the host gateway, so it must not be a loopback address for them.
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// Host sandboxes reach the proxy at. `host.docker.internal` is mapped to the host
+    /// gateway of Docker and Podman containers. Local sandboxes use `127.0.0.1`.
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Hosts the proxy reaches even when they resolve to non-public addresses
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+fn default_listen() -> SocketAddr {
+    (DOCKER_BRIDGE_GATEWAY, 3128).into()
+}
+
+fn default_host() -> String {
+    DOCKER_HOST_GATEWAY.to_string()
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            listen: default_listen(),
+            host: default_host(),
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+/// Where the requests of a sandbox are recorded.
+#[derive(Clone)]
+struct Recorder {
+    sandbox_id: String,
+    tenant: String,
+    view: SandboxView,
+    store: Option<Arc<TrajectoryStore>>,
+}
+
+impl Recorder {
+    async fn record(&self, request: NetworkRequest) {
+        debug!(
+            sandbox_id = %self.sandbox_id,
+            method = %request.method,
+            url = %request.url,
+            status = ?request.status,
+            "Proxied request"
+        );
+        self.view.push_request(request.clone());
+        if let Some(store) = &self.store {
+            let event = TrajectoryEvent::Request(request);
+            if let Err(e) = store.append(&self.tenant, &self.sandbox_id, &event).await {
+                warn!(sandbox_id = %self.sandbox_id, error = %e, "Failed to persist request");
+            }
+        }
+    }
+}
+
+/// Recording proxy shared by the sandboxes, see the module documentation.
+pub struct Proxy {
+    host: String,
+    port: u16,
+    /// Sandboxes by proxy token
+    recorders: Arc<DashMap<String, Recorder>>,
+    resolver: Arc<Resolver>,
+    http: reqwest::Client,
+}
+
+/// Resolves the destinations of the proxy, refusing non-public addresses of hosts that
+/// are not allowed.
+struct Resolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Refusal> {
+        // IPv6 addresses are bracketed in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = lookup_host((host, port))
+            .await
+            .map_err(|e| Refusal::Unresolved(e.to_string()))?
+            .collect();
+        if self.allowed_hosts.iter().any(|allowed| allowed == host) {
+            return Ok(addrs);
+        }
+        match addrs.iter().find(|addr| !is_public(addr.ip())) {
+            Some(addr) => Err(Refusal::NotPublic(addr.ip())),
+            None => Ok(addrs),
+        }
+    }
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = Resolver {
+            allowed_hosts: self.allowed_hosts.clone(),
+        };
+        Box::pin(async move {
+            // reqwest fills in the port of the URL
+            let addrs = resolver.resolve(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Why the proxy did not reach a destination.
+#[derive(Debug, thiserror::Error)]
+enum Refusal {
+    #[error("Failed to resolve destination: {0}")]
+    Unresolved(String),
+    #[error("Destination {0} is not a public address")]
+    NotPublic(IpAddr),
+}
+
+impl Refusal {
+    fn status(&self) -> StatusCode {
+        match self {
+            Refusal::Unresolved(_) => StatusCode::BAD_GATEWAY,
+            Refusal::NotPublic(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// Whether the address is reachable on the internet, rather than the host, its
+/// networks or special purpose ranges.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Token of a sandbox, revoked when dropped along with the sandbox.
+pub struct Registration {
+    token: String,
+    recorders: Arc<DashMap<String, Recorder>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.recorders.remove(&self.token);
+    }
+}
+
+impl Proxy {
+    /// Binds the proxy and serves it in the background.
+    pub fn start(config: &ProxyConfig) -> anyhow::Result<Arc<Self>> {
+        let listener = std::net::TcpListener::bind(config.listen)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+        let resolver = Arc::new(Resolver {
+            allowed_hosts: config.allowed_hosts.clone(),
+        });
+        let proxy = Arc::new(Proxy {
+            host: config.host.clone(),
+            port: addr.port(),
+            recorders: Arc::new(DashMap::new()),
+            resolver: resolver.clone(),
+            http: reqwest::Client::builder()
+                .no_proxy()
+                .redirect(reqwest::redirect::Policy::none())
+                .dns_resolver(resolver)
+                .build()?,
+        });
+        info!(addr = %addr, "Recording proxy listening");
+        tokio::spawn(proxy.clone().serve(listener));
+        Ok(proxy)
+    }
+
+    /// Routes the sandbox through the proxy: sets its proxy variables and maps the host
+    /// of the proxy if needed. Requests are recorded until the registration is dropped.
+    pub fn register(&self, sandbox: &mut Sandbox) -> Registration {
+        let token = Uuid::new_v4().simple().to_string();
+        let url = format!("http://{}@{}:{}", token, self.host, self.port);
+        for var in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+            sandbox.env.insert(var.to_string(), url.clone());
+        }
+        for var in ["NO_PROXY", "no_proxy"] {
+            sandbox
+                .env
+                .insert(var.to_string(), "localhost,127.0.0.1".to_string());
+        }
+        if self.host == DOCKER_HOST_GATEWAY {
+            sandbox
+                .extra_hosts
+                .push(format!("{}:host-gateway", DOCKER_HOST_GATEWAY));
+        }
+
+        let recorder = Recorder {
+            sandbox_id: sandbox.id.clone(),
+            tenant: sandbox.tenant.clone(),
+            view: sandbox.view().clone(),
+            store: sandbox.store.clone(),
+        };
+        self.recorders.insert(token.clone(), recorder);
+        Registration {
+            token,
+            recorders: self.recorders.clone(),
+        }
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept proxy connection");
+                    continue;
+                }
+            };
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| proxy.clone().handle(request));
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await
+                {
+                    debug!(peer = %peer, error = %e, "Error serving proxy connection");
+                }
+            });
+        }
+    }
+
+    /// Sandbox named by the `Proxy-Authorization` header, `Basic` with the token as user.
+    fn recorder(&self, headers: &HeaderMap) -> Option<Recorder> {
+        let value = headers.get(header::PROXY_AUTHORIZATION)?.to_str().ok()?;
+        let credentials = BASE64.decode(value.strip_prefix("Basic ")?).ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (token, _) = credentials.split_once(':')?;
+        self.recorders.get(token).map(|recorder| recorder.clone())
+    }
+
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+        let Some(recorder) = self.recorder(request.headers()) else {
+            let mut response = status(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+            response.headers_mut().insert(
+                header::PROXY_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"sos\""),
+            );
+            return Ok(response);
+        };
+        let response = match request.method() == Method::CONNECT {
+            true => self.tunnel(&recorder, request).await,
+            false => self.forward(&recorder, request).await,
+        };
+        Ok(response)
+    }
+
+    /// Opens a tunnel to the `host:port` of a `CONNECT` request.
+    async fn tunnel(&self, recorder: &Recorder, request: Request<Incoming>) -> Response<Body> {
+        let Some((authority, host, port)) = request
+            .uri()
+            .authority()
+            .map(|a| (a.to_string(), a.host().to_string(), a.port_u16().unwrap_or(443)))
+        else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let mut record = NetworkRequest {
+            timestamp: Utc::now(),
+            method: Method::CONNECT.to_string(),
+            url: authority.clone(),
+            status: None,
+            error: None,
+        };
+        // Connects to the addresses checked, not to the host resolved again
+        let addrs = match self.resolver.resolve(&host, port).await {
+            Ok(addrs) => addrs,
+            Err(refusal) => {
+                record.error = Some(refusal.to_string());
+                recorder.record(record).await;
+                return status(refusal.status());
+            }
+        };
+        let mut upstream = match TcpStream::connect(&addrs[..]).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                record.error = Some(e.to_string());
+                recorder.record(record).await;
+                return status(StatusCode::BAD_GATEWAY);
+            }
+        };
+        record.status = Some(StatusCode::OK.as_u16());
+        recorder.record(record).await;
+
+        tokio::spawn(async move {
+            match hyper::upgrade::on(request).await {
+                Ok(upgraded) => {
+                    let mut client = TokioIo::new(upgraded);
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                }
+                Err(e) => debug!(authority = %authority, error = %e, "Proxy tunnel failed"),
+            }
+        });
+        status(StatusCode::OK)
+    }
+
+    /// Forwards a plain HTTP request, given with an absolute URL.
+    async fn forward(&self, recorder: &Recorder, request: Request<Incoming>) -> Response<Body> {
+        let url = request.uri().to_string();
+        if request.uri().scheme().is_none() {
+            return status(StatusCode::BAD_REQUEST);
+        }
+        let mut record = NetworkRequest {
+            timestamp: Utc::now(),
+            method: request.method().to_string(),
+            url: url.clone(),
+            status: None,
+            error: None,
+        };
+        // The resolver of the client checks the host again when connecting, in case it
+        // resolves differently, but does not see hosts given as addresses
+        if let Some(host) = request.uri().host()
+            && let Err(refusal) = self.resolver.resolve(host, 0).await
+        {
+            record.error = Some(refusal.to_string());
+            recorder.record(record).await;
+            return status(refusal.status());
+        }
+
+        let (parts, body) = request.into_parts();
+        let upstream = self
+            .http
+            .request(parts.method, &url)
+            .headers(end_to_end(parts.headers))
+            .body(reqwest::Body::wrap_stream(Body::new(body).into_data_stream()))
+            .send()
+            .await;
+        let upstream = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                record.error = Some(e.to_string());
+                recorder.record(record).await;
+                return status(StatusCode::BAD_GATEWAY);
+            }
+        };
+        record.status = Some(upstream.status().as_u16());
+        recorder.record(record).await;
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = upstream.status();
+        *response.headers_mut() = end_to_end(upstream.headers().clone());
+        *response.body_mut() = Body::from_stream(upstream.bytes_stream());
+        response
+    }
+}
+
+/// Drops the hop-by-hop headers, along with the ones the `Connection` header names.
+fn end_to_end(mut headers: HeaderMap) -> HeaderMap {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    for name in named {
+        headers.remove(name.as_str());
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    headers.remove("proxy-connection");
+    headers.remove("keep-alive");
+    headers
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+use crate::http::{ApiError, SoSState};
+use crate::tls::ClientIdentity;
+
+/// Rate limit section of the server configuration.
+///
+/// Limits apply per client, identified by its tenant, its client certificate or,
+/// failing both, its IP address. Requests whose API key does not authenticate count
+/// toward the limits of their IP address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests per second
+    pub requests_per_second: f64,
+    /// Number of requests that can be made in a burst above the sustained rate
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Maximum number of exec requests in flight
+    #[serde(default = "default_max_concurrent_execs")]
+    pub max_concurrent_execs: usize,
+}
+
+fn default_burst() -> u32 {
+    10
+}
+
+fn default_max_concurrent_execs() -> usize {
+    4
+}
+
+/// Interval between two sweeps of the idle clients.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket and exec slots of a single client.
+struct ClientLimits {
+    tokens: f64,
+    last_refill: Instant,
+    execs: Arc<Semaphore>,
+}
+
+/// Limits of the clients seen since their last idle sweep.
+struct Clients {
+    limits: HashMap<String, ClientLimits>,
+    last_sweep: Instant,
+}
+
+/// Per-client rate limiter shared by all requests.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            clients: Mutex::new(Clients {
+                limits: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Takes a token from the client's bucket.
+    /// Returns the exec semaphore of the client, or `None` if it ran out of tokens.
+    fn acquire(&self, client: &str) -> Option<Arc<Semaphore>> {
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(clients.last_sweep) >= SWEEP_INTERVAL {
+            clients.last_sweep = now;
+            clients
+                .limits
+                .retain(|_, limits| !self.is_idle(limits, now));
+        }
+        let limits = clients
+            .limits
+            .entry(client.to_string())
+            .or_insert_with(|| ClientLimits {
+                tokens: self.config.burst as f64,
+                last_refill: now,
+                execs: Arc::new(Semaphore::new(self.config.max_concurrent_execs)),
+            });
+
+        let elapsed = now.duration_since(limits.last_refill).as_secs_f64();
+        limits.tokens = (limits.tokens + elapsed * self.config.requests_per_second)
+            .min(self.config.burst.max(1) as f64);
+        limits.last_refill = now;
+
+        if limits.tokens < 1.0 {
+            return None;
+        }
+        limits.tokens -= 1.0;
+        Some(limits.execs.clone())
+    }
+
+    /// Whether the bucket of a client refilled and none of its execs are running, so
+    /// forgetting it changes nothing.
+    fn is_idle(&self, limits: &ClientLimits, now: Instant) -> bool {
+        let elapsed = now.duration_since(limits.last_refill).as_secs_f64();
+        let tokens = limits.tokens + elapsed * self.config.requests_per_second;
+        tokens >= self.config.burst.max(1) as f64
+            && limits.execs.available_permits() == self.config.max_concurrent_execs
+    }
+}
+
+/// Key identifying the client of a request. Callers are only keyed on their tenant once
+/// authenticated, so a made-up API key cannot get a fresh bucket.
+fn client_key(state: &SoSState, request: &Request) -> String {
+    let tenant = match state.tenants().is_empty() {
+        true => None,
+        false => state
+            .authenticate(request.headers(), request.extensions())
+            .ok(),
+    };
+    if let Some(tenant) = tenant {
+        return format!("tenant:{}", tenant.name);
+    }
+    if let Some(ClientIdentity(cn)) = request.extensions().get::<ClientIdentity>() {
+        return format!("cert:{}", cn);
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Whether the request runs commands in sandboxes, and counts toward
+/// `max_concurrent_execs`.
+fn is_exec(request: &Request) -> bool {
+    if request.method() != Method::POST {
+        return false;
+    }
+    let segments: Vec<_> = request.uri().path().trim_matches('/').split('/').collect();
+    matches!(
+        segments.as_slice(),
+        ["sandboxes", "exec"]
+            | ["sandboxes", _, "exec" | "verify"]
+            | ["sandboxes", _, "kernel", "execute"]
+            | ["envs", _, "step"]
+    )
+}
+
+fn too_many_requests(message: &str) -> Response {
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED", message).into_response()
+}
+
+/// Rate limiting middleware.
+///
+/// Rejects requests with `429 Too Many Requests` once the client exhausts its token
+/// bucket, and exec requests (commands, kernel code, verifications, fan-outs and
+/// environment steps) when the client already has `max_concurrent_execs` of them
+/// running.
+pub async fn rate_limit(
+    State(state): State<Arc<SoSState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = state.rate_limiter() else {
+        return next.run(request).await;
+    };
+
+    let Some(execs) = limiter.acquire(&client_key(&state, &request)) else {
+        return too_many_requests("Rate limit exceeded");
+    };
+
+    let _permit: Option<OwnedSemaphorePermit> = match is_exec(&request) {
+        true => match execs.try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return too_many_requests("Too many concurrent execs"),
+        },
+        false => None,
+    };
+
+    next.run(request).await
+}
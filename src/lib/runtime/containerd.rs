@@ -0,0 +1,1210 @@
+//! Runtime talking to containerd directly, for hosts without Docker.
+//!
+//! Images are pulled with the transfer service and unpacked into the overlayfs
+//! snapshotter. Containers get the OCI spec Docker would give them, and processes are
+//! run as tasks whose stdio goes through FIFOs. Resource usage is read from the cgroup
+//! v2 files of the container.
+//!
+//! Containers get an empty network namespace of their own: they have no network but
+//! loopback, and cannot reach the services of the host. With `host_network`, runc
+//! containers share the network of the host instead.
+//!
+//! With a VM-based shim such as Kata Containers, the container runs in its own VM and
+//! processes are run by the agent of the VM, over vsock. VMs cannot share the host
+//! network.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use containerd_client::services::v1::container::Runtime as RuntimeInfo;
+use containerd_client::services::v1::containers_client::ContainersClient;
+use containerd_client::services::v1::content_client::ContentClient;
+use containerd_client::services::v1::images_client::ImagesClient;
+use containerd_client::services::v1::snapshots::snapshots_client::SnapshotsClient;
+use containerd_client::services::v1::snapshots::{
+    MountsRequest, PrepareSnapshotRequest, RemoveSnapshotRequest,
+};
+use containerd_client::services::v1::tasks_client::TasksClient;
+use containerd_client::services::v1::transfer_client::TransferClient;
+use containerd_client::services::v1::version_client::VersionClient;
+use containerd_client::services::v1::{
+    Container, CreateContainerRequest, CreateTaskRequest, DeleteContainerRequest,
+    DeleteProcessRequest, DeleteTaskRequest, ExecProcessRequest, GetContainerRequest,
+    GetImageRequest, GetRequest, KillRequest, PauseTaskRequest, ReadContentRequest,
+    ResizePtyRequest, ResumeTaskRequest, StartRequest, TransferOptions, TransferRequest,
+    WaitRequest,
+};
+use containerd_client::to_any;
+use containerd_client::types::Platform;
+use containerd_client::types::transfer::{ImageStore, OciRegistry, UnpackConfiguration};
+use containerd_client::types::v1::Status as TaskStatus;
+use futures::channel::{mpsc, oneshot};
+use futures::{FutureExt, SinkExt, StreamExt};
+use nix::sys::stat::Mode;
+use prost_types::Any;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tracing::{info, warn};
+
+use super::{Attached, ContainerRuntime, ContainerSpec, Exec, ExecOutput, Resize};
+use crate::sandbox::{
+    PullProgress, ResourceUsage, Result, SandboxError, TerminalSize, UsageCounters,
+};
+
+const DEFAULT_SOCKET: &str = "/run/containerd/containerd.sock";
+/// Namespace holding the images and containers of the server
+const NAMESPACE: &str = "sos";
+const SNAPSHOTTER: &str = "overlayfs";
+const RUNC: &str = "io.containerd.runc.v2";
+/// Parent cgroup of the containers, under the cgroup v2 root
+const CGROUP_PARENT: &str = "sos";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+const SPEC_TYPE: &str = "types.containerd.io/opencontainers/runtime-spec/1/Spec";
+const PROCESS_TYPE: &str = "types.containerd.io/opencontainers/runtime-spec/1/Process";
+
+/// Capabilities Docker grants containers by default
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+
+/// Client of a containerd daemon.
+#[derive(Clone)]
+pub struct Containerd {
+    channel: Channel,
+    /// Shim the containers are run with
+    shim: String,
+    /// Whether containers share the network of the host
+    host_network: bool,
+    /// Directory of the FIFOs carrying the stdio of processes, and of the name
+    /// resolution files of containers with DNS options
+    fifo_dir: PathBuf,
+}
+
+/// Configuration of an image, as stored in its config blob.
+#[derive(Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    config: ImageRuntimeConfig,
+    rootfs: ImageRootfs,
+}
+
+#[derive(Default, Deserialize)]
+struct ImageRuntimeConfig {
+    #[serde(rename = "Env", default)]
+    env: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir", default)]
+    working_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageRootfs {
+    diff_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageIndex {
+    manifests: Vec<IndexEntry>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    digest: String,
+    #[serde(default)]
+    platform: Option<IndexPlatform>,
+}
+
+#[derive(Deserialize)]
+struct IndexPlatform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    config: ManifestConfig,
+}
+
+#[derive(Deserialize)]
+struct ManifestConfig {
+    digest: String,
+}
+
+fn start_failed(message: impl Display) -> SandboxError {
+    SandboxError::StartContainerFailed {
+        message: message.to_string(),
+        exit_code: None,
+        logs: String::new(),
+    }
+}
+
+fn read_failed(message: impl Display) -> SandboxError {
+    SandboxError::ContainerReadFailed(message.to_string())
+}
+
+/// Fully qualified reference of an image, as containerd stores it:
+/// `ubuntu` is `docker.io/library/ubuntu:latest`.
+fn normalize_image(image: &str) -> String {
+    let (name, digest) = match image.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image, None),
+    };
+    let domain = name.split_once('/').map(|(first, _)| first);
+    let mut reference = match domain {
+        Some(domain) if domain.contains(['.', ':']) || domain == "localhost" => name.to_string(),
+        Some(_) => format!("docker.io/{}", name),
+        None => format!("docker.io/library/{}", name),
+    };
+    let last = reference.rsplit('/').next().unwrap_or_default();
+    match digest {
+        Some(digest) => reference = format!("{}@{}", reference, digest),
+        None if !last.contains(':') => reference.push_str(":latest"),
+        None => {}
+    }
+    reference
+}
+
+/// Architecture of the host, as named by OCI platforms.
+fn host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+/// Chain ID of the layers, which names the snapshot they are unpacked into.
+fn chain_id(diff_ids: &[String]) -> Option<String> {
+    let (first, rest) = diff_ids.split_first()?;
+    Some(rest.iter().fold(first.clone(), |chain, diff_id| {
+        let digest = Sha256::digest(format!("{} {}", chain, diff_id));
+        format!("sha256:{:x}", digest)
+    }))
+}
+
+/// Keeps reading from an optional pipe, never completing without one.
+async fn read_pipe(pipe: &mut Option<pipe::Receiver>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match pipe {
+        Some(pipe) => pipe.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// `resolv.conf` of the host with the DNS servers and search domains replaced, if given.
+fn resolv_conf(host: &str, dns: &[String], dns_search: &[String]) -> String {
+    let mut lines: Vec<String> = host
+        .lines()
+        .filter(|line| match line.split_whitespace().next() {
+            Some("nameserver") => dns.is_empty(),
+            Some("search" | "domain") => dns_search.is_empty(),
+            _ => true,
+        })
+        .map(str::to_string)
+        .collect();
+    lines.extend(dns.iter().map(|server| format!("nameserver {}", server)));
+    if !dns_search.is_empty() {
+        lines.push(format!("search {}", dns_search.join(" ")));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// `hosts` of the host with the extra `host:ip` entries. The host gateway is the loopback
+/// address, the host itself for containers on the host network.
+fn hosts_file(host: &str, extra_hosts: &[String]) -> String {
+    let mut hosts = host.trim_end().to_string();
+    hosts.push('\n');
+    for (name, ip) in extra_hosts.iter().filter_map(|entry| entry.split_once(':')) {
+        let ip = if ip == "host-gateway" { "127.0.0.1" } else { ip };
+        hosts.push_str(&format!("{}\t{}\n", ip, name));
+    }
+    hosts
+}
+
+/// OCI spec of a sandbox container, close to what Docker would run it with. `etc_dir`
+/// holds the `resolv.conf` and `hosts` of the container, the host's are used without it.
+fn oci_spec(
+    id: &str,
+    spec: &ContainerSpec,
+    image: &ImageConfig,
+    host_network: bool,
+    etc_dir: Option<&Path>,
+) -> serde_json::Value {
+    let mut env = image.config.env.clone().unwrap_or_else(|| {
+        vec!["PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string()]
+    });
+    env.extend(spec.env.iter().map(|(k, v)| format!("{}={}", k, v)));
+    let cwd = match image.config.working_dir.as_deref() {
+        Some(dir) if !dir.is_empty() => dir,
+        _ => "/",
+    };
+
+    let etc_dir = etc_dir.unwrap_or(Path::new("/etc"));
+    let mut mounts = vec![
+        serde_json::json!({"destination": "/proc", "type": "proc", "source": "proc", "options": ["nosuid", "noexec", "nodev"]}),
+        serde_json::json!({"destination": "/dev", "type": "tmpfs", "source": "tmpfs", "options": ["nosuid", "strictatime", "mode=755", "size=65536k"]}),
+        serde_json::json!({"destination": "/dev/pts", "type": "devpts", "source": "devpts", "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"]}),
+        serde_json::json!({"destination": "/dev/shm", "type": "tmpfs", "source": "shm", "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"]}),
+        serde_json::json!({"destination": "/dev/mqueue", "type": "mqueue", "source": "mqueue", "options": ["nosuid", "noexec", "nodev"]}),
+        serde_json::json!({"destination": "/sys", "type": "sysfs", "source": "sysfs", "options": ["nosuid", "noexec", "nodev", "ro"]}),
+        serde_json::json!({"destination": "/sys/fs/cgroup", "type": "cgroup", "source": "cgroup", "options": ["nosuid", "noexec", "nodev", "relatime", "ro"]}),
+        // Name resolution of the host, which containers on the host network share
+        serde_json::json!({"destination": "/etc/resolv.conf", "type": "bind", "source": etc_dir.join("resolv.conf"), "options": ["rbind", "ro"]}),
+        serde_json::json!({"destination": "/etc/hosts", "type": "bind", "source": etc_dir.join("hosts"), "options": ["rbind", "ro"]}),
+    ];
+    mounts.extend(spec.mounts.iter().map(|mount| {
+        let access = if mount.read_only { "ro" } else { "rw" };
+        serde_json::json!({"destination": mount.target, "type": "bind", "source": mount.source, "options": ["rbind", access]})
+    }));
+
+    let mut resources = serde_json::json!({"devices": [{"allow": false, "access": "rwm"}]});
+    if let Some(mb) = spec.limits.memory_mb {
+        resources["memory"] = serde_json::json!({"limit": mb * 1024 * 1024});
+    }
+    if let Some(cpus) = spec.limits.cpus {
+        let period = 100_000;
+        resources["cpu"] =
+            serde_json::json!({"quota": (cpus * period as f64) as i64, "period": period});
+    }
+    if let Some(pids) = spec.limits.pids {
+        resources["pids"] = serde_json::json!({"limit": pids});
+    }
+
+    let mut namespaces = vec![
+        serde_json::json!({"type": "pid"}),
+        serde_json::json!({"type": "ipc"}),
+        serde_json::json!({"type": "uts"}),
+        serde_json::json!({"type": "mount"}),
+        serde_json::json!({"type": "cgroup"}),
+    ];
+    if !host_network {
+        namespaces.push(serde_json::json!({"type": "network"}));
+    }
+
+    serde_json::json!({
+        "ociVersion": "1.1.0",
+        "process": {
+            "terminal": false,
+            "user": {"uid": 0, "gid": 0},
+            "args": ["sleep", "infinity"],
+            "env": env,
+            "cwd": cwd,
+            "capabilities": {
+                "bounding": CAPABILITIES,
+                "effective": CAPABILITIES,
+                "permitted": CAPABILITIES,
+            },
+            "rlimits": [{"type": "RLIMIT_NOFILE", "hard": 1048576, "soft": 1048576}],
+            "noNewPrivileges": false,
+        },
+        "root": {"path": "rootfs", "readonly": false},
+        "hostname": &id[..id.len().min(12)],
+        "mounts": mounts,
+        "linux": {
+            "resources": resources,
+            "cgroupsPath": format!("/{}/{}", CGROUP_PARENT, id),
+            "namespaces": namespaces,
+            "maskedPaths": [
+                "/proc/acpi", "/proc/kcore", "/proc/keys", "/proc/latency_stats",
+                "/proc/timer_list", "/proc/timer_stats", "/proc/sched_debug",
+                "/proc/scsi", "/sys/firmware",
+            ],
+            "readonlyPaths": [
+                "/proc/asound", "/proc/bus", "/proc/fs", "/proc/irq",
+                "/proc/sys", "/proc/sysrq-trigger",
+            ],
+        },
+    })
+}
+
+/// CPU time used by the processes of a cgroup, in microseconds.
+async fn cgroup_cpu_usec(cgroup: &Path) -> Result<u64> {
+    let stat = tokio::fs::read_to_string(cgroup.join("cpu.stat"))
+        .await
+        .map_err(read_failed)?;
+    Ok(cgroup_value(&stat, "usage_usec"))
+}
+
+/// Value of a `key value` line of a cgroup file.
+fn cgroup_value(stat: &str, key: &str) -> u64 {
+    stat.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+async fn read_cgroup_file(cgroup: &Path, file: &str) -> String {
+    tokio::fs::read_to_string(cgroup.join(file))
+        .await
+        .unwrap_or_default()
+}
+
+impl Containerd {
+    /// Connects to containerd at `socket`, or at its default socket.
+    /// Containers are run with `shim`, runc's by default, on the host network when
+    /// `host_network` is set.
+    pub async fn connect(
+        socket: Option<&Path>,
+        shim: Option<String>,
+        host_network: bool,
+    ) -> anyhow::Result<Self> {
+        let shim = shim.unwrap_or_else(|| RUNC.to_string());
+        if host_network && shim != RUNC {
+            anyhow::bail!("runtime.host_network is only supported with runc, not {}", shim);
+        }
+        use anyhow::Context;
+
+        let socket = match socket {
+            Some(socket) => super::socket_path(socket)?.to_string(),
+            None => DEFAULT_SOCKET.to_string(),
+        };
+        let channel = containerd_client::connect(&socket)
+            .await
+            .with_context(|| format!("Failed to connect to containerd at {}", socket))?;
+        let version = VersionClient::new(channel.clone())
+            .version(())
+            .await
+            .with_context(|| format!("Failed to reach containerd at {}", socket))?
+            .into_inner();
+        info!(
+            runtime = "containerd",
+            version = %version.version,
+            revision = %version.revision,
+            "Connected to container runtime"
+        );
+
+        let fifo_dir = std::env::temp_dir().join("sos-containerd");
+        std::fs::create_dir_all(&fifo_dir)
+            .with_context(|| format!("Failed to create {}", fifo_dir.display()))?;
+        Ok(Containerd {
+            channel,
+            shim,
+            host_network,
+            fifo_dir,
+        })
+    }
+
+    /// Request scoped to the namespace of the server.
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("containerd-namespace", NAMESPACE.parse().unwrap());
+        request
+    }
+
+    async fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let request = self.request(ReadContentRequest {
+            digest: digest.to_string(),
+            offset: 0,
+            size: 0,
+        });
+        let mut stream = ContentClient::new(self.channel.clone())
+            .read(request)
+            .await
+            .map_err(start_failed)?
+            .into_inner();
+        let mut blob = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            blob.extend(chunk.map_err(start_failed)?.data);
+        }
+        Ok(blob)
+    }
+
+    /// Reads the configuration of a pulled image, resolving its index to the manifest
+    /// of the host platform.
+    async fn image_config(&self, name: &str) -> Result<ImageConfig> {
+        let request = self.request(GetImageRequest {
+            name: name.to_string(),
+        });
+        let target = ImagesClient::new(self.channel.clone())
+            .get(request)
+            .await
+            .map_err(start_failed)?
+            .into_inner()
+            .image
+            .and_then(|image| image.target)
+            .ok_or_else(|| start_failed(format!("Image {} has no target", name)))?;
+
+        let mut manifest = self.read_blob(&target.digest).await?;
+        if target.media_type.contains("index") || target.media_type.contains("manifest.list") {
+            let index: ImageIndex = serde_json::from_slice(&manifest).map_err(start_failed)?;
+            let entry = index
+                .manifests
+                .iter()
+                .find(|entry| {
+                    entry.platform.as_ref().is_some_and(|platform| {
+                        platform.os == "linux" && platform.architecture == host_arch()
+                    })
+                })
+                .ok_or_else(|| {
+                    start_failed(format!(
+                        "Image {} has no linux/{} manifest",
+                        name,
+                        host_arch()
+                    ))
+                })?;
+            manifest = self.read_blob(&entry.digest).await?;
+        }
+        let manifest: ImageManifest = serde_json::from_slice(&manifest).map_err(start_failed)?;
+        let config = self.read_blob(&manifest.config.digest).await?;
+        serde_json::from_slice(&config).map_err(start_failed)
+    }
+
+    /// Process spec of the container, the base of the processes run in it.
+    async fn process_spec(&self, container_id: &str) -> Result<serde_json::Value> {
+        let request = self.request(GetContainerRequest {
+            id: container_id.to_string(),
+        });
+        let spec = ContainersClient::new(self.channel.clone())
+            .get(request)
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?
+            .into_inner()
+            .container
+            .and_then(|container| container.spec)
+            .ok_or_else(|| SandboxError::CreateExecFailed("Container has no spec".to_string()))?;
+        let mut spec: serde_json::Value = serde_json::from_slice(&spec.value)
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+        Ok(spec["process"].take())
+    }
+
+    /// Writes the `resolv.conf` and `hosts` of a container with DNS options, based on
+    /// the host's. Returns their directory, or `None` if the container has no DNS options.
+    async fn resolution_files(&self, id: &str, spec: &ContainerSpec) -> Result<Option<PathBuf>> {
+        if spec.dns.is_empty() && spec.dns_search.is_empty() && spec.extra_hosts.is_empty() {
+            return Ok(None);
+        }
+        let dir = self.fifo_dir.join(id);
+        tokio::fs::create_dir_all(&dir).await.map_err(start_failed)?;
+        let host_resolv_conf = tokio::fs::read_to_string("/etc/resolv.conf")
+            .await
+            .unwrap_or_default();
+        let host_hosts = tokio::fs::read_to_string("/etc/hosts")
+            .await
+            .unwrap_or_default();
+        let files = [
+            ("resolv.conf", resolv_conf(&host_resolv_conf, &spec.dns, &spec.dns_search)),
+            ("hosts", hosts_file(&host_hosts, &spec.extra_hosts)),
+        ];
+        for (name, contents) in files {
+            tokio::fs::write(dir.join(name), contents)
+                .await
+                .map_err(start_failed)?;
+        }
+        Ok(Some(dir))
+    }
+
+    fn fifo(&self, exec_id: &str, stream: &str) -> Result<PathBuf> {
+        let path = self.fifo_dir.join(format!("{}-{}", exec_id, stream));
+        nix::unistd::mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)
+            .map_err(|e| SandboxError::CreateExecFailed(format!("Failed to create FIFO: {}", e)))?;
+        Ok(path)
+    }
+
+    /// Starts `cmd` as the process `exec_id` of the container, with its stdio on FIFOs,
+    /// and forwards its output until it exits.
+    async fn spawn(
+        &self,
+        container_id: &str,
+        exec_id: String,
+        cmd: Vec<String>,
+        env: &HashMap<String, String>,
+        terminal: bool,
+    ) -> Result<(
+        Option<pipe::Sender>,
+        mpsc::Receiver<ExecOutput>,
+        oneshot::Receiver<Result<i64>>,
+    )> {
+        let exec_failed = |e: std::io::Error| SandboxError::CreateExecFailed(e.to_string());
+
+        // Opened for reading and writing, so opening does not wait for the other end
+        let stdout_path = self.fifo(&exec_id, "stdout")?;
+        let stdout = pipe::OpenOptions::new()
+            .read_write(true)
+            .open_receiver(&stdout_path)
+            .map_err(exec_failed)?;
+        // A terminal has a single output stream
+        let (stderr_path, stderr) = match terminal {
+            true => (None, None),
+            false => {
+                let path = self.fifo(&exec_id, "stderr")?;
+                let stderr = pipe::OpenOptions::new()
+                    .read_write(true)
+                    .open_receiver(&path)
+                    .map_err(exec_failed)?;
+                (Some(path), Some(stderr))
+            }
+        };
+        let (stdin_path, stdin) = match terminal {
+            true => {
+                let path = self.fifo(&exec_id, "stdin")?;
+                let stdin = pipe::OpenOptions::new()
+                    .read_write(true)
+                    .open_sender(&path)
+                    .map_err(exec_failed)?;
+                (Some(path), Some(stdin))
+            }
+            false => (None, None),
+        };
+
+        let mut process = self.process_spec(container_id).await?;
+        process["args"] = serde_json::json!(cmd);
+        if let Some(vars) = process["env"].as_array_mut() {
+            vars.extend(env.iter().map(|(k, v)| serde_json::json!(format!("{}={}", k, v))));
+        }
+        process["terminal"] = serde_json::json!(terminal);
+        let process = serde_json::to_vec(&process)
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+
+        let path_str = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let request = self.request(ExecProcessRequest {
+            container_id: container_id.to_string(),
+            exec_id: exec_id.clone(),
+            stdin: path_str(&stdin_path),
+            stdout: stdout_path.to_string_lossy().to_string(),
+            stderr: path_str(&stderr_path),
+            terminal,
+            spec: Some(Any {
+                type_url: PROCESS_TYPE.to_string(),
+                value: process,
+            }),
+        });
+        let mut tasks = TasksClient::new(self.channel.clone());
+        tasks
+            .exec(request)
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+        let request = self.request(StartRequest {
+            container_id: container_id.to_string(),
+            exec_id: exec_id.clone(),
+        });
+        tasks
+            .start(request)
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+
+        let (tx, rx) = mpsc::channel(64);
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let fifos = [Some(stdout_path), stderr_path, stdin_path]
+            .into_iter()
+            .flatten()
+            .collect();
+        let runtime = self.clone();
+        let container_id = container_id.to_string();
+        tokio::spawn(async move {
+            let exit_code = runtime
+                .pump(&container_id, &exec_id, stdout, stderr, tx)
+                .await;
+            let _ = exit_tx.send(exit_code);
+
+            let request = runtime.request(DeleteProcessRequest {
+                container_id,
+                exec_id,
+            });
+            let _ = TasksClient::new(runtime.channel.clone())
+                .delete_process(request)
+                .await;
+            for fifo in fifos {
+                let _ = tokio::fs::remove_file(fifo).await;
+            }
+        });
+        Ok((stdin, rx, exit_rx))
+    }
+
+    /// Forwards the output of a process until it exits. Returns its exit code.
+    async fn pump(
+        &self,
+        container_id: &str,
+        exec_id: &str,
+        mut stdout: pipe::Receiver,
+        mut stderr: Option<pipe::Receiver>,
+        mut tx: mpsc::Sender<ExecOutput>,
+    ) -> Result<i64> {
+        let request = self.request(WaitRequest {
+            container_id: container_id.to_string(),
+            exec_id: exec_id.to_string(),
+        });
+        let mut tasks = TasksClient::new(self.channel.clone());
+        let wait = tasks.wait(request);
+        tokio::pin!(wait);
+
+        let mut out_buf = vec![0; 64 * 1024];
+        let mut err_buf = vec![0; 64 * 1024];
+        let status = loop {
+            // A send fails once the output is no longer read, the process still runs
+            tokio::select! {
+                read = stdout.read(&mut out_buf) => match read {
+                    Ok(n) if n > 0 => {
+                        let chunk = Bytes::copy_from_slice(&out_buf[..n]);
+                        let _ = tx.send(ExecOutput::Stdout(chunk)).await;
+                    }
+                    _ => {}
+                },
+                read = read_pipe(&mut stderr, &mut err_buf) => match read {
+                    Ok(n) if n > 0 => {
+                        let chunk = Bytes::copy_from_slice(&err_buf[..n]);
+                        let _ = tx.send(ExecOutput::Stderr(chunk)).await;
+                    }
+                    _ => {}
+                },
+                status = &mut wait => break status,
+            }
+        };
+
+        // The FIFOs hold what was written right before the process exited
+        while let Ok(n @ 1..) = stdout.try_read(&mut out_buf) {
+            let _ = tx
+                .send(ExecOutput::Stdout(Bytes::copy_from_slice(&out_buf[..n])))
+                .await;
+        }
+        if let Some(stderr) = &stderr {
+            while let Ok(n @ 1..) = stderr.try_read(&mut err_buf) {
+                let _ = tx
+                    .send(ExecOutput::Stderr(Bytes::copy_from_slice(&err_buf[..n])))
+                    .await;
+            }
+        }
+
+        let status = status.map_err(read_failed)?.into_inner();
+        Ok(status.exit_status as i64)
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for Containerd {
+    /// Pulls through the transfer service of containerd 1.7+, which reports no progress.
+    async fn pull(
+        &self,
+        image: &str,
+        _on_progress: &mut (dyn for<'p> FnMut(&'p PullProgress) + Send),
+    ) -> Result<bool> {
+        let pull_failed = |e: tonic::Status| SandboxError::PullImageFailed {
+            source: Box::new(e),
+        };
+        let name = normalize_image(image);
+        let request = self.request(GetImageRequest { name: name.clone() });
+        if ImagesClient::new(self.channel.clone())
+            .get(request)
+            .await
+            .is_ok()
+        {
+            return Ok(false);
+        }
+
+        info!(image = %image, "Pulling image");
+        let started = Instant::now();
+        let platform = Platform {
+            os: "linux".to_string(),
+            architecture: host_arch().to_string(),
+            ..Default::default()
+        };
+        let source = OciRegistry {
+            reference: name.clone(),
+            resolver: Default::default(),
+        };
+        let destination = ImageStore {
+            name: name.clone(),
+            platforms: vec![platform.clone()],
+            unpacks: vec![UnpackConfiguration {
+                platform: Some(platform),
+                snapshotter: SNAPSHOTTER.to_string(),
+            }],
+            ..Default::default()
+        };
+        let request = self.request(TransferRequest {
+            source: Some(to_any(&source)),
+            destination: Some(to_any(&destination)),
+            options: Some(TransferOptions::default()),
+        });
+        TransferClient::new(self.channel.clone())
+            .transfer(request)
+            .await
+            .map_err(pull_failed)?;
+        info!(
+            image = %image,
+            elapsed_seconds = started.elapsed().as_secs(),
+            "Image pulled"
+        );
+        Ok(true)
+    }
+
+    async fn create(&self, spec: &ContainerSpec) -> Result<String> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let image = normalize_image(&spec.image);
+        let config = self.image_config(&image).await?;
+        let parent = chain_id(&config.rootfs.diff_ids)
+            .ok_or_else(|| start_failed(format!("Image {} has no layers", image)))?;
+
+        let request = self.request(PrepareSnapshotRequest {
+            snapshotter: SNAPSHOTTER.to_string(),
+            key: id.clone(),
+            parent,
+            labels: HashMap::new(),
+        });
+        SnapshotsClient::new(self.channel.clone())
+            .prepare(request)
+            .await
+            .map_err(start_failed)?;
+
+        let etc_dir = self.resolution_files(&id, spec).await?;
+        let spec_json = serde_json::to_vec(&oci_spec(
+            &id,
+            spec,
+            &config,
+            self.host_network,
+            etc_dir.as_deref(),
+        ))
+        .map_err(start_failed)?;
+        let container = Container {
+            id: id.clone(),
+            image,
+            runtime: Some(RuntimeInfo {
+                name: self.shim.clone(),
+                options: None,
+            }),
+            spec: Some(Any {
+                type_url: SPEC_TYPE.to_string(),
+                value: spec_json,
+            }),
+            snapshotter: SNAPSHOTTER.to_string(),
+            snapshot_key: id.clone(),
+            ..Default::default()
+        };
+        let request = self.request(CreateContainerRequest {
+            container: Some(container),
+        });
+        if let Err(e) = ContainersClient::new(self.channel.clone())
+            .create(request)
+            .await
+        {
+            let _ = self.remove(&id).await;
+            return Err(start_failed(e));
+        }
+        Ok(id)
+    }
+
+    async fn start(&self, container_id: &str) -> Result<()> {
+        let request = self.request(MountsRequest {
+            snapshotter: SNAPSHOTTER.to_string(),
+            key: container_id.to_string(),
+        });
+        let rootfs = SnapshotsClient::new(self.channel.clone())
+            .mounts(request)
+            .await
+            .map_err(start_failed)?
+            .into_inner()
+            .mounts;
+
+        let mut tasks = TasksClient::new(self.channel.clone());
+        let request = self.request(CreateTaskRequest {
+            container_id: container_id.to_string(),
+            rootfs,
+            ..Default::default()
+        });
+        tasks.create(request).await.map_err(start_failed)?;
+        let request = self.request(StartRequest {
+            container_id: container_id.to_string(),
+            exec_id: String::new(),
+        });
+        tasks.start(request).await.map_err(start_failed)?;
+        Ok(())
+    }
+
+    async fn attach(&self, container_id: &str, cmd: Vec<String>) -> Result<Attached> {
+        let exec_id = uuid::Uuid::new_v4().simple().to_string();
+        let (input, output, _) = self
+            .spawn(container_id, exec_id.clone(), cmd, &HashMap::new(), true)
+            .await?;
+        let input = input.expect("Terminal processes have an input");
+        let output = output
+            .map(|chunk| match chunk {
+                ExecOutput::Stdout(bytes) | ExecOutput::Stderr(bytes) => bytes,
+            })
+            .boxed();
+
+        let runtime = self.clone();
+        let container_id = container_id.to_string();
+        let resize: Resize = Box::new(move |size: TerminalSize| {
+            let request = runtime.request(ResizePtyRequest {
+                container_id: container_id.clone(),
+                exec_id: exec_id.clone(),
+                width: size.cols as u32,
+                height: size.rows as u32,
+            });
+            let mut tasks = TasksClient::new(runtime.channel.clone());
+            async move {
+                tasks
+                    .resize_pty(request)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| SandboxError::ContainerWriteFailed(e.to_string()))
+            }
+            .boxed()
+        });
+        Ok(Attached {
+            input: Box::pin(input),
+            output,
+            resize,
+        })
+    }
+
+    async fn exec(&self, container_id: &str, cmd: Vec<String>) -> Result<Exec> {
+        self.exec_with_env(container_id, cmd, &HashMap::new()).await
+    }
+
+    async fn exec_with_env(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        env: &HashMap<String, String>,
+    ) -> Result<Exec> {
+        let exec_id = uuid::Uuid::new_v4().simple().to_string();
+        let (_, output, exit_code) = self.spawn(container_id, exec_id, cmd, env, false).await?;
+        let exit_code = async move {
+            exit_code
+                .await
+                .map_err(|_| read_failed("Exec ended without an exit code"))?
+        };
+        Ok(Exec {
+            output: output.map(Ok).boxed(),
+            exit_code: exit_code.boxed(),
+        })
+    }
+
+    /// Reads the cgroup of the container from the host, without an exec.
+    async fn usage(&self, container_id: &str) -> Result<UsageCounters> {
+        let cgroup = Path::new(CGROUP_ROOT)
+            .join(CGROUP_PARENT)
+            .join(container_id);
+        Ok(UsageCounters {
+            cpu_usec: cgroup_cpu_usec(&cgroup).await?,
+            memory_peak_bytes: read_cgroup_file(&cgroup, "memory.peak").await.trim().parse().ok(),
+        })
+    }
+
+    async fn pause(&self, container_id: &str) -> Result<bool> {
+        let request = self.request(PauseTaskRequest {
+            container_id: container_id.to_string(),
+        });
+        TasksClient::new(self.channel.clone())
+            .pause(request)
+            .await
+            .map_err(|e| SandboxError::PauseFailed(e.to_string()))?;
+        Ok(true)
+    }
+
+    async fn unpause(&self, container_id: &str) -> Result<()> {
+        let request = self.request(ResumeTaskRequest {
+            container_id: container_id.to_string(),
+        });
+        TasksClient::new(self.channel.clone())
+            .resume(request)
+            .await
+            .map_err(|e| SandboxError::PauseFailed(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Reads the cgroup of the container. Network usage is not reported.
+    async fn stats(&self, container_id: &str) -> Result<ResourceUsage> {
+        let cgroup = Path::new(CGROUP_ROOT)
+            .join(CGROUP_PARENT)
+            .join(container_id);
+        let started = Instant::now();
+        let usage = cgroup_cpu_usec(&cgroup).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let elapsed = started.elapsed().as_micros() as f64;
+        let cpu_percent =
+            cgroup_cpu_usec(&cgroup).await?.saturating_sub(usage) as f64 / elapsed * 100.0;
+
+        let memory: u64 = read_cgroup_file(&cgroup, "memory.current")
+            .await
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        // Same as `docker stats`: the page cache can be reclaimed, so it is not counted
+        let cache = cgroup_value(
+            &read_cgroup_file(&cgroup, "memory.stat").await,
+            "inactive_file",
+        );
+        // Unlimited containers are limited by the memory of the host
+        let memory_limit_bytes = match read_cgroup_file(&cgroup, "memory.max").await.trim().parse()
+        {
+            Ok(limit) => limit,
+            Err(_) => {
+                let meminfo = tokio::fs::read_to_string("/proc/meminfo")
+                    .await
+                    .unwrap_or_default();
+                let total_kb = meminfo
+                    .lines()
+                    .find_map(|line| line.strip_prefix("MemTotal:"))
+                    .and_then(|total| {
+                        total
+                            .trim()
+                            .trim_end_matches("kB")
+                            .trim()
+                            .parse::<u64>()
+                            .ok()
+                    })
+                    .unwrap_or(0);
+                total_kb * 1024
+            }
+        };
+        let (block_read_bytes, block_write_bytes) = read_cgroup_file(&cgroup, "io.stat")
+            .await
+            .split_whitespace()
+            .filter_map(|field| field.split_once('='))
+            .fold((0, 0), |(read, write), (key, value)| {
+                let value = value.parse().unwrap_or(0);
+                match key {
+                    "rbytes" => (read + value, write),
+                    "wbytes" => (read, write + value),
+                    _ => (read, write),
+                }
+            });
+
+        Ok(ResourceUsage {
+            cpu_percent,
+            memory_usage_bytes: memory.saturating_sub(cache),
+            memory_limit_bytes,
+            network_rx_bytes: 0,
+            network_tx_bytes: 0,
+            block_read_bytes,
+            block_write_bytes,
+            pids: read_cgroup_file(&cgroup, "pids.current")
+                .await
+                .trim()
+                .parse()
+                .unwrap_or(0),
+        })
+    }
+
+    async fn exit_code(&self, container_id: &str) -> Result<Option<i64>> {
+        let process = TasksClient::new(self.channel.clone())
+            .get(self.request(GetRequest {
+                container_id: container_id.to_string(),
+                exec_id: String::new(),
+            }))
+            .await
+            .map_err(read_failed)?
+            .into_inner()
+            .process
+            .ok_or_else(|| read_failed("Task has no process"))?;
+        match process.status() {
+            TaskStatus::Stopped => Ok(Some(process.exit_status as i64)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn remove(&self, container_id: &str) -> Result<()> {
+        let mut tasks = TasksClient::new(self.channel.clone());
+        let request = self.request(KillRequest {
+            container_id: container_id.to_string(),
+            exec_id: String::new(),
+            signal: 9,
+            all: true,
+        });
+        // The task is missing when the container never started
+        if tasks.kill(request).await.is_ok() {
+            let request = self.request(WaitRequest {
+                container_id: container_id.to_string(),
+                exec_id: String::new(),
+            });
+            let _ = tasks.wait(request).await;
+        }
+        let request = self.request(DeleteTaskRequest {
+            container_id: container_id.to_string(),
+        });
+        let _ = tasks.delete(request).await;
+
+        let request = self.request(DeleteContainerRequest {
+            id: container_id.to_string(),
+        });
+        let deleted = ContainersClient::new(self.channel.clone())
+            .delete(request)
+            .await;
+        let request = self.request(RemoveSnapshotRequest {
+            snapshotter: SNAPSHOTTER.to_string(),
+            key: container_id.to_string(),
+        });
+        if let Err(e) = SnapshotsClient::new(self.channel.clone())
+            .remove(request)
+            .await
+        {
+            warn!(container_id = %container_id, error = %e, "Failed to remove snapshot");
+        }
+        // Only there for containers with DNS options
+        let _ = tokio::fs::remove_dir_all(self.fifo_dir.join(container_id)).await;
+        deleted
+            .map(|_| ())
+            .map_err(|e| SandboxError::StopContainerFailed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::{Mount, ResourceLimits};
+
+    fn container_spec() -> ContainerSpec {
+        ContainerSpec {
+            image: "ubuntu".to_string(),
+            env: HashMap::from([("FOO".to_string(), "bar".to_string())]),
+            limits: ResourceLimits {
+                memory_mb: Some(512),
+                cpus: Some(1.5),
+                pids: Some(100),
+            },
+            mounts: vec![Mount {
+                source: "/data".to_string(),
+                target: "/workspace/data".to_string(),
+                read_only: true,
+            }],
+            dns: Vec::new(),
+            dns_search: Vec::new(),
+            extra_hosts: Vec::new(),
+        }
+    }
+
+    fn image_config(env: Option<Vec<String>>, working_dir: Option<&str>) -> ImageConfig {
+        ImageConfig {
+            config: ImageRuntimeConfig {
+                env,
+                working_dir: working_dir.map(str::to_string),
+            },
+            rootfs: ImageRootfs {
+                diff_ids: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_normalize_image() {
+        assert_eq!(normalize_image("ubuntu"), "docker.io/library/ubuntu:latest");
+        assert_eq!(normalize_image("python:3.12"), "docker.io/library/python:3.12");
+        assert_eq!(normalize_image("org/tool"), "docker.io/org/tool:latest");
+        assert_eq!(normalize_image("ghcr.io/org/tool:v1"), "ghcr.io/org/tool:v1");
+        assert_eq!(
+            normalize_image("localhost:5000/tool"),
+            "localhost:5000/tool:latest"
+        );
+        assert_eq!(
+            normalize_image("ubuntu@sha256:abc"),
+            "docker.io/library/ubuntu@sha256:abc"
+        );
+    }
+
+    #[test]
+    fn test_chain_id() {
+        assert_eq!(chain_id(&[]), None);
+        let layers = ["sha256:aaa".to_string(), "sha256:bbb".to_string()];
+        assert_eq!(chain_id(&layers[..1]).as_deref(), Some("sha256:aaa"));
+        assert_eq!(
+            chain_id(&layers).as_deref(),
+            Some("sha256:56efb1d4f6c79b745d37d6eff87e3ed8dd2be28104e124ba73fd6e6c4892c792")
+        );
+    }
+
+    #[test]
+    fn test_resolution_files() {
+        let host = "# comment\nnameserver 10.0.0.1\nsearch corp\noptions ndots:1\n";
+        assert_eq!(resolv_conf(host, &[], &[]), host);
+        assert_eq!(
+            resolv_conf(host, &["1.1.1.1".to_string()], &["example.com".to_string()]),
+            "# comment\noptions ndots:1\nnameserver 1.1.1.1\nsearch example.com\n"
+        );
+        let extra_hosts = ["db:10.0.0.5".to_string(), "host:host-gateway".to_string()];
+        assert_eq!(
+            hosts_file("127.0.0.1\tlocalhost\n\n", &extra_hosts),
+            "127.0.0.1\tlocalhost\n10.0.0.5\tdb\n127.0.0.1\thost\n"
+        );
+    }
+
+    #[test]
+    fn test_oci_spec() {
+        let id = "0123456789abcdef";
+        let image = image_config(Some(vec!["PATH=/usr/bin".to_string()]), Some("/app"));
+        let etc_dir = Path::new("/var/lib/sos/etc");
+        let oci = oci_spec(id, &container_spec(), &image, false, Some(etc_dir));
+
+        assert_eq!(oci["process"]["env"], serde_json::json!(["PATH=/usr/bin", "FOO=bar"]));
+        assert_eq!(oci["process"]["cwd"], "/app");
+        assert_eq!(oci["hostname"], "0123456789ab");
+        assert_eq!(oci["linux"]["cgroupsPath"], "/sos/0123456789abcdef");
+
+        let resources = &oci["linux"]["resources"];
+        assert_eq!(resources["memory"]["limit"], 512 * 1024 * 1024);
+        assert_eq!(resources["cpu"]["quota"], 150_000);
+        assert_eq!(resources["cpu"]["period"], 100_000);
+        assert_eq!(resources["pids"]["limit"], 100);
+
+        let mounts = oci["mounts"].as_array().unwrap();
+        let mount = |destination: &str| {
+            mounts
+                .iter()
+                .find(|mount| mount["destination"] == destination)
+                .unwrap_or_else(|| panic!("No mount at {}", destination))
+        };
+        assert_eq!(mount("/etc/hosts")["source"], "/var/lib/sos/etc/hosts");
+        assert_eq!(mount("/etc/resolv.conf")["source"], "/var/lib/sos/etc/resolv.conf");
+        assert_eq!(mount("/workspace/data")["source"], "/data");
+        assert_eq!(mount("/workspace/data")["options"], serde_json::json!(["rbind", "ro"]));
+
+        let namespaces = &oci["linux"]["namespaces"];
+        assert!(namespaces.as_array().unwrap().contains(&serde_json::json!({"type": "network"})));
+        let host_network = oci_spec(id, &container_spec(), &image, true, None);
+        let namespaces = host_network["linux"]["namespaces"].as_array().unwrap();
+        assert!(!namespaces.contains(&serde_json::json!({"type": "network"})));
+        assert_eq!(host_network["mounts"][7]["source"], "/etc/resolv.conf");
+    }
+
+    #[test]
+    fn test_oci_spec_defaults() {
+        let spec = ContainerSpec {
+            env: HashMap::new(),
+            limits: ResourceLimits::default(),
+            ..container_spec()
+        };
+        let oci = oci_spec("abc", &spec, &image_config(None, Some("")), false, None);
+
+        let env = oci["process"]["env"].as_array().unwrap();
+        assert_eq!(env.len(), 1);
+        assert!(env[0].as_str().unwrap().starts_with("PATH="));
+        assert_eq!(oci["process"]["cwd"], "/");
+        assert_eq!(oci["hostname"], "abc");
+        let resources = oci["linux"]["resources"].as_object().unwrap();
+        assert_eq!(resources.keys().collect::<Vec<_>>(), ["devices"]);
+    }
+
+    #[test]
+    fn test_cgroup_value() {
+        let stat = "usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n";
+        assert_eq!(cgroup_value(stat, "usage_usec"), 1234);
+        assert_eq!(cgroup_value(stat, "system_usec"), 234);
+        assert_eq!(cgroup_value(stat, "missing"), 0);
+    }
+}
@@ -0,0 +1,707 @@
+//! Runtime of Docker and Podman, through the Docker API.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bollard::{
+    Docker,
+    container::LogOutput,
+    exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults},
+};
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt, TryStreamExt, future};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use super::{Attached, ContainerRuntime, ContainerSpec, Exec, ExecOutput, Resize};
+use crate::sandbox::{
+    Mount, PULL_PROGRESS_INTERVAL, PullProgress, ResourceUsage, Result, SandboxError,
+    TerminalSize, UsageCounters,
+};
+
+impl From<bollard::errors::Error> for SandboxError {
+    fn from(source: bollard::errors::Error) -> Self {
+        SandboxError::PullImageFailed {
+            source: Box::new(source),
+        }
+    }
+}
+
+/// Docker or Podman, with the OCI runtime containers are run with.
+pub struct DockerRuntime {
+    pub docker: Docker,
+    /// Runtime registered with the engine, such as `kata` or `runsc`, instead of its
+    /// default one
+    pub oci_runtime: Option<String>,
+    /// Creates a network for each container, see [`RuntimeConfig::isolate_networks`]
+    ///
+    /// [`RuntimeConfig::isolate_networks`]: super::RuntimeConfig::isolate_networks
+    pub isolate_networks: bool,
+}
+
+/// Prefix of the networks created for single containers, removed with them.
+const NETWORK_PREFIX: &str = "sos-sandbox-";
+
+impl DockerRuntime {
+    /// Creates a bridge network for a single container. Bridge networks cannot reach
+    /// each other, so its container is only reachable from the host.
+    async fn create_network(&self) -> Result<String> {
+        let name = format!("{}{}", NETWORK_PREFIX, uuid::Uuid::new_v4().simple());
+        let request = bollard::models::NetworkCreateRequest {
+            name: name.clone(),
+            driver: Some("bridge".to_string()),
+            ..Default::default()
+        };
+        self.docker
+            .create_network(request)
+            .await
+            .map_err(|e| SandboxError::StartContainerFailed {
+                message: format!("Failed to create network: {}", e),
+                exit_code: None,
+                logs: String::new(),
+            })?;
+        debug!(network = %name, "Created sandbox network");
+        Ok(name)
+    }
+
+    /// Removes a network created for a single container, once the container is gone.
+    async fn remove_network(&self, name: &str) {
+        match self.docker.remove_network(name).await {
+            Ok(()) => debug!(network = %name, "Removed sandbox network"),
+            Err(e) => warn!(network = %name, error = %e, "Failed to remove sandbox network"),
+        }
+    }
+}
+
+/// Cgroups of the containers of a Docker engine on the host, with the systemd and the
+/// cgroupfs drivers, or of a rootful Podman.
+const HOST_CGROUPS: &[(&str, &str)] = &[
+    ("/sys/fs/cgroup/system.slice/docker-", ".scope"),
+    ("/sys/fs/cgroup/docker/", ""),
+    ("/sys/fs/cgroup/machine.slice/libpod-", ".scope"),
+];
+
+/// Peak memory of a container from its cgroup v2 on the host, if it can be found there.
+async fn host_memory_peak(container_id: &str) -> Option<u64> {
+    for (prefix, suffix) in HOST_CGROUPS {
+        let path = format!("{}{}{}/memory.peak", prefix, container_id, suffix);
+        if let Ok(peak) = tokio::fs::read_to_string(path).await {
+            return peak.trim().parse().ok();
+        }
+    }
+    None
+}
+
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+    async fn pull(
+        &self,
+        image: &str,
+        on_progress: &mut (dyn for<'p> FnMut(&'p PullProgress) + Send),
+    ) -> Result<bool> {
+        use bollard::query_parameters::CreateImageOptions;
+
+        if self.docker.inspect_image(image).await.is_ok() {
+            return Ok(false);
+        }
+
+        info!(image = %image, "Pulling image");
+        let started = Instant::now();
+        let pull_options = Some(CreateImageOptions {
+            from_image: Some(image.to_string()),
+            ..Default::default()
+        });
+        // Downloaded and total bytes, and whether the layer is done, by layer ID
+        let mut layers: HashMap<String, (u64, u64, bool)> = HashMap::new();
+        let mut last_report = started;
+        let mut pull_stream = self.docker.create_image(pull_options, None, None);
+        while let Some(info) = pull_stream.try_next().await? {
+            let (Some(id), Some(status)) = (info.id, info.status) else {
+                continue;
+            };
+            let layer = layers.entry(id).or_default();
+            let completed = match status.as_str() {
+                "Downloading" => {
+                    if let Some(detail) = info.progress_detail {
+                        layer.0 = detail.current.unwrap_or(0).max(0) as u64;
+                        layer.1 = detail.total.unwrap_or(0).max(0) as u64;
+                    }
+                    false
+                }
+                "Download complete" => {
+                    layer.0 = layer.1;
+                    false
+                }
+                "Pull complete" | "Already exists" => !std::mem::replace(&mut layer.2, true),
+                _ => false,
+            };
+
+            if completed || last_report.elapsed() >= PULL_PROGRESS_INTERVAL {
+                last_report = Instant::now();
+                let progress = PullProgress {
+                    layers: layers.len(),
+                    layers_done: layers.values().filter(|layer| layer.2).count(),
+                    downloaded_bytes: layers.values().map(|layer| layer.0).sum(),
+                    total_bytes: layers.values().map(|layer| layer.1).sum(),
+                };
+                debug!(image = %image, progress = ?progress, "Pull progress");
+                on_progress(&progress);
+            }
+        }
+        info!(
+            image = %image,
+            elapsed_seconds = started.elapsed().as_secs(),
+            "Image pulled"
+        );
+        Ok(true)
+    }
+
+    async fn create(&self, spec: &ContainerSpec) -> Result<String> {
+        use bollard::query_parameters::CreateContainerOptions;
+
+        let network = match self.isolate_networks {
+            true => Some(self.create_network().await?),
+            false => None,
+        };
+        let host_config = bollard::models::HostConfig {
+            memory: spec.limits.memory_mb.map(|mb| mb * 1024 * 1024),
+            nano_cpus: spec.limits.cpus.map(|cpus| (cpus * 1e9) as i64),
+            pids_limit: spec.limits.pids,
+            runtime: self.oci_runtime.clone(),
+            binds: (!spec.mounts.is_empty())
+                .then(|| spec.mounts.iter().map(Mount::to_bind).collect()),
+            network_mode: network.clone(),
+            dns: (!spec.dns.is_empty()).then(|| spec.dns.clone()),
+            dns_search: (!spec.dns_search.is_empty()).then(|| spec.dns_search.clone()),
+            extra_hosts: (!spec.extra_hosts.is_empty()).then(|| spec.extra_hosts.clone()),
+            ..Default::default()
+        };
+        let env = (!spec.env.is_empty()).then(|| {
+            spec.env
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect()
+        });
+
+        let config = bollard::models::ContainerCreateBody {
+            image: Some(spec.image.clone()),
+            cmd: Some(vec!["sleep".to_string(), "infinity".to_string()]),
+            env,
+            host_config: Some(host_config),
+            tty: Some(true),
+            open_stdin: Some(true),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+
+        let create_response = match self
+            .docker
+            .create_container(None::<CreateContainerOptions>, config)
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                if let Some(network) = &network {
+                    self.remove_network(network).await;
+                }
+                return Err(SandboxError::StartContainerFailed {
+                    message: e.to_string(),
+                    exit_code: None,
+                    logs: String::new(),
+                });
+            }
+        };
+        Ok(create_response.id)
+    }
+
+    async fn start(&self, container_id: &str) -> Result<()> {
+        use bollard::query_parameters::{
+            InspectContainerOptions, LogsOptions, StartContainerOptions,
+        };
+        use bollard::secret::ContainerStateStatusEnum;
+
+        self.docker
+            .start_container(container_id, None::<StartContainerOptions>)
+            .await
+            .map_err(|e| SandboxError::StartContainerFailed {
+                message: e.to_string(),
+                exit_code: None,
+                logs: String::new(),
+            })?;
+        let mut attempts = 0;
+        let max_attempts = 6; // ~3 seconds at 500ms intervals
+        loop {
+            let inspect = self
+                .docker
+                .inspect_container(container_id, None::<InspectContainerOptions>)
+                .await
+                .map_err(|e| SandboxError::StartContainerFailed {
+                    message: format!("Failed to inspect container: {}", e),
+                    exit_code: None,
+                    logs: String::new(),
+                })?;
+
+            if inspect.state.as_ref().and_then(|s| s.running) == Some(true) {
+                return Ok(());
+            }
+
+            if attempts >= max_attempts {
+                // Fetch logs for diagnostics
+                let mut log_stream = self.docker.logs(
+                    container_id,
+                    Some(LogsOptions {
+                        stdout: true,
+                        stderr: true,
+                        tail: "all".to_string(),
+                        ..Default::default()
+                    }),
+                );
+
+                let mut logs = String::new();
+                while let Some(item) = log_stream.next().await {
+                    match item.map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))? {
+                        LogOutput::StdOut { message } => logs += &String::from_utf8_lossy(&message),
+                        LogOutput::StdErr { message } => logs += &String::from_utf8_lossy(&message),
+                        _ => {}
+                    }
+                }
+
+                let exit_code = inspect.state.clone().and_then(|s| s.exit_code);
+                let error_msg = inspect
+                    .state
+                    .clone()
+                    .and_then(|s| s.error.clone())
+                    .unwrap_or_default();
+                let status = inspect
+                    .state
+                    .and_then(|s| s.status)
+                    .unwrap_or(ContainerStateStatusEnum::EMPTY);
+
+                error!(
+                    "Container {} failed to start. Status: {}, Exit code: {:?}, Error: {}, Logs: {}",
+                    container_id, status, exit_code, error_msg, logs
+                );
+
+                return Err(SandboxError::StartContainerFailed {
+                    message: format!(
+                        "Container exited immediately. Status: {}, Error: {}",
+                        status, error_msg
+                    ),
+                    exit_code,
+                    logs,
+                });
+            }
+
+            attempts += 1;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    async fn attach(&self, container_id: &str, cmd: Vec<String>) -> Result<Attached> {
+        let create_exec_res = self
+            .docker
+            .create_exec(
+                container_id,
+                CreateExecOptions {
+                    cmd: Some(cmd),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    attach_stdin: Some(true),
+                    tty: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+
+        let start_exec_res = self
+            .docker
+            .start_exec(
+                &create_exec_res.id,
+                Some(StartExecOptions {
+                    detach: false,
+                    tty: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+        let (output, input) = match start_exec_res {
+            StartExecResults::Attached { output, input, .. } => (output, input),
+            _ => {
+                return Err(SandboxError::StartContainerFailed {
+                    message: "Failed to start exec, didn't attach.".to_string(),
+                    exit_code: None,
+                    logs: String::new(),
+                });
+            }
+        };
+
+        // A terminal has a single stream, the output ends at the first error
+        let output = output
+            .take_while(|chunk| future::ready(chunk.is_ok()))
+            .filter_map(|chunk| {
+                future::ready(match chunk {
+                    Ok(LogOutput::Console { message }) => Some(message),
+                    _ => None,
+                })
+            })
+            .boxed();
+
+        let docker = self.docker.clone();
+        let exec_id = create_exec_res.id;
+        let resize: Resize = Box::new(move |size: TerminalSize| {
+            let docker = docker.clone();
+            let exec_id = exec_id.clone();
+            async move {
+                let options = ResizeExecOptions {
+                    height: size.rows,
+                    width: size.cols,
+                };
+                docker
+                    .resize_exec(&exec_id, options)
+                    .await
+                    .map_err(|e| SandboxError::ContainerWriteFailed(e.to_string()))
+            }
+            .boxed()
+        });
+        Ok(Attached {
+            input,
+            output,
+            resize,
+        })
+    }
+
+    async fn exec(&self, container_id: &str, cmd: Vec<String>) -> Result<Exec> {
+        self.exec_with_env(container_id, cmd, &HashMap::new()).await
+    }
+
+    async fn exec_with_env(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        env: &HashMap<String, String>,
+    ) -> Result<Exec> {
+        let exec_config = CreateExecOptions {
+            cmd: Some(cmd),
+            env: Some(env.iter().map(|(k, v)| format!("{}={}", k, v)).collect()),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            attach_stdin: Some(false),
+            tty: Some(false),
+            ..Default::default()
+        };
+        let exec = self
+            .docker
+            .create_exec(container_id, exec_config)
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+        let start_res = self
+            .docker
+            .start_exec(&exec.id, None::<StartExecOptions>)
+            .await
+            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+
+        let output = match start_res {
+            StartExecResults::Attached { output, .. } => output
+                .filter_map(|chunk| {
+                    future::ready(match chunk {
+                        Ok(LogOutput::StdOut { message }) => Some(Ok(ExecOutput::Stdout(message))),
+                        Ok(LogOutput::StdErr { message }) => Some(Ok(ExecOutput::Stderr(message))),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(SandboxError::ContainerReadFailed(e.to_string()))),
+                    })
+                })
+                .boxed(),
+            StartExecResults::Detached => futures::stream::empty().boxed(),
+        };
+        let docker = self.docker.clone();
+        let exit_code = async move { exec_exit_code(&docker, &exec.id).await }.boxed();
+        Ok(Exec { output, exit_code })
+    }
+
+    async fn stats(&self, container_id: &str) -> Result<ResourceUsage> {
+        use bollard::query_parameters::StatsOptions;
+
+        let options = StatsOptions {
+            stream: false,
+            one_shot: false,
+        };
+        let stats = self
+            .docker
+            .stats(container_id, Some(options))
+            .next()
+            .await
+            .ok_or_else(|| SandboxError::ContainerReadFailed("No stats returned".to_string()))?
+            .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?;
+
+        let total_cpu = |cpu: &Option<bollard::models::ContainerCpuStats>| {
+            let cpu = cpu.as_ref();
+            (
+                cpu.and_then(|c| c.cpu_usage.as_ref())
+                    .and_then(|u| u.total_usage)
+                    .unwrap_or(0),
+                cpu.and_then(|c| c.system_cpu_usage).unwrap_or(0),
+            )
+        };
+        let (cpu, system) = total_cpu(&stats.cpu_stats);
+        let (pre_cpu, pre_system) = total_cpu(&stats.precpu_stats);
+        let online_cpus = stats
+            .cpu_stats
+            .as_ref()
+            .and_then(|c| c.online_cpus)
+            .unwrap_or(1);
+        let cpu_delta = cpu.saturating_sub(pre_cpu) as f64;
+        let system_delta = system.saturating_sub(pre_system) as f64;
+        let cpu_percent = match system_delta > 0.0 {
+            true => cpu_delta / system_delta * online_cpus as f64 * 100.0,
+            false => 0.0,
+        };
+
+        let memory = stats.memory_stats.unwrap_or_default();
+        // Same as `docker stats`: the page cache can be reclaimed, so it is not counted
+        let cache = memory
+            .stats
+            .as_ref()
+            .and_then(|s| {
+                s.get("inactive_file")
+                    .or_else(|| s.get("total_inactive_file"))
+            })
+            .copied()
+            .unwrap_or(0);
+        let (network_rx_bytes, network_tx_bytes) = stats
+            .networks
+            .unwrap_or_default()
+            .values()
+            .fold((0, 0), |(rx, tx), net| {
+                (
+                    rx + net.rx_bytes.unwrap_or(0),
+                    tx + net.tx_bytes.unwrap_or(0),
+                )
+            });
+        let (block_read_bytes, block_write_bytes) = stats
+            .blkio_stats
+            .and_then(|b| b.io_service_bytes_recursive)
+            .unwrap_or_default()
+            .iter()
+            .fold((0, 0), |(read, write), entry| {
+                let value = entry.value.unwrap_or(0);
+                match entry.op.as_deref().map(str::to_lowercase).as_deref() {
+                    Some("read") => (read + value, write),
+                    Some("write") => (read, write + value),
+                    _ => (read, write),
+                }
+            });
+
+        Ok(ResourceUsage {
+            cpu_percent,
+            memory_usage_bytes: memory.usage.unwrap_or(0).saturating_sub(cache),
+            memory_limit_bytes: memory.limit.unwrap_or(0),
+            network_rx_bytes,
+            network_tx_bytes,
+            block_read_bytes,
+            block_write_bytes,
+            pids: stats.pids_stats.and_then(|p| p.current).unwrap_or(0),
+        })
+    }
+
+    async fn pause(&self, container_id: &str) -> Result<bool> {
+        self.docker
+            .pause_container(container_id)
+            .await
+            .map_err(|e| SandboxError::PauseFailed(e.to_string()))?;
+        Ok(true)
+    }
+
+    async fn unpause(&self, container_id: &str) -> Result<()> {
+        self.docker
+            .unpause_container(container_id)
+            .await
+            .map_err(|e| SandboxError::PauseFailed(e.to_string()))
+    }
+
+    /// Reads a single sample of the stats of the container, without an exec. Docker only
+    /// reports the peak memory with cgroup v1; with v2, it is read from the cgroup of the
+    /// container on the host, when the engine runs on it.
+    async fn usage(&self, container_id: &str) -> Result<UsageCounters> {
+        use bollard::query_parameters::StatsOptions;
+
+        let options = StatsOptions {
+            stream: false,
+            one_shot: true,
+        };
+        let stats = self
+            .docker
+            .stats(container_id, Some(options))
+            .next()
+            .await
+            .ok_or_else(|| SandboxError::ContainerReadFailed("No stats returned".to_string()))?
+            .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?;
+        let cpu_nsec = stats
+            .cpu_stats
+            .and_then(|c| c.cpu_usage)
+            .and_then(|u| u.total_usage)
+            .ok_or_else(|| {
+                SandboxError::ContainerReadFailed("No CPU usage returned".to_string())
+            })?;
+        let memory_peak_bytes = match stats.memory_stats.and_then(|m| m.max_usage) {
+            Some(peak) => Some(peak),
+            None => host_memory_peak(container_id).await,
+        };
+        Ok(UsageCounters {
+            cpu_usec: cpu_nsec / 1000,
+            memory_peak_bytes,
+        })
+    }
+
+    async fn logs(
+        &self,
+        container_id: &str,
+        follow: bool,
+    ) -> Result<BoxStream<'static, Result<Bytes>>> {
+        use bollard::query_parameters::LogsOptions;
+
+        let options = LogsOptions {
+            stdout: true,
+            stderr: true,
+            follow,
+            tail: "all".to_string(),
+            ..Default::default()
+        };
+        let logs = self
+            .docker
+            .logs(container_id, Some(options))
+            .filter_map(|item| {
+                future::ready(match item {
+                    Ok(LogOutput::StdOut { message })
+                    | Ok(LogOutput::StdErr { message })
+                    | Ok(LogOutput::Console { message }) => Some(Ok(message)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(SandboxError::ContainerReadFailed(e.to_string()))),
+                })
+            })
+            .boxed();
+        Ok(logs)
+    }
+
+    async fn download(
+        &self,
+        container_id: &str,
+        path: &str,
+        max_bytes: Option<usize>,
+    ) -> Result<Vec<u8>> {
+        use bollard::query_parameters::DownloadFromContainerOptions;
+
+        let options = DownloadFromContainerOptions {
+            path: path.to_string(),
+        };
+        let mut stream = self
+            .docker
+            .download_from_container(container_id, Some(options));
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?;
+            archive.extend_from_slice(&chunk);
+            if let Some(max) = max_bytes.filter(|max| archive.len() > *max) {
+                return Err(SandboxError::ArchiveTooLarge(max));
+            }
+        }
+        Ok(archive)
+    }
+
+    async fn upload(&self, container_id: &str, dir: &str, archive: &[u8]) -> Result<()> {
+        use bollard::query_parameters::UploadToContainerOptions;
+
+        // The daemon only extracts into a directory that exists
+        let mkdir = ["mkdir", "-p", dir].map(str::to_string).to_vec();
+        let mut exec = self.exec(container_id, mkdir).await?;
+        let mut errors = Vec::new();
+        while let Some(chunk) = exec.output.next().await {
+            if let ExecOutput::Stderr(bytes) = chunk? {
+                errors.extend_from_slice(&bytes);
+            }
+        }
+        if exec.exit_code.await? != 0 {
+            return Err(SandboxError::ContainerWriteFailed(
+                String::from_utf8_lossy(&errors).to_string(),
+            ));
+        }
+
+        let options = UploadToContainerOptions {
+            path: dir.to_string(),
+            ..Default::default()
+        };
+        self.docker
+            .upload_to_container(
+                container_id,
+                Some(options),
+                bollard::body_full(Bytes::copy_from_slice(archive)),
+            )
+            .await
+            .map_err(|e| SandboxError::ContainerWriteFailed(e.to_string()))
+    }
+
+    async fn exit_code(&self, container_id: &str) -> Result<Option<i64>> {
+        use bollard::query_parameters::InspectContainerOptions;
+
+        let state = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?
+            .state
+            .unwrap_or_default();
+        match state.running {
+            Some(true) => Ok(None),
+            _ => Ok(Some(state.exit_code.unwrap_or(-1))),
+        }
+    }
+
+    async fn remove(&self, container_id: &str) -> Result<()> {
+        use bollard::query_parameters::{InspectContainerOptions, RemoveContainerOptions};
+
+        // The network of the container is found before it goes, it is removed after
+        let network = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .ok()
+            .and_then(|inspect| inspect.host_config?.network_mode)
+            .filter(|mode| mode.starts_with(NETWORK_PREFIX));
+        self.docker
+            .remove_container(
+                container_id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .map_err(|e| SandboxError::StopContainerFailed(e.to_string()))?;
+        if let Some(network) = network {
+            self.remove_network(&network).await;
+        }
+        Ok(())
+    }
+}
+
+/// Exit code of an exec whose output stream ended. Podman may still report the exec
+/// as running for a moment, so it is inspected again until it has finished.
+async fn exec_exit_code(docker: &Docker, exec_id: &str) -> Result<i64> {
+    for _ in 0..20 {
+        let inspect = docker
+            .inspect_exec(exec_id)
+            .await
+            .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?;
+        if let (None | Some(false), Some(exit_code)) = (inspect.running, inspect.exit_code) {
+            return Ok(exit_code);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Err(SandboxError::ContainerReadFailed(
+        "Exec finished without an exit code".to_string(),
+    ))
+}
@@ -0,0 +1,633 @@
+//! Runtime running sandboxes as host processes, for machines without a container engine.
+//!
+//! Each sandbox gets a work directory under the temporary directory of the host, which
+//! its processes start in and use as `HOME`. Images are ignored and commands run with
+//! the tools of the host. With bubblewrap, the processes see the host filesystem
+//! read-only apart from their work directory, their mounts and a `/tmp` of their own,
+//! kept next to the work directory for the lifetime of the sandbox. Either way,
+//! sandboxes are not isolated from each other nor from the host: only use this runtime
+//! with trusted code.
+//!
+//! The session shell runs on a pseudo-terminal, like in a container. Every process is
+//! started in its own session, so the processes of a sandbox are the sessions it
+//! started, which are killed when it is removed. A session whose ID was reused by an
+//! unrelated process, once all of its own exited, is left alone.
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::{FutureExt, SinkExt, StreamExt};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::{Pid, SysconfVar, sysconf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use super::{Attached, ContainerRuntime, ContainerSpec, Exec, ExecOutput, Resize};
+use crate::sandbox::{
+    Mount, PullProgress, ResourceUsage, Result, SandboxError, TerminalSize, UsageCounters,
+};
+
+/// Sandbox running on the host.
+struct LocalContainer {
+    dir: PathBuf,
+    /// Directory mounted at `/tmp` under bubblewrap
+    tmp: PathBuf,
+    env: HashMap<String, String>,
+    mounts: Vec<Mount>,
+    /// Sessions of the processes started in the sandbox
+    sessions: Vec<Session>,
+}
+
+/// Session started in a sandbox, whose ID is the PID of its leader.
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    id: u32,
+    /// Start time of the leader, in clock ticks after boot, telling it apart from a
+    /// later process with the same PID
+    start_time: Option<u64>,
+}
+
+/// Runtime running sandboxes as host processes.
+pub struct Local {
+    root: PathBuf,
+    /// Runs the processes under bubblewrap (`bwrap`)
+    bubblewrap: bool,
+    containers: Mutex<HashMap<String, LocalContainer>>,
+}
+
+fn exec_failed(e: impl std::fmt::Display) -> SandboxError {
+    SandboxError::CreateExecFailed(e.to_string())
+}
+
+/// Exit code of a process, as a shell reports it.
+fn exit_code(status: std::process::ExitStatus) -> i64 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as i64,
+        (None, Some(signal)) => 128 + signal as i64,
+        (None, None) => -1,
+    }
+}
+
+/// Forwards what a pipe outputs until it is closed.
+async fn forward(
+    mut pipe: impl AsyncRead + Unpin,
+    mut tx: mpsc::Sender<ExecOutput>,
+    chunk: fn(Bytes) -> ExecOutput,
+) {
+    let mut buf = vec![0; 64 * 1024];
+    while let Ok(n @ 1..) = pipe.read(&mut buf).await {
+        // The process keeps running when its output is no longer read
+        let _ = tx.send(chunk(Bytes::copy_from_slice(&buf[..n]))).await;
+    }
+}
+
+impl Local {
+    pub fn new(bubblewrap: bool) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let root = std::env::temp_dir().join("sos-local");
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create {}", root.display()))?;
+        if bubblewrap {
+            std::process::Command::new("bwrap")
+                .arg("--version")
+                .output()
+                .context("bubblewrap (bwrap) is not installed")?;
+        }
+        info!(root = %root.display(), bubblewrap, "Running sandboxes as local processes");
+        Ok(Local {
+            root,
+            bubblewrap,
+            containers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Command running `cmd` in the sandbox, in a session of its own.
+    fn command(&self, container_id: &str, cmd: &[String]) -> Result<Command> {
+        let containers = self.containers.lock().unwrap();
+        let container = containers
+            .get(container_id)
+            .ok_or_else(|| exec_failed("No such sandbox"))?;
+        let (program, args) = cmd.split_first().ok_or_else(|| exec_failed("Empty command"))?;
+
+        let mut command = match self.bubblewrap {
+            true => {
+                let mut command = Command::new("bwrap");
+                command.args(["--ro-bind", "/", "/", "--dev", "/dev"]);
+                command.arg("--bind").arg(&container.tmp).arg("/tmp");
+                command.arg("--bind").arg(&container.dir).arg(&container.dir);
+                for mount in &container.mounts {
+                    let bind = if mount.read_only { "--ro-bind" } else { "--bind" };
+                    command.args([bind, &mount.source, &mount.target]);
+                }
+                command.args(["--die-with-parent", "--"]).arg(program).args(args);
+                command
+            }
+            false => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+        };
+        command
+            .current_dir(&container.dir)
+            .env("HOME", &container.dir)
+            .envs(&container.env)
+            .kill_on_drop(false);
+        Ok(command)
+    }
+
+    /// Records the session of a process started in the sandbox.
+    fn track(&self, container_id: &str, pid: Option<u32>) {
+        let mut containers = self.containers.lock().unwrap();
+        if let (Some(container), Some(pid)) = (containers.get_mut(container_id), pid) {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+            container.sessions.push(Session {
+                id: pid,
+                start_time: parse_stat(&stat).and_then(|fields| fields.get(START_TIME).copied()),
+            });
+        }
+    }
+
+    fn sessions(&self, container_id: &str) -> Result<Vec<Session>> {
+        let containers = self.containers.lock().unwrap();
+        let container = containers
+            .get(container_id)
+            .ok_or(SandboxError::NotStarted)?;
+        Ok(container.sessions.clone())
+    }
+}
+
+/// Index of the session ID in the fields returned by [`parse_stat`].
+const SESSION_ID: usize = 3;
+/// Index of the start time in the fields returned by [`parse_stat`].
+const START_TIME: usize = 19;
+
+/// Fields of a `/proc/<pid>/stat` file that follow the command, the state being 0.
+fn parse_stat(stat: &str) -> Option<Vec<u64>> {
+    // The command may contain spaces and parentheses, the other fields are numbers but
+    // the state
+    let (_, fields) = stat.rsplit_once(") ")?;
+    Some(
+        fields
+            .split(' ')
+            .map(|field| field.trim().parse().unwrap_or(0))
+            .collect(),
+    )
+}
+
+/// `/proc` directories of the processes of the sessions, with the fields of their
+/// `stat` file that follow the command. Sessions whose leader has another start time
+/// than when it was tracked belong to a process that reused its PID, and are skipped.
+async fn session_processes(sessions: &[Session]) -> Vec<(PathBuf, Vec<u64>)> {
+    let mut processes = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir("/proc").await else {
+        return processes;
+    };
+    let mut reused = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(stat) = tokio::fs::read_to_string(entry.path().join("stat")).await else {
+            continue;
+        };
+        let Some(fields) = parse_stat(&stat) else {
+            continue;
+        };
+        let Some(session) = fields
+            .get(SESSION_ID)
+            .and_then(|id| sessions.iter().find(|session| session.id as u64 == *id))
+        else {
+            continue;
+        };
+        let pid = entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok());
+        let start_time = fields.get(START_TIME).copied();
+        if pid == Some(session.id) && session.start_time.is_some_and(|t| Some(t) != start_time) {
+            reused.push(session.id);
+        }
+        processes.push((entry.path(), fields));
+    }
+    processes.retain(|(_, fields)| {
+        fields.get(SESSION_ID).is_some_and(|id| !reused.contains(&(*id as u32)))
+    });
+    processes
+}
+
+/// CPU ticks, resident memory, and read and written bytes of the processes of the
+/// sessions, with their number.
+async fn session_usage(sessions: &[Session]) -> (u64, u64, u64, u64, u64) {
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).ok().flatten().unwrap_or(4096) as u64;
+    let mut usage = (0, 0, 0, 0, 0);
+    for (dir, fields) in session_processes(sessions).await {
+        let field = |index: usize| fields.get(index).copied().unwrap_or(0);
+        usage.0 += field(11) + field(12);
+        usage.1 += field(21) * page_size;
+        // Only readable for processes of the same user
+        let io = tokio::fs::read_to_string(dir.join("io"))
+            .await
+            .unwrap_or_default();
+        for line in io.lines() {
+            match line.split_once(": ") {
+                Some(("read_bytes", value)) => usage.2 += value.parse().unwrap_or(0),
+                Some(("write_bytes", value)) => usage.3 += value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        usage.4 += 1;
+    }
+    usage
+}
+
+#[async_trait]
+impl ContainerRuntime for Local {
+    /// Images are not used, the host provides the tools.
+    async fn pull(
+        &self,
+        _image: &str,
+        _on_progress: &mut (dyn for<'p> FnMut(&'p PullProgress) + Send),
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Creates the work and `/tmp` directories of the sandbox. Resource limits are not applied.
+    async fn create(&self, spec: &ContainerSpec) -> Result<String> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let dir = self.root.join(&id);
+        let tmp = self.root.join(format!("{}.tmp", id));
+        for dir in [&dir, &tmp] {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| SandboxError::StartContainerFailed {
+                    message: format!("Failed to create {}: {}", dir.display(), e),
+                    exit_code: None,
+                    logs: String::new(),
+                })?;
+        }
+        if spec.limits.memory_mb.is_some() || spec.limits.cpus.is_some() || spec.limits.pids.is_some() {
+            warn!(sandbox_dir = %dir.display(), "Resource limits are not applied to local sandboxes");
+        }
+        if !spec.dns.is_empty() || !spec.dns_search.is_empty() || !spec.extra_hosts.is_empty() {
+            warn!(sandbox_dir = %dir.display(), "DNS options are not applied to local sandboxes");
+        }
+        let container = LocalContainer {
+            dir,
+            tmp,
+            env: spec.env.clone(),
+            mounts: spec.mounts.clone(),
+            sessions: Vec::new(),
+        };
+        self.containers.lock().unwrap().insert(id.clone(), container);
+        Ok(id)
+    }
+
+    async fn start(&self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn attach(&self, id: &str, cmd: Vec<String>) -> Result<Attached> {
+        let pty = nix::pty::openpty(None, None).map_err(exec_failed)?;
+        let terminal = File::from(pty.slave);
+        let mut command = self.command(id, &cmd)?;
+        command
+            .stdin(terminal.try_clone().map_err(exec_failed)?)
+            .stdout(terminal.try_clone().map_err(exec_failed)?)
+            .stderr(terminal);
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                // Make the terminal the controlling terminal of the session, for job control
+                if nix::libc::ioctl(0, nix::libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().map_err(exec_failed)?;
+        // The child holds the terminal, the parent only needs the other end
+        drop(command);
+        self.track(id, child.id());
+        tokio::spawn(async move {
+            let _ = child.wait().await;
+        });
+
+        let master = File::from(pty.master);
+        let terminal = master.try_clone().map_err(exec_failed)?;
+        let input = tokio::fs::File::from_std(master.try_clone().map_err(exec_failed)?);
+        let reader = tokio::fs::File::from_std(master);
+        // Reading the terminal fails once the process exits
+        let output = futures::stream::unfold(reader, |mut reader| async move {
+            let mut buf = vec![0; 64 * 1024];
+            match reader.read(&mut buf).await {
+                Ok(n @ 1..) => {
+                    buf.truncate(n);
+                    Some((Bytes::from(buf), reader))
+                }
+                _ => None,
+            }
+        })
+        .boxed();
+
+        let resize: Resize = Box::new(move |size: TerminalSize| {
+            let winsize = nix::libc::winsize {
+                ws_row: size.rows,
+                ws_col: size.cols,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            // SAFETY: the terminal is open as long as the closure, and winsize outlives the call
+            let result = unsafe {
+                nix::libc::ioctl(terminal.as_raw_fd(), nix::libc::TIOCSWINSZ as _, &winsize)
+            };
+            let result = match result {
+                0.. => Ok(()),
+                _ => Err(SandboxError::ContainerWriteFailed(
+                    std::io::Error::last_os_error().to_string(),
+                )),
+            };
+            futures::future::ready(result).boxed()
+        });
+        Ok(Attached {
+            input: Box::pin(input),
+            output,
+            resize,
+        })
+    }
+
+    async fn exec(&self, id: &str, cmd: Vec<String>) -> Result<Exec> {
+        self.exec_with_env(id, cmd, &HashMap::new()).await
+    }
+
+    async fn exec_with_env(
+        &self,
+        id: &str,
+        cmd: Vec<String>,
+        env: &HashMap<String, String>,
+    ) -> Result<Exec> {
+        let mut command = self.command(id, &cmd)?;
+        command
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().map_err(exec_failed)?;
+        self.track(id, child.id());
+
+        let (tx, rx) = mpsc::channel(64);
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        tokio::spawn(async move {
+            futures::join!(
+                forward(stdout, tx.clone(), ExecOutput::Stdout),
+                forward(stderr, tx, ExecOutput::Stderr),
+            );
+            let status = child
+                .wait()
+                .await
+                .map(exit_code)
+                .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()));
+            let _ = exit_tx.send(status);
+        });
+
+        let exit_code = async move {
+            exit_rx.await.map_err(|_| {
+                SandboxError::ContainerReadFailed("Exec ended without an exit code".to_string())
+            })?
+        };
+        Ok(Exec {
+            output: rx.map(Ok).boxed(),
+            exit_code: exit_code.boxed(),
+        })
+    }
+
+    /// Sums the CPU time of the processes of the sandbox, including the children they
+    /// waited for. Their peak memory is not tracked.
+    async fn usage(&self, id: &str) -> Result<UsageCounters> {
+        let sessions = self.sessions(id)?;
+        let ticks_per_second = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as u64;
+        let ticks: u64 = session_processes(&sessions)
+            .await
+            .iter()
+            .map(|(_, fields)| fields.iter().skip(11).take(4).sum::<u64>())
+            .sum();
+        Ok(UsageCounters {
+            cpu_usec: ticks * 1_000_000 / ticks_per_second,
+            memory_peak_bytes: None,
+        })
+    }
+
+    /// Sums the usage of the processes of the sandbox. Network usage is not reported.
+    async fn stats(&self, id: &str) -> Result<ResourceUsage> {
+        let sessions = self.sessions(id)?;
+        let ticks_per_second = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as f64;
+        let started = Instant::now();
+        let (ticks, ..) = session_usage(&sessions).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (ticks_after, memory, read, written, pids) = session_usage(&sessions).await;
+        let cpu_seconds = ticks_after.saturating_sub(ticks) as f64 / ticks_per_second;
+
+        Ok(ResourceUsage {
+            cpu_percent: cpu_seconds / started.elapsed().as_secs_f64() * 100.0,
+            memory_usage_bytes: memory,
+            memory_limit_bytes: 0,
+            network_rx_bytes: 0,
+            network_tx_bytes: 0,
+            block_read_bytes: read,
+            block_write_bytes: written,
+            pids,
+        })
+    }
+
+    /// Kills the processes of the sandbox and deletes its work and `/tmp` directories.
+    async fn remove(&self, id: &str) -> Result<()> {
+        let container = self
+            .containers
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| SandboxError::StopContainerFailed("No such sandbox".to_string()))?;
+        // Jobs of the session shell run in process groups of their own
+        for (dir, _) in session_processes(&container.sessions).await {
+            let pid = dir.file_name().and_then(|pid| pid.to_str()?.parse().ok());
+            if let Some(pid) = pid {
+                let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+            }
+        }
+        let _ = tokio::fs::remove_dir_all(&container.tmp).await;
+        tokio::fs::remove_dir_all(&container.dir)
+            .await
+            .map_err(|e| SandboxError::StopContainerFailed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> Local {
+        Local::new(false).expect("Failed to create the local runtime")
+    }
+
+    async fn create(runtime: &Local) -> String {
+        let spec = ContainerSpec {
+            image: String::new(),
+            env: HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+            limits: Default::default(),
+            mounts: Vec::new(),
+            dns: Vec::new(),
+            dns_search: Vec::new(),
+            extra_hosts: Vec::new(),
+        };
+        runtime.create(&spec).await.expect("Failed to create sandbox")
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        ["sh", "-c", script].map(str::to_string).to_vec()
+    }
+
+    /// Whether the process is gone, or a zombie nobody waited for yet.
+    fn is_dead(pid: u32) -> bool {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        stat.is_empty() || stat.contains(") Z ")
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (my (odd) cmd) S 1 4242 4240 0 -1 4194560 120 0 0 0 7 3 0 0 20 0 1 0 \
+                    98765 2490368 230 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 3 0 0 0 0 0\n";
+        let fields = parse_stat(stat).unwrap();
+        assert_eq!(fields[0], 0);
+        assert_eq!(fields[SESSION_ID], 4240);
+        assert_eq!(fields[11] + fields[12], 10);
+        assert_eq!(fields[START_TIME], 98765);
+        assert_eq!(fields.last(), Some(&0));
+        assert!(parse_stat("garbage").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let runtime = local();
+        let id = create(&runtime).await;
+
+        let env = HashMap::from([("NAME".to_string(), "world".to_string())]);
+        let mut exec = runtime
+            .exec_with_env(&id, sh("echo $GREETING $NAME; echo $HOME; echo oops >&2; exit 3"), &env)
+            .await
+            .expect("Failed to exec");
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        while let Some(output) = exec.output.next().await {
+            match output.unwrap() {
+                ExecOutput::Stdout(bytes) => stdout.extend_from_slice(&bytes),
+                ExecOutput::Stderr(bytes) => stderr.extend_from_slice(&bytes),
+            }
+        }
+        assert_eq!(exec.exit_code.await.unwrap(), 3);
+        let dir = runtime.root.join(&id);
+        assert_eq!(
+            String::from_utf8_lossy(&stdout),
+            format!("hello world\n{}\n", dir.display())
+        );
+        assert_eq!(String::from_utf8_lossy(&stderr), "oops\n");
+
+        runtime.remove(&id).await.unwrap();
+        assert!(runtime.exec(&id, sh("true")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attach() {
+        use tokio::io::AsyncWriteExt;
+
+        let runtime = local();
+        let id = create(&runtime).await;
+
+        let mut attached = runtime.attach(&id, sh("exec sh")).await.expect("Failed to attach");
+        (attached.resize)(TerminalSize { cols: 100, rows: 30 }).await.unwrap();
+        attached
+            .input
+            .write_all(b"stty size; echo $((40 + 2)); exit\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        let read = async {
+            while let Some(bytes) = attached.output.next().await {
+                output.extend_from_slice(&bytes);
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), read)
+            .await
+            .expect("The shell did not exit");
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("30 100"), "Unexpected output: {}", output);
+        assert!(output.contains("42"), "Unexpected output: {}", output);
+
+        runtime.remove(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let runtime = local();
+        let id = create(&runtime).await;
+        let dir = runtime.root.join(&id);
+
+        // A background job, left running in the session
+        let mut exec = runtime
+            .exec(&id, sh("sleep 60 >/dev/null 2>&1 & echo $!"))
+            .await
+            .expect("Failed to exec");
+        let mut stdout = Vec::new();
+        while let Some(output) = exec.output.next().await {
+            if let Ok(ExecOutput::Stdout(bytes)) = output {
+                stdout.extend_from_slice(&bytes);
+            }
+        }
+        let pid: u32 = String::from_utf8_lossy(&stdout).trim().parse().unwrap();
+        assert!(!is_dead(pid));
+
+        runtime.remove(&id).await.expect("Failed to remove sandbox");
+        assert!(!dir.exists());
+        for _ in 0..50 {
+            if is_dead(pid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(is_dead(pid));
+        assert!(runtime.remove(&id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reused_session_is_skipped() {
+        let runtime = local();
+        let id = create(&runtime).await;
+
+        let _exec = runtime.exec(&id, sh("sleep 60")).await.expect("Failed to exec");
+        let session = runtime.sessions(&id).unwrap()[0];
+        assert!(session.start_time.is_some());
+        assert!(!session_processes(&[session]).await.is_empty());
+
+        // The same PID with another start time is another process
+        let reused = Session {
+            start_time: session.start_time.map(|t| t + 1),
+            ..session
+        };
+        assert!(session_processes(&[reused]).await.is_empty());
+
+        runtime.remove(&id).await.unwrap();
+    }
+}
@@ -0,0 +1,577 @@
+//! Container runtimes.
+//!
+//! Sandboxes run on Docker by default, or on Podman through its Docker-compatible API,
+//! or on containerd directly for hosts without Docker (with the `containerd` feature).
+//! Without any container engine, the local runtime runs them as host processes (with the
+//! `local` feature).
+//! Each runtime implements [`ContainerRuntime`], the handful of container operations a
+//! sandbox needs. The few differences between Docker and Podman are handled in the
+//! Docker runtime and when connecting.
+//!
+//! For stronger isolation of untrusted code, containers can run in lightweight VMs by
+//! naming a VM-based OCI runtime, such as Kata Containers or firecracker-containerd,
+//! as [`RuntimeConfig::oci_runtime`]. The session shell works the same inside them.
+mod docker;
+#[cfg(feature = "local")]
+mod local;
+
+#[cfg(feature = "containerd")]
+mod containerd;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bollard::{API_DEFAULT_VERSION, Docker};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
+use tracing::{info, warn};
+
+#[cfg(feature = "containerd")]
+pub use containerd::Containerd;
+pub use docker::DockerRuntime;
+#[cfg(feature = "local")]
+pub use local::Local;
+
+use crate::sandbox::{
+    Mount, PullProgress, ResourceLimits, ResourceUsage, Result, SandboxError, TerminalSize,
+    UsageCounters,
+};
+use crate::task::shell_quote;
+
+/// Seconds before a request to the runtime times out, bollard's default.
+const TIMEOUT_SECS: u64 = 120;
+
+/// Most base64 written by a single command of [`ContainerRuntime::upload`], below the
+/// 128 KiB Linux limit on the length of an argument.
+const UPLOAD_CHUNK_BYTES: usize = 96 * 1024;
+
+/// Socket of a rootful Podman service.
+const PODMAN_ROOT_SOCKET: &str = "/run/podman/podman.sock";
+
+/// Socket of the Docker daemon on a host reached over SSH, unless the URL names one.
+const REMOTE_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Longest wait for an SSH tunnel to forward the Docker socket.
+const SSH_TUNNEL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Container runtime the server talks to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+    Containerd,
+    Local,
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Runtime::Docker => write!(f, "docker"),
+            Runtime::Podman => write!(f, "podman"),
+            Runtime::Containerd => write!(f, "containerd"),
+            Runtime::Local => write!(f, "local"),
+        }
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "docker" => Ok(Runtime::Docker),
+            "podman" => Ok(Runtime::Podman),
+            "containerd" => Ok(Runtime::Containerd),
+            "local" => Ok(Runtime::Local),
+            _ => Err(format!(
+                "Unknown runtime '{}', expected docker, podman, containerd or local",
+                s
+            )),
+        }
+    }
+}
+
+/// Container of a sandbox, as created by [`ContainerRuntime::create`].
+#[derive(Debug, Clone)]
+pub struct ContainerSpec {
+    pub image: String,
+    pub env: HashMap<String, String>,
+    pub limits: ResourceLimits,
+    pub mounts: Vec<Mount>,
+    /// DNS servers, instead of the ones of the runtime
+    pub dns: Vec<String>,
+    pub dns_search: Vec<String>,
+    /// `host:ip` entries added to `/etc/hosts`, where `ip` may be `host-gateway`
+    pub extra_hosts: Vec<String>,
+}
+
+/// Resizes the terminal of an attached process.
+pub type Resize = Box<dyn Fn(TerminalSize) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Process running in a terminal with its input attached: the session shell.
+pub struct Attached {
+    pub input: Pin<Box<dyn AsyncWrite + Send>>,
+    /// Terminal output, ending when the process exits or the connection breaks
+    pub output: BoxStream<'static, Bytes>,
+    /// Resizes the terminal, which starts with the size the runtime defaults to
+    pub resize: Resize,
+}
+
+/// Chunk of output of a process run without a terminal.
+#[derive(Debug, Clone)]
+pub enum ExecOutput {
+    Stdout(Bytes),
+    Stderr(Bytes),
+}
+
+/// Process run to completion without a terminal.
+pub struct Exec {
+    pub output: BoxStream<'static, Result<ExecOutput>>,
+    /// Exit code of the process, available once its output ended
+    pub exit_code: BoxFuture<'static, Result<i64>>,
+}
+
+/// Container operations used by sandboxes, the only way they reach a container engine.
+/// Errors are sandbox errors, so they are reported the same whatever the runtime.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Pulls the image unless it is already present. Returns whether it was pulled.
+    /// `on_progress` is called as layers download, at most every
+    /// [`PULL_PROGRESS_INTERVAL`](crate::sandbox::PULL_PROGRESS_INTERVAL) and whenever a
+    /// layer completes, by the runtimes that report progress.
+    async fn pull(
+        &self,
+        image: &str,
+        on_progress: &mut (dyn for<'p> FnMut(&'p PullProgress) + Send),
+    ) -> Result<bool>;
+
+    /// Creates the container of a sandbox, kept alive by `sleep infinity`. Returns its ID.
+    async fn create(&self, spec: &ContainerSpec) -> Result<String>;
+
+    /// Starts a created container and waits for it to be running.
+    async fn start(&self, id: &str) -> Result<()>;
+
+    /// Runs `cmd` in a terminal with its input attached.
+    async fn attach(&self, id: &str, cmd: Vec<String>) -> Result<Attached>;
+
+    /// Runs `cmd` without a terminal, keeping stdout and stderr apart.
+    async fn exec(&self, id: &str, cmd: Vec<String>) -> Result<Exec>;
+
+    /// Runs `cmd` as [`ContainerRuntime::exec`] does, with `env` added to its environment.
+    /// Any process of the container can read the arguments of the others, while their
+    /// environment is private to their user, so secrets are passed here. By default, the
+    /// runtime cannot set it and fails.
+    async fn exec_with_env(
+        &self,
+        id: &str,
+        cmd: Vec<String>,
+        env: &HashMap<String, String>,
+    ) -> Result<Exec> {
+        if env.is_empty() {
+            return self.exec(id, cmd).await;
+        }
+        Err(SandboxError::CreateExecFailed(
+            "The runtime cannot set the environment of a command".to_string(),
+        ))
+    }
+
+    /// Samples the resource usage of the container. May take about a second, as CPU
+    /// usage is measured over two readings.
+    async fn stats(&self, id: &str) -> Result<ResourceUsage>;
+
+    /// Freezes every process of the container with the freezer of the runtime, as
+    /// `docker pause` does. Returns false when the runtime cannot, as by default.
+    async fn pause(&self, _id: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Resumes the processes of a container frozen by [`ContainerRuntime::pause`].
+    async fn unpause(&self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads the cumulative resource counters of the container, around each command to
+    /// account for what it used. Must not exec in the container, which would be counted
+    /// and slow every command down. By default, the runtime reports no counters.
+    async fn usage(&self, _id: &str) -> Result<UsageCounters> {
+        Err(SandboxError::ContainerReadFailed(
+            "The runtime does not report resource counters".to_string(),
+        ))
+    }
+
+    /// Streams the output of the main process of the container, until it exits when
+    /// `follow` is set. By default, the runtime keeps no logs and the stream is empty.
+    async fn logs(&self, _id: &str, _follow: bool) -> Result<BoxStream<'static, Result<Bytes>>> {
+        Ok(futures::stream::empty().boxed())
+    }
+
+    /// Downloads a path of the container as a tar archive. Fails with
+    /// [`SandboxError::ArchiveTooLarge`] as soon as the archive grows past `max_bytes`.
+    /// By default, archives it with `tar` in the container, which must provide it.
+    async fn download(&self, id: &str, path: &str, max_bytes: Option<usize>) -> Result<Vec<u8>> {
+        let path = Path::new(path);
+        let (dir, base) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(base)) => (dir.to_string_lossy(), base.to_string_lossy()),
+            _ => (path.to_string_lossy(), ".".into()),
+        };
+        let cmd = ["tar", "-cf", "-", "-C", &*dir, &*base]
+            .map(str::to_string)
+            .to_vec();
+        let mut exec = self.exec(id, cmd).await?;
+        let mut archive = Vec::new();
+        let mut errors = Vec::new();
+        while let Some(chunk) = exec.output.next().await {
+            match chunk? {
+                ExecOutput::Stdout(bytes) => archive.extend_from_slice(&bytes),
+                ExecOutput::Stderr(bytes) => errors.extend_from_slice(&bytes),
+            }
+            // Dropping the exec leaves tar to fail on a closed pipe
+            if let Some(max) = max_bytes.filter(|max| archive.len() > *max) {
+                return Err(SandboxError::ArchiveTooLarge(max));
+            }
+        }
+        match exec.exit_code.await? {
+            0 => Ok(archive),
+            _ => Err(SandboxError::ContainerReadFailed(
+                String::from_utf8_lossy(&errors).to_string(),
+            )),
+        }
+    }
+
+    /// Extracts a tar archive into a directory of the container, created if missing. By
+    /// default, writes the archive with standalone commands carrying it in base64, a chunk
+    /// at a time, then extracts it with `tar`, which the container must provide.
+    async fn upload(&self, id: &str, dir: &str, archive: &[u8]) -> Result<()> {
+        let tmp = format!("/tmp/.sos-upload-{}", uuid::Uuid::new_v4().simple());
+        let encoded = BASE64.encode(archive);
+        let mut cmds = vec![format!(": > {tmp}")];
+        for chunk in encoded.as_bytes().chunks(UPLOAD_CHUNK_BYTES) {
+            // The base64 alphabet needs no quoting
+            let chunk = std::str::from_utf8(chunk).expect("base64 is ASCII");
+            cmds.push(format!("printf '%s' {chunk} >> {tmp}"));
+        }
+        let dir = shell_quote(dir);
+        cmds.push(format!(
+            "mkdir -p {dir} && base64 -d {tmp} | tar -xf - -C {dir}; s=$?; rm -f {tmp}; exit $s"
+        ));
+        for cmd in cmds {
+            let mut exec = self.exec(id, vec!["sh".into(), "-c".into(), cmd]).await?;
+            let mut errors = Vec::new();
+            while let Some(chunk) = exec.output.next().await {
+                if let ExecOutput::Stderr(bytes) = chunk? {
+                    errors.extend_from_slice(&bytes);
+                }
+            }
+            if exec.exit_code.await? != 0 {
+                return Err(SandboxError::ContainerWriteFailed(
+                    String::from_utf8_lossy(&errors).to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Exit code of the main process of the container once it stopped, `None` while it
+    /// runs. A container that no longer exists fails to be read. By default, the runtime
+    /// cannot tell and reports it running.
+    async fn exit_code(&self, _id: &str) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Kills and removes the container.
+    async fn remove(&self, id: &str) -> Result<()>;
+}
+
+/// Runtime section of the server configuration.
+///
+/// ```toml
+/// [runtime]
+/// kind = "podman"
+/// socket = "/run/user/1000/podman/podman.sock"
+/// ```
+///
+/// Driving the Docker daemon of another host, over TCP with TLS or over SSH:
+///
+/// ```toml
+/// [runtime]
+/// host = "tcp://containers.internal:2376"
+/// tls_cert_path = "/etc/sos/docker"  # ca.pem, cert.pem and key.pem
+/// ```
+///
+/// Running each sandbox in a Kata Containers VM through containerd:
+///
+/// ```toml
+/// [runtime]
+/// kind = "containerd"
+/// oci_runtime = "io.containerd.kata.v2"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub kind: Runtime,
+    /// Unix socket of the runtime API, with or without a `unix://` prefix. Docker
+    /// defaults to `DOCKER_HOST` or its usual socket, Podman to the rootless socket of
+    /// the user if it exists, then to the rootful one, and containerd to
+    /// `/run/containerd/containerd.sock`.
+    pub socket: Option<PathBuf>,
+    /// Docker or Podman API on another host: `tcp://host:2376`, or `ssh://user@host`
+    /// (optionally with a port and the path of the remote socket). SSH connections go
+    /// through the `ssh` client, with its keys and configuration. Also accepts a
+    /// `unix://` socket.
+    pub host: Option<String>,
+    /// Directory with the `ca.pem`, `cert.pem` and `key.pem` files authenticating with a
+    /// TCP host using TLS, as for `DOCKER_CERT_PATH`. Plain HTTP is used without it.
+    pub tls_cert_path: Option<PathBuf>,
+    /// OCI runtime containers are run with, instead of runc. With Docker or Podman, the
+    /// name of a runtime registered with the engine (`kata`, `runsc`). With containerd,
+    /// the name of a shim (`io.containerd.kata.v2`, or `aws.firecracker` with
+    /// firecracker-containerd).
+    pub oci_runtime: Option<String>,
+    /// Runs the processes of local sandboxes under bubblewrap, which must be installed
+    pub bubblewrap: bool,
+    /// Gives each container its own bridge network, removed with it, instead of the
+    /// default bridge shared by all of them. Sandboxes still reach the outside, but not
+    /// each other. Docker and Podman only.
+    pub isolate_networks: bool,
+    /// Runs containerd containers on the host network. Off by default, as it lets
+    /// sandboxes reach the services of the host: containers then get a network namespace
+    /// of their own, with only a loopback interface. Containerd with runc only.
+    pub host_network: bool,
+}
+
+impl RuntimeConfig {
+    /// Connects to the runtime and logs which engine answered.
+    pub async fn connect(&self) -> anyhow::Result<Arc<dyn ContainerRuntime>> {
+        match self.kind {
+            Runtime::Docker | Runtime::Podman => Ok(Arc::new(DockerRuntime {
+                docker: self.connect_docker().await?,
+                oci_runtime: self.oci_runtime.clone(),
+                isolate_networks: self.isolate_networks,
+            })),
+            #[cfg(feature = "containerd")]
+            Runtime::Containerd => {
+                let containerd = Containerd::connect(
+                    self.socket.as_deref(),
+                    self.oci_runtime.clone(),
+                    self.host_network,
+                )
+                .await?;
+                Ok(Arc::new(containerd))
+            }
+            #[cfg(not(feature = "containerd"))]
+            Runtime::Containerd => anyhow::bail!("sos was built without the containerd feature"),
+            #[cfg(feature = "local")]
+            Runtime::Local => Ok(Arc::new(Local::new(self.bubblewrap)?)),
+            #[cfg(not(feature = "local"))]
+            Runtime::Local => anyhow::bail!("sos was built without the local feature"),
+        }
+    }
+
+    /// Checks the remote host and network settings, so a typo fails on startup.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.isolate_networks && !matches!(self.kind, Runtime::Docker | Runtime::Podman) {
+            anyhow::bail!("runtime.isolate_networks is only supported by docker and podman");
+        }
+        if self.host_network && self.kind != Runtime::Containerd {
+            anyhow::bail!("runtime.host_network is only supported by containerd");
+        }
+        let Some(host) = &self.host else {
+            return Ok(());
+        };
+        if !matches!(self.kind, Runtime::Docker | Runtime::Podman) {
+            anyhow::bail!("runtime.host is only supported by docker and podman");
+        }
+        if self.socket.is_some() {
+            anyhow::bail!("runtime.host and runtime.socket cannot both be set");
+        }
+        match host.split_once("://") {
+            Some(("tcp" | "http" | "https" | "ssh" | "unix", address)) if !address.is_empty() => {}
+            _ => anyhow::bail!(
+                "Invalid runtime host '{}', expected tcp://, ssh:// or unix://",
+                host
+            ),
+        }
+        if let Some(dir) = &self.tls_cert_path {
+            for file in ["ca.pem", "cert.pem", "key.pem"] {
+                let path = dir.join(file);
+                anyhow::ensure!(path.is_file(), "Missing TLS file {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects to a runtime with a Docker-compatible API, Docker or Podman.
+    ///
+    /// Podman's compatible API rejects client versions newer than the one it
+    /// implements, so the API version is negotiated with it, which requires it to be
+    /// reachable. Docker is connected to lazily, as before.
+    pub async fn connect_docker(&self) -> anyhow::Result<Docker> {
+        if matches!(self.kind, Runtime::Containerd | Runtime::Local) {
+            anyhow::bail!("{} has no Docker-compatible API", self.kind);
+        }
+        let docker = match (&self.host, &self.socket, self.kind) {
+            (Some(host), _, _) => self.connect_host(host).await?,
+            (None, Some(socket), _) => connect_socket(socket, self.kind)?,
+            // Honors DOCKER_HOST, with DOCKER_TLS_VERIFY and DOCKER_CERT_PATH
+            (None, None, Runtime::Docker) => Docker::connect_with_defaults()?,
+            (None, None, _) => connect_socket(&default_podman_socket(), self.kind)?,
+        };
+        let docker = match self.kind {
+            Runtime::Podman => docker
+                .negotiate_version()
+                .await
+                .with_context(|| format!("Failed to reach podman at {}", self.endpoint()))?,
+            _ => docker,
+        };
+        log_engine(&docker, self.kind).await;
+        Ok(docker)
+    }
+
+    /// Where the runtime is reached, for messages.
+    fn endpoint(&self) -> String {
+        match (&self.host, &self.socket) {
+            (Some(host), _) => host.clone(),
+            (None, Some(socket)) => socket.display().to_string(),
+            (None, None) => "its default socket".to_string(),
+        }
+    }
+
+    async fn connect_host(&self, host: &str) -> anyhow::Result<Docker> {
+        let (scheme, address) = host
+            .split_once("://")
+            .with_context(|| format!("Invalid runtime host '{}'", host))?;
+        let docker = match (scheme, &self.tls_cert_path) {
+            ("unix", _) => return connect_socket(Path::new(address), self.kind),
+            ("ssh", _) => {
+                let socket = ssh_tunnel(address).await?;
+                return connect_socket(&socket, self.kind);
+            }
+            ("tcp" | "https", Some(dir)) => {
+                // Only the ring provider is built, install it for the Docker client. Without
+                // the server and client, the application embedding the library installs it
+                #[cfg(any(feature = "server", feature = "client"))]
+                let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+                Docker::connect_with_ssl(
+                    address,
+                    &dir.join("key.pem"),
+                    &dir.join("cert.pem"),
+                    &dir.join("ca.pem"),
+                    TIMEOUT_SECS,
+                    API_DEFAULT_VERSION,
+                )
+            }
+            ("tcp" | "http", None) => {
+                Docker::connect_with_http(address, TIMEOUT_SECS, API_DEFAULT_VERSION)
+            }
+            _ => anyhow::bail!("Unsupported runtime host '{}'", host),
+        };
+        docker.with_context(|| format!("Failed to connect to {} at {}", self.kind, host))
+    }
+}
+
+fn connect_socket(socket: &Path, kind: Runtime) -> anyhow::Result<Docker> {
+    let path = socket_path(socket)?;
+    Docker::connect_with_socket(path, TIMEOUT_SECS, API_DEFAULT_VERSION)
+        .with_context(|| format!("Failed to connect to {} at {}", kind, path))
+}
+
+/// Forwards the Docker socket of a host reached over SSH (`user@host[:port][/socket]`)
+/// to a local socket, through an `ssh` process kept running with the server.
+async fn ssh_tunnel(destination: &str) -> anyhow::Result<PathBuf> {
+    let (destination, remote_socket) = match destination.split_once('/') {
+        Some((destination, socket)) => (destination, format!("/{}", socket)),
+        None => (destination, REMOTE_DOCKER_SOCKET.to_string()),
+    };
+    let (destination, port) = match destination.rsplit_once(':') {
+        Some((destination, port)) if port.parse::<u16>().is_ok() => (destination, Some(port)),
+        _ => (destination, None),
+    };
+    let local = std::env::temp_dir().join(format!("sos-ssh-{}.sock", uuid::Uuid::new_v4()));
+
+    let mut command = tokio::process::Command::new("ssh");
+    command
+        .args(["-N", "-o", "ExitOnForwardFailure=yes", "-o", "BatchMode=yes", "-L"])
+        .arg(format!("{}:{}", local.display(), remote_socket));
+    if let Some(port) = port {
+        command.args(["-p", port]);
+    }
+    let mut child = command
+        .arg(destination)
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run ssh")?;
+
+    let started = tokio::time::Instant::now();
+    while !local.exists() {
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("ssh to {} exited ({}) before forwarding the socket", destination, status);
+        }
+        if started.elapsed() > SSH_TUNNEL_TIMEOUT {
+            anyhow::bail!("Timed out forwarding the Docker socket of {} over ssh", destination);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    info!(destination = %destination, socket = %local.display(), "Forwarding the Docker socket over ssh");
+    let destination = destination.to_string();
+    tokio::spawn(async move {
+        let status = child.wait().await;
+        warn!(destination = %destination, status = ?status, "SSH tunnel to the runtime exited");
+    });
+    Ok(local)
+}
+
+/// Path of a socket given with or without a `unix://` prefix.
+fn socket_path(socket: &Path) -> anyhow::Result<&str> {
+    let path = socket
+        .to_str()
+        .context("Runtime socket path is not valid UTF-8")?;
+    Ok(path.strip_prefix("unix://").unwrap_or(path))
+}
+
+fn default_podman_socket() -> PathBuf {
+    let rootless = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| Path::new(&dir).join("podman").join("podman.sock"))
+        .filter(|socket| socket.exists());
+    rootless.unwrap_or_else(|| PathBuf::from(PODMAN_ROOT_SOCKET))
+}
+
+/// Logs the engine behind the socket, warning when it is not the configured runtime.
+async fn log_engine(docker: &Docker, expected: Runtime) {
+    let version = match docker.version().await {
+        Ok(version) => version,
+        Err(e) => {
+            warn!(runtime = %expected, error = %e, "Container runtime is not reachable");
+            return;
+        }
+    };
+    // Podman lists itself as a component of the version it reports
+    let is_podman = version
+        .components
+        .unwrap_or_default()
+        .iter()
+        .any(|component| component.name.to_lowercase().contains("podman"));
+    let engine = if is_podman { Runtime::Podman } else { Runtime::Docker };
+    info!(
+        runtime = %engine,
+        version = version.version.as_deref().unwrap_or("unknown"),
+        api_version = version.api_version.as_deref().unwrap_or("unknown"),
+        "Connected to container runtime"
+    );
+    if engine != expected {
+        warn!(configured = %expected, detected = %engine, "Container runtime differs from the configured one");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, atomic::AtomicBool},
+    time::Duration,
+};
+
+use regex::Regex;
+
+use super::{
+    Hook, Mount, Observer, OutputFilters, RepoSpec, ResourceLimits, RestartPolicy, Result,
+    Sandbox, SandboxError, SandboxStatus, SandboxView, Shell, TerminalSize, ToolBundle, shell::Markers,
+};
+use crate::runtime::ContainerRuntime;
+use crate::store::TrajectoryStore;
+
+/// Settings of a [`Sandbox`] before it is created, from [`Sandbox::builder`].
+///
+/// The image and the runtime are required. Setters taking several values add to those
+/// set before.
+#[derive(Default)]
+pub struct SandboxBuilder {
+    image: Option<String>,
+    setup_commands: Vec<String>,
+    tenant: Option<String>,
+    labels: HashMap<String, String>,
+    env: HashMap<String, String>,
+    limits: ResourceLimits,
+    mounts: Vec<Mount>,
+    dns: Vec<String>,
+    dns_search: Vec<String>,
+    extra_hosts: Vec<String>,
+    shell: Shell,
+    shell_init: Vec<String>,
+    tools: Vec<ToolBundle>,
+    repo: Option<RepoSpec>,
+    hooks: Vec<Arc<dyn Hook>>,
+    observers: Vec<Arc<dyn Observer>>,
+    terminal_size: Option<TerminalSize>,
+    verify_command: Option<String>,
+    output_filters: OutputFilters,
+    max_commands: Option<usize>,
+    blocked_commands: Vec<Regex>,
+    restart_policy: RestartPolicy,
+    time_limit: Option<Duration>,
+    store: Option<Arc<TrajectoryStore>>,
+    runtime: Option<Arc<dyn ContainerRuntime>>,
+}
+
+impl SandboxBuilder {
+    /// Image of the container
+    pub fn image(mut self, image: impl Into<String>) -> Self {
+        self.image = Some(image.into());
+        self
+    }
+
+    /// Commands run on start, chained with `&&`
+    pub fn setup<S: Into<String>>(mut self, commands: impl IntoIterator<Item = S>) -> Self {
+        self.setup_commands.extend(commands.into_iter().map(Into::into));
+        self
+    }
+
+    /// Namespace owning the sandbox, the default tenant when unset
+    pub fn tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    pub fn labels<K: Into<String>, V: Into<String>>(
+        mut self,
+        labels: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.labels
+            .extend(labels.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Environment variables of the container
+    pub fn env<K: Into<String>, V: Into<String>>(
+        mut self,
+        vars: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.env
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn mounts(mut self, mounts: impl IntoIterator<Item = Mount>) -> Self {
+        self.mounts.extend(mounts);
+        self
+    }
+
+    /// DNS servers of the container, instead of the runtime's
+    pub fn dns<S: Into<String>>(mut self, servers: impl IntoIterator<Item = S>) -> Self {
+        self.dns.extend(servers.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn dns_search<S: Into<String>>(mut self, domains: impl IntoIterator<Item = S>) -> Self {
+        self.dns_search.extend(domains.into_iter().map(Into::into));
+        self
+    }
+
+    /// `host:ip` entries added to `/etc/hosts`
+    pub fn extra_hosts<S: Into<String>>(mut self, hosts: impl IntoIterator<Item = S>) -> Self {
+        self.extra_hosts.extend(hosts.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    /// Lines run by the session shell after configuring it
+    pub fn shell_init<S: Into<String>>(mut self, lines: impl IntoIterator<Item = S>) -> Self {
+        self.shell_init.extend(lines.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn tools(mut self, tools: impl IntoIterator<Item = ToolBundle>) -> Self {
+        self.tools.extend(tools);
+        self
+    }
+
+    /// Repository cloned on start, before the setup commands
+    pub fn repo(mut self, repo: impl Into<Option<RepoSpec>>) -> Self {
+        self.repo = repo.into();
+        self
+    }
+
+    pub fn hooks(mut self, hooks: impl IntoIterator<Item = Arc<dyn Hook>>) -> Self {
+        self.hooks.extend(hooks);
+        self
+    }
+
+    pub fn observers(mut self, observers: impl IntoIterator<Item = Arc<dyn Observer>>) -> Self {
+        self.observers.extend(observers);
+        self
+    }
+
+    pub fn terminal_size(mut self, size: impl Into<Option<TerminalSize>>) -> Self {
+        self.terminal_size = size.into();
+        self
+    }
+
+    /// Command run standalone by [`Sandbox::verify`]
+    pub fn verify_command(mut self, command: impl Into<Option<String>>) -> Self {
+        self.verify_command = command.into();
+        self
+    }
+
+    /// Filters applied to the output of the commands before it is returned and recorded
+    pub fn output_filters(mut self, filters: OutputFilters) -> Self {
+        self.output_filters = filters;
+        self
+    }
+
+    /// Number of commands recorded in the trajectory after which the sandbox runs no more
+    /// session commands
+    pub fn max_commands(mut self, max: impl Into<Option<usize>>) -> Self {
+        self.max_commands = max.into();
+        self
+    }
+
+    /// Patterns of the commands the sandbox refuses to run
+    pub fn blocked_commands(mut self, patterns: impl IntoIterator<Item = Regex>) -> Self {
+        self.blocked_commands.extend(patterns);
+        self
+    }
+
+    /// What the sandbox does once its container or session shell died
+    pub fn restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.restart_policy = policy;
+        self
+    }
+
+    /// Lifetime after which the server stops the sandbox
+    pub fn time_limit(mut self, limit: impl Into<Option<Duration>>) -> Self {
+        self.time_limit = limit.into();
+        self
+    }
+
+    /// Store the trajectory is persisted to
+    pub fn store(mut self, store: impl Into<Option<Arc<TrajectoryStore>>>) -> Self {
+        self.store = store.into();
+        self
+    }
+
+    /// Runtime the container runs on, see [`crate::runtime::RuntimeConfig::connect`]
+    pub fn runtime(mut self, runtime: Arc<dyn ContainerRuntime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Creates the sandbox with a new ID. It is started with [`Sandbox::start`].
+    pub fn build(self) -> Result<Sandbox> {
+        let image = self.image.ok_or(SandboxError::MissingSetting("image"))?;
+        let runtime = self.runtime.ok_or(SandboxError::MissingSetting("runtime"))?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let view = SandboxView::new(&id);
+
+        Ok(Sandbox {
+            id,
+            image,
+            setup_commands: self.setup_commands,
+            tenant: self
+                .tenant
+                .unwrap_or_else(|| crate::tenant::DEFAULT_TENANT.to_string()),
+            labels: self.labels,
+            env: self.env,
+            limits: self.limits,
+            mounts: self.mounts,
+            dns: self.dns,
+            dns_search: self.dns_search,
+            extra_hosts: self.extra_hosts,
+            shell: self.shell,
+            shell_init: self.shell_init,
+            tools: self.tools,
+            repo: self.repo,
+            hooks: self.hooks,
+            observers: self.observers,
+            terminal_size: self.terminal_size,
+            verify_command: self.verify_command,
+            output_filters: self.output_filters,
+            max_commands: self.max_commands,
+            blocked_commands: self.blocked_commands,
+            restart_policy: self.restart_policy,
+            restarts: 0,
+            time_limit: self.time_limit,
+            runtime,
+            status: SandboxStatus::Created,
+            permits: Vec::new(),
+            input: None,
+            output_receiver: None,
+            resize_terminal: None,
+            markers: Markers::random(),
+            output_truncated: Arc::new(AtomicBool::new(false)),
+            session_closed: Arc::new(AtomicBool::new(false)),
+            start_time: None,
+            store: self.store,
+            session_view: Arc::new(RwLock::new(view.clone())),
+            view,
+            session_pid: None,
+            paused: false,
+            kernel_started: false,
+        })
+    }
+}
@@ -0,0 +1,143 @@
+//! Filters scrubbing the output of commands before it is returned and recorded.
+//!
+//! Terminal noise costs tokens to whoever reads the trajectory and tells them nothing:
+//! progress bars redrawn hundreds of times with carriage returns, base64 blobs dumped by
+//! `cat` or in JSON, and the same line printed over and over. Operators enable the
+//! filters in the `[output_filters]` section of the server configuration:
+//!
+//! ```toml
+//! [output_filters]
+//! collapse_progress = true
+//! strip_base64 = 200
+//! max_repeated_lines = 5
+//! ```
+//!
+//! Session, streamed and standalone commands run through the API are filtered. The
+//! chunks of a streamed command are sent as they are printed, only its recorded result
+//! is filtered. Raw output, verify commands and kernel executions are left as they are.
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use super::types::CommandResult;
+
+/// Shortest line of a base64 blob wrapped over several lines, `base64` wraps at 76
+const MIN_WRAPPED_BASE64_LINE: usize = 60;
+
+lazy_static! {
+    /// Run of base64, possibly wrapped over lines of at least [`MIN_WRAPPED_BASE64_LINE`]
+    /// characters
+    static ref BASE64: Regex = Regex::new(&format!(
+        r"(?:[A-Za-z0-9+/]{{{},}}\r?\n)*[A-Za-z0-9+/]+={{0,2}}",
+        MIN_WRAPPED_BASE64_LINE
+    ))
+    .unwrap();
+}
+
+/// Output filters section of the server configuration. Every filter is disabled unless
+/// set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputFilters {
+    /// Keeps the last state of lines redrawn with carriage returns, such as the progress
+    /// bars of pip, apt or curl
+    pub collapse_progress: bool,
+    /// Replaces base64 blobs of at least this many characters, newlines excluded, with a
+    /// placeholder giving their size
+    pub strip_base64: Option<usize>,
+    /// Keeps at most this many consecutive identical lines, followed by a line counting
+    /// the others
+    pub max_repeated_lines: Option<usize>,
+}
+
+impl OutputFilters {
+    /// Checks the configured sizes.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.strip_base64 == Some(0) || self.max_repeated_lines == Some(0) {
+            anyhow::bail!("Output filter sizes must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.collapse_progress && self.strip_base64.is_none() && self.max_repeated_lines.is_none()
+    }
+
+    /// Filters the output: progress bars are collapsed first, so the lines they leave can
+    /// count as repeated.
+    pub fn apply(&self, output: &str) -> String {
+        let mut output = output.to_string();
+        if self.collapse_progress {
+            output = collapse_progress(&output);
+        }
+        if let Some(min) = self.strip_base64 {
+            output = strip_base64(&output, min);
+        }
+        if let Some(max) = self.max_repeated_lines {
+            output = cap_repeated_lines(&output, max);
+        }
+        output
+    }
+
+    /// Filters the output, stdout and stderr of a result. The raw output is kept as the
+    /// terminal wrote it.
+    pub fn apply_to(&self, result: &mut CommandResult) {
+        if self.is_empty() {
+            return;
+        }
+        result.output = self.apply(&result.output);
+        result.stdout = self.apply(&result.stdout);
+        result.stderr = self.apply(&result.stderr);
+    }
+}
+
+/// Keeps the text after the last carriage return of every line.
+fn collapse_progress(output: &str) -> String {
+    output
+        .split('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            line.rsplit('\r').find(|part| !part.is_empty()).unwrap_or("")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn strip_base64(output: &str, min: usize) -> String {
+    BASE64
+        .replace_all(output, |caps: &Captures| {
+            let blob = &caps[0];
+            let size = blob.chars().filter(|c| !c.is_whitespace()).count();
+            match size >= min {
+                true => format!("[{} characters of base64 stripped]", size),
+                false => blob.to_string(),
+            }
+        })
+        .into_owned()
+}
+
+fn cap_repeated_lines(output: &str, max: usize) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous: Option<&str> = None;
+    let mut repeats = 0;
+    let flush = |lines: &mut Vec<String>, repeats: usize| {
+        if repeats > max {
+            lines.push(format!("[previous line repeated {} more times]", repeats - max));
+        }
+    };
+    for line in output.split('\n') {
+        if previous == Some(line) {
+            repeats += 1;
+            if repeats <= max {
+                lines.push(line.to_string());
+            }
+            continue;
+        }
+        flush(&mut lines, repeats);
+        lines.push(line.to_string());
+        previous = Some(line);
+        repeats = 1;
+    }
+    flush(&mut lines, repeats);
+    lines.join("\n")
+}
@@ -0,0 +1,237 @@
+//! Lifecycle hooks.
+//!
+//! Hooks run at points of the life of a sandbox: once it started, before and after each
+//! command executed through the API, and when it stops, while its container still runs.
+//! A failing start hook fails the start, and a failing `before_exec` hook rejects the
+//! command before it runs. Failures of the other hooks are only logged.
+//!
+//! Operators register hook commands and a webhook in the `[hooks]` section of the server
+//! configuration, or their own [`Hook`]s when embedding the server.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::shell::Shell;
+use super::types::{CommandResult, Error, Result};
+use super::Sandbox;
+use crate::task::shell_quote;
+
+/// Code run at points of the life of a sandbox. Every method does nothing by default.
+#[async_trait]
+pub trait Hook: Send + Sync {
+    /// Called once the sandbox started, after its setup commands.
+    async fn on_start(&self, _sandbox: &mut Sandbox) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before a command runs. An error rejects the command.
+    async fn before_exec(&self, _sandbox: &mut Sandbox, _command: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after a command ran, with its result.
+    async fn after_exec(
+        &self,
+        _sandbox: &mut Sandbox,
+        _command: &str,
+        _result: &CommandResult,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the sandbox stops, before its container is removed.
+    async fn on_stop(&self, _sandbox: &mut Sandbox) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Hooks section of the server configuration.
+///
+/// Commands run standalone in the container with POSIX sh, in order, with
+/// `SOS_SANDBOX_ID` set, `SOS_COMMAND` for the exec hooks and `SOS_EXIT_CODE` after a
+/// command. The webhook gets a JSON [`HookEvent`] at every point.
+///
+/// ```toml
+/// [hooks]
+/// on_start = ["pip config set global.index-url http://cache:3141/root/pypi"]
+/// on_stop = ["tar -czf /artifacts/$SOS_SANDBOX_ID.tgz /workspace"]
+/// webhook = "http://127.0.0.1:9000/sos-hooks"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub on_start: Vec<String>,
+    pub before_exec: Vec<String>,
+    pub after_exec: Vec<String>,
+    pub on_stop: Vec<String>,
+    /// URL every hook event is POSTed to, with the `server` feature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+impl HooksConfig {
+    /// Hooks of the configuration, the commands before the webhook.
+    pub fn hooks(&self) -> Vec<Arc<dyn Hook>> {
+        let mut hooks: Vec<Arc<dyn Hook>> = Vec::new();
+        let has_commands = !(self.on_start.is_empty()
+            && self.before_exec.is_empty()
+            && self.after_exec.is_empty()
+            && self.on_stop.is_empty());
+        if has_commands {
+            hooks.push(Arc::new(CommandHook {
+                on_start: self.on_start.clone(),
+                before_exec: self.before_exec.clone(),
+                after_exec: self.after_exec.clone(),
+                on_stop: self.on_stop.clone(),
+            }));
+        }
+        #[cfg(feature = "server")]
+        if let Some(url) = &self.webhook {
+            hooks.push(Arc::new(WebhookHook {
+                url: url.clone(),
+                http: reqwest::Client::new(),
+            }));
+        }
+        hooks
+    }
+}
+
+/// Runs commands in the container of the sandbox.
+pub struct CommandHook {
+    pub on_start: Vec<String>,
+    pub before_exec: Vec<String>,
+    pub after_exec: Vec<String>,
+    pub on_stop: Vec<String>,
+}
+
+impl CommandHook {
+    async fn run(
+        &self,
+        sandbox: &mut Sandbox,
+        commands: &[String],
+        vars: &[(&str, String)],
+    ) -> Result<()> {
+        let mut env = format!("export SOS_SANDBOX_ID={}; ", shell_quote(&sandbox.id));
+        for (name, value) in vars {
+            env.push_str(&format!("export {}={}; ", name, shell_quote(value)));
+        }
+        for command in commands {
+            let result = sandbox
+                .exec_standalone_with(Shell::Sh, format!("{}{}", env, command))
+                .await?;
+            if result.exit_code != 0 {
+                return Err(Error::HookFailed(format!(
+                    "{} exited with {}: {}",
+                    command,
+                    result.exit_code,
+                    result.output.trim()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Hook for CommandHook {
+    async fn on_start(&self, sandbox: &mut Sandbox) -> Result<()> {
+        self.run(sandbox, &self.on_start, &[]).await
+    }
+
+    async fn before_exec(&self, sandbox: &mut Sandbox, command: &str) -> Result<()> {
+        let vars = [("SOS_COMMAND", command.to_string())];
+        self.run(sandbox, &self.before_exec, &vars).await
+    }
+
+    async fn after_exec(
+        &self,
+        sandbox: &mut Sandbox,
+        command: &str,
+        result: &CommandResult,
+    ) -> Result<()> {
+        let vars = [
+            ("SOS_COMMAND", command.to_string()),
+            ("SOS_EXIT_CODE", result.exit_code.to_string()),
+        ];
+        self.run(sandbox, &self.after_exec, &vars).await
+    }
+
+    async fn on_stop(&self, sandbox: &mut Sandbox) -> Result<()> {
+        self.run(sandbox, &self.on_stop, &[]).await
+    }
+}
+
+/// Body of the requests of [`WebhookHook`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookEvent {
+    /// `start`, `before_exec`, `after_exec` or `stop`
+    pub hook: String,
+    pub sandbox_id: String,
+    pub tenant: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+}
+
+/// POSTs a [`HookEvent`] to a URL. A failed request or a response other than 2xx is a
+/// failure of the hook. Built with the `server` feature, which brings the HTTP client.
+#[cfg(feature = "server")]
+pub struct WebhookHook {
+    pub url: String,
+    pub http: reqwest::Client,
+}
+
+#[cfg(feature = "server")]
+impl WebhookHook {
+    async fn send(
+        &self,
+        hook: &str,
+        sandbox: &Sandbox,
+        command: Option<&str>,
+        exit_code: Option<i64>,
+    ) -> Result<()> {
+        let event = HookEvent {
+            hook: hook.to_string(),
+            sandbox_id: sandbox.id.clone(),
+            tenant: sandbox.tenant.clone(),
+            command: command.map(str::to_string),
+            exit_code,
+        };
+        self.http
+            .post(&self.url)
+            .json(&event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::HookFailed(format!("Webhook {}: {}", hook, e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl Hook for WebhookHook {
+    async fn on_start(&self, sandbox: &mut Sandbox) -> Result<()> {
+        self.send("start", sandbox, None, None).await
+    }
+
+    async fn before_exec(&self, sandbox: &mut Sandbox, command: &str) -> Result<()> {
+        self.send("before_exec", sandbox, Some(command), None).await
+    }
+
+    async fn after_exec(
+        &self,
+        sandbox: &mut Sandbox,
+        command: &str,
+        result: &CommandResult,
+    ) -> Result<()> {
+        self.send("after_exec", sandbox, Some(command), Some(result.exit_code))
+            .await
+    }
+
+    async fn on_stop(&self, sandbox: &mut Sandbox) -> Result<()> {
+        self.send("stop", sandbox, None, None).await
+    }
+}
@@ -1,10 +1,19 @@
-use super::shell::{PS1_MARKER, PS2_MARKER, EXIT_MARKER};
+use super::shell::Markers;
+use super::types::OutputChunk;
 use bytes::Bytes;
-use futures::{StreamExt, channel::mpsc::UnboundedReceiver};
+use futures::{StreamExt, channel::mpsc::Receiver};
 use strip_ansi_escapes::strip_str;
 use thiserror::Error;
 use tokio::time::{self, Duration, Instant, sleep};
 
+/// Chunks of session output buffered between the container and the reader. Once the
+/// buffer is full, the output is held back in the terminal of the session until read,
+/// so no output and no marker is ever dropped.
+pub const OUTPUT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Most output kept for a single command, in bytes. Past it, the oldest output is dropped.
+pub const MAX_COMMAND_OUTPUT: usize = 8 * 1024 * 1024;
+
 #[derive(Error, Debug)]
 pub enum ReadError {
     #[error("Overall timeout reached while waiting for output")]
@@ -13,13 +22,27 @@ pub enum ReadError {
     StreamClosed,
 }
 
+/// Session output read by [`read_stream_until_idle`].
+pub struct SessionOutput {
+    /// Output with the ANSI escape sequences stripped
+    pub text: String,
+    /// Output as the terminal wrote it, escape sequences included
+    pub raw: String,
+    /// Whether the beginning of the output was dropped, see [`trim_output`]
+    pub truncated: bool,
+}
+
+/// Reads the session output until the expected markers are seen or it goes idle.
 pub async fn read_stream_until_idle(
-    receiver: &mut UnboundedReceiver<Bytes>,
+    receiver: &mut Receiver<Bytes>,
+    markers: &Markers,
     overall_timeout: f64,
     idle_timeout: f64,
     short_circuit_after_n_markers: usize,
-) -> Result<String, ReadError> {
+) -> Result<SessionOutput, ReadError> {
     let mut accumulated = String::new();
+    let mut raw = String::new();
+    let mut truncated = false;
     let start = Instant::now();
 
     let mut markers_seen = 0;
@@ -31,13 +54,25 @@ pub async fn read_stream_until_idle(
 
         match time::timeout(Duration::from_secs_f64(idle_timeout), receiver.next()).await {
             Ok(Some(chunk)) => {
-                let new_chunk = strip_str(String::from_utf8_lossy(&chunk).to_string());
+                let raw_chunk = String::from_utf8_lossy(&chunk);
+                raw += &raw_chunk;
+                trim_output(&mut raw);
+                let new_chunk = strip_ansi(&raw_chunk);
                 accumulated += &new_chunk;
+                if trim_output(&mut accumulated) {
+                    truncated = true;
+                    // Recount the markers kept from the previous chunks, some were dropped
+                    let previous = accumulated.len().saturating_sub(new_chunk.len());
+                    last_count = markers
+                        .output_regex
+                        .find_iter(&accumulated[..previous])
+                        .count();
+                }
                 // We can't just naively check for markers and break early here as we could have multiple outputs
                 // split across multiple chunks. This normally happens if the command was multiline. To avoid
                 // having to rely on the idle timeout only to check for markers, we use the number of newlines
                 // in the input command as a hint to how many ouputs we should expect.
-                let current_count = OUTPUT_MARKER_REGEX.find_iter(&accumulated).count();
+                let current_count = markers.output_regex.find_iter(&accumulated).count();
                 if current_count > last_count {
                     markers_seen += current_count - last_count; // More than one marker per chunk is possible
                     last_count = current_count;
@@ -52,7 +87,7 @@ pub async fn read_stream_until_idle(
             }
             Err(_) => {
                 // Idle timeout
-                if OUTPUT_MARKER_REGEX.is_match(&accumulated) {
+                if markers.output_regex.is_match(&accumulated) {
                     break;
                 }
                 // Micro-poll for quick checks
@@ -60,29 +95,200 @@ pub async fn read_stream_until_idle(
             }
         }
     }
-    Ok(accumulated)
+    Ok(SessionOutput {
+        // Line endings split across chunks
+        text: accumulated.replace("\r\n", "\n"),
+        raw,
+        truncated,
+    })
 }
 
-pub fn strip_markers_and_extract_exit_code(output: &str) -> (String, i64, bool) {
+/// Splits session output into [`OutputChunk`]s as it arrives, a line at a time, so
+/// markers split across reads are still recognized.
+pub struct ChunkParser {
+    markers: Markers,
+    /// Lines of the command, dropped from the output of shells echoing their input
+    echo: Vec<String>,
+    /// Output after the last complete line
+    pending: String,
+}
+
+impl ChunkParser {
+    pub fn new(markers: Markers, echo: Vec<String>) -> Self {
+        ChunkParser {
+            markers,
+            echo,
+            pending: String::new(),
+        }
+    }
+
+    /// Chunks of the lines completed by `output`.
+    pub fn push(&mut self, output: &str) -> Vec<OutputChunk> {
+        self.pending += &strip_ansi(output);
+        let mut chunks = Vec::new();
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            self.parse_line(&line, &mut chunks);
+        }
+        chunks
+    }
+
+    /// Chunks of the incomplete last line, unless it could be the start of a marker.
+    pub fn flush(&mut self) -> Vec<OutputChunk> {
+        let mut chunks = Vec::new();
+        if !self.pending.contains('#') {
+            let line = std::mem::take(&mut self.pending);
+            self.parse_line(&line, &mut chunks);
+        }
+        chunks
+    }
+
+    fn parse_line(&self, line: &str, chunks: &mut Vec<OutputChunk>) {
+        // The TTY turns newlines into `\r\n`
+        let line = line.replace(&self.markers.ps2, "").replace("\r\n", "\n");
+        if line.is_empty() || self.echo.iter().any(|input| input == line.trim_end()) {
+            return;
+        }
+        // The stderr tagger marks the start of stderr lines, stdout without a newline may
+        // come before it
+        match line.find(&self.markers.err) {
+            Some(idx) => {
+                self.parse_text(&line[..idx], OutputChunk::Stdout, chunks);
+                self.parse_text(&line[idx + self.markers.err.len()..], OutputChunk::Stderr, chunks);
+            }
+            None => self.parse_text(&line, OutputChunk::Stdout, chunks),
+        }
+    }
+
+    /// Text of one stream, split around the prompt and exit markers it holds.
+    fn parse_text(&self, text: &str, chunk: fn(String) -> OutputChunk, chunks: &mut Vec<OutputChunk>) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let prompt = self.markers.output_regex.captures(rest).map(|cap| {
+                let found = cap.get(0).expect("Regex matched");
+                (found.range(), OutputChunk::Marker(cap[1].parse().unwrap_or(-1)))
+            });
+            let exit = self.markers.exit_regex.captures(rest).map(|cap| {
+                let found = cap.get(0).expect("Regex matched");
+                // Along with the newline echo prints after it
+                let after = &rest[found.end()..];
+                let end = found.end() + after.len() - after.trim_start_matches(['\r', '\n']).len();
+                (found.start()..end, OutputChunk::Exit(cap[1].parse().unwrap_or(0)))
+            });
+            let Some((range, marker)) = [prompt, exit]
+                .into_iter()
+                .flatten()
+                .min_by_key(|(range, _)| range.start)
+            else {
+                chunks.push(chunk(rest.to_string()));
+                return;
+            };
+            if range.start > 0 {
+                chunks.push(chunk(rest[..range.start].to_string()));
+            }
+            chunks.push(marker);
+            rest = &rest[range.end..];
+        }
+    }
+}
+
+/// Strips the ANSI escape sequences of terminal output. Unlike [`strip_str`], the
+/// carriage returns progress bars redraw their line with are kept, for
+/// [`OutputFilters`](super::filter::OutputFilters) to collapse. The `\r\n` line endings of
+/// the TTY become `\n`.
+pub fn strip_ansi(output: &str) -> String {
+    output
+        .split('\r')
+        .map(strip_str)
+        .collect::<Vec<_>>()
+        .join("\r")
+        .replace("\r\n", "\n")
+}
+
+/// Drops the oldest output once it grew past twice [`MAX_COMMAND_OUTPUT`], keeping the
+/// most recent part: the markers the output is parsed with come last. Returns whether
+/// anything was dropped.
+pub fn trim_output(output: &mut String) -> bool {
+    if output.len() <= 2 * MAX_COMMAND_OUTPUT {
+        return false;
+    }
+    let mut cut = output.len() - MAX_COMMAND_OUTPUT;
+    while !output.is_char_boundary(cut) {
+        cut += 1;
+    }
+    output.drain(..cut);
+    true
+}
+
+/// Same as [`trim_output`] for raw output.
+pub fn trim_bytes(output: &mut Vec<u8>) -> bool {
+    if output.len() <= 2 * MAX_COMMAND_OUTPUT {
+        return false;
+    }
+    output.drain(..output.len() - MAX_COMMAND_OUTPUT);
+    true
+}
+
+/// Splits the raw session output into its stdout and stderr parts, in their original order
+/// within each stream. Stderr lines are the ones tagged with the ERR marker by the session
+/// shell. Prompt markers end up in the stderr part.
+pub fn split_streams(output: &str, markers: &Markers) -> (String, String) {
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut rest = output;
+    while let Some(idx) = rest.find(&markers.err) {
+        stdout.push_str(&rest[..idx]);
+        let line = &rest[idx + markers.err.len()..];
+        let end = line.find('\n').map_or(line.len(), |i| i + 1);
+        stderr.push_str(&line[..end]);
+        rest = &line[end..];
+    }
+    stdout.push_str(rest);
+    (stdout, stderr)
+}
+
+/// Drops the lines of the output repeating a line of the command, for shells echoing
+/// their input, see [`Shell::echoes_input`](super::shell::Shell::echoes_input).
+pub fn strip_echo(output: &str, cmd: &str) -> String {
+    let input: Vec<&str> = cmd.lines().map(str::trim_end).collect();
+    output
+        .lines()
+        .filter(|line| !input.contains(&line.trim_end()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Strips the markers from the session output. Returns the output, the exit code of the
+/// last command and, if the session exited, the status it exited with.
+pub fn strip_markers_and_extract_exit_code(
+    output: &str,
+    markers: &Markers,
+) -> (String, i64, Option<i64>) {
     let mut last_exit_code = -1i64;
-    // First remove PS2 markers
-    let mut cleaned = output.replace(&PS2_MARKER, "").to_string();
+    // First remove PS2 and stderr markers
+    let mut cleaned = output.replace(&markers.ps2, "").replace(&markers.err, "");
     // Then remove EXIT markers
-    let mut exit_marker_seen = false;
-    if let Some(idx) = cleaned.find(&EXIT_MARKER) {
-        exit_marker_seen = true;
-        // Find the last OUTPUT_MARKER_REGEX match after EXIT_MARKER
-        if let Some(last_marker) = OUTPUT_MARKER_REGEX.find_iter(&cleaned).last() {
+    let mut exit_status = None;
+    if let Some(idx) = cleaned.find(&markers.exit) {
+        exit_status = Some(
+            markers
+                .exit_regex
+                .captures(&cleaned[idx..])
+                .and_then(|cap| cap[1].parse::<i64>().ok())
+                .unwrap_or(0),
+        );
+        // Find the last PS1 marker match after the EXIT marker
+        if let Some(last_marker) = markers.output_regex.find_iter(&cleaned).last() {
             // Only include the last marker match itself (not all output up to it)
             cleaned = cleaned[..idx].to_string() + last_marker.as_str();
         } else {
-            // No marker after EXIT_MARKER, just cut at EXIT_MARKER
+            // No marker after the EXIT marker, just cut at it
             cleaned = cleaned[..idx].to_string();
         }
     }
 
     // Then strip output marker (PS1) and extract the exit code
-    let mut matches = OUTPUT_MARKER_REGEX.captures_iter(&cleaned);
+    let mut matches = markers.output_regex.captures_iter(&cleaned);
     while let Some(cap) = matches.next() {
         if let Some(code_str) = cap.get(1) {
             last_exit_code = code_str
@@ -92,19 +298,9 @@ pub fn strip_markers_and_extract_exit_code(output: &str) -> (String, i64, bool)
         }
     }
 
-    cleaned = OUTPUT_MARKER_REGEX.replace_all(&cleaned, "").to_string();
-    cleaned = cleaned.replace(&PS1_MARKER, "");
+    cleaned = markers.output_regex.replace_all(&cleaned, "").to_string();
+    cleaned = cleaned.replace(&markers.ps1, "");
 
     cleaned = cleaned.trim_end().to_string();
-    (cleaned, last_exit_code, exit_marker_seen)
-}
-
-use lazy_static::lazy_static;
-use regex::Regex;
-
-lazy_static! {
-    pub static ref OUTPUT_MARKER_REGEX: Regex = {
-        let pattern = format!(r"{}(\d+):", regex::escape(PS1_MARKER));
-        Regex::new(&pattern).expect("Invalid PS1 marker regex")
-    };
+    (cleaned, last_exit_code, exit_status)
 }
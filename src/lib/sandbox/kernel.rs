@@ -0,0 +1,173 @@
+//! Jupyter kernel run inside the container.
+//!
+//! The kernel is an `ipykernel` started in the background on the first execution, so the
+//! image needs `ipykernel` and `jupyter_client`. Each execution runs a small driver with
+//! `jupyter_client` as a standalone command: it sends the `execute_request`, collects the
+//! outputs published by the kernel until the `execute_reply` and prints them as JSON.
+//! The kernel keeps its state between executions.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use const_format::formatcp;
+use serde::{Deserialize, Serialize};
+use strip_ansi_escapes::strip_str;
+
+use super::types::{CommandResult, Error, Result};
+
+const KERNEL_DIR: &str = "/tmp/.sos-kernel";
+const CONNECTION_FILE: &str = formatcp!("{}/kernel.json", KERNEL_DIR);
+const PID_FILE: &str = formatcp!("{}/kernel.pid", KERNEL_DIR);
+const LOG_FILE: &str = formatcp!("{}/kernel.log", KERNEL_DIR);
+const DRIVER_FILE: &str = formatcp!("{}/driver.py", KERNEL_DIR);
+
+// Interrupts the kernel with SIGINT on timeout, as a notebook would, so it is usable again.
+const DRIVER: &str = r#"import base64, json, os, signal, sys
+from jupyter_client import BlockingKernelClient
+
+connection_file, pid_file, timeout, code = sys.argv[1:5]
+code = base64.b64decode(code).decode()
+client = BlockingKernelClient(connection_file=connection_file)
+client.load_connection_file()
+client.start_channels()
+client.wait_for_ready(timeout=30)
+
+outputs = []
+def on_output(msg):
+    kind, content = msg["msg_type"], msg["content"]
+    if kind == "stream":
+        outputs.append({"type": "stream", "name": content["name"], "text": content["text"]})
+    elif kind in ("execute_result", "display_data"):
+        outputs.append({"type": kind, "data": content["data"]})
+    elif kind == "error":
+        outputs.append({"type": "error", "ename": content["ename"], "evalue": content["evalue"], "traceback": content["traceback"]})
+
+try:
+    reply = client.execute_interactive(code, timeout=float(timeout), output_hook=on_output, allow_stdin=False)
+    reply = {"status": reply["content"]["status"], "execution_count": reply["content"].get("execution_count")}
+except TimeoutError:
+    with open(pid_file) as f:
+        os.kill(int(f.read()), signal.SIGINT)
+    reply = {"status": "timeout", "execution_count": None}
+reply["outputs"] = outputs
+print(json.dumps(reply))
+"#;
+
+/// Output published by the kernel while executing code. `data` maps MIME types to
+/// their content, binary content such as `image/png` being base64 encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KernelOutput {
+    Stream {
+        name: String,
+        text: String,
+    },
+    ExecuteResult {
+        data: HashMap<String, serde_json::Value>,
+    },
+    DisplayData {
+        data: HashMap<String, serde_json::Value>,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+        traceback: Vec<String>,
+    },
+}
+
+/// Reply to an execution. `status` is `ok`, `error`, `aborted`, or `timeout` when the
+/// kernel did not reply in time and was interrupted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KernelReply {
+    pub status: String,
+    pub execution_count: Option<i64>,
+    pub outputs: Vec<KernelOutput>,
+}
+
+impl KernelReply {
+    /// Text rendering of the outputs, recorded in the trajectory. Rich outputs are
+    /// rendered with their `text/plain` part, if any.
+    pub fn to_result(&self) -> CommandResult {
+        let mut output = String::new();
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        for item in &self.outputs {
+            let text = match item {
+                KernelOutput::Stream { name, text } => {
+                    match name.as_str() {
+                        "stderr" => stderr.push_str(text),
+                        _ => stdout.push_str(text),
+                    }
+                    text.clone()
+                }
+                KernelOutput::ExecuteResult { data } | KernelOutput::DisplayData { data } => {
+                    match data.get("text/plain").and_then(|text| text.as_str()) {
+                        Some(text) => {
+                            stdout.push_str(text);
+                            stdout.push('\n');
+                            format!("{}\n", text)
+                        }
+                        None => continue,
+                    }
+                }
+                KernelOutput::Error { traceback, .. } => {
+                    let text = strip_str(traceback.join("\n")) + "\n";
+                    stderr.push_str(&text);
+                    text
+                }
+            };
+            output.push_str(&text);
+        }
+        CommandResult {
+            output: output.trim_end().to_string(),
+            stdout: stdout.trim_end().to_string(),
+            stderr: stderr.trim_end().to_string(),
+            exit_code: (self.status != "ok") as i64,
+            exited: false,
+            truncated: false,
+            raw_output: None,
+        }
+    }
+}
+
+/// Builds the command that writes the driver and starts the kernel in the background,
+/// waiting for its connection file. POSIX sh.
+pub fn start_cmd() -> String {
+    format!(
+        "{{ mkdir -p {dir} && \
+         python3 -c 'import base64, sys; open(sys.argv[1], \"wb\").write(base64.b64decode(sys.argv[2]))' {driver} {script} && \
+         python3 -c 'import ipykernel, jupyter_client' && \
+         (nohup python3 -m ipykernel_launcher -f {conn} > {log} 2>&1 & echo $! > {pid}); }} || exit 1; \
+         i=0; while [ ! -s {conn} ] && [ $i -lt 100 ]; do sleep 0.1; i=$((i + 1)); done; \
+         [ -s {conn} ] || {{ cat {log} >&2; exit 1; }}",
+        dir = KERNEL_DIR,
+        driver = DRIVER_FILE,
+        script = BASE64.encode(DRIVER),
+        conn = CONNECTION_FILE,
+        log = LOG_FILE,
+        pid = PID_FILE,
+    )
+}
+
+/// Builds the command that executes `code` in the kernel and prints the reply.
+pub fn execute_cmd(code: &str, timeout: Duration) -> String {
+    format!(
+        "python3 {} {} {} {} {}",
+        DRIVER_FILE,
+        CONNECTION_FILE,
+        PID_FILE,
+        timeout.as_secs_f64(),
+        BASE64.encode(code),
+    )
+}
+
+/// Parses the reply printed by the driver, the last line of its output.
+pub fn parse_reply(result: &CommandResult) -> Result<KernelReply> {
+    result
+        .stdout
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| serde_json::from_str(line).ok())
+        .ok_or_else(|| Error::KernelFailed(result.output.trim().to_string()))
+}
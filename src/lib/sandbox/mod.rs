@@ -1,475 +1,1170 @@
+mod builder;
+mod filter;
+mod hooks;
 mod io;
+mod kernel;
+mod observer;
+mod patch;
+mod repo;
 mod shell;
+mod stream;
+mod tools;
 pub mod types;
+mod verifier;
+mod view;
 
-use std::{pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use shell::Markers;
 pub use types::{
-    CommandExecution, CommandResult, Error as SandboxError, Result, Status as SandboxStatus,
+    CommandExecution, CommandResult, CommandUsage, Error as SandboxError, Mount, NetworkRequest,
+    OutputChunk, PullProgress, ResourceLimits, ResourceUsage, RestartPolicy, Result,
+    Status as SandboxStatus, TerminalSize, Trajectory, UsageCounters, Verification,
 };
 
-use bollard::{
-    Docker,
-    container::LogOutput,
-    exec::{CreateExecOptions, StartExecOptions, StartExecResults},
-    query_parameters::RemoveContainerOptions,
-};
+pub use builder::SandboxBuilder;
+pub use filter::OutputFilters;
+pub use hooks::{CommandHook, Hook, HookEvent, HooksConfig};
+#[cfg(feature = "server")]
+pub use hooks::WebhookHook;
+pub use kernel::{KernelOutput, KernelReply};
+pub use observer::Observer;
+pub use patch::changed_files;
+pub use repo::{DEFAULT_REPO_DIR, RepoAuth, RepoSpec};
+pub use shell::Shell;
+pub use tools::{ToolBundle, ToolFile};
+pub use view::SandboxView;
+
+/// Shortest interval between two pull progress reports.
+pub const PULL_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of times a sandbox is restarted after crashing, see [`RestartPolicy::OnCrash`].
+pub const MAX_RESTARTS: u32 = 3;
+
 use bytes::Bytes;
-use futures::{StreamExt, channel::mpsc::UnboundedReceiver};
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt, channel::mpsc::Receiver};
+use regex::Regex;
 use tokio::sync::Mutex;
-use tokio::time::Instant;
+use tokio::time::{self, Instant};
 use tokio::{io::AsyncWriteExt, sync::OwnedSemaphorePermit};
-use tracing::error;
+use tracing::{debug, error, info, warn};
+
+use crate::runtime::{Attached, ContainerRuntime, ContainerSpec, ExecOutput, Resize};
+use crate::store::{TrajectoryEvent, TrajectoryStore};
 pub struct Sandbox {
     /// UUID for the sandbox
     pub id: String,
-    /// Docker image to use for the sandbox container
+    /// Image to use for the sandbox container
     pub image: String,
-    /// Commands to run on startup
-    pub setup_commands: String,
+    /// Commands to run on startup, each in its own exec
+    pub setup_commands: Vec<String>,
+    /// Namespace (tenant) owning the sandbox
+    pub tenant: String,
+    /// Free-form labels used to select groups of sandboxes
+    pub labels: HashMap<String, String>,
+    /// Environment variables set in the container
+    pub env: HashMap<String, String>,
+    /// Resource limits of the container
+    pub limits: ResourceLimits,
+    /// Host paths mounted into the container
+    pub mounts: Vec<Mount>,
+    /// DNS servers of the container, instead of the runtime's
+    pub dns: Vec<String>,
+    /// DNS search domains of the container
+    pub dns_search: Vec<String>,
+    /// `host:ip` entries added to `/etc/hosts` of the container
+    pub extra_hosts: Vec<String>,
+    /// Shell running the session and the standalone commands
+    pub shell: Shell,
+    /// Lines run by the session shell after configuring it: aliases, functions, PATH
+    /// additions or hardening
+    pub shell_init: Vec<String>,
+    /// Tool bundles installed on start and put on the `PATH`
+    pub tools: Vec<ToolBundle>,
+    /// Repository cloned on start, before the setup commands
+    pub repo: Option<RepoSpec>,
+    /// Hooks run at start, around commands executed with [`Sandbox::exec`] and at stop
+    pub hooks: Vec<Arc<dyn Hook>>,
+    /// Notified of the commands and status changes, after the trajectory is recorded
+    pub observers: Vec<Arc<dyn Observer>>,
+    /// Size of the session terminal, the runtime's default when unset
+    pub terminal_size: Option<TerminalSize>,
+    /// Command run standalone by `verify` to score the sandbox
+    pub verify_command: Option<String>,
+    /// Filters applied to the output of the commands run through [`Sandbox::exec`] and
+    /// [`Sandbox::exec_session_stream`]
+    pub output_filters: OutputFilters,
+    /// Number of commands recorded in the trajectory after which the sandbox is
+    /// [`SandboxStatus::Exhausted`]
+    pub max_commands: Option<usize>,
+    /// Commands matching any of these patterns are refused by [`Sandbox::exec`] and
+    /// [`Sandbox::exec_session_stream`] instead of being run
+    pub blocked_commands: Vec<Regex>,
+    /// What the sandbox does once its container or session shell died
+    pub restart_policy: RestartPolicy,
+    /// Lifetime after which the server stops the sandbox, instead of its default timeout
+    pub time_limit: Option<Duration>,
     /// Instant when the sandbox and container were started
     pub start_time: Option<Instant>,
+    /// Store the trajectory is persisted to, if any
+    pub store: Option<Arc<TrajectoryStore>>,
     /// Current status of the sandbox
     status: SandboxStatus,
-    /// Semaphore permit for the sandbox. Used to limit the number of concurrent sandboxes.
-    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    /// Semaphore permits for the sandbox. Used to limit the number of concurrent sandboxes
+    /// (globally and per tenant).
+    permits: Vec<OwnedSemaphorePermit>,
     /// Input stream for the sandbox (stdin)
     input: Option<Mutex<Pin<Box<dyn tokio::io::AsyncWrite + Send>>>>,
     /// Output stream for the sandbox (stdout/stderr)
-    output_receiver: Option<Mutex<UnboundedReceiver<Bytes>>>,
-    /// Docker client
-    docker: Arc<Docker>,
-    /// Trajectory of commands executed in the sandbox
-    trajectory: Vec<CommandExecution>,
-    /// Last standalone command exit code
-    last_standalone_exit_code: Option<i64>,
+    output_receiver: Option<Mutex<Receiver<Bytes>>>,
+    /// Resizes the session terminal
+    resize_terminal: Option<Resize>,
+    /// Markers printed by the session shell, random per sandbox
+    markers: Markers,
+    /// Set when session output was dropped, until the next command result reports it
+    output_truncated: Arc<AtomicBool>,
+    /// Set when the output of the session ended, as the session shell or the container died
+    session_closed: Arc<AtomicBool>,
+    /// Number of times the sandbox was restarted after crashing
+    restarts: u32,
+    /// Runtime the container runs on
+    runtime: Arc<dyn ContainerRuntime>,
+    /// Status and trajectory, readable without locking the sandbox
+    view: SandboxView,
+    /// View the output of the session is pushed to. Shared with the task reading the
+    /// session, so a pooled sandbox hands its session over to the sandbox claiming it.
+    session_view: Arc<RwLock<SandboxView>>,
+    /// PID of the session shell inside the container (leader of the agent's process session)
+    session_pid: Option<u32>,
+    /// Whether [`Sandbox::freeze`] paused the whole container with the runtime
+    paused: bool,
+    /// Whether the Jupyter kernel was started, see [`kernel`]
+    kernel_started: bool,
 }
 
 impl Sandbox {
-    pub fn new(image: String, setup_commands: String, docker: Arc<Docker>) -> Self {
-        use uuid::Uuid;
+    /// Settings of a new sandbox, created with [`SandboxBuilder::build`].
+    pub fn builder() -> SandboxBuilder {
+        SandboxBuilder::default()
+    }
 
-        let id = Uuid::new_v4().to_string();
+    pub fn get_status(&self) -> &SandboxStatus {
+        &self.status
+    }
 
-        Sandbox {
-            id,
-            image,
-            setup_commands,
-            docker,
-            status: SandboxStatus::Created,
-            permit: None,
-            input: None,
-            output_receiver: None,
-            start_time: None,
-            trajectory: Vec::new(),
-            last_standalone_exit_code: None,
+    fn set_status(&mut self, status: SandboxStatus) {
+        self.view.set_status(&status);
+        for observer in &self.observers {
+            observer.on_status_change(&self.id, &status);
         }
+        self.status = status;
     }
 
-    pub fn get_status(&self) -> &SandboxStatus {
-        &self.status
+    /// Status and trajectory of the sandbox, which can be read while it is locked
+    pub fn view(&self) -> &SandboxView {
+        &self.view
     }
 
     /// Get the trajectory of commands executed in this sandbox
-    pub fn get_trajectory(&self) -> &[CommandExecution] {
-        &self.trajectory
+    pub fn get_trajectory(&self) -> Vec<CommandExecution> {
+        self.view.snapshot().commands
     }
 
     /// Get the number of commands executed
     pub fn command_count(&self) -> usize {
-        self.trajectory.len()
+        self.view.command_count()
+    }
+
+    /// Get the results of the verify command
+    pub fn get_verifications(&self) -> Vec<Verification> {
+        self.view.snapshot().verifications
     }
 
     /// Get the last standalone command exit code
     pub fn get_last_standalone_exit_code(&self) -> Option<i64> {
-        self.last_standalone_exit_code
+        self.view.last_standalone_exit_code()
+    }
+
+    /// Copy of the commands and verifications of the sandbox
+    pub fn snapshot(&self) -> Trajectory {
+        self.view.snapshot()
     }
 
     /// Format the trajectory as a human-readable string
     pub fn format_trajectory(&self) -> String {
-        let mut output = String::new();
-        for cmd in self.trajectory.iter() {
-            output.push_str(&format!("$ {}\n", cmd.command));
-
-            match &cmd.result {
-                Some(result) => {
-                    if !result.output.is_empty() {
-                        output.push_str(&result.output);
-                        output.push('\n');
-                    }
-                }
-                None => {
-                    output.push_str("Status: Command started but no result recorded\n");
-                }
+        self.snapshot().format()
+    }
+
+    /// Appends the event to the trajectory store. Failures are logged, the sandbox
+    /// keeps running without persistence.
+    async fn persist(&self, event: TrajectoryEvent) {
+        if let Some(store) = &self.store
+            && let Err(e) = store.append(&self.tenant, &self.id, &event).await
+        {
+            warn!(sandbox_id = %self.id, error = %e, "Failed to persist trajectory");
+        }
+    }
+
+    /// The trajectory recorder, then the observers of the sandbox.
+    fn all_observers(&self) -> Vec<Arc<dyn Observer>> {
+        let mut observers: Vec<Arc<dyn Observer>> = vec![Arc::new(observer::Recorder)];
+        observers.extend(self.observers.iter().cloned());
+        observers
+    }
+
+    async fn command_started(&self, execution: &CommandExecution) {
+        for observer in self.all_observers() {
+            observer.on_command_start(self, execution).await;
+        }
+    }
+
+    async fn command_ended(&self, execution: &CommandExecution) {
+        for observer in self.all_observers() {
+            observer.on_command_end(self, execution).await;
+        }
+    }
+
+    /// Executes code in the Jupyter kernel of the sandbox, starting it first if needed.
+    /// The execution is recorded in the trajectory like a session command, with the
+    /// text rendering of its outputs. Code matching `blocked_commands` is refused.
+    pub async fn exec_kernel(&mut self, code: String, timeout: Duration) -> Result<KernelReply> {
+        if let SandboxStatus::Exhausted(_) = self.status {
+            return Err(self.budget_exhausted());
+        }
+        self.check_blocked(&code).await?;
+        if !self.kernel_started {
+            let result = self.exec_standalone_with(Shell::Sh, kernel::start_cmd()).await?;
+            if result.exit_code != 0 {
+                return Err(SandboxError::KernelFailed(result.output.trim().to_string()));
             }
+            self.kernel_started = true;
         }
 
-        output
+        let usage_before = self.usage_counters().await;
+        let execution_start = Instant::now();
+        let mut command_execution = CommandExecution {
+            command: code.clone(),
+            timestamp: Utc::now(),
+            result: None,
+            duration: None,
+            usage: None,
+            blocked: None,
+        };
+        self.command_started(&command_execution).await;
+        let result = self
+            .exec_standalone_with(Shell::Sh, kernel::execute_cmd(&code, timeout))
+            .await?;
+        let reply = kernel::parse_reply(&result)?;
+
+        command_execution.result = Some(reply.to_result());
+        command_execution.duration = Some(execution_start.elapsed());
+        command_execution.usage = self.command_usage(usage_before).await;
+        self.command_ended(&command_execution).await;
+        self.spend_budget();
+        Ok(reply)
     }
 
-    pub async fn start(&mut self, permit: OwnedSemaphorePermit) -> Result<()> {
-        if !matches!(self.status, SandboxStatus::Created) {
-            return Err(SandboxError::AlreadyStarted);
+    /// Moves the sandbox to [`SandboxStatus::Exhausted`] once the commands recorded in its
+    /// trajectory reach `max_commands`. Called after recording one.
+    fn spend_budget(&mut self) {
+        let spent = self
+            .max_commands
+            .is_some_and(|max| self.view.command_count() >= max);
+        if let (true, SandboxStatus::Started(cid)) = (spent, &self.status) {
+            let cid = cid.clone();
+            info!(sandbox_id = %self.id, "Command budget exhausted");
+            self.set_status(SandboxStatus::Exhausted(cid));
         }
+    }
 
-        self.pull_image_if_missing().await?;
-        let container_id = self.create_and_start_container().await?;
-        self.status = SandboxStatus::Started(container_id.clone());
+    fn budget_exhausted(&self) -> SandboxError {
+        SandboxError::BudgetExhausted(self.max_commands.unwrap_or_default())
+    }
 
-        // Run initial shell setup
-        self.run_setup_commands().await?;
-        self.attach_and_configure_shell().await?;
+    /// Handles a crash noticed since the last command: the output of the session ended
+    /// while the sandbox was started. Fails unless the sandbox was restarted, in which case
+    /// the next command runs in the new session. The server also checks idle sandboxes
+    /// periodically, so their status shows the crash.
+    pub async fn check_session(&mut self) -> Result<()> {
+        let closed = self.session_closed.load(Ordering::Relaxed);
+        if !closed || !matches!(self.status, SandboxStatus::Started(_)) {
+            return Ok(());
+        }
+        match self.crash().await {
+            (_, true) => Ok(()),
+            (exit_code, false) => Err(crashed(exit_code, false)),
+        }
+    }
 
-        self.start_time = Some(Instant::now());
-        self.permit = Some(permit);
-        Ok(())
+    /// Turns a failure to write to or read from the session into a crash. Other errors
+    /// are returned as they are.
+    async fn session_failed(&mut self, e: SandboxError) -> SandboxError {
+        match e {
+            SandboxError::ContainerWriteFailed(_) | SandboxError::ContainerReadFailed(_) => {
+                let (exit_code, restarted) = self.crash().await;
+                crashed(exit_code, restarted)
+            }
+            e => e,
+        }
     }
 
-    async fn pull_image_if_missing(&mut self) -> Result<()> {
-        use bollard::query_parameters::CreateImageOptions;
-        use futures::TryStreamExt;
-
-        match self.docker.inspect_image(&self.image).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                // Image doesn't exist locally, pull it
-                let pull_options = Some(CreateImageOptions {
-                    from_image: Some(self.image.clone()),
-                    ..Default::default()
-                });
+    /// Moves the started sandbox to [`SandboxStatus::Crashed`], with the exit code of its
+    /// container when it stopped, then restarts it per its `restart_policy`: a container
+    /// still running gets a new session shell, one that died is replaced and set up again.
+    /// Returns the exit code and whether the sandbox was restarted.
+    async fn crash(&mut self) -> (Option<i64>, bool) {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid) => cid.clone(),
+            _ => return (None, false),
+        };
+        let (exit_code, running) = match self.runtime.exit_code(&cid).await {
+            Ok(None) => (None, true),
+            Ok(Some(exit_code)) => (Some(exit_code), false),
+            // The container is gone
+            Err(_) => (None, false),
+        };
+        warn!(
+            sandbox_id = %self.id,
+            exit_code = ?exit_code,
+            container_running = running,
+            "Sandbox crashed"
+        );
+        self.input = None;
+        self.output_receiver = None;
+        self.resize_terminal = None;
+        self.session_pid = None;
+        self.set_status(SandboxStatus::Crashed(cid.clone(), exit_code));
 
-                let mut pull_stream = self.docker.create_image(pull_options, None, None);
-                while let Some(_) = pull_stream.try_next().await? {
-                    // TODO: print progress
-                }
-                return Ok(());
+        if self.restart_policy == RestartPolicy::Never || self.restarts >= MAX_RESTARTS {
+            return (exit_code, false);
+        }
+        self.restarts += 1;
+        let restarted = match running {
+            true => {
+                self.set_status(SandboxStatus::Started(cid.clone()));
+                self.attach_and_configure_shell().await
+            }
+            false => self.restart_container(&cid).await,
+        };
+        match restarted {
+            Ok(()) => {
+                info!(sandbox_id = %self.id, restarts = self.restarts, "Sandbox restarted");
+                (exit_code, true)
+            }
+            Err(e) => {
+                warn!(sandbox_id = %self.id, error = %e, "Failed to restart sandbox");
+                let cid = match &self.status {
+                    SandboxStatus::Started(cid) => cid.clone(),
+                    _ => cid,
+                };
+                self.set_status(SandboxStatus::Crashed(cid, exit_code));
+                (exit_code, false)
             }
         }
     }
 
-    async fn create_and_start_container(&mut self) -> Result<String> {
-        use bollard::query_parameters::{
-            CreateContainerOptions, InspectContainerOptions, LogsOptions, StartContainerOptions,
+    /// Replaces the dead container of the sandbox with a new one, set up as on start. The
+    /// files of the old container are lost.
+    async fn restart_container(&mut self, cid: &str) -> Result<()> {
+        let _ = self.runtime.remove(cid).await;
+        self.kernel_started = false;
+        self.create_and_start_container().await?;
+        self.resolve_shell().await?;
+        self.install_tools().await?;
+        self.clone_repo().await?;
+        self.run_setup_commands().await?;
+        self.attach_and_configure_shell().await
+    }
+
+    /// Replaces the session shell that exited with a fresh one in the same container.
+    /// The previous session is hung up first.
+    pub async fn reopen_session(&mut self) -> Result<()> {
+        let cid = match &self.status {
+            SandboxStatus::Exited(cid, _) => cid.clone(),
+            SandboxStatus::Started(_) | SandboxStatus::Frozen(_) => {
+                return Err(SandboxError::SessionNotExited);
+            }
+            SandboxStatus::Exhausted(_) => return Err(self.budget_exhausted()),
+            SandboxStatus::Crashed(_, exit_code) => return Err(crashed(*exit_code, false)),
+            _ => return Err(SandboxError::NotStarted),
         };
-        use bollard::secret::ContainerStateStatusEnum;
-
-        let config = bollard::models::ContainerCreateBody {
-            image: Some(self.image.clone()),
-            cmd: Some(vec!["sleep".to_string(), "infinity".to_string()]),
-            tty: Some(true),
-            open_stdin: Some(true),
-            attach_stdin: Some(true),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            ..Default::default()
+        if let Some(session_pid) = self.session_pid.take() {
+            self.exec_standalone_with(Shell::Sh, shell::hangup_cmd(session_pid))
+                .await?;
+        }
+        self.set_status(SandboxStatus::Started(cid));
+        self.attach_and_configure_shell().await?;
+        self.spend_budget();
+        Ok(())
+    }
+
+    /// Resizes the session terminal. Commands pick the new size up as on any terminal
+    /// resize.
+    pub async fn resize(&mut self, size: TerminalSize) -> Result<()> {
+        match &self.status {
+            SandboxStatus::Started(_) | SandboxStatus::Frozen(_) => {}
+            SandboxStatus::Exited(..) => return Err(SandboxError::AlreadyExited),
+            SandboxStatus::Exhausted(_) => return Err(self.budget_exhausted()),
+            _ => return Err(SandboxError::NotStarted),
+        }
+        let resize = self.resize_terminal.as_ref().ok_or(SandboxError::NotStarted)?;
+        resize(size).await?;
+        self.terminal_size = Some(size);
+        Ok(())
+    }
+
+    /// Runs the verify command standalone and records its verdict.
+    pub async fn verify(&mut self) -> Result<Verification> {
+        let command = self.verify_command.clone().ok_or(SandboxError::NoVerifier)?;
+        let result = self.exec_standalone_cmd(command).await?;
+        let (score, passed) = verifier::parse_verdict(&result.output, result.exit_code);
+
+        let verification = Verification {
+            timestamp: Utc::now(),
+            after_step: self.view.command_count(),
+            score,
+            passed,
+            output: result.output,
+            exit_code: result.exit_code,
         };
+        self.view.push_verification(verification.clone());
+        self.persist(TrajectoryEvent::Verification(verification.clone()))
+            .await;
+        Ok(verification)
+    }
 
-        let create_response = self
-            .docker
-            .create_container(None::<CreateContainerOptions>, config)
-            .await
-            .map_err(|e| SandboxError::StartContainerFailed {
-                message: e.to_string(),
-                exit_code: None,
-                logs: String::new(),
-            })?;
+    pub async fn start(&mut self, permits: Vec<OwnedSemaphorePermit>) -> Result<()> {
+        self.start_from(permits, None).await
+    }
 
-        self.status = SandboxStatus::Started(create_response.id.clone());
+    /// Starts the sandbox in the container of a warm sandbox, see [`crate::pool`].
+    /// Only the setup commands run, after the session shell is configured.
+    pub async fn start_warm(
+        &mut self,
+        permits: Vec<OwnedSemaphorePermit>,
+        warm: Sandbox,
+    ) -> Result<()> {
+        self.start_from(permits, Some(warm)).await
+    }
 
-        self.docker
-            .start_container(&create_response.id, None::<StartContainerOptions>)
-            .await
-            .map_err(|e| SandboxError::StartContainerFailed {
-                message: e.to_string(),
-                exit_code: None,
-                logs: String::new(),
-            })?;
-        let mut attempts = 0;
-        let max_attempts = 6; // ~3 seconds at 500ms intervals
-        let container_id = create_response.id.clone();
-        loop {
-            let inspect = self
-                .docker
-                .inspect_container(&container_id, None::<InspectContainerOptions>)
-                .await
-                .map_err(|e| SandboxError::StartContainerFailed {
-                    message: format!("Failed to inspect container: {}", e),
-                    exit_code: None,
-                    logs: String::new(),
-                })?;
-
-            if inspect.state.as_ref().and_then(|s| s.running) == Some(true) {
-                break; // Success
+    /// Creates the container and configures the session shell, without running the
+    /// setup commands. Used to warm up pooled sandboxes.
+    pub(crate) async fn prepare(&mut self) -> Result<()> {
+        self.pull_image_if_missing().await?;
+        let container_id = self.create_and_start_container().await?;
+        self.set_status(SandboxStatus::Started(container_id));
+        self.resolve_shell().await?;
+        self.attach_and_configure_shell().await
+    }
+
+    /// Marks the sandbox as waiting for capacity to start. Returns false if it was
+    /// already queued.
+    pub(crate) fn queue(&mut self) -> Result<bool> {
+        match self.status {
+            SandboxStatus::Created => {
+                self.set_status(SandboxStatus::Queued);
+                Ok(true)
             }
+            SandboxStatus::Queued => Ok(false),
+            _ => Err(SandboxError::AlreadyStarted),
+        }
+    }
 
-            if attempts >= max_attempts {
-                // Fetch logs for diagnostics
-                let mut log_stream = self.docker.logs(
-                    &container_id,
-                    Some(LogsOptions {
-                        stdout: true,
-                        stderr: true,
-                        tail: "all".to_string(),
-                        ..Default::default()
-                    }),
-                );
-
-                let mut logs = String::new();
-                while let Some(item) = log_stream.next().await {
-                    match item.map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))? {
-                        LogOutput::StdOut { message } => logs += &String::from_utf8_lossy(&message),
-                        LogOutput::StdErr { message } => logs += &String::from_utf8_lossy(&message),
-                        _ => {}
-                    }
+    /// Takes the sandbox out of the queue, once it got capacity or failed to start.
+    pub(crate) fn dequeue(&mut self) {
+        if matches!(self.status, SandboxStatus::Queued) {
+            self.set_status(SandboxStatus::Created);
+        }
+    }
+
+    async fn start_from(
+        &mut self,
+        permits: Vec<OwnedSemaphorePermit>,
+        warm: Option<Sandbox>,
+    ) -> Result<()> {
+        if !matches!(self.status, SandboxStatus::Created) {
+            return Err(SandboxError::AlreadyStarted);
+        }
+
+        match warm {
+            Some(warm) => {
+                self.set_status(warm.status);
+                // The session of the warm sandbox now logs to this one
+                *warm.session_view.write().unwrap() = self.view.clone();
+                self.session_view = warm.session_view;
+                self.shell = warm.shell;
+                self.input = warm.input;
+                self.output_receiver = warm.output_receiver;
+                self.resize_terminal = warm.resize_terminal;
+                self.markers = warm.markers;
+                self.output_truncated = warm.output_truncated;
+                self.session_closed = warm.session_closed;
+                self.session_pid = warm.session_pid;
+                if let Some(size) = self.terminal_size {
+                    self.resize(size).await?;
                 }
+                self.install_tools().await?;
+                self.clone_repo().await?;
+                self.run_setup_commands().await?;
+            }
+            None => {
+                self.pull_image_if_missing().await?;
+                let container_id = self.create_and_start_container().await?;
+                self.set_status(SandboxStatus::Started(container_id.clone()));
+                self.resolve_shell().await?;
 
-                let exit_code = inspect.state.clone().and_then(|s| s.exit_code);
-                let error_msg = inspect
-                    .state
-                    .clone()
-                    .and_then(|s| s.error.clone())
-                    .unwrap_or_default();
-                let status = inspect
-                    .state
-                    .and_then(|s| s.status.clone())
-                    .unwrap_or(ContainerStateStatusEnum::EMPTY);
-
-                error!(
-                    "Container {} failed to start. Status: {}, Exit code: {:?}, Error: {}, Logs: {}",
-                    container_id, status, exit_code, error_msg, logs
-                );
-
-                return Err(SandboxError::StartContainerFailed {
-                    message: format!(
-                        "Container exited immediately. Status: {}, Error: {}",
-                        status, error_msg
-                    ),
-                    exit_code,
-                    logs,
-                });
+                // Run initial shell setup
+                self.install_tools().await?;
+                self.clone_repo().await?;
+                self.run_setup_commands().await?;
+                self.attach_and_configure_shell().await?;
             }
+        }
 
-            attempts += 1;
-            tokio::time::sleep(Duration::from_millis(500)).await;
+        let started_at = Utc::now();
+        self.start_time = Some(Instant::now());
+        self.view.set_started_at(started_at);
+        self.permits = permits;
+        self.persist(TrajectoryEvent::Start {
+            sandbox_id: self.id.clone(),
+            image: self.image.clone(),
+            started_at,
+        })
+        .await;
+        for hook in self.hooks.clone() {
+            hook.on_start(self).await?;
         }
+        Ok(())
+    }
 
-        Ok(create_response.id)
+    async fn pull_image_if_missing(&mut self) -> Result<()> {
+        self.runtime.pull(&self.image, &mut |_| {}).await.map(|_| ())
     }
 
+    async fn create_and_start_container(&mut self) -> Result<String> {
+        let spec = ContainerSpec {
+            image: self.image.clone(),
+            env: self.env.clone(),
+            limits: self.limits.clone(),
+            mounts: self.mounts.clone(),
+            dns: self.dns.clone(),
+            dns_search: self.dns_search.clone(),
+            extra_hosts: self.extra_hosts.clone(),
+        };
+        let container_id = self.runtime.create(&spec).await?;
+        self.set_status(SandboxStatus::Started(container_id.clone()));
+        self.runtime.start(&container_id).await?;
+        Ok(container_id)
+    }
+
+    async fn install_tools(&mut self) -> Result<()> {
+        for bundle in self.tools.clone() {
+            for cmd in bundle.install_cmds() {
+                let result = self.exec_standalone_with(Shell::Sh, cmd).await?;
+                if result.exit_code != 0 {
+                    return Err(SandboxError::ToolInstallFailed(
+                        bundle.name.clone(),
+                        result.output.trim().to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clones the repository of the sandbox. The command carries the credentials, so it
+    /// is neither logged nor part of the error.
+    async fn clone_repo(&mut self) -> Result<()> {
+        let Some(repo) = self.repo.clone() else {
+            return Ok(());
+        };
+        let result = self
+            .exec_standalone_env(Shell::Sh, repo.clone_cmd(), &repo.clone_env())
+            .await?;
+        if result.exit_code != 0 {
+            return Err(SandboxError::RepoCloneFailed(
+                repo.url.clone(),
+                result.output.trim().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs the setup commands one by one, stopping at the first that fails.
     async fn run_setup_commands(&mut self) -> Result<()> {
-        if !self.setup_commands.is_empty() {
-            let CommandResult { output, exit_code, exited: _ } = self
-                .exec_standalone_cmd(self.setup_commands.clone())
-                .await?;
+        for (index, command) in self.setup_commands.clone().into_iter().enumerate() {
+            let CommandResult { output, exit_code, .. } =
+                self.exec_standalone_cmd(command.clone()).await?;
             if exit_code != 0 {
-                error!(
-                    "Setup commands ({}) failed: {}",
-                    self.setup_commands, output
-                );
-                return Err(SandboxError::SetupCommandsFailed(output));
+                error!("Setup command {} ({}) failed: {}", index, command, output);
+                return Err(SandboxError::SetupCommandsFailed { index, command, output });
             }
         }
         Ok(())
     }
 
-    async fn attach_and_configure_shell(&mut self) -> Result<()> {
+    /// Falls back to POSIX sh when bash was asked for but the image has none, as with
+    /// alpine or busybox images. Probed directly so the trajectory is left untouched.
+    async fn resolve_shell(&mut self) -> Result<()> {
         let container_id = match &self.status {
             SandboxStatus::Started(cid) => cid,
             _ => return Err(SandboxError::NotStarted),
         };
-
-        let create_exec_res = self
-            .docker
-            .create_exec(
-                container_id,
-                CreateExecOptions {
-                    cmd: Some(shell::init_cmd()),
-                    attach_stdout: Some(true),
-                    attach_stderr: Some(true),
-                    attach_stdin: Some(true),
-                    tty: Some(true),
-                    ..Default::default()
-                },
-            )
+        if self.shell != Shell::Bash {
+            return Ok(());
+        }
+        let mut probe = self
+            .runtime
+            .exec(container_id, Shell::Sh.standalone_cmd(shell::HAS_BASH_CMD))
             .await?;
+        while probe.output.next().await.is_some() {}
+        if probe.exit_code.await? != 0 {
+            info!(sandbox_id = %self.id, image = %self.image, "No bash in the image, using sh");
+            self.shell = Shell::Sh;
+        }
+        Ok(())
+    }
 
-        let start_exec_res = self
-            .docker
-            .start_exec(
-                &create_exec_res.id,
-                Some(StartExecOptions {
-                    detach: false,
-                    tty: true,
-                    ..Default::default()
-                }),
-            )
-            .await?;
-        let (mut output, input) = match start_exec_res {
-            StartExecResults::Attached { output, input, .. } => (output, input),
-            _ => {
-                return Err(SandboxError::StartContainerFailed {
-                    message: "Failed to start exec, didn't attach.".to_string(),
-                    exit_code: None,
-                    logs: String::new(),
-                });
-            }
+    async fn attach_and_configure_shell(&mut self) -> Result<()> {
+        let container_id = match &self.status {
+            SandboxStatus::Started(cid) => cid,
+            _ => return Err(SandboxError::NotStarted),
         };
 
+        let Attached {
+            input,
+            mut output,
+            resize,
+        } = self.runtime.attach(container_id, self.shell.init_cmd()).await?;
+        if let Some(size) = self.terminal_size {
+            resize(size).await?;
+        }
+        self.resize_terminal = Some(resize);
+
         // Spawn a task to forward the output stream to the channel
-        let (tx, rx) = futures::channel::mpsc::unbounded::<Bytes>();
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Bytes>(io::OUTPUT_CHANNEL_CAPACITY);
+        // Each session gets its own flag, a previous one closing says nothing of it
+        self.session_closed = Arc::new(AtomicBool::new(false));
+        let closed = self.session_closed.clone();
+        let view = self.session_view.clone();
         tokio::spawn(async move {
-            while let Some(res) = output.next().await {
-                if let Ok(chunk) = res {
-                    let bytes = match chunk {
-                        LogOutput::Console { message } => message,
-                        _ => continue,
-                    };
-                    let _ = tx.unbounded_send(bytes);
-                } else {
+            while let Some(bytes) = output.next().await {
+                view.read().unwrap().push_session_output(&bytes);
+                // Waits for the reader when the buffer is full rather than drop output,
+                // which could hold the marker ending a command. The session then blocks
+                // on its terminal until the output is read.
+                if tx.send(bytes).await.is_err() {
                     break;
                 }
             }
+            closed.store(true, Ordering::Relaxed);
         });
 
         self.input = Some(Mutex::new(input));
         self.output_receiver = Some(Mutex::new(rx));
 
-        self.write_cmd(shell::CONF_CMD.to_string()).await?;
+        let mut conf = self.shell.conf_cmd(&self.markers);
+        let mut lines = self.shell_init.len();
+        if !self.tools.is_empty() {
+            conf.push_str(&tools::path_cmd(&self.tools));
+            conf.push('\n');
+            lines += 1;
+        }
+        for line in &self.shell_init {
+            conf.push_str(line);
+            conf.push('\n');
+        }
+        self.write_cmd(conf).await?;
 
-        let _ = self.read_until_idle_after_marker(2.0, 0.1, 1).await?;
+        let _ = self
+            .read_until_idle_after_marker(2.0, 0.1, 1 + lines)
+            .await?;
+
+        // Remember the shell PID so the agent's processes can be frozen later on.
+        self.write_cmd(self.shell.pid_cmd().to_string()).await?;
+        let output = self.read_until_idle_after_marker(2.0, 0.1, 1).await?;
+        let (pid, _, _) = io::strip_markers_and_extract_exit_code(&output.text, &self.markers);
+        self.session_pid = pid.trim().parse().ok();
+        self.output_truncated.store(false, Ordering::Relaxed);
         Ok(())
     }
 
-    pub async fn exec_session_cmd(&mut self, cmd: String) -> Result<CommandResult> {
+    /// Runs a command in the session, or in a new process when `standalone`, between the
+    /// exec hooks.
+    ///
+    /// A command still running after `timeout` fails with [`SandboxError::CommandTimedOut`].
+    /// A session command is interrupted, as with Ctrl-C, to get the prompt back, and
+    /// recorded with exit code 124; a standalone one is left to finish in the background.
+    pub async fn exec(
+        &mut self,
+        cmd: String,
+        standalone: bool,
+        timeout: Option<Duration>,
+    ) -> Result<CommandResult> {
+        if let (false, SandboxStatus::Exhausted(_)) = (standalone, &self.status) {
+            return Err(self.budget_exhausted());
+        }
+        self.check_blocked(&cmd).await?;
+        let hooks = self.hooks.clone();
+        for hook in &hooks {
+            hook.before_exec(self, &cmd).await?;
+        }
+        let mut result = match (standalone, timeout) {
+            (true, None) => self.exec_standalone_cmd(cmd.clone()).await?,
+            (true, Some(timeout)) => time::timeout(timeout, self.exec_standalone_cmd(cmd.clone()))
+                .await
+                .map_err(|_| SandboxError::CommandTimedOut(timeout.as_secs()))??,
+            (false, _) => self.exec_session_cmd(cmd.clone(), timeout).await?,
+        };
+        // Session commands are filtered before they are recorded
+        if standalone {
+            self.output_filters.apply_to(&mut result);
+        }
+        for hook in &hooks {
+            if let Err(e) = hook.after_exec(self, &cmd, &result).await {
+                warn!("after_exec hook of {} failed: {}", self.id, e);
+            }
+        }
+        Ok(result)
+    }
+
+    pub(crate) async fn exec_session_cmd(
+        &mut self,
+        cmd: String,
+        timeout: Option<Duration>,
+    ) -> Result<CommandResult> {
+        self.check_session().await?;
         let cid = match &self.status {
             SandboxStatus::Started(cid) => cid.clone(),
-            SandboxStatus::Exited(_) => return Err(SandboxError::AlreadyExited),
+            SandboxStatus::Exited(..) => return Err(SandboxError::AlreadyExited),
+            SandboxStatus::Frozen(_) => return Err(SandboxError::Frozen),
+            SandboxStatus::Exhausted(_) => return Err(self.budget_exhausted()),
+            SandboxStatus::Crashed(_, exit_code) => return Err(crashed(*exit_code, false)),
             _ => return Err(SandboxError::NotStarted),
         };
 
+        let usage_before = self.usage_counters().await;
         let execution_start = Instant::now();
         let mut command_execution = CommandExecution {
             command: cmd.clone(),
-            timestamp: execution_start,
+            timestamp: Utc::now(),
             result: None,
+            duration: None,
+            usage: None,
+            blocked: None,
         };
 
+        self.command_started(&command_execution).await;
         // Write raw command
-        self.write_cmd(format!("{}\n", &cmd)).await?;
+        if let Err(e) = self.write_cmd(format!("{}\n", &cmd)).await {
+            return Err(self.session_failed(e).await);
+        }
 
         // Hint how many commands were executed by counting the number of newlines present.
         // Might not be an exact match but it allows us to cut the timeout short.
         let n_commands_hint = cmd.split('\n').count();
-        let output = match self
-            .read_until_idle_after_marker(2.0, 0.2, n_commands_hint)
+        let overall_timeout = timeout.map_or(2.0, |timeout| timeout.as_secs_f64());
+        let session_output = match self
+            .read_until_idle_after_marker(overall_timeout, 0.2, n_commands_hint)
             .await
         {
             Ok(s) => s,
+            Err(SandboxError::TimeoutWaitingForMarker(_)) if timeout.is_some() => {
+                // Still running: interrupt it and drop its output up to the next prompt
+                if let Err(e) = self.write_cmd("\x03".to_string()).await {
+                    return Err(self.session_failed(e).await);
+                }
+                if let Err(e) = self.read_until_idle_after_marker(2.0, 0.2, 1).await {
+                    warn!("No prompt after interrupting a command of {}: {}", self.id, e);
+                }
+                self.output_truncated.store(false, Ordering::Relaxed);
+                // The command ran all the same: it is recorded, with the exit code of
+                // `timeout(1)` and its output dropped, and spends the budget
+                command_execution.result = Some(CommandResult {
+                    output: String::new(),
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    exit_code: 124,
+                    exited: false,
+                    truncated: true,
+                    raw_output: None,
+                });
+                command_execution.duration = Some(execution_start.elapsed());
+                command_execution.usage = self.command_usage(usage_before).await;
+                self.command_ended(&command_execution).await;
+                self.spend_budget();
+                return Err(SandboxError::CommandTimedOut(
+                    timeout.unwrap_or_default().as_secs(),
+                ));
+            }
             Err(SandboxError::TimeoutWaitingForMarker(_)) => {
                 // Step 1: try a newline to complete open constructs
-                self.write_cmd("\n".to_string()).await?;
-                match self.read_until_idle_after_marker(2.0, 0.2, 1).await {
-                    Ok(s2) => s2,
+                if let Err(e) = self.write_cmd("\n".to_string()).await {
+                    return Err(self.session_failed(e).await);
+                }
+                let retried = match self.read_until_idle_after_marker(2.0, 0.2, 1).await {
                     Err(SandboxError::TimeoutWaitingForMarker(_)) => {
                         // Step 2: try Ctrl-D (safe due to 'set -o ignoreeof')
-                        self.write_cmd("\x04".to_string()).await?;
-                        // Final attempt to reach PS1
-                        self.read_until_idle_after_marker(2.0, 0.2, 1).await?
+                        match self.write_cmd("\x04".to_string()).await {
+                            // Final attempt to reach PS1
+                            Ok(()) => self.read_until_idle_after_marker(2.0, 0.2, 1).await,
+                            Err(e) => Err(e),
+                        }
                     }
-                    Err(e) => return Err(e),
+                    result => result,
+                };
+                match retried {
+                    Ok(s2) => s2,
+                    Err(e) => return Err(self.session_failed(e).await),
                 }
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(self.session_failed(e).await),
         };
 
         // Find all markers, remove them, and get last exit code (if input included multiple commands)
-        let (output, exit_code, exit_marker_seen) =
-            io::strip_markers_and_extract_exit_code(&output);
+        let raw_output = &session_output.text;
+        let (output, last_exit_code, exit_status) =
+            io::strip_markers_and_extract_exit_code(raw_output, &self.markers);
 
         // Session was terminated by a command.
-        if exit_marker_seen {
-            self.status = SandboxStatus::Exited(cid.clone());
+        if let Some(status) = exit_status {
+            self.set_status(SandboxStatus::Exited(cid.clone(), status));
         }
 
-        let result = CommandResult { output, exit_code, exited: exit_marker_seen };
-        command_execution.result = Some(result.clone());
-        self.trajectory.push(command_execution);
+        let (stdout, stderr) = io::split_streams(raw_output, &self.markers);
+        let mut stdout = io::strip_markers_and_extract_exit_code(&stdout, &self.markers).0;
+        let mut terminal =
+            io::strip_markers_and_extract_exit_code(&session_output.raw, &self.markers).0;
+        let output = match self.shell.echoes_input() {
+            true => {
+                stdout = io::strip_echo(&stdout, &cmd);
+                terminal = io::strip_echo(&terminal, &cmd);
+                io::strip_echo(&output, &cmd)
+            }
+            false => output,
+        };
+        let mut result = CommandResult {
+            output,
+            stdout,
+            stderr: io::strip_markers_and_extract_exit_code(&stderr, &self.markers).0,
+            exit_code: exit_status.unwrap_or(last_exit_code),
+            exited: exit_status.is_some(),
+            truncated: self.output_truncated.swap(false, Ordering::Relaxed),
+            raw_output: Some(terminal),
+        };
+        self.output_filters.apply_to(&mut result);
+        // The raw output is only returned, the trajectory keeps the text
+        command_execution.result = Some(CommandResult {
+            raw_output: None,
+            ..result.clone()
+        });
+        command_execution.duration = Some(execution_start.elapsed());
+        command_execution.usage = self.command_usage(usage_before).await;
+        self.command_ended(&command_execution).await;
+        self.spend_budget();
 
         // Drain any remaining output to next prompt
 
         Ok(result)
     }
 
-    pub async fn exec_standalone_cmd(&mut self, cmd: String) -> Result<CommandResult> {
+    /// Runs a command in the session, yielding its output as it is printed and a marker
+    /// as each command of the input finishes. The stream ends once all of them did, and
+    /// the command is then recorded in the trajectory. There is no timeout: dropping the
+    /// stream early leaves the rest of the output to the next command.
+    pub async fn exec_session_stream(
+        &mut self,
+        cmd: String,
+    ) -> Result<impl futures::Stream<Item = OutputChunk> + Send + Unpin + '_> {
+        self.check_session().await?;
         let cid = match &self.status {
-            SandboxStatus::Started(cid) | SandboxStatus::Exited(cid) => cid,
+            SandboxStatus::Started(cid) => cid.clone(),
+            SandboxStatus::Exited(..) => return Err(SandboxError::AlreadyExited),
+            SandboxStatus::Frozen(_) => return Err(SandboxError::Frozen),
+            SandboxStatus::Exhausted(_) => return Err(self.budget_exhausted()),
+            SandboxStatus::Crashed(_, exit_code) => return Err(crashed(*exit_code, false)),
             _ => return Err(SandboxError::NotStarted),
         };
-        let exec_config = CreateExecOptions {
-            cmd: Some(shell::standalone_cmd(&cmd)),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            attach_stdin: Some(false),
-            tty: Some(false),
-            ..Default::default()
+
+        self.check_blocked(&cmd).await?;
+        let usage_before = self.usage_counters().await;
+        let execution = CommandExecution {
+            command: cmd.clone(),
+            timestamp: Utc::now(),
+            result: None,
+            duration: None,
+            usage: None,
+            blocked: None,
         };
-        let exec = self
-            .docker
-            .create_exec(cid, exec_config)
-            .await
-            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
-        let start_res = self
-            .docker
-            .start_exec(&exec.id, None::<StartExecOptions>)
-            .await
-            .map_err(|e| SandboxError::CreateExecFailed(e.to_string()))?;
+        self.command_started(&execution).await;
+        if let Err(e) = self.write_cmd(format!("{}\n", &cmd)).await {
+            return Err(self.session_failed(e).await);
+        }
+        Ok(stream::session_stream(self, cid, execution, usage_before))
+    }
+
+    /// Records an interactive shell attached at `timestamp` for `duration` in the
+    /// trajectory, without a result as what was typed in it is not recorded. It counts as a
+    /// command toward `max_commands`.
+    pub async fn record_attach(
+        &mut self,
+        command: &str,
+        timestamp: DateTime<Utc>,
+        duration: Duration,
+    ) {
+        let execution = CommandExecution {
+            command: command.to_string(),
+            timestamp,
+            result: None,
+            duration: Some(duration),
+            usage: None,
+            blocked: None,
+        };
+        self.command_started(&execution).await;
+        self.command_ended(&execution).await;
+        self.spend_budget();
+    }
+
+    /// Refuses a command matching a blocked command pattern of the sandbox. The attempt is
+    /// recorded in the trajectory, without a result.
+    async fn check_blocked(&mut self, cmd: &str) -> Result<()> {
+        let Some(pattern) = self
+            .blocked_commands
+            .iter()
+            .find(|p| p.is_match(cmd))
+            .map(|p| p.to_string())
+        else {
+            return Ok(());
+        };
+        let execution = CommandExecution {
+            command: cmd.to_string(),
+            timestamp: Utc::now(),
+            result: None,
+            duration: None,
+            usage: None,
+            blocked: Some(pattern.clone()),
+        };
+        self.command_started(&execution).await;
+        self.command_ended(&execution).await;
+        self.spend_budget();
+        info!(sandbox_id = %self.id, pattern = %pattern, "Blocked command");
+        Err(SandboxError::CommandBlocked(pattern))
+    }
+
+    /// Reads the resource counters of the container, if the runtime can. Resource
+    /// accounting is best effort and never fails a command.
+    async fn usage_counters(&self) -> Option<UsageCounters> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Exhausted(cid) => cid,
+            _ => return None,
+        };
+        match self.runtime.usage(cid).await {
+            Ok(counters) => Some(counters),
+            Err(e) => {
+                debug!("Could not read the resource counters of {}: {}", cid, e);
+                None
+            }
+        }
+    }
+
+    /// Resources used since the counters were read as `before`.
+    async fn command_usage(&self, before: Option<UsageCounters>) -> Option<CommandUsage> {
+        let after = self.usage_counters().await?;
+        Some(CommandUsage::between(&before?, &after))
+    }
+
+    pub(crate) async fn exec_standalone_cmd(&mut self, cmd: String) -> Result<CommandResult> {
+        let cmd = match self.tools.is_empty() {
+            true => cmd,
+            false => format!("{}; {}", tools::path_cmd(&self.tools), cmd),
+        };
+        self.exec_standalone_with(self.shell, cmd).await
+    }
+
+    /// Runs a standalone command with another shell than the sandbox's.
+    async fn exec_standalone_with(&mut self, shell: Shell, cmd: String) -> Result<CommandResult> {
+        self.exec_standalone_env(shell, cmd, &HashMap::new()).await
+    }
+
+    /// Runs a standalone command with variables added to its environment, for secrets.
+    async fn exec_standalone_env(
+        &mut self,
+        shell: Shell,
+        cmd: String,
+        env: &HashMap<String, String>,
+    ) -> Result<CommandResult> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Frozen(cid)
+            | SandboxStatus::Exhausted(cid)
+            | SandboxStatus::Crashed(cid, _) => cid,
+            _ => return Err(SandboxError::NotStarted),
+        };
+        let mut exec = self
+            .runtime
+            .exec_with_env(cid, shell.standalone_cmd(&cmd), env)
+            .await?;
         let mut out = Vec::new();
-        if let StartExecResults::Attached { output, .. } = start_res {
-            let mut output = output;
-            while let Some(item) = output.next().await {
-                match item.map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))? {
-                    LogOutput::StdOut { message } => out.extend(&message),
-                    LogOutput::StdErr { message } => out.extend(&message),
-                    _ => continue,
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = exec.output.next().await {
+            match chunk? {
+                ExecOutput::Stdout(message) => {
+                    out.extend(&message);
+                    stdout.extend(&message);
+                }
+                ExecOutput::Stderr(message) => {
+                    out.extend(&message);
+                    stderr.extend(&message);
                 }
             }
+            truncated |= io::trim_bytes(&mut out)
+                | io::trim_bytes(&mut stdout)
+                | io::trim_bytes(&mut stderr);
         }
-        let inspect = self
-            .docker
-            .inspect_exec(&exec.id)
-            .await
-            .map_err(|e| SandboxError::ContainerReadFailed(e.to_string()))?;
-        let exit_code = inspect
-            .exit_code
-            .expect("Exit code not present in inspect exec");
-        self.last_standalone_exit_code = Some(exit_code);
+        let exit_code = exec.exit_code.await?;
+        self.view.set_last_standalone_exit_code(exit_code);
         let out_str = String::from_utf8_lossy(&out).to_string();
         Ok(CommandResult {
             output: out_str,
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
             exit_code,
-            exited: false
+            exited: false,
+            truncated,
+            raw_output: None,
         })
     }
 
+    /// Downloads a path of the container as a tar archive, of at most `max_bytes`.
+    pub async fn download(&self, path: &str, max_bytes: Option<usize>) -> Result<Vec<u8>> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Frozen(cid)
+            | SandboxStatus::Exhausted(cid)
+            | SandboxStatus::Crashed(cid, _) => cid,
+            _ => return Err(SandboxError::NotStarted),
+        };
+        self.runtime.download(cid, path, max_bytes).await
+    }
+
+    /// Git workspace of the sandbox: the directory of its repository, or
+    /// [`DEFAULT_REPO_DIR`].
+    pub fn workspace(&self) -> &str {
+        self.repo.as_ref().map_or(DEFAULT_REPO_DIR, |repo| repo.dir())
+    }
+
+    /// Diff of the changes in the git working tree of `dir`, untracked files included.
+    pub async fn diff(&mut self, dir: &str) -> Result<String> {
+        let result = self.exec_standalone_with(Shell::Sh, patch::diff_cmd(dir)).await?;
+        if result.exit_code != 0 {
+            return Err(SandboxError::PatchFailed(result.stderr.trim().to_string()));
+        }
+        Ok(result.stdout)
+    }
+
+    /// Applies a unified diff to the git working tree of `dir`.
+    pub async fn apply_patch(&mut self, dir: &str, diff: &str) -> Result<()> {
+        for cmd in patch::apply_cmds(dir, diff) {
+            let result = self.exec_standalone_with(Shell::Sh, cmd).await?;
+            if result.exit_code != 0 {
+                return Err(SandboxError::PatchFailed(result.output.trim().to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts a tar archive, as returned by [`Sandbox::download`], into a directory of
+    /// the container.
+    pub async fn upload(&self, dir: &str, archive: &[u8]) -> Result<()> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Exhausted(cid) => cid,
+            SandboxStatus::Frozen(_) => return Err(SandboxError::Frozen),
+            _ => return Err(SandboxError::NotStarted),
+        };
+        self.runtime.upload(cid, dir, archive).await
+    }
+
+    /// Samples the resource usage of the running container. Takes about a second, as
+    /// the CPU usage is measured over two readings.
+    pub async fn stats(&self) -> Result<ResourceUsage> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Frozen(cid)
+            | SandboxStatus::Exhausted(cid) => cid,
+            _ => return Err(SandboxError::NotStarted),
+        };
+        self.runtime.stats(cid).await
+    }
+
+    /// Removes the container of a sandbox that failed to start, with its network, and
+    /// releases its permits. Its hooks do not run, as it never started.
+    pub(crate) async fn abort_start(&mut self) {
+        self.permits.clear();
+        let cid = match &self.status {
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Frozen(cid)
+            | SandboxStatus::Exhausted(cid)
+            | SandboxStatus::Crashed(cid, _) => Some(cid.clone()),
+            _ => None,
+        };
+        if let Some(cid) = cid
+            && let Err(e) = self.runtime.remove(&cid).await
+        {
+            warn!(sandbox_id = %self.id, error = %e, "Failed to remove the container");
+        }
+        self.set_status(SandboxStatus::Stopped(Ok(())));
+        self.input = None;
+        self.output_receiver = None;
+    }
+
     pub async fn stop(&mut self) -> Result<()> {
-        // Release the semaphore
-        self.permit.take();
+        // Release the semaphores
+        self.permits.clear();
 
         return match &self.status {
             SandboxStatus::Stopped(_) => Err(SandboxError::NotStarted), // Already stopped
             SandboxStatus::Created => Err(SandboxError::NotStarted),
-            SandboxStatus::Started(cid) | SandboxStatus::Exited(cid) => {
+            // Cancels the queued start
+            SandboxStatus::Queued => {
+                self.set_status(SandboxStatus::Stopped(Ok(())));
+                Ok(())
+            }
+            SandboxStatus::Started(cid)
+            | SandboxStatus::Exited(cid, _)
+            | SandboxStatus::Frozen(cid)
+            | SandboxStatus::Exhausted(cid)
+            | SandboxStatus::Crashed(cid, _) => {
+                let cid = cid.clone();
+                // The hooks exec in the container, which cannot while paused
+                if self.paused {
+                    let _ = self.runtime.unpause(&cid).await;
+                    self.paused = false;
+                }
+                for hook in self.hooks.clone() {
+                    if let Err(e) = hook.on_stop(self).await {
+                        warn!("on_stop hook of {} failed: {}", self.id, e);
+                    }
+                }
                 // Stop the container but don't remove it
-                let _ = self
-                    .docker
-                    .remove_container(
-                        &cid,
-                        Some(RemoveContainerOptions {
-                            force: true,
-                            ..Default::default()
-                        }),
-                    )
-                    .await;
-                self.status = SandboxStatus::Stopped(Ok(()));
+                let _ = self.runtime.remove(&cid).await;
+                self.set_status(SandboxStatus::Stopped(Ok(())));
                 // Close input/output streams
                 self.input = None;
                 self.output_receiver = None;
@@ -478,6 +1173,65 @@ impl Sandbox {
         };
     }
 
+    /// Freeze the session shell and every process it spawned so the container filesystem can be
+    /// inspected in a consistent state.
+    ///
+    /// Uses the cgroup v2 freezer in a cgroup of the sandbox when the container can manage
+    /// its own cgroups, in which case standalone commands keep working while frozen.
+    /// Otherwise, the runtime pauses the whole container, and standalone commands fail
+    /// until it is unfrozen. Runtimes that cannot pause containers fall back to stopping
+    /// the process groups of the session with SIGSTOP.
+    pub async fn freeze(&mut self) -> Result<()> {
+        let cid = match &self.status {
+            SandboxStatus::Started(cid) => cid.clone(),
+            SandboxStatus::Frozen(_) => return Err(SandboxError::Frozen),
+            SandboxStatus::Exited(..) => return Err(SandboxError::AlreadyExited),
+            SandboxStatus::Exhausted(_) => return Err(self.budget_exhausted()),
+            _ => return Err(SandboxError::NotStarted),
+        };
+        let session_pid = self.session_pid.ok_or(SandboxError::NotStarted)?;
+
+        let mut result = self
+            .exec_standalone_with(Shell::Sh, shell::freeze_cmd(session_pid, &self.id))
+            .await?;
+        if result.exit_code == shell::NO_FREEZER {
+            self.paused = self.runtime.pause(&cid).await?;
+            if !self.paused {
+                result = self
+                    .exec_standalone_with(Shell::Sh, shell::stop_cmd(session_pid))
+                    .await?;
+            }
+        }
+        if !self.paused && result.exit_code != 0 {
+            return Err(SandboxError::ExecFailed(result.output, result.exit_code));
+        }
+        self.set_status(SandboxStatus::Frozen(cid));
+        Ok(())
+    }
+
+    /// Resume the processes frozen by [`Sandbox::freeze`].
+    pub async fn unfreeze(&mut self) -> Result<()> {
+        let cid = match &self.status {
+            SandboxStatus::Frozen(cid) => cid.clone(),
+            _ => return Err(SandboxError::NotFrozen),
+        };
+        let session_pid = self.session_pid.ok_or(SandboxError::NotStarted)?;
+
+        if self.paused {
+            self.runtime.unpause(&cid).await?;
+            self.paused = false;
+        } else {
+            let CommandResult { output, exit_code, .. } = self
+                .exec_standalone_with(Shell::Sh, shell::thaw_cmd(session_pid, &self.id))
+                .await?;
+            if exit_code != 0 {
+                return Err(SandboxError::ExecFailed(output, exit_code));
+            }
+        }
+        self.set_status(SandboxStatus::Started(cid));
+        Ok(())
+    }
+
     async fn write_cmd(&mut self, cmd: String) -> Result<()> {
         let mut input = self
             .input
@@ -501,7 +1255,7 @@ impl Sandbox {
         overall_timeout: f64,
         idle_timeout: f64,
         short_circuit_after_n_markers: usize,
-    ) -> Result<String> {
+    ) -> Result<io::SessionOutput> {
         let receiver = self
             .output_receiver
             .as_ref()
@@ -510,13 +1264,19 @@ impl Sandbox {
 
         let result = io::read_stream_until_idle(
             &mut receiver_guard,
+            &self.markers,
             overall_timeout,
             idle_timeout,
             short_circuit_after_n_markers,
         )
         .await;
         match result {
-            Ok(s) => Ok(s),
+            Ok(output) => {
+                if output.truncated {
+                    self.output_truncated.store(true, Ordering::Relaxed);
+                }
+                Ok(output)
+            }
             Err(io::ReadError::OverallTimeout) => Err(SandboxError::TimeoutWaitingForMarker(
                 "Marker not seen before timeout (possible incomplete input)".to_string(),
             )),
@@ -526,3 +1286,10 @@ impl Sandbox {
         }
     }
 }
+
+fn crashed(exit_code: Option<i64>, restarted: bool) -> SandboxError {
+    SandboxError::Crashed {
+        exit_code,
+        restarted,
+    }
+}
@@ -0,0 +1,46 @@
+//! Observers of the commands and status of a sandbox.
+//!
+//! Unlike [hooks](super::hooks), observers cannot fail or reject anything: they record
+//! what happens. The trajectory of the sandbox is recorded by the first of them, which
+//! every sandbox has, before its own [`Sandbox::observers`].
+use async_trait::async_trait;
+
+use super::Sandbox;
+use super::types::{CommandExecution, OutputChunk, Status};
+use crate::store::TrajectoryEvent;
+
+/// Notified of the commands recorded in the trajectory of a sandbox, session and kernel
+/// ones, and of its status changes. Every method does nothing by default.
+///
+/// The sandbox waits for the command methods. The others are called for every chunk of
+/// output and from synchronous code, and must return quickly.
+#[async_trait]
+pub trait Observer: Send + Sync {
+    /// Called before the command is sent, with its execution yet to have a result.
+    async fn on_command_start(&self, _sandbox: &Sandbox, _command: &CommandExecution) {}
+
+    /// Called for each chunk of output of a command run with
+    /// [`Sandbox::exec_session_stream`].
+    fn on_output_chunk(&self, _sandbox_id: &str, _chunk: &OutputChunk) {}
+
+    /// Called once the command finished, with its result, duration and usage.
+    async fn on_command_end(&self, _sandbox: &Sandbox, _command: &CommandExecution) {}
+
+    /// Called as the status of the sandbox changes, including when the manager queues,
+    /// restarts or reaps it. Being removed from the manager or archived is no status
+    /// change, [`Manager::events`](crate::manager::Manager::events) publishes those.
+    fn on_status_change(&self, _sandbox_id: &str, _status: &Status) {}
+}
+
+/// Appends the commands to the trajectory of the sandbox, and to its store if it has one.
+pub(super) struct Recorder;
+
+#[async_trait]
+impl Observer for Recorder {
+    async fn on_command_end(&self, sandbox: &Sandbox, command: &CommandExecution) {
+        sandbox.view.push_command(command.clone());
+        sandbox
+            .persist(TrajectoryEvent::Command(command.clone()))
+            .await;
+    }
+}
@@ -0,0 +1,40 @@
+//! Patches of the git workspace of the sandbox.
+//!
+//! The diff covers every change of the working tree against `HEAD`, untracked files
+//! included, as SWE-style evaluations expect. It is taken with a copy of the index, so the
+//! staging area of the agent is left untouched. The image needs `git`, and `base64` to
+//! apply patches, which are written to the container a chunk at a time.
+use super::tools::write_cmds;
+use crate::task::shell_quote;
+
+/// File the patch to apply is written to, removed once applied.
+const PATCH_FILE: &str = "/tmp/.sos-patch";
+
+/// Command printing the diff of the working tree of `dir` against `HEAD`. POSIX sh.
+pub fn diff_cmd(dir: &str) -> String {
+    format!(
+        "cd {} && i=$(mktemp) && \
+         {{ cp \"$(git rev-parse --git-dir)/index\" \"$i\" 2>/dev/null || rm -f \"$i\"; }} && \
+         GIT_INDEX_FILE=\"$i\" git add -A && GIT_INDEX_FILE=\"$i\" git diff --cached --binary; \
+         s=$?; rm -f \"$i\"; exit $s",
+        shell_quote(dir)
+    )
+}
+
+/// Commands applying `patch` to the working tree of `dir`, in order. POSIX sh.
+pub fn apply_cmds(dir: &str, patch: &str) -> Vec<String> {
+    let mut cmds = write_cmds(PATCH_FILE, patch.as_bytes());
+    cmds.push(format!(
+        "cd {} && git apply --whitespace=nowarn {PATCH_FILE}; s=$?; rm -f {PATCH_FILE}; exit $s",
+        shell_quote(dir)
+    ));
+    cmds
+}
+
+/// Paths changed by a diff, as named after it.
+pub fn changed_files(diff: &str) -> Vec<String> {
+    diff.lines()
+        .filter_map(|line| line.strip_prefix("diff --git a/"))
+        .filter_map(|paths| paths.split_once(" b/").map(|(_, path)| path.to_string()))
+        .collect()
+}
@@ -0,0 +1,116 @@
+//! Git repository cloned into the container on start.
+//!
+//! The clone runs as a standalone command after the tool bundles are installed and before
+//! the setup commands, so these can build or install the checkout. It needs `git` in the
+//! image. Credentials are passed to git as an HTTP header for the clone only, in its
+//! environment rather than its arguments, which any process of the container can read.
+//! They are neither written to the `.git/config` of the checkout nor recorded in the
+//! trajectory, and the API returns them redacted.
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+
+use crate::task::shell_quote;
+
+/// Directory the repository is cloned into when `dir` is not given.
+pub const DEFAULT_REPO_DIR: &str = "/workspace";
+
+/// Token of the repositories returned by the API.
+pub const REDACTED_TOKEN: &str = "<redacted>";
+
+/// Repository to clone on start.
+///
+/// `ref` is a branch, a tag or a commit, checked out detached, the default branch when
+/// not given. `depth` makes a shallow clone of that many commits.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepoSpec {
+    pub url: String,
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<RepoAuth>,
+    /// Directory of the checkout, [`DEFAULT_REPO_DIR`] by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+}
+
+/// Credentials of an HTTPS repository, sent with basic authentication.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RepoAuth {
+    /// Defaults to `x-access-token`, which forges accepting tokens as passwords ignore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Token or password
+    pub token: String,
+}
+
+impl fmt::Debug for RepoAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RepoAuth")
+            .field("username", &self.username)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+impl RepoSpec {
+    pub fn dir(&self) -> &str {
+        self.dir.as_deref().unwrap_or(DEFAULT_REPO_DIR)
+    }
+
+    /// Copy of the spec with its token replaced by [`REDACTED_TOKEN`], to be returned by
+    /// the API.
+    pub fn redacted(&self) -> RepoSpec {
+        RepoSpec {
+            auth: self.auth.as_ref().map(|auth| RepoAuth {
+                username: auth.username.clone(),
+                token: REDACTED_TOKEN.to_string(),
+            }),
+            ..self.clone()
+        }
+    }
+
+    /// Environment of [`RepoSpec::clone_cmd`], configuring git to send the credentials, if
+    /// any, as an HTTP header.
+    pub fn clone_env(&self) -> HashMap<String, String> {
+        let Some(auth) = &self.auth else {
+            return HashMap::new();
+        };
+        let username = auth.username.as_deref().unwrap_or("x-access-token");
+        let credentials = BASE64.encode(format!("{}:{}", username, auth.token));
+        [
+            ("GIT_CONFIG_COUNT", "1".to_string()),
+            ("GIT_CONFIG_KEY_0", "http.extraHeader".to_string()),
+            (
+                "GIT_CONFIG_VALUE_0",
+                format!("Authorization: Basic {}", credentials),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+
+    /// Command cloning the repository, run with [`RepoSpec::clone_env`]. POSIX sh.
+    pub fn clone_cmd(&self) -> String {
+        let url = shell_quote(&self.url);
+        let dir = shell_quote(self.dir());
+        let depth = match self.depth {
+            Some(depth) => format!(" --depth {}", depth),
+            None => String::new(),
+        };
+        match &self.reference {
+            // Fetching the ref alone works for commits as well as branches and tags
+            Some(reference) => format!(
+                "git init -q {dir} && cd {dir} && git remote add origin {url} && \
+                 git fetch -q{depth} origin {} && git checkout -q FETCH_HEAD",
+                shell_quote(reference)
+            ),
+            None => format!("git clone -q{depth} {url} {dir}"),
+        }
+    }
+}
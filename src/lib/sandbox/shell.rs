@@ -1,14 +1,48 @@
-use const_format::{concatcp, formatcp};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-// Change this
-pub const UNIQUE_MARKER: &str = "TR0N-F1GHTS-4-TH3-U23R2";
+/// Markers the session shell prints around command output: its prompts, the `exit` of
+/// the session and the start of stderr lines. They embed a token drawn at random for
+/// each sandbox, so output cannot match them by accident. They do not stop a command
+/// set on spoofing the end of a command or its exit code: the session can read the
+/// token back from `$PS1`, the `exit` function or the arguments of the stderr tagger.
+#[derive(Debug, Clone)]
+pub struct Markers {
+    pub ps1: String,
+    pub ps2: String,
+    pub exit: String,
+    pub err: String,
+    /// PS1 marker with the exit code following it
+    pub output_regex: Regex,
+    /// EXIT marker with the status following it
+    pub exit_regex: Regex,
+}
 
-pub const PS1_MARKER: &str = formatcp!("#PS1-{}#:", UNIQUE_MARKER);
-pub const PS2_MARKER: &str = formatcp!("#PS2-{}#:", UNIQUE_MARKER);
-pub const EXIT_MARKER: &str = formatcp!("#EXIT-{}#:", UNIQUE_MARKER);
+impl Markers {
+    pub fn new(token: &str) -> Self {
+        let ps1 = format!("#PS1-{}#:", token);
+        let exit = format!("#EXIT-{}#:", token);
+        // The prompt ends with a newline, which the TTY may turn into `\r\n`
+        let output_regex = Regex::new(&format!(r"{}(\d+):(\r?\n)?", regex::escape(&ps1)))
+            .expect("Invalid PS1 marker regex");
+        let exit_regex = Regex::new(&format!(r"{}(\d+):", regex::escape(&exit)))
+            .expect("Invalid EXIT marker regex");
+        Markers {
+            ps1,
+            ps2: format!("#PS2-{}#:", token),
+            exit,
+            err: format!("#ERR-{}#:", token),
+            output_regex,
+            exit_regex,
+        }
+    }
 
-const PS1: &str = formatcp!("{}$?:", PS1_MARKER); // Also includes the exit code
-const PS2: &str = formatcp!("{}", PS2_MARKER);
+    /// Markers with a token from the random UUID generator, backed by the system CSPRNG.
+    pub fn random() -> Self {
+        Self::new(&Uuid::new_v4().simple().to_string())
+    }
+}
 
 // Disables stdin from being echoe back to the terminal.
 const SILENCE_INPUT: &str = "stty -echo; ";
@@ -19,40 +53,312 @@ const FAIL_ON_PIPE_FAILURE: &str = "set -o pipefail; ";
 // Disables bracketed paste mode which adds a lot of noise to the output.
 const DISABLE_BRACKETED_PASTE: &str = "bind 'set enable-bracketed-paste off'; ";
 
-// Sets the prompt to include the exit code of the last standalone command.
-const SET_PS1: &str = formatcp!("PS1='{}'; ", PS1);
+// Sets the prompt to include the exit code of the last standalone command. The prompt ends
+// with a newline so the stderr tagger below forwards it right away.
+fn set_ps1(markers: &Markers) -> String {
+    format!("PS1=$'{}$?:\\n'; ", markers.ps1)
+}
 
 // Disables the input prompt, should never be used anyway but just in case.
-const SET_PS2: &str = formatcp!("PS2='{}'; ", PS2);
+fn set_ps2(markers: &Markers) -> String {
+    format!("PS2='{}'; ", markers.ps2)
+}
 
 // Since we use the prompts to detect when commands finish, we need to make sure they are not overwritten
 // to avoid jailbreaks in the simulation. Agent can still echo the prompts though.
 const READONLY_PROMPTS: &str = "readonly PS1; readonly PS2; ";
 
-// Overrides the default exit command to not exit the shell.
-// TODO: echo special marker on exit to terminate the session.
-const EXIT_COMMAND: &str = formatcp!("exit() {{ echo '{}'; return 0; }}; export -f exit; ", EXIT_MARKER);
+// Overrides the default exit command to not exit the shell. It prints the EXIT marker with the
+// status the shell would have exited with, the last command's one by default.
+fn exit_command(markers: &Markers) -> String {
+    format!(
+        "exit() {{ echo '{}'\"${{1:-$?}}:\"; return 0; }}; export -f exit; ",
+        markers.exit
+    )
+}
 
 // Ignore EOF to prevent the shell from exiting when the input stream is closed.
 const IGNORE_EOF: &str = "set -o ignoreeof; ";
 
-/// Builds the command to configure the shell.
-pub const CONF_CMD: &str = concatcp!(
-    SILENCE_INPUT,
-    DISABLE_BRACKETED_PASTE,
-    SET_PS1,
-    SET_PS2,
-    READONLY_PROMPTS,
-    EXIT_COMMAND,
-    FAIL_ON_PIPE_FAILURE,
-    IGNORE_EOF,
-    "\n"
-);
-
-// TODO: support any POSIX shell
-pub fn standalone_cmd(cmd: &str) -> Vec<String> {
-    vec!["/bin/bash".to_string(), "-c".to_string(), cmd.to_string()]
-}
-pub fn init_cmd() -> Vec<String> {
-    vec!["/bin/bash".to_string(), "-i".to_string()]
+// Routes stderr through a tagger that prefixes every line with the ERR marker, so stdout and
+// stderr can be told apart on the TTY. The prompt is written to stderr too, which guarantees a
+// command's stderr is fully forwarded before its prompt marker shows up.
+fn tag_stderr(markers: &Markers) -> String {
+    format!(
+        "exec 2> >(while IFS= read -r l; do printf '%s%s\\n' '{}' \"$l\"; done); ",
+        markers.err
+    )
+}
+
+/// Builds the command to configure bash.
+fn bash_conf_cmd(markers: &Markers) -> String {
+    [
+        SILENCE_INPUT,
+        DISABLE_BRACKETED_PASTE,
+        &set_ps1(markers),
+        &set_ps2(markers),
+        READONLY_PROMPTS,
+        &exit_command(markers),
+        FAIL_ON_PIPE_FAILURE,
+        IGNORE_EOF,
+        &tag_stderr(markers),
+        "\n",
+    ]
+    .concat()
+}
+
+// POSIX sh has no `$'...'` quoting: the newline ending the prompt comes from printf, and a
+// trailing character keeps the command substitution from stripping it.
+fn sh_set_ps1(markers: &Markers) -> String {
+    format!("PS1=$(printf '{}$?:\\n_'); PS1=${{PS1%_}}; ", markers.ps1)
+}
+
+// `exit` is a special builtin functions cannot override in POSIX sh, but aliases can.
+fn sh_exit_command(markers: &Markers) -> String {
+    format!(
+        "sos_exit() {{ echo '{}'\"${{1:-$?}}:\"; return 0; }}; alias exit=sos_exit; ",
+        markers.exit
+    )
+}
+
+// dash has neither option, busybox ash has both.
+const SH_OPTIONS: &str = "set -o pipefail 2>/dev/null; set -o ignoreeof 2>/dev/null; ";
+
+// Same as tag_stderr without process substitution: the tagger reads from a FIFO, removed
+// once both ends are open.
+fn sh_tag_stderr(markers: &Markers) -> String {
+    format!(
+        "f=/tmp/.sos-stderr-$$; mkfifo $f && (while IFS= read -r l; do printf '%s%s\\n' '{}' \"$l\"; done < $f &) && exec 2>$f; rm -f $f; unset f; ",
+        markers.err
+    )
+}
+
+/// Builds the command to configure a POSIX sh (dash, busybox ash).
+fn sh_conf_cmd(markers: &Markers) -> String {
+    [
+        SILENCE_INPUT,
+        &sh_set_ps1(markers),
+        &set_ps2(markers),
+        READONLY_PROMPTS,
+        &sh_exit_command(markers),
+        SH_OPTIONS,
+        &sh_tag_stderr(markers),
+        "\n",
+    ]
+    .concat()
+}
+
+// Without its line editor zsh reads plain lines, so the terminal no longer echoes them, and
+// the prompt is printed as is: no partial line marker, no right prompt, no hooks.
+const ZSH_PLAIN_INPUT: &str = "unsetopt zle prompt_cr prompt_sp; setopt prompt_subst; \
+    RPROMPT=''; precmd_functions=(); preexec_functions=(); ";
+
+// Functions can override builtins in zsh, but cannot be exported.
+fn zsh_exit_command(markers: &Markers) -> String {
+    format!(
+        "exit() {{ echo '{}'\"${{1:-$?}}:\"; return 0; }}; ",
+        markers.exit
+    )
+}
+
+/// Builds the command to configure zsh.
+fn zsh_conf_cmd(markers: &Markers) -> String {
+    [
+        SILENCE_INPUT,
+        ZSH_PLAIN_INPUT,
+        &set_ps1(markers),
+        &set_ps2(markers),
+        READONLY_PROMPTS,
+        &zsh_exit_command(markers),
+        FAIL_ON_PIPE_FAILURE,
+        IGNORE_EOF,
+        &tag_stderr(markers),
+        "\n",
+    ]
+    .concat()
+}
+
+// fish has no prompt variables, its prompt is a function printing the status of the last
+// command. It has no pipefail and cannot redirect its own stderr, so the stderr of fish
+// sessions is not told apart.
+fn fish_prompt(markers: &Markers) -> String {
+    format!(
+        "function fish_prompt; printf '%s%s:\\n' '{}' $status; end; \
+        function fish_right_prompt; end; function fish_mode_prompt; end; function fish_greeting; end; \
+        set -g fish_autosuggestion_enabled 0; ",
+        markers.ps1
+    )
+}
+
+fn fish_exit_command(markers: &Markers) -> String {
+    format!(
+        "function exit; set -l s $status; set -q argv[1]; and set s $argv[1]; echo '{}'$s':'; end; ",
+        markers.exit
+    )
+}
+
+/// Builds the command to configure fish.
+fn fish_conf_cmd(markers: &Markers) -> String {
+    [
+        SILENCE_INPUT,
+        &fish_prompt(markers),
+        &fish_exit_command(markers),
+        "\n",
+    ]
+    .concat()
+}
+
+/// Succeeds when the image has the bash [`Shell::Bash`] runs.
+pub const HAS_BASH_CMD: &str = "[ -x /bin/bash ]";
+
+/// Shell running the session and the standalone commands of a sandbox.
+/// Sandboxes asking for bash fall back to sh when the image has no bash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    #[default]
+    Bash,
+    /// POSIX sh, for images without bash such as alpine
+    Sh,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    fn program(&self) -> &'static str {
+        match self {
+            Shell::Bash => "/bin/bash",
+            Shell::Sh => "/bin/sh",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+        }
+    }
+
+    /// Command configuring the session shell to print `markers`.
+    pub fn conf_cmd(&self, markers: &Markers) -> String {
+        match self {
+            Shell::Bash => bash_conf_cmd(markers),
+            Shell::Sh => sh_conf_cmd(markers),
+            Shell::Zsh => zsh_conf_cmd(markers),
+            Shell::Fish => fish_conf_cmd(markers),
+        }
+    }
+
+    /// Command printing the PID of the session shell.
+    pub fn pid_cmd(&self) -> &'static str {
+        match self {
+            Shell::Fish => "echo $fish_pid\n",
+            _ => "echo $$\n",
+        }
+    }
+
+    /// Whether the shell echoes its input whatever the terminal settings. fish always
+    /// draws the command line itself.
+    pub fn echoes_input(&self) -> bool {
+        matches!(self, Shell::Fish)
+    }
+
+    pub fn standalone_cmd(&self, cmd: &str) -> Vec<String> {
+        vec![self.program().to_string(), "-c".to_string(), cmd.to_string()]
+    }
+
+    pub fn init_cmd(&self) -> Vec<String> {
+        vec![self.program().to_string(), "-i".to_string()]
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shell::Bash => write!(f, "bash"),
+            Shell::Sh => write!(f, "sh"),
+            Shell::Zsh => write!(f, "zsh"),
+            Shell::Fish => write!(f, "fish"),
+        }
+    }
+}
+
+impl std::str::FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bash" => Ok(Shell::Bash),
+            "sh" => Ok(Shell::Sh),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("Unknown shell '{}', expected bash, sh, zsh or fish", s)),
+        }
+    }
+}
+
+// Collects the PIDs of every process in the session shell's session (the agent's processes),
+// skipping the session leader itself. Standalone execs live in their own session so they are
+// never matched.
+const SESSION_PIDS: &str = r#"pids=""; for p in /proc/[0-9]*; do s=$(sed 's/.*) //' "$p/stat" 2>/dev/null | cut -d' ' -f4); [ "$s" = "$SOS_SID" ] && [ "${p#/proc/}" != "$SOS_SID" ] && pids="$pids ${p#/proc/}"; done; "#;
+
+// Collects the process groups of the session shell's session: the group of the shell and those
+// of the jobs it put in the background.
+const SESSION_GROUPS: &str = r#"pgs=""; for p in /proc/[0-9]*; do set -- $(sed 's/.*) //' "$p/stat" 2>/dev/null); [ "$4" = "$SOS_SID" ] && case " $pgs " in *" $3 "*) ;; *) pgs="$pgs $3";; esac; done; "#;
+
+// Parent of the cgroups the sessions are frozen in, when the container can manage its own
+// cgroups (v2). Each sandbox has its own, as containers may share the cgroup hierarchy.
+const FREEZER_CGROUP_PREFIX: &str = "/sys/fs/cgroup/sos-frozen-";
+
+/// Exit code of [`freeze_cmd`] when the container cannot manage its own cgroups.
+pub const NO_FREEZER: i64 = 3;
+
+/// Builds the command that freezes the processes of the session led by `session_pid`
+/// with the cgroup v2 freezer, in a cgroup of the sandbox `id`. POSIX sh, so it runs
+/// whatever the shell of the sandbox. Exits with [`NO_FREEZER`] when the cgroup is not
+/// writable.
+pub fn freeze_cmd(session_pid: u32, id: &str) -> String {
+    format!(
+        "SOS_SID={sid}; cg={cg}{id}; \
+         mkdir -p $cg 2>/dev/null && [ -w $cg/cgroup.freeze ] || exit {no_freezer}; \
+         {pids}for p in $SOS_SID $pids; do echo $p > $cg/cgroup.procs; done; \
+         echo 1 > $cg/cgroup.freeze",
+        sid = session_pid,
+        cg = FREEZER_CGROUP_PREFIX,
+        id = id,
+        no_freezer = NO_FREEZER,
+        pids = SESSION_PIDS,
+    )
+}
+
+/// Builds the command that stops the process groups of the session led by
+/// `session_pid` with SIGSTOP, for containers without a freezer.
+pub fn stop_cmd(session_pid: u32) -> String {
+    format!(
+        "SOS_SID={sid}; {pgs}for pg in $pgs; do kill -s STOP -- -$pg; done",
+        sid = session_pid,
+        pgs = SESSION_GROUPS,
+    )
+}
+
+/// Builds the command that hangs up the session led by `session_pid`, as when its
+/// terminal is closed.
+pub fn hangup_cmd(session_pid: u32) -> String {
+    format!(
+        "SOS_SID={sid}; {pids}kill -HUP $SOS_SID $pids 2>/dev/null; true",
+        sid = session_pid,
+        pids = SESSION_PIDS,
+    )
+}
+
+/// Builds the command that resumes the processes frozen by [`freeze_cmd`] in the cgroup
+/// of the sandbox `id`, or stopped by [`stop_cmd`].
+pub fn thaw_cmd(session_pid: u32, id: &str) -> String {
+    format!(
+        "SOS_SID={sid}; cg={cg}{id}; \
+         if [ -w $cg/cgroup.freeze ]; then \
+           echo 0 > $cg/cgroup.freeze; \
+         else \
+           {pgs}for pg in $pgs; do kill -s CONT -- -$pg; done; \
+         fi",
+        sid = session_pid,
+        cg = FREEZER_CGROUP_PREFIX,
+        id = id,
+        pgs = SESSION_GROUPS,
+    )
 }
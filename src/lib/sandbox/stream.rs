@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+
+use futures::{Stream, StreamExt, stream};
+use tokio::time::{self, Duration, Instant};
+
+use super::io::ChunkParser;
+use super::{
+    CommandExecution, CommandResult, OutputChunk, Sandbox, SandboxStatus, UsageCounters,
+};
+
+/// Quiet time after a prompt that ends the command, as for buffered session commands.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Session command whose output is being streamed, see [`Sandbox::exec_session_stream`].
+struct SessionStream<'a> {
+    sandbox: &'a mut Sandbox,
+    /// Container of the sandbox, kept when the command exits the session
+    cid: String,
+    parser: ChunkParser,
+    ready: VecDeque<OutputChunk>,
+    /// Number of lines of the input, prompts expected before the command is over
+    expected_markers: usize,
+    markers_seen: usize,
+    execution: CommandExecution,
+    execution_start: Instant,
+    usage_before: Option<UsageCounters>,
+    output: String,
+    stdout: String,
+    stderr: String,
+    exit_code: i64,
+    exit_status: Option<i64>,
+    done: bool,
+}
+
+/// Streams the output of `execution`, whose command was written to the session.
+pub(super) fn session_stream(
+    sandbox: &mut Sandbox,
+    cid: String,
+    execution: CommandExecution,
+    usage_before: Option<UsageCounters>,
+) -> impl Stream<Item = OutputChunk> + Send + Unpin + '_ {
+    let echo = match sandbox.shell.echoes_input() {
+        true => execution.command.lines().map(|line| line.trim_end().to_string()).collect(),
+        false => Vec::new(),
+    };
+    let state = SessionStream {
+        parser: ChunkParser::new(sandbox.markers.clone(), echo),
+        sandbox,
+        cid,
+        ready: VecDeque::new(),
+        expected_markers: execution.command.split('\n').count(),
+        markers_seen: 0,
+        execution,
+        execution_start: Instant::now(),
+        usage_before,
+        output: String::new(),
+        stdout: String::new(),
+        stderr: String::new(),
+        exit_code: -1,
+        exit_status: None,
+        done: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        let chunk = state.next_chunk().await?;
+        Some((chunk, state))
+    })
+    .boxed()
+}
+
+impl SessionStream<'_> {
+    async fn next_chunk(&mut self) -> Option<OutputChunk> {
+        loop {
+            if let Some(chunk) = self.ready.pop_front() {
+                return Some(chunk);
+            }
+            if self.done {
+                return None;
+            }
+            self.read().await;
+        }
+    }
+
+    /// Reads the next output of the session, or finishes the command when it is over.
+    async fn read(&mut self) {
+        let Some(receiver) = &self.sandbox.output_receiver else {
+            return self.finish().await;
+        };
+        let received = time::timeout(IDLE_TIMEOUT, async { receiver.lock().await.next().await }).await;
+        match received {
+            Ok(Some(bytes)) => {
+                let chunks = self.parser.push(&String::from_utf8_lossy(&bytes));
+                self.take(chunks);
+                if self.markers_seen >= self.expected_markers {
+                    self.finish().await;
+                }
+            }
+            Ok(None) => self.finish().await,
+            Err(_) if self.markers_seen > 0 => self.finish().await,
+            Err(_) => {
+                let chunks = self.parser.flush();
+                self.take(chunks);
+            }
+        }
+    }
+
+    fn take(&mut self, chunks: Vec<OutputChunk>) {
+        for chunk in &chunks {
+            for observer in &self.sandbox.observers {
+                observer.on_output_chunk(&self.sandbox.id, chunk);
+            }
+            match chunk {
+                OutputChunk::Stdout(text) => {
+                    self.output += text;
+                    self.stdout += text;
+                }
+                OutputChunk::Stderr(text) => {
+                    self.output += text;
+                    self.stderr += text;
+                }
+                OutputChunk::Marker(code) => {
+                    self.exit_code = *code;
+                    self.markers_seen += 1;
+                }
+                OutputChunk::Exit(status) => self.exit_status = Some(*status),
+            }
+        }
+        self.ready.extend(chunks);
+    }
+
+    /// Records the command in the trajectory, once its output was read.
+    async fn finish(&mut self) {
+        self.done = true;
+        let chunks = self.parser.flush();
+        self.take(chunks);
+
+        if let Some(status) = self.exit_status {
+            self.sandbox.set_status(SandboxStatus::Exited(self.cid.clone(), status));
+        }
+        let mut result = CommandResult {
+            output: self.output.trim_end().to_string(),
+            stdout: self.stdout.trim_end().to_string(),
+            stderr: self.stderr.trim_end().to_string(),
+            exit_code: self.exit_status.unwrap_or(self.exit_code),
+            exited: self.exit_status.is_some(),
+            truncated: self.sandbox.output_truncated.swap(false, Ordering::Relaxed),
+            raw_output: None,
+        };
+        self.sandbox.output_filters.apply_to(&mut result);
+        self.execution.result = Some(result);
+        self.execution.duration = Some(self.execution_start.elapsed());
+        self.execution.usage = self.sandbox.command_usage(self.usage_before.take()).await;
+        self.sandbox.command_ended(&self.execution).await;
+        self.sandbox.spend_budget();
+    }
+}
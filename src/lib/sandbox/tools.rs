@@ -0,0 +1,86 @@
+//! Tool bundles installed into the container on start.
+//!
+//! A bundle is installed under `$HOME/.sos/tools/<name>`, which is put on the `PATH` of
+//! the session and of standalone commands. Files are written by standalone commands
+//! carrying their content in base64, a chunk at a time as a single command argument is
+//! limited in size, so the image needs `base64`, and `tar` for bundles shipped as archives.
+use std::sync::Arc;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::Bytes;
+
+use crate::task::shell_quote;
+
+/// Directory bundles are installed in, expanded by the shell.
+const TOOLS_DIR: &str = "$HOME/.sos/tools";
+
+/// Most base64 written by a single command, below the 128 KiB Linux limit on the length
+/// of an argument.
+const CHUNK_BYTES: usize = 96 * 1024;
+
+/// File of a tool bundle.
+#[derive(Debug, Clone)]
+pub struct ToolFile {
+    /// Path relative to the directory of the bundle
+    pub path: String,
+    pub content: Bytes,
+    pub executable: bool,
+    /// Whether the file is a tar archive, gzipped when its name ends with `gz`, extracted
+    /// in the directory of the bundle instead of installed
+    pub archive: bool,
+}
+
+/// Named set of scripts or binaries installed into the container on start.
+#[derive(Debug, Clone)]
+pub struct ToolBundle {
+    pub name: String,
+    pub files: Arc<Vec<ToolFile>>,
+}
+
+impl ToolBundle {
+    fn dir(&self) -> String {
+        format!("\"{}/{}\"", TOOLS_DIR, self.name)
+    }
+
+    /// Commands installing the bundle, in order. POSIX sh.
+    pub fn install_cmds(&self) -> Vec<String> {
+        let dir = self.dir();
+        let mut cmds = vec![format!("rm -rf {dir} && mkdir -p {dir}")];
+        for file in self.files.iter() {
+            let target = format!("{}/{}", dir, shell_quote(&file.path));
+            cmds.push(format!("mkdir -p \"$(dirname {target})\""));
+            cmds.extend(write_cmds(&target, &file.content));
+            if file.archive {
+                let gzip = if file.path.ends_with("gz") { "z" } else { "" };
+                cmds.push(format!("tar -x{gzip}f {target} -C {dir} && rm {target}"));
+            } else if file.executable {
+                cmds.push(format!("chmod +x {target}"));
+            }
+        }
+        cmds
+    }
+}
+
+/// Commands writing `content` to `target`, a path quoted or expanded by the shell. POSIX
+/// sh, needing `base64`.
+pub(super) fn write_cmds(target: &str, content: &[u8]) -> Vec<String> {
+    let encoded = BASE64.encode(content);
+    let mut cmds = vec![format!(": > {target}.b64")];
+    for chunk in encoded.as_bytes().chunks(CHUNK_BYTES) {
+        // The base64 alphabet needs no quoting
+        let chunk = std::str::from_utf8(chunk).expect("base64 is ASCII");
+        cmds.push(format!("printf '%s' {chunk} >> {target}.b64"));
+    }
+    cmds.push(format!("base64 -d {target}.b64 > {target} && rm {target}.b64"));
+    cmds
+}
+
+/// Command putting the directories of `bundles` first on the `PATH`. Also understood by
+/// fish, whose quoted `$PATH` is joined with colons.
+pub fn path_cmd(bundles: &[ToolBundle]) -> String {
+    let dirs: Vec<String> = bundles
+        .iter()
+        .map(|bundle| format!("{}/{}", TOOLS_DIR, bundle.name))
+        .collect();
+    format!("export PATH=\"{}:$PATH\"", dirs.join(":"))
+}
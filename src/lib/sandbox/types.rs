@@ -1,6 +1,8 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
-use tokio::time::Instant;
-use tracing::error;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -12,12 +14,31 @@ pub enum Error {
     AlreadyStarted,
     #[error("Sandbox session already exited")]
     AlreadyExited,
-    #[error("Setup commands failed: {0}")]
-    SetupCommandsFailed(String),
+    #[error("Sandbox session has not exited")]
+    SessionNotExited,
+    #[error("Sandbox session is frozen")]
+    Frozen,
+    #[error("Sandbox session is not frozen")]
+    NotFrozen,
+    #[error("Sandbox has no verify command")]
+    NoVerifier,
+    #[error("Setup command {index} ({command}) failed: {output}")]
+    SetupCommandsFailed {
+        index: usize,
+        command: String,
+        output: String,
+    },
+    #[error("Hook failed: {0}")]
+    HookFailed(String),
+    #[error("Failed to install tool bundle {0}: {1}")]
+    ToolInstallFailed(String, String),
+    #[error("Failed to clone {0}: {1}")]
+    RepoCloneFailed(String, String),
+    #[error("Patch failed: {0}")]
+    PatchFailed(String),
     #[error("Failed to pull image")]
     PullImageFailed {
-        #[from]
-        source: bollard::errors::Error,
+        source: Box<dyn std::error::Error + Send + Sync>,
     },
     #[error("Failed to stop container: {0}")]
     StopContainerFailed(String),
@@ -27,48 +48,336 @@ pub enum Error {
         exit_code: Option<i64>,
         logs: String,
     },
+    #[error("Failed to pause or unpause container: {0}")]
+    PauseFailed(String),
     #[error("Container write failed")]
     ContainerWriteFailed(String),
     #[error("Container read failed: {0}")]
     ContainerReadFailed(String),
+    #[error("Archive is larger than {0} bytes")]
+    ArchiveTooLarge(usize),
     #[error("Exec failed: {0} (exit code: {1})")]
     ExecFailed(String, i64),
     #[error("Failed to create exec: {0}")]
     CreateExecFailed(String),
     #[error("Timeout waiting for marker: {0}")]
     TimeoutWaitingForMarker(String),
+    #[error("Command did not finish within {0} seconds")]
+    CommandTimedOut(u64),
+    #[error("Command is blocked in this sandbox by pattern {0}")]
+    CommandBlocked(String),
+    #[error("Sandbox used up its budget of {0} commands")]
+    BudgetExhausted(usize),
+    #[error("Sandbox crashed, container exit code: {exit_code:?}, restarted: {restarted}")]
+    Crashed {
+        exit_code: Option<i64>,
+        restarted: bool,
+    },
+    #[error("Jupyter kernel failed: {0}")]
+    KernelFailed(String),
+    #[error("Sandbox builder has no {0}")]
+    MissingSetting(&'static str),
 }
 
-// TODO: capture exit code on exit command
 #[derive(Debug)]
 pub enum Status {
     Created,
-    Started(String),     // container id
-    Exited(String), // Session exited but container is still running
-    Stopped(Result<()>), // result of stop
+    Queued,                       // Waiting for capacity to start
+    Started(String),              // container id
+    Exited(String, i64),          // Session exited with a status but container is still running
+    Frozen(String),               // Session processes are frozen, container is still running
+    Exhausted(String),            // No session commands left in the budget, container is still running
+    Crashed(String, Option<i64>), // Session or container died, exit code of the container if stopped
+    Stopped(Result<()>),          // result of stop
 }
 
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Status::Created => write!(f, "created"),
+            Status::Queued => write!(f, "queued"),
             Status::Started(_) => write!(f, "started"),
-            Status::Exited(_) => write!(f, "exited"),
+            Status::Exited(..) => write!(f, "exited"),
+            Status::Frozen(_) => write!(f, "frozen"),
+            Status::Exhausted(_) => write!(f, "exhausted"),
+            Status::Crashed(..) => write!(f, "crashed"),
             Status::Stopped(_) => write!(f, "stopped"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// What a sandbox does once its container or session shell died.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Stays [`Status::Crashed`] until stopped
+    #[default]
+    Never,
+    /// Reattaches the session shell when the container still runs, replaces the container
+    /// otherwise, up to [`MAX_RESTARTS`](super::MAX_RESTARTS) times
+    OnCrash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandExecution {
     pub command: String,
-    pub timestamp: Instant,
+    /// Wall-clock time the command was sent to the session
+    pub timestamp: DateTime<Utc>,
     pub result: Option<CommandResult>,
+    /// Time taken by the command, set with the result
+    #[serde(with = "duration_secs")]
+    pub duration: Option<Duration>,
+    /// Resources used while the command ran, set with the result when the runtime
+    /// could be sampled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CommandUsage>,
+    /// Blocked command pattern of the sandbox the command matched, in which case it was
+    /// not run and has no result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+/// Resources used by the sandbox while a command ran, from its counters read before and
+/// after the command. Processes the command left running in the background are counted
+/// while it runs, as are others still running from earlier commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandUsage {
+    pub cpu_seconds: f64,
+    /// Highest memory usage of the sandbox since it started, up to the end of the
+    /// command, when the runtime tracks it. It is not reset between commands, so it only
+    /// tells the command apart from earlier ones when it grows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_peak_bytes: Option<u64>,
+}
+
+impl CommandUsage {
+    pub fn between(before: &UsageCounters, after: &UsageCounters) -> Self {
+        CommandUsage {
+            cpu_seconds: after.cpu_usec.saturating_sub(before.cpu_usec) as f64 / 1e6,
+            memory_peak_bytes: after.memory_peak_bytes,
+        }
+    }
+}
+
+/// (De)serializes durations as fractional seconds.
+mod duration_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        duration.map(|d| d.as_secs_f64()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(d)?
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Result of a command. `output` interleaves both streams, in the order their lines
+/// were written. `truncated` is set when part of the output was dropped, because it
+/// exceeded the 8 MiB kept per command or came faster than it was read. Session output
+/// has its ANSI escape sequences stripped, `raw_output` keeps them. When the command
+/// `exited` the session, `exit_code` is the status it exited with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandResult {
     pub output: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
     pub exit_code: i64,
     pub exited: bool,
+    #[serde(default)]
+    pub truncated: bool,
+    /// Session output as the terminal wrote it, not kept in the trajectory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_output: Option<String>,
+}
+
+/// Piece of the output of a session command, from [`Sandbox::exec_session_stream`](super::Sandbox::exec_session_stream).
+/// Output comes a line at a time, or as much of the last line as was printed when the
+/// command goes quiet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputChunk {
+    Stdout(String),
+    Stderr(String),
+    /// Prompt printed after each command of the input, with its exit code
+    Marker(i64),
+    /// The command exited the session with this status
+    Exit(i64),
+}
+
+/// Result of running the verify command of a sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    pub timestamp: DateTime<Utc>,
+    /// Number of trajectory commands executed when the verification ran
+    pub after_step: usize,
+    pub score: f64,
+    pub passed: bool,
+    pub output: String,
+    pub exit_code: i64,
+}
+
+/// HTTP request made by the sandbox through the recording proxy, see [`crate::proxy`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkRequest {
+    /// Wall-clock time the request reached the proxy
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    /// URL of plain HTTP requests, `host:port` of HTTPS tunnels (`CONNECT`)
+    pub url: String,
+    /// Status of the response, unset when the upstream could not be reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Commands, verifications and network requests of a sandbox, live or loaded from the
+/// trajectory store.
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    pub sandbox_id: String,
+    /// Wall-clock time the sandbox started
+    pub started_at: Option<DateTime<Utc>>,
+    pub commands: Vec<CommandExecution>,
+    pub verifications: Vec<Verification>,
+    pub requests: Vec<NetworkRequest>,
+}
+
+impl Trajectory {
+    /// Seconds between the start of the sandbox and the command.
+    pub fn offset(&self, cmd: &CommandExecution) -> f64 {
+        let start = self.started_at.unwrap_or(cmd.timestamp);
+        (cmd.timestamp - start).as_seconds_f64()
+    }
+
+    /// Format the trajectory as a human-readable string
+    pub fn format(&self) -> String {
+        let mut output = String::new();
+        for cmd in self.commands.iter() {
+            output.push_str(&format!("$ {}\n", cmd.command));
+
+            match (&cmd.result, &cmd.blocked) {
+                (Some(result), _) => {
+                    if !result.output.is_empty() {
+                        output.push_str(&result.output);
+                        output.push('\n');
+                    }
+                }
+                (None, Some(pattern)) => {
+                    output.push_str(&format!("Status: Blocked by pattern {}\n", pattern));
+                }
+                (None, None) => {
+                    output.push_str("Status: Command started but no result recorded\n");
+                }
+            }
+        }
+
+        for verification in self.verifications.iter() {
+            output.push_str(&format!(
+                "# verification after step {}: score {} ({})\n",
+                verification.after_step,
+                verification.score,
+                if verification.passed { "passed" } else { "failed" }
+            ));
+        }
+
+        output
+    }
+}
+
+/// Resource limits applied to the sandbox container.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Memory limit in MiB
+    pub memory_mb: Option<i64>,
+    /// Number of CPUs (fractional values allowed)
+    pub cpus: Option<f64>,
+    /// Maximum number of processes
+    pub pids: Option<i64>,
+}
+
+/// Size of the session terminal, in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for TerminalSize {
+    fn default() -> Self {
+        TerminalSize { cols: 80, rows: 24 }
+    }
+}
+
+/// Resource usage of a sandbox container, sampled from its runtime.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// CPU usage in percent of a single CPU, above 100 when several CPUs are busy
+    pub cpu_percent: f64,
+    /// Memory in use, excluding the page cache
+    pub memory_usage_bytes: u64,
+    pub memory_limit_bytes: u64,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+    pub pids: u64,
+}
+
+/// Cumulative resource counters of a sandbox container, cheap to read unlike
+/// [`ResourceUsage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageCounters {
+    /// CPU time used by the processes of the container, in microseconds
+    pub cpu_usec: u64,
+    /// Highest memory usage of the container since it started
+    pub memory_peak_bytes: Option<u64>,
+}
+
+/// Progress of an image pull, summed over the layers of the image.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PullProgress {
+    pub layers: usize,
+    /// Layers downloaded and extracted, or already present
+    pub layers_done: usize,
+    pub downloaded_bytes: u64,
+    /// Size of the layers whose download started
+    pub total_bytes: u64,
+}
+
+impl PullProgress {
+    /// Downloaded share of the known layer sizes, in percent.
+    pub fn percent(&self) -> Option<f64> {
+        (self.total_bytes > 0)
+            .then(|| self.downloaded_bytes as f64 / self.total_bytes as f64 * 100.0)
+    }
+}
+
+/// Host path bind-mounted into the sandbox container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl Mount {
+    /// Docker bind specification (`source:target[:ro]`)
+    pub fn to_bind(&self) -> String {
+        match self.read_only {
+            true => format!("{}:{}:ro", self.source, self.target),
+            false => format!("{}:{}", self.source, self.target),
+        }
+    }
 }
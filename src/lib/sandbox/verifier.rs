@@ -0,0 +1,33 @@
+/// Parses the output of a verify command into a `(score, passed)` verdict.
+///
+/// The last non-empty line of the output decides:
+/// - `PASS`/`PASSED` or `FAIL`/`FAILED` (any case) give a score of 1.0 or 0.0.
+/// - A number, optionally prefixed with `score:` or `reward:`, is the score; the
+///   verdict passes when the command exited with 0.
+/// - Anything else falls back to the exit code: 1.0 and passed on 0, 0.0 otherwise.
+pub fn parse_verdict(output: &str, exit_code: i64) -> (f64, bool) {
+    let last_line = output
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+
+    match last_line.to_ascii_lowercase().as_str() {
+        "pass" | "passed" => return (1.0, true),
+        "fail" | "failed" => return (0.0, false),
+        _ => {}
+    }
+
+    let lower = last_line.to_ascii_lowercase();
+    let value = ["score:", "reward:"]
+        .iter()
+        .find_map(|prefix| lower.strip_prefix(prefix))
+        .unwrap_or(&lower)
+        .trim();
+    match value.parse::<f64>() {
+        Ok(score) if score.is_finite() => (score, exit_code == 0),
+        _ if exit_code == 0 => (1.0, true),
+        _ => (0.0, false),
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use super::types::{CommandExecution, NetworkRequest, Status, Trajectory, Verification};
+
+/// Most recent session output kept for `GET /sandboxes/{id}/logs`, in bytes.
+const SESSION_LOG_BYTES: usize = 256 * 1024;
+
+/// Part of a sandbox that is read by the API: its status and trajectory.
+///
+/// Kept behind its own lock, separate from the sandbox, so reads never wait for a
+/// command to finish. The lock is never held across an await.
+#[derive(Clone)]
+pub struct SandboxView {
+    state: Arc<RwLock<ViewState>>,
+    /// Broadcasts every command added to the trajectory, with its index
+    events: broadcast::Sender<(usize, CommandExecution)>,
+    /// Broadcasts the raw output of the session as it is read from the terminal
+    session_output: broadcast::Sender<Bytes>,
+}
+
+struct ViewState {
+    sandbox_id: String,
+    status: String,
+    container_id: Option<String>,
+    started_at: Option<DateTime<Utc>>,
+    trajectory: Vec<CommandExecution>,
+    verifications: Vec<Verification>,
+    requests: Vec<NetworkRequest>,
+    last_standalone_exit_code: Option<i64>,
+    archive_url: Option<String>,
+    session_log: VecDeque<u8>,
+}
+
+impl ViewState {
+    fn trajectory(&self) -> Trajectory {
+        Trajectory {
+            sandbox_id: self.sandbox_id.clone(),
+            started_at: self.started_at,
+            commands: self.trajectory.clone(),
+            verifications: self.verifications.clone(),
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+impl SandboxView {
+    pub(crate) fn new(sandbox_id: &str) -> Self {
+        SandboxView {
+            state: Arc::new(RwLock::new(ViewState {
+                sandbox_id: sandbox_id.to_string(),
+                status: Status::Created.to_string(),
+                container_id: None,
+                started_at: None,
+                trajectory: Vec::new(),
+                verifications: Vec::new(),
+                requests: Vec::new(),
+                last_standalone_exit_code: None,
+                archive_url: None,
+                session_log: VecDeque::new(),
+            })),
+            events: broadcast::channel(64).0,
+            session_output: broadcast::channel(256).0,
+        }
+    }
+
+    /// Status of the sandbox, as shown by `GET /sandboxes`
+    pub fn status(&self) -> String {
+        self.state.read().unwrap().status.clone()
+    }
+
+    /// Wall-clock time when the sandbox was started
+    pub fn started_at(&self) -> Option<DateTime<Utc>> {
+        self.state.read().unwrap().started_at
+    }
+
+    /// ID of the container, while it runs
+    pub fn container_id(&self) -> Option<String> {
+        self.state.read().unwrap().container_id.clone()
+    }
+
+    /// Number of commands executed in the session
+    pub fn command_count(&self) -> usize {
+        self.state.read().unwrap().trajectory.len()
+    }
+
+    /// Exit code of the last command of the session that finished
+    pub fn last_exit_code(&self) -> Option<i64> {
+        let state = self.state.read().unwrap();
+        state.trajectory.iter().rev().find_map(|cmd| cmd.result.as_ref()).map(|result| result.exit_code)
+    }
+
+    pub fn last_standalone_exit_code(&self) -> Option<i64> {
+        self.state.read().unwrap().last_standalone_exit_code
+    }
+
+    /// Object storage URL the sandbox was archived to when it stopped
+    pub fn archive_url(&self) -> Option<String> {
+        self.state.read().unwrap().archive_url.clone()
+    }
+
+    pub fn set_archive_url(&self, url: String) {
+        self.state.write().unwrap().archive_url = Some(url);
+    }
+
+    /// Copy of the commands and verifications of the sandbox
+    pub fn snapshot(&self) -> Trajectory {
+        self.state.read().unwrap().trajectory()
+    }
+
+    /// Snapshot of the trajectory, with a subscription to the commands added after it
+    pub fn subscribe(&self) -> (Trajectory, broadcast::Receiver<(usize, CommandExecution)>) {
+        // Commands are added under the write lock, so none is missed or seen twice
+        let state = self.state.read().unwrap();
+        (state.trajectory(), self.events.subscribe())
+    }
+
+    /// Latest raw output of the session, markers and echoed commands included, with a
+    /// subscription to the output read after it
+    pub fn subscribe_session(&self) -> (Vec<u8>, broadcast::Receiver<Bytes>) {
+        let state = self.state.read().unwrap();
+        let log = state.session_log.iter().copied().collect();
+        (log, self.session_output.subscribe())
+    }
+
+    pub(crate) fn push_session_output(&self, output: &Bytes) {
+        let mut state = self.state.write().unwrap();
+        state.session_log.extend(output.iter());
+        let excess = state.session_log.len().saturating_sub(SESSION_LOG_BYTES);
+        state.session_log.drain(..excess);
+        let _ = self.session_output.send(output.clone());
+    }
+
+    pub(crate) fn set_status(&self, status: &Status) {
+        let mut state = self.state.write().unwrap();
+        state.status = status.to_string();
+        state.container_id = match status {
+            Status::Started(cid)
+            | Status::Exited(cid, _)
+            | Status::Frozen(cid)
+            | Status::Exhausted(cid)
+            | Status::Crashed(cid, _) => Some(cid.clone()),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn set_started_at(&self, started_at: DateTime<Utc>) {
+        self.state.write().unwrap().started_at = Some(started_at);
+    }
+
+    pub(crate) fn set_last_standalone_exit_code(&self, exit_code: i64) {
+        self.state.write().unwrap().last_standalone_exit_code = Some(exit_code);
+    }
+
+    pub(crate) fn push_command(&self, command: CommandExecution) {
+        let mut state = self.state.write().unwrap();
+        state.trajectory.push(command.clone());
+        // No subscribers is fine
+        let _ = self.events.send((state.trajectory.len() - 1, command));
+    }
+
+    pub(crate) fn push_verification(&self, verification: Verification) {
+        self.state.write().unwrap().verifications.push(verification);
+    }
+
+    pub(crate) fn push_request(&self, request: NetworkRequest) {
+        self.state.write().unwrap().requests.push(request);
+    }
+}
@@ -0,0 +1,105 @@
+//! Durable trajectory storage.
+//!
+//! Every sandbox gets a JSONL file under `<dir>/<tenant>/<sandbox id>.jsonl`, appended to
+//! as the sandbox starts, runs commands and is verified. Trajectories stay available
+//! after the sandbox is removed and across server restarts.
+use std::io;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::sandbox::{CommandExecution, NetworkRequest, Trajectory, Verification};
+
+/// Line of a stored trajectory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TrajectoryEvent {
+    Start {
+        sandbox_id: String,
+        image: String,
+        started_at: DateTime<Utc>,
+    },
+    Command(CommandExecution),
+    Verification(Verification),
+    Request(NetworkRequest),
+}
+
+/// Append-only store of trajectories on disk.
+#[derive(Debug)]
+pub struct TrajectoryStore {
+    dir: PathBuf,
+}
+
+impl TrajectoryStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TrajectoryStore { dir: dir.into() }
+    }
+
+    /// Path of the trajectory file. Only sandbox IDs are accepted, so requested IDs
+    /// cannot escape the store directory.
+    fn path(&self, tenant: &str, sandbox_id: &str) -> Option<PathBuf> {
+        Uuid::parse_str(sandbox_id).ok()?;
+        Some(self.dir.join(tenant).join(format!("{}.jsonl", sandbox_id)))
+    }
+
+    /// Appends an event to the trajectory of the sandbox.
+    pub async fn append(
+        &self,
+        tenant: &str,
+        sandbox_id: &str,
+        event: &TrajectoryEvent,
+    ) -> io::Result<()> {
+        let path = self
+            .path(tenant, sandbox_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid sandbox ID"))?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    /// Reads the trajectory of a sandbox, or `None` if it was never stored.
+    /// A truncated last line, left by a crash mid-write, is skipped.
+    pub async fn load(&self, tenant: &str, sandbox_id: &str) -> io::Result<Option<Trajectory>> {
+        let Some(path) = self.path(tenant, sandbox_id) else {
+            return Ok(None);
+        };
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut trajectory = Trajectory {
+            sandbox_id: sandbox_id.to_string(),
+            started_at: None,
+            commands: Vec::new(),
+            verifications: Vec::new(),
+            requests: Vec::new(),
+        };
+        for line in text.lines() {
+            match serde_json::from_str(line) {
+                Ok(TrajectoryEvent::Start { started_at, .. }) => {
+                    trajectory.started_at = Some(started_at)
+                }
+                Ok(TrajectoryEvent::Command(cmd)) => trajectory.commands.push(cmd),
+                Ok(TrajectoryEvent::Verification(v)) => trajectory.verifications.push(v),
+                Ok(TrajectoryEvent::Request(request)) => trajectory.requests.push(request),
+                Err(_) => continue,
+            }
+        }
+        Ok(Some(trajectory))
+    }
+}
@@ -0,0 +1,230 @@
+//! SWE-bench task adapter.
+//!
+//! Converts SWE-bench-style instances into [`Task`](crate::task::Task)s: the sandbox clones the repository
+//! at the base commit and applies the test patch during setup, the problem statement
+//! becomes the instructions and the eval command is the verify command.
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+
+use anyhow::Context;
+#[cfg(feature = "server")]
+use axum::{Json, extract::State};
+#[cfg(feature = "server")]
+use lazy_static::lazy_static;
+#[cfg(feature = "server")]
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[cfg(feature = "server")]
+use crate::http::{ApiError, ApiJson, Caller, SoSState, require_admin};
+#[cfg(feature = "server")]
+use crate::task::{Task, TaskFile, shell_quote};
+
+#[cfg(feature = "server")]
+lazy_static! {
+    static ref REPO: Regex = Regex::new(r"^[\w.-]+/[\w.-]+$").unwrap();
+    static ref COMMIT: Regex = Regex::new(r"^[0-9a-fA-F]{7,40}$").unwrap();
+}
+
+/// Where the test patch is seeded before it is applied.
+#[cfg(feature = "server")]
+const TEST_PATCH_PATH: &str = "/tmp/sos/test.patch";
+
+/// A SWE-bench instance, as found in the dataset JSON/JSONL exports.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SweBenchInstance {
+    pub instance_id: String,
+    /// GitHub repository, e.g. `django/django`
+    pub repo: String,
+    pub base_commit: String,
+    #[serde(default)]
+    pub test_patch: String,
+    #[serde(default)]
+    pub problem_statement: String,
+    /// Command running the tests, overriding [`SweBenchOptions::eval_command`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_command: Option<String>,
+    /// Tests that must pass once the issue is fixed. The dataset stores them as a JSON
+    /// encoded string, plain lists are accepted too.
+    #[serde(rename = "FAIL_TO_PASS", default, deserialize_with = "string_list")]
+    pub fail_to_pass: Vec<String>,
+    #[serde(rename = "PASS_TO_PASS", default, deserialize_with = "string_list")]
+    pub pass_to_pass: Vec<String>,
+}
+
+/// Settings shared by every imported instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweBenchOptions {
+    /// Image of the sandboxes. Needs git and the toolchain of the repositories.
+    pub image: String,
+    /// Directory the repository is cloned into
+    #[serde(default = "default_workdir")]
+    pub workdir: String,
+    /// Commands run in the workdir after checking out the base commit, e.g. to install
+    /// the project
+    #[serde(default)]
+    pub setup_commands: Vec<String>,
+    /// Command running the tests in the workdir. Defaults to running the
+    /// `FAIL_TO_PASS` and `PASS_TO_PASS` tests with pytest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit_secs: Option<u64>,
+}
+
+fn default_workdir() -> String {
+    "/testbed".to_string()
+}
+
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringList {
+        List(Vec<String>),
+        Encoded(String),
+    }
+    match StringList::deserialize(deserializer)? {
+        StringList::List(list) => Ok(list),
+        StringList::Encoded(s) if s.trim().is_empty() => Ok(Vec::new()),
+        StringList::Encoded(s) => serde_json::from_str(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(feature = "server")]
+impl SweBenchInstance {
+    /// Converts the instance into a task named after its instance ID.
+    pub fn to_task(&self, options: &SweBenchOptions) -> Result<Task, ApiError> {
+        if self.instance_id.is_empty() {
+            return Err(ApiError::invalid("SWE-bench instances need an instance_id"));
+        }
+        if !REPO.is_match(&self.repo) {
+            return Err(ApiError::invalid(format!(
+                "Invalid repository {} of {}",
+                self.repo, self.instance_id
+            )));
+        }
+        if !COMMIT.is_match(&self.base_commit) {
+            return Err(ApiError::invalid(format!(
+                "Invalid base commit {} of {}",
+                self.base_commit, self.instance_id
+            )));
+        }
+
+        let workdir = shell_quote(&options.workdir);
+        let mut setup_commands = vec![
+            format!(
+                "git clone --quiet https://github.com/{}.git {workdir}",
+                self.repo
+            ),
+            format!("git -C {workdir} checkout --quiet {}", self.base_commit),
+        ];
+        setup_commands.extend(
+            options
+                .setup_commands
+                .iter()
+                .map(|command| format!("cd {workdir} && {command}")),
+        );
+
+        let mut files = Vec::new();
+        if !self.test_patch.is_empty() {
+            files.push(TaskFile {
+                path: TEST_PATCH_PATH.to_string(),
+                content: self.test_patch.clone(),
+                executable: false,
+            });
+            setup_commands.push(format!("git -C {workdir} apply {TEST_PATCH_PATH}"));
+        }
+
+        let eval_command = self
+            .eval_command
+            .clone()
+            .or_else(|| options.eval_command.clone())
+            .unwrap_or_else(|| self.pytest_command());
+
+        Ok(Task {
+            name: self.instance_id.clone(),
+            image: options.image.clone(),
+            setup_commands,
+            files,
+            instructions: self.problem_statement.clone(),
+            verify_command: Some(format!("cd {workdir} && {eval_command}")),
+            time_limit_secs: options.time_limit_secs,
+            labels: HashMap::from([
+                ("swebench.instance_id".to_string(), self.instance_id.clone()),
+                ("swebench.repo".to_string(), self.repo.clone()),
+            ]),
+            ..Default::default()
+        })
+    }
+
+    /// Runs the tests of the instance with pytest.
+    fn pytest_command(&self) -> String {
+        let tests: Vec<String> = self
+            .fail_to_pass
+            .iter()
+            .chain(&self.pass_to_pass)
+            .map(|test| shell_quote(test))
+            .collect();
+        format!("python -m pytest -q {}", tests.join(" "))
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// Reads instances from a JSON array or a JSONL file.
+pub fn load_instances(path: impl AsRef<Path>) -> anyhow::Result<Vec<SweBenchInstance>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if text.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&text)?);
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid instance on line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+/// POST `/tasks/import/swebench` payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweBenchImport {
+    pub instances: Vec<SweBenchInstance>,
+    #[serde(flatten)]
+    pub options: SweBenchOptions,
+}
+
+/// POST `/tasks/import/swebench` handler.
+///
+/// Registers a task per instance, replacing existing tasks with the same name, and
+/// returns the task names. Nothing is registered if any instance is invalid. Only admin
+/// tenants import tasks.
+#[cfg(feature = "server")]
+pub async fn import_swebench(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(import): ApiJson<SweBenchImport>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    require_admin(&tenant, "Importing tasks")?;
+    let tasks = import
+        .instances
+        .iter()
+        .map(|instance| instance.to_task(&import.options))
+        .collect::<Result<Vec<_>, _>>()?;
+    for task in &tasks {
+        task.validate()?;
+    }
+
+    let names = tasks.iter().map(|task| task.name.clone()).collect();
+    let mut registry = state.tasks.write().await;
+    for task in tasks {
+        registry.insert(task.name.clone(), task);
+    }
+    Ok(Json(names))
+}
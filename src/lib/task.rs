@@ -0,0 +1,238 @@
+//! Evaluation tasks.
+//!
+//! A task describes everything needed to run an agent against a problem: the sandbox
+//! to build, files to seed into it, the instructions given to the agent and the command
+//! that verifies the result. Tasks are loaded from YAML or JSON files, or registered
+//! with `POST /tasks`, and `POST /tasks/{name}/instantiate` turns one into a started
+//! sandbox.
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+
+use anyhow::Context;
+#[cfg(feature = "server")]
+use axum::{
+    Json,
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::CreatePayload;
+#[cfg(feature = "server")]
+use crate::api::InstantiateResponse;
+#[cfg(feature = "server")]
+use crate::http::{ApiError, ApiJson, Caller, SoSState, require_admin, validate_image};
+use crate::sandbox::ResourceLimits;
+
+/// Task definition.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Task {
+    pub name: String,
+    pub image: String,
+    /// Commands run after the files are seeded
+    #[serde(default)]
+    pub setup_commands: Vec<String>,
+    /// Files written into the container before the setup commands run
+    #[serde(default)]
+    pub files: Vec<TaskFile>,
+    /// Instructions given to the agent
+    #[serde(default)]
+    pub instructions: String,
+    /// Command scoring the result, see `POST /sandboxes/{id}/verify`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_command: Option<String>,
+    /// Seconds after which the sandbox is stopped, overriding the server timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit_secs: Option<u64>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<ResourceLimits>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// File seeded into the sandbox of a task.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskFile {
+    /// Absolute path in the container. Parent directories are created.
+    pub path: String,
+    pub content: String,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+/// Quotes a string for the shell.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl TaskFile {
+    /// Shell command writing the file.
+    fn write_cmd(&self) -> String {
+        let path = shell_quote(&self.path);
+        let mut cmd = format!(
+            "mkdir -p \"$(dirname {path})\" && printf '%s' {} > {path}",
+            shell_quote(&self.content)
+        );
+        if self.executable {
+            cmd.push_str(&format!(" && chmod +x {path}"));
+        }
+        cmd
+    }
+}
+
+impl Task {
+    /// Reads a task from a YAML or JSON file, depending on its extension.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let task = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => serde_yaml::from_str(&text)?,
+        };
+        Ok(task)
+    }
+
+    /// Reads every `.yaml`, `.yml` and `.json` task in a directory.
+    pub fn load_dir(dir: impl AsRef<Path>) -> anyhow::Result<Vec<Self>> {
+        let dir = dir.as_ref();
+        let mut paths = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        paths
+            .iter()
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("yaml" | "yml" | "json")
+                )
+            })
+            .map(|path| Task::load(path).with_context(|| format!("Invalid task {}", path.display())))
+            .collect()
+    }
+
+    /// Checks the task before registering it.
+    #[cfg(feature = "server")]
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.name.is_empty() || self.image.is_empty() {
+            return Err(ApiError::invalid("Tasks need a name and an image"));
+        }
+        validate_image(&self.image)?;
+        if self.files.iter().any(|file| !file.path.starts_with('/')) {
+            return Err(ApiError::invalid("Task file paths must be absolute"));
+        }
+        Ok(())
+    }
+
+    /// Create payload of a sandbox running the task. The files are seeded by setup
+    /// commands that run before the task's own.
+    pub fn to_payload(&self) -> CreatePayload {
+        let setup_commands = self
+            .files
+            .iter()
+            .map(TaskFile::write_cmd)
+            .chain(self.setup_commands.iter().cloned())
+            .collect();
+        CreatePayload {
+            image: self.image.clone(),
+            setup_commands,
+            labels: self.labels.clone(),
+            env: self.env.clone(),
+            limits: self.limits.clone(),
+            verify_command: self.verify_command.clone(),
+            time_limit_secs: self.time_limit_secs,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+fn task_not_found(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "TASK_NOT_FOUND",
+        format!("Task {} not found", name),
+    )
+}
+
+/// GET `/tasks` handler.
+///
+/// Returns all the registered tasks, shared by the tenants.
+#[cfg(feature = "server")]
+pub async fn list_tasks(
+    State(state): State<Arc<SoSState>>,
+    Caller(_): Caller,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let tasks = state.tasks.read().await;
+    let mut list: Vec<Task> = tasks.values().cloned().collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(list))
+}
+
+/// GET `/tasks/{name}` handler.
+#[cfg(feature = "server")]
+pub async fn get_task(
+    UrlPath(name): UrlPath<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(_): Caller,
+) -> Result<Json<Task>, ApiError> {
+    let tasks = state.tasks.read().await;
+    tasks
+        .get(&name)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| task_not_found(&name))
+}
+
+/// POST `/tasks` handler.
+///
+/// Registers a task, replacing any existing task with the same name. Tasks are shared by
+/// the tenants, so only admin tenants register them.
+#[cfg(feature = "server")]
+pub async fn create_task(
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+    ApiJson(task): ApiJson<Task>,
+) -> Result<(), ApiError> {
+    require_admin(&tenant, "Registering tasks")?;
+    task.validate()?;
+    state.tasks.write().await.insert(task.name.clone(), task);
+    Ok(())
+}
+
+/// POST `/tasks/{name}/instantiate` handler.
+///
+/// Creates and starts a sandbox for the task, seeding its files, and returns the
+/// sandbox ID with the task instructions.
+#[cfg(feature = "server")]
+pub async fn instantiate_task(
+    UrlPath(name): UrlPath<String>,
+    State(state): State<Arc<SoSState>>,
+    Caller(tenant): Caller,
+) -> Result<Json<InstantiateResponse>, ApiError> {
+    let task = state
+        .tasks
+        .read()
+        .await
+        .get(&name)
+        .cloned()
+        .ok_or_else(|| task_not_found(&name))?;
+
+    let id = state.create_sandbox(&tenant, task.to_payload()).await?;
+    if let Err(e) = state.start_sandbox(&tenant, &id).await {
+        // A sandbox that failed to start is already gone, one the policy refused is not
+        let _ = state.stop_sandbox(&tenant, &id, true).await;
+        return Err(e);
+    }
+
+    Ok(Json(InstantiateResponse {
+        id,
+        instructions: task.instructions,
+    }))
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::error;
+
+use crate::policy::{Policy, PolicyConfig};
+
+/// Name of the namespace used when the server has no tenants configured.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Tenant entry of the server configuration.
+///
+/// Each API key or client certificate identity maps to a namespace with its own
+/// sandbox quota.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// Namespace name
+    pub name: String,
+    /// API key sent by clients as `Authorization: Bearer <api_key>`
+    #[serde(default)]
+    pub api_key: String,
+    /// Common name of the client certificates that belong to the tenant
+    #[serde(default)]
+    pub client_cert_cn: Option<String>,
+    /// Maximum number of concurrent sandboxes in the namespace
+    pub max_sandboxes: usize,
+    /// Allows the tenant to administer the server, e.g. reload its configuration
+    #[serde(default)]
+    pub admin: bool,
+    /// Rules of the admission policy that replace the server's for the tenant
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// Namespace isolating a group of sandboxes.
+///
+/// Sandboxes are only visible to callers of the tenant that created them, and the
+/// tenant semaphore caps how many of them can run at the same time.
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    pub semaphore: Arc<Semaphore>,
+    /// Permits of `semaphore`, see [`Tenant::set_max_sandboxes`]
+    max_sandboxes: AtomicUsize,
+    pub admin: bool,
+    /// Rules overriding the admission policy of the server
+    pub policy: Arc<Policy>,
+}
+
+impl Tenant {
+    pub fn new(name: String, max_sandboxes: usize) -> Self {
+        Tenant {
+            name,
+            semaphore: Arc::new(Semaphore::new(max_sandboxes)),
+            max_sandboxes: AtomicUsize::new(max_sandboxes),
+            admin: false,
+            policy: Arc::default(),
+        }
+    }
+
+    /// Tenant used for every request when no tenants are configured.
+    /// Its quota is unbounded, only the global semaphore applies. Servers without
+    /// tenants are open, so it administers them too.
+    pub fn unrestricted() -> Self {
+        Tenant {
+            admin: true,
+            ..Tenant::new(DEFAULT_TENANT.to_string(), Semaphore::MAX_PERMITS)
+        }
+    }
+
+    /// Changes the quota of the tenant, without stopping its running sandboxes.
+    pub fn set_max_sandboxes(&self, max_sandboxes: usize) {
+        let previous = self.max_sandboxes.swap(max_sandboxes, Ordering::SeqCst);
+        resize_semaphore(&self.semaphore, previous, max_sandboxes);
+    }
+}
+
+impl Tenant {
+    /// The tenant with the settings of `config`, sharing the semaphore of this one so its
+    /// running sandboxes keep counting toward the quota.
+    fn reconfigured(&self, config: &TenantConfig) -> Tenant {
+        let tenant = Tenant {
+            name: config.name.clone(),
+            semaphore: self.semaphore.clone(),
+            max_sandboxes: AtomicUsize::new(self.max_sandboxes.load(Ordering::SeqCst)),
+            admin: config.admin,
+            policy: tenant_policy(config),
+        };
+        tenant.set_max_sandboxes(config.max_sandboxes);
+        tenant
+    }
+}
+
+impl From<&TenantConfig> for Tenant {
+    fn from(config: &TenantConfig) -> Self {
+        Tenant {
+            admin: config.admin,
+            policy: tenant_policy(config),
+            ..Tenant::new(config.name.clone(), config.max_sandboxes)
+        }
+    }
+}
+
+/// Compiles the policy of a tenant. The configuration was checked on load, so it only
+/// fails for tenants built by hand, which are then refused everything.
+fn tenant_policy(config: &TenantConfig) -> Arc<Policy> {
+    let policy = Policy::new(&config.policy).unwrap_or_else(|e| {
+        error!(tenant = %config.name, error = %format!("{:#}", e), "Invalid tenant policy, denying all");
+        Policy::deny_all()
+    });
+    Arc::new(policy)
+}
+
+/// Changes the number of permits of a semaphore from `from` to `to`.
+///
+/// Permits are added right away. Removed ones are taken as they are released, by a task
+/// that holds them forever, so sandboxes holding them keep running and new ones wait
+/// until the semaphore is back under `to`.
+pub(crate) fn resize_semaphore(semaphore: &Arc<Semaphore>, from: usize, to: usize) {
+    if to >= from {
+        semaphore.add_permits(to - from);
+        return;
+    }
+    let excess = from - to;
+    let forgotten = semaphore.forget_permits(excess);
+    if forgotten < excess {
+        let semaphore = semaphore.clone();
+        let remaining = (excess - forgotten) as u32;
+        tokio::spawn(async move {
+            if let Ok(permits) = semaphore.acquire_many_owned(remaining).await {
+                permits.forget();
+            }
+        });
+    }
+}
+
+/// Tenants of the server, indexed by API key and by client certificate identity.
+#[derive(Debug, Default)]
+pub struct Tenants {
+    pub by_api_key: HashMap<String, Arc<Tenant>>,
+    pub by_cert: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Indexes the tenants of the configuration. Those already in `previous` keep their
+    /// semaphore, with the quota of the configuration.
+    pub fn new(configs: &[TenantConfig], previous: &Tenants) -> Self {
+        let mut tenants = Tenants::default();
+        for config in configs {
+            let tenant = Arc::new(match previous.get(&config.name) {
+                Some(tenant) => tenant.reconfigured(config),
+                None => Tenant::from(config),
+            });
+            if !config.api_key.is_empty() {
+                tenants.by_api_key.insert(config.api_key.clone(), tenant.clone());
+            }
+            if let Some(cn) = &config.client_cert_cn {
+                tenants.by_cert.insert(cn.clone(), tenant);
+            }
+        }
+        tenants
+    }
+
+    /// Whether the server has no tenants, and is open.
+    pub fn is_empty(&self) -> bool {
+        self.by_api_key.is_empty() && self.by_cert.is_empty()
+    }
+
+    /// Looks up a tenant by name.
+    pub fn get(&self, name: &str) -> Option<&Arc<Tenant>> {
+        self.by_api_key
+            .values()
+            .chain(self.by_cert.values())
+            .find(|tenant| tenant.name == name)
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::{Router, extract::ConnectInfo, http::Request};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self, RootCertStore,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+    },
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+/// TLS section of the server configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the server certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the server private key
+    pub key: PathBuf,
+    /// PEM file with the CAs trusted to sign client certificates.
+    /// Enables client certificate authentication.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Reject connections that do not present a valid client certificate.
+    /// Without it, clients may still authenticate with an API key instead.
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+/// Identity of the client certificate presented on the connection, i.e. its
+/// subject common name. Inserted as a request extension by [`serve`].
+#[derive(Debug, Clone)]
+pub struct ClientIdentity(pub String);
+
+impl TlsConfig {
+    /// Builds the rustls server configuration from the PEM files.
+    pub fn server_config(&self) -> Result<rustls::ServerConfig> {
+        let certs = load_certs(&self.cert)?;
+        let key = load_key(&self.key)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match self.require_client_cert {
+                    true => verifier.build()?,
+                    false => verifier.allow_unauthenticated().build()?,
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("Invalid TLS certificate or key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+/// Extracts the subject common name of a certificate.
+fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
+
+/// Wait after a failed accept before accepting again.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serves the app over HTTPS.
+///
+/// Accepts TCP connections from the listener, performs the TLS handshake and hands the
+/// decrypted stream to hyper. Connections that fail the handshake are dropped without
+/// affecting the rest of the server. Every request of the connection carries the peer
+/// address as [`ConnectInfo`] and, when the client presented a certificate, its
+/// [`ClientIdentity`]. Failed accepts are logged and retried after [`ACCEPT_BACKOFF`].
+pub async fn serve(listener: TcpListener, app: Router, config: rustls::ServerConfig) -> Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Such as running out of file descriptors, which other connections free
+                warn!(error = %e, "Failed to accept connection");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!(peer = %peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(common_name)
+                .map(ClientIdentity);
+
+            let service = TowerToHyperService::new(app.map_request(
+                move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo::<SocketAddr>(peer));
+                    if let Some(identity) = identity.clone() {
+                        request.extensions_mut().insert(identity);
+                    }
+                    request
+                },
+            ));
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                warn!(peer = %peer, error = %e, "Error serving TLS connection");
+            }
+        });
+    }
+}
@@ -0,0 +1,183 @@
+//! Tool bundles.
+//!
+//! Operators declare named bundles of helper scripts or binaries in the server
+//! configuration, either a directory shipped with the server or a file fetched from a
+//! URL. Sandboxes list the bundles they need in `tools`: they are installed on start,
+//! before the setup commands, and put on the `PATH`. Bundles are read or fetched on
+//! first use and kept in memory.
+//!
+//! ```toml
+//! [[tool_bundles]]
+//! name = "agent-helpers"
+//! path = "/etc/sos/tools/agent-helpers"
+//!
+//! [[tool_bundles]]
+//! name = "rg"
+//! url = "https://example.com/ripgrep-x86_64-unknown-linux-musl.tar.gz"
+//! ```
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::http::ApiError;
+use crate::sandbox::{ToolBundle, ToolFile};
+
+/// Tool bundle section of the server configuration. Exactly one of `path` and `url`
+/// is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolBundleConfig {
+    /// Name sandboxes ask for the bundle by, also its directory in the container
+    pub name: String,
+    /// Directory whose files are installed, keeping their layout and executable bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// File fetched on first use. `.tar`, `.tar.gz` and `.tgz` archives are extracted,
+    /// other files are installed as an executable named after the bundle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl ToolBundleConfig {
+    /// Checks the bundle when loading the configuration.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            && !self.name.starts_with('.');
+        if !valid_name {
+            anyhow::bail!(
+                "Invalid tool bundle name '{}', expected letters, digits, '-', '_' and '.'",
+                self.name
+            );
+        }
+        match (&self.path, &self.url) {
+            (Some(path), None) if path.is_dir() => Ok(()),
+            (Some(path), None) => anyhow::bail!(
+                "Tool bundle {}: {} is not a directory",
+                self.name,
+                path.display()
+            ),
+            (None, Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {
+                Ok(())
+            }
+            (None, Some(url)) => anyhow::bail!("Tool bundle {}: invalid URL {}", self.name, url),
+            _ => anyhow::bail!("Tool bundle {} needs either a path or a URL", self.name),
+        }
+    }
+
+    async fn load(&self, http: &reqwest::Client) -> Result<Vec<ToolFile>, ApiError> {
+        match (&self.path, &self.url) {
+            (Some(path), _) => read_dir(path).await.map_err(|e| {
+                ApiError::internal(format!("Failed to read tool bundle {}: {}", self.name, e))
+            }),
+            (None, Some(url)) => {
+                let content = fetch(http, url).await.map_err(|e| {
+                    ApiError::new(
+                        StatusCode::BAD_GATEWAY,
+                        "TOOL_BUNDLE_UNAVAILABLE",
+                        format!("Failed to fetch tool bundle {}: {}", self.name, e),
+                    )
+                })?;
+                let path = url.split(['?', '#']).next().unwrap_or(url);
+                let archive = [".tar", ".tar.gz", ".tgz"]
+                    .iter()
+                    .any(|ext| path.ends_with(ext));
+                let file = match archive {
+                    true => ToolFile {
+                        path: path.rsplit('/').next().unwrap_or("bundle.tar").to_string(),
+                        content,
+                        executable: false,
+                        archive: true,
+                    },
+                    false => ToolFile {
+                        path: self.name.clone(),
+                        content,
+                        executable: true,
+                        archive: false,
+                    },
+                };
+                Ok(vec![file])
+            }
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+}
+
+async fn fetch(http: &reqwest::Client, url: &str) -> reqwest::Result<bytes::Bytes> {
+    http.get(url).send().await?.error_for_status()?.bytes().await
+}
+
+/// Files under `root`, with their path relative to it.
+async fn read_dir(root: &Path) -> std::io::Result<Vec<ToolFile>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = tokio::fs::metadata(entry.path()).await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            files.push(ToolFile {
+                path: relative.to_string_lossy().to_string(),
+                content: tokio::fs::read(&path).await?.into(),
+                executable: metadata.permissions().mode() & 0o111 != 0,
+                archive: false,
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Tool bundles of the server, loaded on first use.
+pub struct ToolRegistry {
+    configs: HashMap<String, ToolBundleConfig>,
+    loaded: Mutex<HashMap<String, ToolBundle>>,
+    http: reqwest::Client,
+}
+
+impl ToolRegistry {
+    pub fn new(configs: &[ToolBundleConfig]) -> Self {
+        ToolRegistry {
+            configs: configs
+                .iter()
+                .map(|config| (config.name.clone(), config.clone()))
+                .collect(),
+            loaded: Mutex::new(HashMap::new()),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// The bundle named `name`, read or fetched if it was not yet.
+    pub async fn get(&self, name: &str) -> Result<ToolBundle, ApiError> {
+        let config = self.configs.get(name).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "TOOL_BUNDLE_NOT_FOUND",
+                format!("Tool bundle {} not found", name),
+            )
+        })?;
+        // Held while loading, so a bundle is fetched once however many sandboxes ask
+        let mut loaded = self.loaded.lock().await;
+        if let Some(bundle) = loaded.get(name) {
+            return Ok(bundle.clone());
+        }
+        let bundle = ToolBundle {
+            name: name.to_string(),
+            files: Arc::new(config.load(&self.http).await?),
+        };
+        loaded.insert(name.to_string(), bundle.clone());
+        Ok(bundle)
+    }
+}
@@ -1,23 +1,69 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use bollard::Docker;
 use serde_json::json;
+use sos::archive::ArchiveConfig;
+use sos::audit::AuditRecord;
+use sos::api::{
+    CopyPayload, CreatePayload, EnvSpec, ExecPayload, LogSource, PullResult, SandboxInfo,
+    ServerEventKind, StartStatus, TrajectoryRecord,
+};
+use sos::client::SosClient;
+use sos::config::{CorsConfig, ServerConfig, Template};
+use sos::daemon::PidFile;
 use sos::http::{SoSState, create_app};
-use tokio::sync::{Mutex, Semaphore};
+use sos::listen::BindAddress;
+use sos::Manager;
+use sos::pool::PoolConfig;
+use sos::rate_limit::RateLimitConfig;
+use sos::runtime::{Attached, ContainerRuntime, ContainerSpec, Exec, Runtime, RuntimeConfig};
+use sos::sandbox::{
+    CommandExecution, HooksConfig, KernelOutput, Observer, OutputChunk, OutputFilters,
+    Mount, PullProgress, RepoAuth, RepoSpec, ResourceLimits, ResourceUsage, RestartPolicy,
+    Sandbox, SandboxError, SandboxStatus, Shell, TerminalSize,
+};
+use sos::swebench::{SweBenchImport, SweBenchInstance, SweBenchOptions};
+use sos::task::{Task, TaskFile};
+use sos::tenant::TenantConfig;
+use sos::tls::TlsConfig;
+use sos::tools::ToolBundleConfig;
 use tokio::time::{Duration, sleep};
 
 // Helpers
+/// Runtime the tests run on: Docker, or the one named by `SOS_RUNTIME` (`podman`,
+/// `containerd` or `local`). `SOS_RUNTIME_SOCKET` overrides the socket of the runtime and
+/// `SOS_OCI_RUNTIME` runs the containers with another OCI runtime, such as `kata`.
+fn runtime_config() -> RuntimeConfig {
+    RuntimeConfig {
+        kind: std::env::var("SOS_RUNTIME")
+            .map_or(Runtime::Docker, |runtime| runtime.parse().expect("Invalid SOS_RUNTIME")),
+        socket: std::env::var_os("SOS_RUNTIME_SOCKET").map(PathBuf::from),
+        oci_runtime: std::env::var("SOS_OCI_RUNTIME").ok(),
+        ..Default::default()
+    }
+}
+
+async fn connect_runtime() -> Arc<dyn ContainerRuntime> {
+    runtime_config()
+        .connect()
+        .await
+        .expect("Failed to connect to the container runtime")
+}
+
 async fn start_test_server() -> String {
-    let semaphore = Arc::new(Semaphore::new(10));
-    let state = Arc::new(SoSState {
-        docker: Arc::new(
-            Docker::connect_with_local_defaults().expect("Failed to connect to docker"),
-        ),
-        sandboxes: Arc::new(Mutex::new(HashMap::new())),
-        semaphore,
-    });
+    start_test_server_with_config(ServerConfig::default()).await
+}
 
+async fn start_test_server_with_config(config: ServerConfig) -> String {
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        config,
+    ));
+    start_test_server_with_state(state).await
+}
+
+async fn start_test_server_with_state(state: Arc<SoSState>) -> String {
     let app = create_app(state);
 
     // Use a random port for testing
@@ -545,6 +591,7 @@ async fn test_exit_command_response_includes_exit_true() {
         exec_result["output"], "hi",
         "Output should include only 'hi'"
     );
+    assert_eq!(exec_result["exit_code"], 7, "Exit code should be the exit status");
 
     let exec_result = execute_command(&client, &base_url, &sandbox_id, "echo 'Container still running'", Some(true)).await;
     assert_eq!(
@@ -563,3 +610,3521 @@ async fn test_exit_command_response_includes_exit_true() {
 
     cleanup_sandbox(&client, &base_url, &sandbox_id).await;
 }
+
+#[tokio::test]
+async fn test_freeze_and_unfreeze() {
+    let base_url = start_test_server().await;
+    let client = reqwest::Client::new();
+    let sandbox_id = create_and_start_sandbox(&client, &base_url).await;
+
+    // Background writer owned by the agent session
+    execute_command(
+        &client,
+        &base_url,
+        &sandbox_id,
+        "(while true; do date +%s%N >> /tmp/ticks; sleep 0.1; done) &",
+        None,
+    )
+    .await;
+
+    let response = client
+        .post(&format!("{}/sandboxes/{}/freeze", base_url, sandbox_id))
+        .send()
+        .await
+        .expect("Failed to send freeze request");
+    assert_eq!(response.status(), 200, "Freeze should return 200");
+
+    // Session commands are rejected while frozen
+    let response = client
+        .post(&format!("{}/sandboxes/{}/exec", base_url, sandbox_id))
+        .json(&json!({ "command": "echo hi" }))
+        .send()
+        .await
+        .expect("Failed to send exec request");
+    assert_eq!(response.status(), 400, "Session exec should fail while frozen");
+
+    // The filesystem stays consistent for inspection, even when the runtime paused the
+    // whole container and standalone commands cannot run
+    let ticks = || async {
+        client
+            .get(&format!("{}/sandboxes/{}/files?path=/tmp/ticks", base_url, sandbox_id))
+            .send()
+            .await
+            .expect("Failed to send download request")
+            .bytes()
+            .await
+            .expect("Failed to read the archive")
+    };
+    let before = ticks().await;
+    sleep(Duration::from_millis(500)).await;
+    let after = ticks().await;
+    assert_eq!(before, after, "Frozen processes should not write");
+
+    let response = client
+        .post(&format!("{}/sandboxes/{}/unfreeze", base_url, sandbox_id))
+        .send()
+        .await
+        .expect("Failed to send unfreeze request");
+    assert_eq!(response.status(), 200, "Unfreeze should return 200");
+
+    let exec_result = execute_command(&client, &base_url, &sandbox_id, "echo thawed", None).await;
+    assert_eq!(exec_result["output"], "thawed");
+
+    cleanup_sandbox(&client, &base_url, &sandbox_id).await;
+}
+
+#[tokio::test]
+async fn test_fan_out_exec() {
+    let base_url = start_test_server().await;
+    let client = reqwest::Client::new();
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let response = client
+            .post(&format!("{}/sandboxes", base_url))
+            .json(&json!({
+                "image": "ubuntu:latest",
+                "setup_commands": [],
+                "labels": { "experiment": "fan-out" }
+            }))
+            .send()
+            .await
+            .expect("Failed to create sandbox");
+        let created: serde_json::Value = response.json().await.unwrap();
+        let id = created["id"].as_str().unwrap().to_string();
+        client
+            .post(&format!("{}/sandboxes/{}/start", base_url, id))
+            .send()
+            .await
+            .expect("Failed to start sandbox");
+        ids.push(id);
+    }
+    let unlabeled = create_and_start_sandbox(&client, &base_url).await;
+
+    let response = client
+        .post(&format!("{}/sandboxes/exec", base_url))
+        .json(&json!({ "command": "echo broadcast", "labels": { "experiment": "fan-out" } }))
+        .send()
+        .await
+        .expect("Failed to send fan-out request");
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    let results = body["results"].as_object().expect("results should be a map");
+    assert_eq!(results.len(), 2, "Only labeled sandboxes should be selected");
+    for id in &ids {
+        assert_eq!(results[id]["output"], "broadcast");
+        assert_eq!(results[id]["exit_code"], 0);
+    }
+    assert!(!results.contains_key(&unlabeled));
+
+    for id in ids.iter().chain([&unlabeled]) {
+        cleanup_sandbox(&client, &base_url, id).await;
+    }
+}
+
+#[tokio::test]
+async fn test_template_token_redacted() {
+    let config = ServerConfig {
+        templates: vec![Template {
+            name: "private".to_string(),
+            image: "python:3.12".to_string(),
+            repo: Some(RepoSpec {
+                url: "https://github.com/org/private.git".to_string(),
+                auth: Some(RepoAuth {
+                    username: None,
+                    token: "not-a-real-token".to_string(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = reqwest::Client::new();
+
+    for path in ["templates", "templates/private"] {
+        let body = client
+            .get(&format!("{}/{}", base_url, path))
+            .send()
+            .await
+            .expect("Failed to get templates")
+            .text()
+            .await
+            .unwrap();
+        assert!(!body.contains("not-a-real-token"), "{} leaks the token", path);
+        assert!(body.contains("<redacted>"));
+    }
+}
+
+#[tokio::test]
+async fn test_create_from_template() {
+    let config = ServerConfig {
+        templates: vec![Template {
+            name: "greeter".to_string(),
+            image: "ubuntu:latest".to_string(),
+            setup_commands: vec!["echo hello > /tmp/greeting".to_string()],
+            env: [("GREETING".to_string(), "hola".to_string())].into(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(&format!("{}/templates", base_url))
+        .send()
+        .await
+        .expect("Failed to list templates");
+    let templates: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(templates[0]["name"], "greeter");
+
+    let response = client
+        .post(&format!("{}/sandboxes", base_url))
+        .json(&json!({ "template": "greeter" }))
+        .send()
+        .await
+        .expect("Failed to create sandbox");
+    assert_eq!(response.status(), 200, "Create from template should return 200");
+    let created: serde_json::Value = response.json().await.unwrap();
+    let sandbox_id = created["id"].as_str().unwrap().to_string();
+    client
+        .post(&format!("{}/sandboxes/{}/start", base_url, sandbox_id))
+        .send()
+        .await
+        .expect("Failed to start sandbox");
+
+    let exec_result = execute_command(
+        &client,
+        &base_url,
+        &sandbox_id,
+        "cat /tmp/greeting && echo $GREETING",
+        None,
+    )
+    .await;
+    assert_eq!(exec_result["output"], "hello\nhola");
+
+    // Unknown templates are rejected
+    let response = client
+        .post(&format!("{}/sandboxes", base_url))
+        .json(&json!({ "template": "missing" }))
+        .send()
+        .await
+        .expect("Failed to send create request");
+    assert_eq!(response.status(), 404);
+
+    cleanup_sandbox(&client, &base_url, &sandbox_id).await;
+}
+
+#[tokio::test]
+async fn test_whoami() {
+    let client = SosClient::new(start_test_server().await);
+    assert_eq!(client.whoami().await.unwrap(), "default");
+
+    let config = ServerConfig {
+        tenants: vec![TenantConfig {
+            name: "team-a".to_string(),
+            api_key: "key-a".to_string(),
+            max_sandboxes: 1,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = SosClient::with_api_key(base_url.clone(), "key-a").unwrap();
+    assert_eq!(client.whoami().await.unwrap(), "team-a");
+    let error = SosClient::new(base_url).whoami().await.unwrap_err();
+    assert!(matches!(error, sos::client::ClientError::Api { status: 401, .. }));
+    assert!(!error.is_connect());
+
+    // Nothing listens on port 9 of localhost
+    let error = SosClient::new("http://127.0.0.1:9").whoami().await.unwrap_err();
+    assert!(error.is_connect());
+}
+
+#[tokio::test]
+async fn test_sandbox_builder() {
+    let error = Sandbox::builder().image("ubuntu:latest").build().err().unwrap();
+    assert!(matches!(error, SandboxError::MissingSetting("runtime")));
+
+    let mut sandbox = Sandbox::builder()
+        .image("ubuntu:latest")
+        .setup(["touch /tmp/a", "touch /tmp/b"])
+        .env([("GREETING", "hello")])
+        .runtime(connect_runtime().await)
+        .build()
+        .unwrap();
+    assert_eq!(sandbox.setup_commands, ["touch /tmp/a", "touch /tmp/b"]);
+    assert_eq!(sandbox.tenant, "default");
+    sandbox.start(Vec::new()).await.unwrap();
+    let result = sandbox
+        .exec("ls /tmp/b && echo $GREETING".to_string(), false, None)
+        .await
+        .unwrap();
+    assert!(result.output.contains("hello"), "{}", result.output);
+    sandbox.stop().await.unwrap();
+
+    // Setup stops at the first failing command, which the error names
+    let mut sandbox = Sandbox::builder()
+        .image("ubuntu:latest")
+        .setup(["touch /tmp/a", "echo broken; false", "touch /tmp/b"])
+        .runtime(connect_runtime().await)
+        .build()
+        .unwrap();
+    let error = sandbox.start(Vec::new()).await.err().unwrap();
+    match error {
+        SandboxError::SetupCommandsFailed { index, command, output } => {
+            assert_eq!(index, 1);
+            assert_eq!(command, "echo broken; false");
+            assert!(output.contains("broken"), "{}", output);
+        }
+        error => panic!("Unexpected error: {}", error),
+    }
+    let result = sandbox.exec("ls /tmp/b".to_string(), true, None).await;
+    assert!(!result.is_ok_and(|result| result.exit_code == 0));
+    sandbox.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_exec_session_stream() {
+    use futures::StreamExt;
+
+    let mut sandbox = Sandbox::builder()
+        .image("ubuntu:latest")
+        .runtime(connect_runtime().await)
+        .build()
+        .unwrap();
+    sandbox.start(Vec::new()).await.unwrap();
+
+    let chunks: Vec<OutputChunk> = sandbox
+        .exec_session_stream("echo out; echo err >&2; false".to_string())
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert!(chunks.contains(&OutputChunk::Stdout("out\n".to_string())), "{:?}", chunks);
+    assert!(chunks.contains(&OutputChunk::Stderr("err\n".to_string())), "{:?}", chunks);
+    assert_eq!(chunks.last(), Some(&OutputChunk::Marker(1)));
+
+    let trajectory = sandbox.get_trajectory();
+    let result = trajectory[0].result.as_ref().unwrap();
+    assert_eq!((result.stdout.as_str(), result.exit_code), ("out", 1));
+
+    let chunks: Vec<OutputChunk> = sandbox
+        .exec_session_stream("exit 3".to_string())
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert!(chunks.contains(&OutputChunk::Exit(3)), "{:?}", chunks);
+    assert!(matches!(sandbox.get_status(), SandboxStatus::Exited(_, 3)));
+    sandbox.stop().await.unwrap();
+}
+
+/// Observer recording what it is told, as strings.
+#[derive(Default)]
+struct RecordingObserver {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait::async_trait]
+impl Observer for RecordingObserver {
+    async fn on_command_start(&self, _sandbox: &Sandbox, command: &CommandExecution) {
+        self.events.lock().unwrap().push(format!("start {}", command.command));
+    }
+
+    fn on_output_chunk(&self, _sandbox_id: &str, chunk: &OutputChunk) {
+        self.events.lock().unwrap().push(format!("chunk {:?}", chunk));
+    }
+
+    async fn on_command_end(&self, sandbox: &Sandbox, command: &CommandExecution) {
+        // The trajectory is recorded first
+        assert_eq!(sandbox.command_count(), 1);
+        let exit_code = command.result.as_ref().unwrap().exit_code;
+        self.events.lock().unwrap().push(format!("end {}", exit_code));
+    }
+
+    fn on_status_change(&self, _sandbox_id: &str, status: &SandboxStatus) {
+        self.events.lock().unwrap().push(format!("status {}", status));
+    }
+}
+
+#[tokio::test]
+async fn test_observers() {
+    use futures::StreamExt;
+
+    let observer = Arc::new(RecordingObserver::default());
+    let mut sandbox = Sandbox::builder()
+        .image("ubuntu:latest")
+        .observers([observer.clone() as Arc<dyn Observer>])
+        .runtime(connect_runtime().await)
+        .build()
+        .unwrap();
+    sandbox.start(Vec::new()).await.unwrap();
+    let stream = sandbox.exec_session_stream("echo hi".to_string()).await.unwrap();
+    stream.collect::<Vec<_>>().await;
+    sandbox.stop().await.unwrap();
+
+    let events = observer.events.lock().unwrap().clone();
+    assert!(events.contains(&"status started".to_string()), "{:?}", events);
+    assert_eq!(events.last().map(String::as_str), Some("status stopped"));
+    let commands: Vec<&String> = events.iter().filter(|e| !e.starts_with("status")).collect();
+    assert_eq!(
+        commands,
+        ["start echo hi", "chunk Stdout(\"hi\\n\")", "chunk Marker(0)", "end 0"]
+    );
+}
+
+#[tokio::test]
+async fn test_manager_in_process() {
+    let manager = Arc::new(Manager::new(connect_runtime().await, ServerConfig::default()));
+    let tenant = manager.default_tenant.clone();
+    let payload = CreatePayload {
+        image: "ubuntu:latest".to_string(),
+        ..Default::default()
+    };
+    let id = manager.create_sandbox(&tenant, payload).await.unwrap();
+    manager.start_sandbox(&tenant, &id).await.unwrap();
+    let result = manager
+        .exec_in(&tenant, &id, "echo embedded".to_string(), false, None)
+        .await
+        .unwrap();
+    assert_eq!(result.output.trim(), "embedded");
+    let trajectory = manager.trajectory(&tenant, &id).await.unwrap();
+    assert_eq!(trajectory.commands.len(), 1);
+
+    // Past its time limit, the reaper removes the sandbox
+    manager.reap(Duration::ZERO).await;
+    let error = manager.get_entry(&tenant, &id).err().unwrap();
+    assert_eq!(error.code, "SANDBOX_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn test_manager_observers() {
+    let config = ServerConfig {
+        max_sandboxes: 1,
+        ..Default::default()
+    };
+    let mut manager = Manager::new(connect_runtime().await, config);
+    let observer = Arc::new(RecordingObserver::default());
+    manager.observers.push(observer.clone());
+    let manager = Arc::new(manager);
+    let tenant = manager.default_tenant.clone();
+    let payload = || CreatePayload {
+        image: "ubuntu:latest".to_string(),
+        ..Default::default()
+    };
+
+    // The manager queues the second sandbox, and starts it once the reaper stopped the first
+    let first = manager.create_sandbox(&tenant, payload()).await.unwrap();
+    let second = manager.create_sandbox(&tenant, payload()).await.unwrap();
+    let status = manager.start_or_queue(tenant.clone(), &first).await.unwrap();
+    assert_eq!(status, StartStatus::Started);
+    let status = manager.start_or_queue(tenant.clone(), &second).await.unwrap();
+    assert_eq!(status, StartStatus::Queued);
+    manager.reap(Duration::ZERO).await;
+    let entry = manager.get_entry(&tenant, &second).unwrap();
+    for _ in 0..100 {
+        if entry.view.status() == "started" {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    manager.stop_sandbox(&tenant, &second, true).await.unwrap();
+
+    let events = observer.events.lock().unwrap().clone();
+    let count = |status: &str| events.iter().filter(|e| *e == status).count();
+    assert_eq!(count("status queued"), 1, "{:?}", events);
+    assert_eq!(count("status created"), 1, "{:?}", events);
+    assert_eq!(count("status started"), 2, "{:?}", events);
+    assert_eq!(count("status stopped"), 2, "{:?}", events);
+}
+
+#[tokio::test]
+async fn test_tenant_isolation() {
+    let tenant = |name: &str, api_key: &str| TenantConfig {
+        name: name.to_string(),
+        api_key: api_key.to_string(),
+        max_sandboxes: 2,
+        ..Default::default()
+    };
+    let config = ServerConfig {
+        tenants: vec![tenant("team-a", "key-a"), tenant("team-b", "key-b")],
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = reqwest::Client::new();
+
+    // Requests without a valid key are rejected
+    let response = client
+        .get(&format!("{}/sandboxes", base_url))
+        .send()
+        .await
+        .expect("Failed to send list request");
+    assert_eq!(response.status(), 401);
+    let response = client
+        .get(&format!("{}/sandboxes", base_url))
+        .bearer_auth("wrong-key")
+        .send()
+        .await
+        .expect("Failed to send list request");
+    assert_eq!(response.status(), 401);
+    let response = client
+        .get(&format!("{}/templates", base_url))
+        .send()
+        .await
+        .expect("Failed to send templates request");
+    assert_eq!(response.status(), 401);
+
+    // Only admin tenants register templates
+    let response = client
+        .post(&format!("{}/templates", base_url))
+        .bearer_auth("key-a")
+        .json(&json!({ "name": "mine", "image": "ubuntu:latest" }))
+        .send()
+        .await
+        .expect("Failed to send template request");
+    assert_eq!(response.status(), 403);
+
+    let response = client
+        .post(&format!("{}/sandboxes", base_url))
+        .bearer_auth("key-a")
+        .json(&json!({ "image": "ubuntu:latest" }))
+        .send()
+        .await
+        .expect("Failed to create sandbox");
+    assert_eq!(response.status(), 200);
+    let created: serde_json::Value = response.json().await.unwrap();
+    let sandbox_id = created["id"].as_str().unwrap().to_string();
+
+    // Team A sees its sandbox, team B does not
+    let response = client
+        .get(&format!("{}/sandboxes", base_url))
+        .bearer_auth("key-a")
+        .send()
+        .await
+        .expect("Failed to list sandboxes");
+    let sandboxes: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(sandboxes.as_array().unwrap().len(), 1);
+
+    let response = client
+        .get(&format!("{}/sandboxes", base_url))
+        .bearer_auth("key-b")
+        .send()
+        .await
+        .expect("Failed to list sandboxes");
+    let sandboxes: serde_json::Value = response.json().await.unwrap();
+    assert!(sandboxes.as_array().unwrap().is_empty());
+
+    for action in ["start", "trajectory"] {
+        let request = match action {
+            "start" => client.post(&format!("{}/sandboxes/{}/start", base_url, sandbox_id)),
+            _ => client.get(&format!("{}/sandboxes/{}/trajectory", base_url, sandbox_id)),
+        };
+        let response = request
+            .bearer_auth("key-b")
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), 404, "Team B should not reach team A's sandbox ({})", action);
+    }
+
+    let response = client
+        .post(&format!("{}/sandboxes/{}/start", base_url, sandbox_id))
+        .bearer_auth("key-a")
+        .send()
+        .await
+        .expect("Failed to start sandbox");
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post(&format!("{}/sandboxes/{}/stop", base_url, sandbox_id))
+        .bearer_auth("key-b")
+        .json(&json!({ "remove": true }))
+        .send()
+        .await
+        .expect("Failed to send stop request");
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .post(&format!("{}/sandboxes/{}/stop", base_url, sandbox_id))
+        .bearer_auth("key-a")
+        .json(&json!({ "remove": true }))
+        .send()
+        .await
+        .expect("Failed to send stop request");
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn test_tls_server() {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("sos-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), key_pair.serialize_pem()).unwrap();
+
+    let tls = TlsConfig {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+        client_ca: None,
+        require_client_cert: false,
+    };
+    let server_config = tls.server_config().expect("Failed to load TLS config");
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        ServerConfig::default(),
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(sos::tls::serve(listener, create_app(state), server_config));
+    sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(cert.pem().as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    let response = client
+        .get(&format!("https://localhost:{}/sandboxes", port))
+        .send()
+        .await
+        .expect("Failed to send HTTPS request");
+    assert_eq!(response.status(), 200);
+
+    // Plain HTTP is not served on the TLS port
+    let response = reqwest::Client::new()
+        .get(&format!("http://localhost:{}/sandboxes", port))
+        .send()
+        .await;
+    assert!(response.is_err() || !response.unwrap().status().is_success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_mtls_client_identity() {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&server_key, &ca, &ca_key)
+        .unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "agent-team-a");
+    let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("sos-mtls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("cert.pem"), server_cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), server_key.serialize_pem()).unwrap();
+
+    let tls = TlsConfig {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+        client_ca: Some(dir.join("ca.pem")),
+        require_client_cert: true,
+    };
+    let config = ServerConfig {
+        tenants: vec![TenantConfig {
+            name: "team-a".to_string(),
+            client_cert_cn: Some("agent-team-a".to_string()),
+            max_sandboxes: 2,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let server_config = tls.server_config().expect("Failed to load TLS config");
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        config,
+    ));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://localhost:{}/sandboxes", listener.local_addr().unwrap().port());
+    tokio::spawn(sos::tls::serve(listener, create_app(state), server_config));
+    sleep(Duration::from_millis(100)).await;
+
+    let ca_cert = reqwest::Certificate::from_pem(ca.pem().as_bytes()).unwrap();
+
+    // Without a client certificate the handshake is rejected
+    let anonymous = reqwest::Client::builder()
+        .add_root_certificate(ca_cert.clone())
+        .build()
+        .unwrap();
+    assert!(anonymous.get(&url).send().await.is_err());
+
+    // The certificate identity maps to the team-a tenant, no API key needed
+    let identity = format!("{}{}", client_cert.pem(), client_key.serialize_pem());
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca_cert)
+        .identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    let response = client
+        .post(&url)
+        .json(&json!({ "image": "ubuntu:latest" }))
+        .send()
+        .await
+        .expect("Failed to create sandbox");
+    assert_eq!(response.status(), 200);
+    let response = client.get(&url).send().await.expect("Failed to list sandboxes");
+    let sandboxes: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(sandboxes.as_array().unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_unix_socket_server() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("sos-unix-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sos.sock");
+    let bind: BindAddress = format!("unix://{}", path.display()).parse().unwrap();
+    assert_eq!(bind, BindAddress::Unix(path.clone()));
+    assert!("localhost".parse::<BindAddress>().is_err());
+
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        ServerConfig::default(),
+    ));
+    let listener = bind.bind(0, Some(0o600)).await.expect("Failed to bind socket");
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    tokio::spawn(sos::listen::serve(listener, create_app(state), None));
+    sleep(Duration::from_millis(100)).await;
+
+    // A second server does not take over the socket of a live one
+    assert!(bind.bind(0, None).await.is_err());
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET /sandboxes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_pid_file() {
+    let path = std::env::temp_dir().join(format!("sos-{}.pid", uuid::Uuid::new_v4()));
+    let pid_file = PidFile::create(&path).expect("Failed to write pid file");
+    let pid = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(pid.trim(), std::process::id().to_string());
+
+    drop(pid_file);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_reload_config() {
+    use sos::client::ClientError;
+
+    let dir = std::env::temp_dir().join(format!("sos-reload-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sos.toml");
+    let tenants = r#"
+        [[tenants]]
+        name = "ops"
+        api_key = "key-ops"
+        max_sandboxes = 1
+        admin = true
+
+        [[tenants]]
+        name = "team-a"
+        api_key = "key-a"
+        max_sandboxes = 1
+    "#;
+    std::fs::write(&path, tenants).unwrap();
+    let config = ServerConfig::load(&path).unwrap();
+    let base_url = start_test_server_with_config(config).await;
+    let ops = SosClient::with_api_key(base_url.clone(), "key-ops").unwrap();
+    let team_a = SosClient::with_api_key(base_url.clone(), "key-a").unwrap();
+
+    // Only admin tenants reload
+    let error = team_a.reload().await.unwrap_err();
+    assert!(matches!(error, ClientError::Api { status: 403, .. }));
+
+    let templates = r#"
+        [[templates]]
+        name = "python"
+        image = "python:3.12"
+
+        [policy]
+        denied_images = ["alpine:*"]
+    "#;
+    let rotated = tenants.replace("key-a", "key-a2");
+    std::fs::write(&path, format!("max_sandboxes = 5\n{}{}", rotated, templates)).unwrap();
+    let reloaded = ops.reload().await.expect("Failed to reload");
+    assert_eq!(reloaded.max_sandboxes, 5);
+    assert_eq!(reloaded.templates, 1);
+    assert_eq!(reloaded.tenants, 2);
+
+    assert_eq!(ops.template("python").await.unwrap().image, "python:3.12");
+    let error = team_a.whoami().await.unwrap_err();
+    assert!(matches!(error, ClientError::Api { status: 401, .. }));
+    let team_a = SosClient::with_api_key(base_url.clone(), "key-a2").unwrap();
+    assert_eq!(team_a.whoami().await.unwrap(), "team-a");
+
+    // A broken file leaves the running policy in place
+    std::fs::write(&path, "max_sandboxes = \"many\"").unwrap();
+    let error = ops.reload().await.unwrap_err();
+    assert!(matches!(error, ClientError::Api { status: 422, .. }));
+    assert_eq!(team_a.whoami().await.unwrap(), "team-a");
+
+    // So does a policy that does not compile, instead of lifting it
+    let broken = format!("{}\n[policy]\nforbidden_commands = [\"(\"]\n", rotated);
+    std::fs::write(&path, broken).unwrap();
+    let error = ops.reload().await.unwrap_err();
+    assert_eq!(error.code(), Some("INVALID_CONFIG"));
+    let alpine = CreatePayload {
+        image: "alpine:latest".to_string(),
+        ..Default::default()
+    };
+    let error = team_a.create(&alpine).await.unwrap_err();
+    assert!(matches!(error, ClientError::Api { status: 403, .. }));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_admission_policy() {
+    use sos::client::ClientError;
+    use sos::policy::{PolicyConfig, PolicyViolation};
+
+    let config = ServerConfig {
+        policy: PolicyConfig {
+            allowed_images: Some(vec!["ubuntu:*".to_string()]),
+            denied_images: Some(vec!["ubuntu:18.04".to_string()]),
+            forbidden_commands: Some(vec![r"rm\s+-rf\s+/(\s|$)".to_string()]),
+            max_setup_command_bytes: Some(64),
+            required_labels: Some(vec!["experiment".to_string()]),
+            allowed_mounts: Some(vec!["/data/*".to_string()]),
+            ..Default::default()
+        },
+        templates: vec![Template {
+            name: "host-etc".to_string(),
+            image: "ubuntu:latest".to_string(),
+            mounts: vec![Mount {
+                source: "/etc".to_string(),
+                target: "/host-etc".to_string(),
+                read_only: true,
+            }],
+            ..Default::default()
+        }],
+        tenants: vec![
+            TenantConfig {
+                name: "agents".to_string(),
+                api_key: "key-agents".to_string(),
+                max_sandboxes: 2,
+                admin: true,
+                ..Default::default()
+            },
+            TenantConfig {
+                name: "red-team".to_string(),
+                api_key: "key-red".to_string(),
+                max_sandboxes: 2,
+                policy: PolicyConfig {
+                    forbidden_commands: Some(Vec::new()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let agents = SosClient::with_api_key(base_url.clone(), "key-agents").unwrap();
+    let red_team = SosClient::with_api_key(base_url, "key-red").unwrap();
+    let payload = |image: &str, setup: &str| CreatePayload {
+        image: image.to_string(),
+        setup_commands: vec![setup.to_string()],
+        labels: [("experiment".to_string(), "policy".to_string())].into(),
+        ..Default::default()
+    };
+    let violation = |error: ClientError| match error {
+        ClientError::Api {
+            status: 403,
+            violation: Some(PolicyViolation { rule, value }),
+            ..
+        } => (rule, value),
+        error => panic!("Expected a policy violation, got {:?}", error),
+    };
+
+    let error = agents.create(&payload("python:3.12", "true")).await.unwrap_err();
+    assert_eq!(violation(error), ("allowed_images".to_string(), "python:3.12".to_string()));
+    let error = agents.create(&payload("ubuntu:18.04", "true")).await.unwrap_err();
+    assert_eq!(violation(error).0, "denied_images");
+    let error = agents.create(&payload("ubuntu:latest", &"x".repeat(65))).await.unwrap_err();
+    assert_eq!(violation(error), ("max_setup_command_bytes".to_string(), "65".to_string()));
+    let unlabeled = CreatePayload {
+        labels: HashMap::new(),
+        ..payload("ubuntu:latest", "true")
+    };
+    let error = agents.create(&unlabeled).await.unwrap_err();
+    assert_eq!(violation(error), ("required_labels".to_string(), "experiment".to_string()));
+    let verified = CreatePayload {
+        verify_command: Some("rm -rf / ; true".to_string()),
+        ..payload("ubuntu:latest", "true")
+    };
+    let error = agents.create(&verified).await.unwrap_err();
+    assert_eq!(violation(error).0, "forbidden_commands");
+    let mounted = CreatePayload {
+        template: Some("host-etc".to_string()),
+        ..payload("", "true")
+    };
+    let error = agents.create(&mounted).await.unwrap_err();
+    assert_eq!(violation(error), ("allowed_mounts".to_string(), "/etc".to_string()));
+    let error = agents.pull_images(&["python:3.12".to_string()]).await.unwrap_err();
+    assert_eq!(violation(error).0, "allowed_images");
+
+    let id = agents
+        .create(&payload("ubuntu:latest", "true"))
+        .await
+        .expect("Failed to create sandbox");
+    agents.start(&id).await.expect("Failed to start sandbox");
+    let error = agents.exec(&id, "rm -rf / --no-preserve-root").await.unwrap_err();
+    assert_eq!(violation(error).0, "forbidden_commands");
+    assert_eq!(agents.exec(&id, "echo allowed").await.unwrap().output, "allowed");
+    agents.stop(&id, true).await.unwrap();
+
+    // The tenant override lifts the forbidden commands, the other rules still apply
+    let error = red_team.create(&payload("python:3.12", "true")).await.unwrap_err();
+    assert_eq!(violation(error).0, "allowed_images");
+    red_team
+        .create(&payload("ubuntu:latest", "rm -rf / --help >/dev/null"))
+        .await
+        .expect("The tenant policy allows the setup command");
+}
+
+#[tokio::test]
+async fn test_blocked_commands() {
+    let config = ServerConfig {
+        templates: vec![Template {
+            name: "safe".to_string(),
+            image: "ubuntu:latest".to_string(),
+            blocked_commands: vec![r"\bshutdown\b".to_string()],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let error = client
+        .create(&CreatePayload {
+            template: Some("safe".to_string()),
+            blocked_commands: vec!["(".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Some("INVALID_REQUEST"));
+
+    let id = client
+        .create(&CreatePayload {
+            template: Some("safe".to_string()),
+            blocked_commands: vec![r"curl[^|]*\|\s*(ba)?sh".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let error = client.exec(&id, "shutdown -h now").await.unwrap_err();
+    assert_eq!(error.code(), Some("COMMAND_BLOCKED"));
+    let error = client
+        .exec_standalone(&id, "curl -s https://example.com/install | sh")
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Some("COMMAND_BLOCKED"));
+    assert_eq!(client.exec(&id, "echo allowed").await.unwrap().output, "allowed");
+
+    // Blocked attempts are recorded without a result
+    let trajectory = client.trajectory(&id).await.unwrap();
+    let commands: Vec<_> = trajectory
+        .trajectory
+        .iter()
+        .map(|c| (c.command.as_str(), c.blocked.as_deref(), c.result.is_some()))
+        .collect();
+    assert_eq!(
+        commands,
+        vec![
+            ("shutdown -h now", Some(r"\bshutdown\b"), false),
+            (
+                "curl -s https://example.com/install | sh",
+                Some(r"curl[^|]*\|\s*(ba)?sh"),
+                false
+            ),
+            ("echo allowed", None, true),
+        ]
+    );
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[test]
+fn test_template_caps_command_budget() {
+    let template = Template {
+        name: "capped".to_string(),
+        image: "ubuntu:latest".to_string(),
+        max_commands: Some(10),
+        ..Default::default()
+    };
+    let budget = |max_commands| {
+        template
+            .apply(CreatePayload {
+                max_commands,
+                ..Default::default()
+            })
+            .max_commands
+    };
+    assert_eq!(budget(None), Some(10));
+    assert_eq!(budget(Some(100)), Some(10));
+    assert_eq!(budget(Some(3)), Some(3));
+}
+
+#[test]
+fn test_template_keeps_restart_policy() {
+    let template = Template {
+        name: "fragile".to_string(),
+        image: "ubuntu:latest".to_string(),
+        restart_policy: Some(RestartPolicy::Never),
+        ..Default::default()
+    };
+    let payload = CreatePayload {
+        restart_policy: Some(RestartPolicy::OnCrash),
+        ..Default::default()
+    };
+    assert_eq!(template.apply(payload).restart_policy, Some(RestartPolicy::Never));
+    let payload = CreatePayload {
+        restart_policy: Some(RestartPolicy::OnCrash),
+        ..Default::default()
+    };
+    let unset = Template {
+        restart_policy: None,
+        ..template
+    };
+    assert_eq!(unset.apply(payload).restart_policy, Some(RestartPolicy::OnCrash));
+}
+
+#[tokio::test]
+async fn test_command_budget() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            max_commands: Some(2),
+            verify_command: Some("true".to_string()),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    client.exec(&id, "echo one").await.unwrap();
+    let status = |sandboxes: Vec<SandboxInfo>| {
+        sandboxes.into_iter().find(|s| s.id == id).map(|s| s.status)
+    };
+    assert_eq!(status(client.list().await.unwrap()).as_deref(), Some("started"));
+    client.exec(&id, "echo two").await.unwrap();
+    assert_eq!(status(client.list().await.unwrap()).as_deref(), Some("exhausted"));
+
+    let error = client.exec(&id, "echo three").await.unwrap_err();
+    assert_eq!(error.code(), Some("BUDGET_EXHAUSTED"));
+    assert_eq!(client.trajectory(&id).await.unwrap().command_count, 2);
+
+    // The episode can still be inspected and scored
+    let result = client.exec_standalone(&id, "echo standalone").await.unwrap();
+    assert_eq!(result.output, "standalone");
+    assert!(client.verify(&id).await.unwrap().passed);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_crashed_sandbox() {
+    let client = SosClient::new(start_test_server().await);
+    let status = |sandboxes: Vec<SandboxInfo>, id: &str| {
+        sandboxes.into_iter().find(|s| s.id == id).map(|s| s.status)
+    };
+
+    // The session shell dies under a command
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    let error = client.exec(&id, "kill -9 $$").await.unwrap_err();
+    assert_eq!(error.code(), Some("SANDBOX_CRASHED"));
+    assert_eq!(status(client.list().await.unwrap(), &id).as_deref(), Some("crashed"));
+    let error = client.exec(&id, "echo again").await.unwrap_err();
+    assert_eq!(error.code(), Some("SANDBOX_CRASHED"));
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    // The container is removed from under an idle sandbox, which is replaced
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            setup_commands: vec!["echo ready > /tmp/setup".to_string()],
+            restart_policy: Some(RestartPolicy::OnCrash),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    let container_id = client.sandbox(&id).await.unwrap().container_id.unwrap();
+    connect_runtime().await.remove(&container_id).await.unwrap();
+    sleep(Duration::from_secs(1)).await;
+
+    let result = client.exec(&id, "cat /tmp/setup").await.unwrap();
+    assert_eq!(result.output, "ready");
+    let detail = client.sandbox(&id).await.unwrap();
+    assert_eq!(detail.info.status, "started");
+    assert_ne!(detail.container_id, Some(container_id));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_output_filters() {
+    let config = ServerConfig {
+        output_filters: OutputFilters {
+            collapse_progress: true,
+            strip_base64: Some(200),
+            max_repeated_lines: Some(2),
+        },
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client
+        .exec(&id, r"printf 'Downloading 10%%\rDownloading 50%%\rDownloading 100%%\n'")
+        .await
+        .unwrap();
+    assert_eq!(result.output, "Downloading 100%");
+
+    // Wrapped over 11 lines by base64
+    let result = client
+        .exec(&id, "head -c 600 /dev/zero | base64; echo done")
+        .await
+        .unwrap();
+    assert_eq!(result.output, "[800 characters of base64 stripped]\ndone");
+
+    let result = client.exec_standalone(&id, "yes same | head -n 5").await.unwrap();
+    assert_eq!(result.output, "same\nsame\n[previous line repeated 3 more times]");
+
+    // The trajectory records the filtered output
+    let trajectory = client.trajectory(&id).await.unwrap();
+    let output = trajectory.trajectory[0].result.as_ref().unwrap().output.clone();
+    assert_eq!(output, "Downloading 100%");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let config = ServerConfig {
+        rate_limit: Some(RateLimitConfig {
+            requests_per_second: 0.5,
+            burst: 3,
+            max_concurrent_execs: 1,
+        }),
+        tenants: ["a", "b"]
+            .map(|name| TenantConfig {
+                name: format!("team-{}", name),
+                api_key: format!("key-{}", name),
+                max_sandboxes: 1,
+                ..Default::default()
+            })
+            .to_vec(),
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = reqwest::Client::new();
+    let statuses = |keys: Vec<String>| {
+        let client = client.clone();
+        let base_url = base_url.clone();
+        async move {
+            let mut statuses = Vec::new();
+            for key in keys {
+                let response = client
+                    .get(&format!("{}/sandboxes", base_url))
+                    .bearer_auth(key)
+                    .send()
+                    .await
+                    .expect("Failed to list sandboxes");
+                statuses.push(response.status().as_u16());
+            }
+            statuses
+        }
+    };
+
+    assert_eq!(statuses(vec!["key-a".to_string(); 4]).await, vec![200, 200, 200, 429]);
+
+    // Other tenants have their own bucket
+    assert_eq!(statuses(vec!["key-b".to_string()]).await, vec![200]);
+
+    // Made-up keys share the bucket of their address
+    let made_up = (0..4).map(|i| format!("made-up-{}", i)).collect();
+    assert_eq!(statuses(made_up).await, vec![401, 401, 401, 429]);
+}
+
+#[tokio::test]
+async fn test_structured_errors() {
+    let base_url = start_test_server().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(&format!("{}/sandboxes/missing/trajectory", base_url))
+        .send()
+        .await
+        .expect("Failed to send trajectory request");
+    assert_eq!(response.status(), 404);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "SANDBOX_NOT_FOUND");
+    assert_eq!(body["error"]["message"], "Sandbox missing not found");
+
+    let response = client
+        .post(&format!("{}/sandboxes", base_url))
+        .json(&json!({ "image": "Not A Valid Image!" }))
+        .send()
+        .await
+        .expect("Failed to send create request");
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "INVALID_REQUEST");
+
+    let response = client
+        .post(&format!("{}/sandboxes", base_url))
+        .header("content-type", "application/json")
+        .body("{\"image\": ")
+        .send()
+        .await
+        .expect("Failed to send create request");
+    assert!(response.status().is_client_error());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "INVALID_REQUEST");
+
+    let sandbox_id = create_and_start_sandbox(&client, &base_url).await;
+    let response = client
+        .post(&format!("{}/sandboxes/{}/exec", base_url, sandbox_id))
+        .json(&json!({ "command": "x".repeat(sos::http::MAX_COMMAND_BYTES + 1) }))
+        .send()
+        .await
+        .expect("Failed to send exec request");
+    assert_eq!(response.status(), 413);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "COMMAND_TOO_LARGE");
+
+    cleanup_sandbox(&client, &base_url, &sandbox_id).await;
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let config = ServerConfig {
+        cors: Some(CorsConfig {
+            allowed_origins: vec!["https://dashboard.example.com".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+        }),
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .request(reqwest::Method::OPTIONS, &format!("{}/sandboxes", base_url))
+        .header("Origin", "https://dashboard.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .expect("Failed to send preflight request");
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://dashboard.example.com"
+    );
+
+    let response = client
+        .get(&format!("{}/sandboxes", base_url))
+        .header("Origin", "https://evil.example.com")
+        .send()
+        .await
+        .expect("Failed to list sandboxes");
+    assert!(response.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn test_client_sdk() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            labels: [("suite".to_string(), "sdk".to_string())].into(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "echo hello").await.unwrap();
+    assert_eq!(result.output, "hello");
+    assert_eq!(result.exit_code, 0);
+    assert!(!result.exited);
+
+    let result = client.exec_standalone(&id, "exit 3").await.unwrap();
+    assert_eq!(result.exit_code, 3);
+
+    let sandboxes = client.list().await.unwrap();
+    assert!(sandboxes.iter().any(|s| s.id == id && s.labels["suite"] == "sdk"));
+
+    let trajectory = client.trajectory(&id).await.unwrap();
+    assert_eq!(trajectory.command_count, 1);
+    assert_eq!(trajectory.trajectory[0].command, "echo hello");
+
+    // API errors carry the server's error code
+    let error = client.start("missing").await.unwrap_err();
+    assert_eq!(error.code(), Some("SANDBOX_NOT_FOUND"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_env_reset_and_step() {
+    let client = SosClient::new(start_test_server().await);
+
+    let env_id = client
+        .create_env(&EnvSpec {
+            sandbox: CreatePayload {
+                image: "ubuntu:latest".to_string(),
+                verify_command: Some("test -f /tmp/done".to_string()),
+                ..Default::default()
+            },
+            instruction: "Create /tmp/done".to_string(),
+            max_steps: Some(3),
+        })
+        .await
+        .expect("Failed to create env");
+
+    // Stepping before reset is rejected
+    let error = client.step(&env_id, "ls").await.unwrap_err();
+    assert_eq!(error.code(), Some("ENV_NOT_RESET"));
+
+    let reset = client.reset(&env_id).await.expect("Failed to reset env");
+    assert_eq!(reset.observation, "Create /tmp/done");
+    assert_eq!(reset.info.step, 0);
+
+    let step = client.step(&env_id, "echo working").await.unwrap();
+    assert_eq!(step.observation, "working");
+    assert!(!step.done);
+    assert_eq!(step.reward, 0.0);
+
+    let step = client.step(&env_id, "touch /tmp/done").await.unwrap();
+    assert!(!step.done);
+
+    // Third step hits max_steps: the episode is truncated and scored
+    let step = client.step(&env_id, "true").await.unwrap();
+    assert!(step.done);
+    assert!(step.truncated);
+    assert_eq!(step.reward, 1.0);
+
+    // A new episode starts from a clean sandbox
+    let reset = client.reset(&env_id).await.unwrap();
+    let step = client.step(&env_id, "ls /tmp/done").await.unwrap();
+    assert_ne!(step.exit_code, 0);
+    assert_eq!(reset.info.sandbox_id, step.info.sandbox_id);
+
+    client.delete_env(&env_id).await.expect("Failed to delete env");
+}
+
+#[tokio::test]
+async fn test_verify() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            verify_command: Some("cat /tmp/score 2>/dev/null || echo FAIL".to_string()),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let verdict = client.verify(&id).await.expect("Failed to verify");
+    assert!(!verdict.passed);
+    assert_eq!(verdict.score, 0.0);
+    assert_eq!(verdict.step, 0);
+
+    client.exec(&id, "echo 'score: 0.75' > /tmp/score").await.unwrap();
+    let verdict = client.verify(&id).await.expect("Failed to verify");
+    assert!(verdict.passed);
+    assert_eq!(verdict.score, 0.75);
+    assert_eq!(verdict.step, 1);
+
+    // Verifications are recorded alongside the trajectory, not in it
+    let trajectory = client.trajectory(&id).await.unwrap();
+    assert_eq!(trajectory.command_count, 1);
+    assert_eq!(trajectory.verifications.len(), 2);
+    assert_eq!(trajectory.verifications[1].score, 0.75);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    // Sandboxes without a verify command cannot be verified
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    client.start(&id).await.unwrap();
+    let error = client.verify(&id).await.unwrap_err();
+    assert_eq!(error.code(), Some("NO_VERIFIER"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_task_instantiate() {
+    let client = SosClient::new(start_test_server().await);
+
+    client
+        .create_task(&Task {
+            name: "seeded".to_string(),
+            image: "ubuntu:latest".to_string(),
+            instructions: "Fix the script".to_string(),
+            files: vec![TaskFile {
+                path: "/work/run.sh".to_string(),
+                content: "echo 'it'\\''s broken'\n".to_string(),
+                executable: true,
+            }],
+            setup_commands: vec!["touch /work/ready".to_string()],
+            verify_command: Some("/work/run.sh | grep -q fixed && echo PASS || echo FAIL".to_string()),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to register task");
+    assert_eq!(client.task("seeded").await.unwrap().files.len(), 1);
+
+    let instance = client.instantiate("seeded").await.expect("Failed to instantiate task");
+    assert_eq!(instance.instructions, "Fix the script");
+
+    // Files are seeded verbatim, before the setup commands run
+    let result = client.exec(&instance.id, "/work/run.sh && ls /work/ready").await.unwrap();
+    assert_eq!(result.output, "it's broken\n/work/ready");
+    assert!(!client.verify(&instance.id).await.unwrap().passed);
+
+    client.exec(&instance.id, "echo 'echo fixed' > /work/run.sh").await.unwrap();
+    assert!(client.verify(&instance.id).await.unwrap().passed);
+
+    let error = client.instantiate("missing").await.unwrap_err();
+    assert_eq!(error.code(), Some("TASK_NOT_FOUND"));
+
+    client.stop(&instance.id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_task_instantiate_unregistered() {
+    let config = ServerConfig {
+        tenants: vec![TenantConfig {
+            name: "team-a".to_string(),
+            api_key: "key-a".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let client =
+        SosClient::with_api_key(start_test_server_with_config(config).await, "key-a").unwrap();
+    let task = Task {
+        name: "local".to_string(),
+        image: "ubuntu:latest".to_string(),
+        instructions: "Nothing to do".to_string(),
+        verify_command: Some("echo PASS".to_string()),
+        ..Default::default()
+    };
+
+    // Only admin tenants register tasks, others run them without registering
+    let error = client.create_task(&task).await.unwrap_err();
+    assert!(matches!(error, sos::client::ClientError::Api { status: 403, .. }));
+    let instance = client.instantiate_task(&task).await.expect("Failed to instantiate task");
+    assert_eq!(instance.instructions, "Nothing to do");
+    assert_eq!(client.list().await.unwrap().len(), 1);
+    assert!(client.verify(&instance.id).await.unwrap().passed);
+
+    client.stop(&instance.id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_swebench_import() {
+    let client = SosClient::new(start_test_server().await);
+
+    let instance: SweBenchInstance = serde_json::from_value(json!({
+        "instance_id": "octo__demo-1",
+        "repo": "octo/demo",
+        "base_commit": "0123abcd",
+        "test_patch": "diff --git a/tests/test_a.py b/tests/test_a.py\n",
+        "problem_statement": "Fix the bug",
+        "FAIL_TO_PASS": "[\"tests/test_a.py::test_fix\"]",
+        "PASS_TO_PASS": []
+    }))
+    .expect("Failed to parse instance");
+    assert_eq!(instance.fail_to_pass, vec!["tests/test_a.py::test_fix"]);
+
+    let names = client
+        .import_swebench(&SweBenchImport {
+            instances: vec![instance.clone()],
+            options: SweBenchOptions {
+                image: "python:3.12".to_string(),
+                workdir: "/testbed".to_string(),
+                setup_commands: vec!["pip install -e .".to_string()],
+                eval_command: None,
+                time_limit_secs: Some(1800),
+            },
+        })
+        .await
+        .expect("Failed to import instances");
+    assert_eq!(names, vec!["octo__demo-1"]);
+
+    let task = client.task("octo__demo-1").await.unwrap();
+    assert_eq!(task.instructions, "Fix the bug");
+    assert_eq!(task.files.len(), 1);
+    assert_eq!(
+        task.setup_commands,
+        vec![
+            "git clone --quiet https://github.com/octo/demo.git '/testbed'",
+            "git -C '/testbed' checkout --quiet 0123abcd",
+            "cd '/testbed' && pip install -e .",
+            "git -C '/testbed' apply /tmp/sos/test.patch",
+        ]
+    );
+    assert_eq!(
+        task.verify_command.as_deref(),
+        Some("cd '/testbed' && python -m pytest -q 'tests/test_a.py::test_fix'")
+    );
+
+    // Invalid instances are rejected as a whole
+    let error = client
+        .import_swebench(&SweBenchImport {
+            instances: vec![SweBenchInstance {
+                repo: "octo/demo; rm -rf /".to_string(),
+                ..instance
+            }],
+            options: SweBenchOptions {
+                image: "python:3.12".to_string(),
+                workdir: "/testbed".to_string(),
+                setup_commands: vec![],
+                eval_command: None,
+                time_limit_secs: None,
+            },
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Some("INVALID_REQUEST"));
+}
+
+#[tokio::test]
+async fn test_trajectory_export_jsonl() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo one").await.unwrap();
+    client.exec(&id, "sleep 0.2; false").await.unwrap();
+
+    let export = client
+        .export_trajectory(&id, "jsonl")
+        .await
+        .expect("Failed to export trajectory");
+    let records: Vec<TrajectoryRecord> = export
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Invalid JSONL record"))
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].command, "echo one");
+    assert_eq!(records[0].output.as_deref(), Some("one"));
+    assert_eq!(records[1].exit_code, Some(1));
+    assert!(records[1].duration.unwrap() >= 0.2);
+    assert!(records[1].started_at >= records[0].finished_at.unwrap());
+
+    let error = client.export_trajectory(&id, "xml").await.unwrap_err();
+    assert_eq!(error.code(), Some("INVALID_REQUEST"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_trajectory_export_cast() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "printf 'a\\nb'").await.unwrap();
+    client.exec(&id, "true").await.unwrap();
+
+    let cast = client
+        .export_trajectory(&id, "cast")
+        .await
+        .expect("Failed to export trajectory");
+    let mut lines = cast.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Invalid asciicast line"));
+    let header = lines.next().unwrap();
+    assert_eq!(header["version"], 2);
+    assert!(header["timestamp"].is_i64());
+    let events: Vec<serde_json::Value> = lines.collect();
+    let texts: Vec<&str> = events.iter().map(|event| event[2].as_str().unwrap()).collect();
+    assert_eq!(texts, vec!["$ printf 'a\\nb'\r\n", "a\r\nb\r\n", "$ true\r\n"]);
+    let times: Vec<f64> = events.iter().map(|event| event[0].as_f64().unwrap()).collect();
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_trajectory_export_chat() {
+    let server_url = start_test_server().await;
+    let client = SosClient::new(server_url.clone());
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo hi").await.unwrap();
+    client.exec(&id, "false").await.unwrap();
+
+    let chat = client.export_chat(&id).await.expect("Failed to export chat");
+    let messages: Vec<(&str, &str)> = chat
+        .messages
+        .iter()
+        .map(|m| (m.role.as_str(), m.content.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("assistant", "echo hi"),
+            ("tool", "hi"),
+            ("assistant", "false"),
+            ("tool", "[exit code: 1]"),
+        ]
+    );
+
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(format!(
+            "{}/sandboxes/{}/trajectory/export?format=chat&output_role=user&system=Be%20brief",
+            server_url, id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["messages"][0]["role"], "system");
+    assert_eq!(response["messages"][0]["content"], "Be brief");
+    assert_eq!(response["messages"][2]["role"], "user");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_trajectory_persistence() {
+    let dir = std::env::temp_dir().join(format!("sos-trajectories-{}", uuid::Uuid::new_v4()));
+    let config = ServerConfig {
+        trajectory_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config.clone()).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo persisted").await.unwrap();
+    client.exec(&id, "sleep 0.2").await.unwrap();
+    let live = client.trajectory(&id).await.unwrap();
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    // The trajectory outlives the sandbox...
+    let stored = client.trajectory(&id).await.expect("Trajectory not persisted");
+    assert_eq!(stored.command_count, 2);
+    assert_eq!(stored.trajectory[0].result.as_ref().unwrap().output, "persisted");
+    assert_eq!(stored.trajectory[1].started_at, live.trajectory[1].started_at);
+    assert!(stored.trajectory[1].duration.unwrap() >= 0.2);
+
+    // ...and the server
+    let client = SosClient::new(start_test_server_with_config(config).await);
+    let formatted = client.trajectory_formatted(&id).await.unwrap();
+    assert_eq!(formatted, "$ echo persisted\npersisted\n$ sleep 0.2\n");
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_archive_on_stop() {
+    let dir = std::env::temp_dir().join(format!("sos-archive-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("file://{}", dir.display());
+    let config = ServerConfig {
+        archive: Some(ArchiveConfig {
+            url: url.clone(),
+            options: Default::default(),
+            workspace: Some("/root".to_string()),
+        }),
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo archived > /root/out.txt").await.unwrap();
+
+    let response = client.stop(&id, false).await.expect("Failed to stop sandbox");
+    assert_eq!(
+        response.archive_url.as_deref(),
+        Some(format!("{}/default/{}", url, id).as_str())
+    );
+    let sandboxes = client.list().await.unwrap();
+    let info = sandboxes.iter().find(|s| s.id == id).unwrap();
+    assert_eq!(info.archive_url, response.archive_url);
+
+    let archived = dir.join("default").join(&id);
+    let trajectory = std::fs::read_to_string(archived.join("trajectory.jsonl")).unwrap();
+    let record: TrajectoryRecord = serde_json::from_str(trajectory.lines().next().unwrap()).unwrap();
+    assert_eq!(record.command, "echo archived > /root/out.txt");
+    assert!(std::fs::metadata(archived.join("workspace.tar")).unwrap().len() > 0);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let path = std::env::temp_dir().join(format!("sos-audit-{}.jsonl", uuid::Uuid::new_v4()));
+    let config = ServerConfig {
+        audit_log: Some(path.clone()),
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo audited").await.unwrap();
+    client.exec(&id, "(exit 2)").await.unwrap();
+    let err = client.exec("missing", "true").await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_FOUND"));
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let records: Vec<AuditRecord> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 6);
+    assert!(records.iter().all(|r| r.tenant.as_deref() == Some("default")));
+
+    let create = &records[0];
+    assert_eq!((create.method.as_str(), create.path.as_str()), ("POST", "/sandboxes"));
+    assert_eq!(create.sandbox_id.as_deref(), Some(id.as_str()));
+
+    let exec = &records[2];
+    assert_eq!(exec.sandbox_id.as_deref(), Some(id.as_str()));
+    assert_eq!(exec.command.as_deref(), Some("echo audited"));
+    assert_eq!((exec.status, exec.exit_code), (200, Some(0)));
+    assert_eq!(records[3].exit_code, Some(2));
+
+    let missing = &records[4];
+    assert_eq!(missing.status, 404);
+    assert_eq!(missing.error.as_deref(), Some("SANDBOX_NOT_FOUND"));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn test_audit_log_large_bodies() {
+    let path = std::env::temp_dir().join(format!("sos-audit-{}.jsonl", uuid::Uuid::new_v4()));
+    let config = ServerConfig {
+        audit_log: Some(path.clone()),
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    // Bodies past the 2 MiB the audit log reads reach the handler and the client whole
+    client
+        .exec(&id, "mkdir -p /tmp/out && head -c 3145728 /dev/urandom > /tmp/out/blob")
+        .await
+        .expect("Failed to exec");
+    let archive = client
+        .download(&id, "/tmp/out", |_, _| {})
+        .await
+        .expect("Failed to download");
+    client
+        .upload(&id, "/tmp/inputs", archive, |_, _| {})
+        .await
+        .expect("Failed to upload");
+    let result = client
+        .exec_standalone(&id, "cmp /tmp/out/blob /tmp/inputs/out/blob && echo same")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "same");
+
+    let result = client
+        .exec_standalone(&id, r"head -c 3145728 /dev/zero | tr '\0' a")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output.len(), 3145728);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+    let records = std::fs::read_to_string(&path).unwrap();
+    assert!(records.lines().any(|line| line.contains("/files")));
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn test_sandbox_stats() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            limits: Some(ResourceLimits {
+                memory_mb: Some(256),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let err = client.stats(&id).await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_STARTED"));
+
+    client.start(&id).await.expect("Failed to start sandbox");
+    let stats = client.stats(&id).await.expect("Failed to get stats");
+    assert_eq!(stats.memory_limit_bytes, 256 * 1024 * 1024);
+    assert!(stats.memory_usage_bytes > 0);
+    assert!(stats.pids >= 1);
+    assert!(stats.cpu_percent >= 0.0);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_sandbox_detail() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            limits: Some(ResourceLimits {
+                memory_mb: Some(256),
+                ..Default::default()
+            }),
+            labels: [("suite".to_string(), "detail".to_string())].into(),
+            time_limit_secs: Some(600),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let detail = client.sandbox(&id).await.expect("Failed to get sandbox");
+    assert_eq!(detail.info.status, "created");
+    assert_eq!(detail.info.labels["suite"], "detail");
+    assert_eq!(detail.limits.memory_mb, Some(256));
+    assert_eq!(detail.time_limit_secs, Some(600));
+    assert!(detail.container_id.is_none());
+    assert!(detail.started_at.is_none());
+
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "true").await.unwrap();
+    client.exec(&id, "false").await.unwrap();
+    let detail = client.sandbox(&id).await.expect("Failed to get sandbox");
+    assert!(detail.container_id.is_some());
+    assert!(detail.started_at.is_some());
+    assert_eq!(detail.info.session_command_count, 2);
+    assert_eq!(detail.last_exit_code, Some(1));
+
+    let err = client.sandbox("missing").await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_FOUND"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_warm_pool() {
+    let config = ServerConfig {
+        pool: vec![PoolConfig {
+            image: "ubuntu:latest".to_string(),
+            size: 1,
+        }],
+        ..Default::default()
+    };
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        config,
+    ));
+    let pool = state.pool.clone().unwrap();
+    let client = SosClient::new(start_test_server_with_state(state).await);
+
+    let wait_for_pool = async || {
+        for _ in 0..120 {
+            if pool.idle("ubuntu:latest").await == 1 {
+                return;
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+        panic!("Pool not filled");
+    };
+    wait_for_pool().await;
+
+    // Plain sandboxes take the warm container, and still run their setup commands
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            setup_commands: vec!["touch /tmp/setup-done".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    assert_eq!(pool.idle("ubuntu:latest").await, 0);
+    let result = client.exec(&id, "ls /tmp/setup-done && echo $((1 + 1))").await.unwrap();
+    assert_eq!(result.exit_code, 0);
+    assert!(result.output.ends_with('2'));
+
+    // The session handed over by the pool logs to the sandbox that took it
+    client.exec(&id, "echo pooled-line").await.expect("Failed to exec");
+    let mut logs = Box::pin(
+        client
+            .logs(&id, LogSource::Session, false)
+            .await
+            .expect("Failed to get logs"),
+    );
+    let mut text = Vec::new();
+    while let Some(chunk) = futures::StreamExt::next(&mut logs).await {
+        text.extend_from_slice(&chunk.expect("Failed to read logs"));
+    }
+    assert!(String::from_utf8_lossy(&text).contains("pooled-line"));
+
+    // The pool is refilled in the background
+    wait_for_pool().await;
+
+    // Sandboxes needing a custom container get a fresh one
+    let custom = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            env: std::collections::HashMap::from([("FOO".to_string(), "bar".to_string())]),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&custom).await.expect("Failed to start sandbox");
+    assert_eq!(pool.idle("ubuntu:latest").await, 1);
+    assert_eq!(client.exec(&custom, "echo $FOO").await.unwrap().output, "bar");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+    client.stop(&custom, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_pull_images() {
+    let client = SosClient::new(start_test_server().await);
+
+    let images = vec![
+        "busybox:latest".to_string(),
+        "sos-test/does-not-exist:latest".to_string(),
+    ];
+    let response = client.pull_images(&images).await.expect("Failed to pull images");
+    assert!(matches!(
+        response.results["busybox:latest"],
+        PullResult::Pulled | PullResult::Present
+    ));
+    assert!(matches!(
+        response.results["sos-test/does-not-exist:latest"],
+        PullResult::Failed { .. }
+    ));
+
+    // Pulled images are not pulled again
+    let response = client.pull_images(&images[..1]).await.unwrap();
+    assert!(matches!(response.results["busybox:latest"], PullResult::Present));
+
+    let err = client.pull_images(&[]).await.unwrap_err();
+    assert_eq!(err.code(), Some("INVALID_REQUEST"));
+    let err = client.pull_images(&["Not An Image".to_string()]).await.unwrap_err();
+    assert_eq!(err.code(), Some("INVALID_REQUEST"));
+}
+
+#[tokio::test]
+async fn test_pull_progress_events() {
+    use bollard::query_parameters::RemoveImageOptions;
+    use futures::StreamExt;
+
+    // Only used by this test, so it can be removed to force a pull
+    let image = "busybox:1.36.1";
+    // Progress is only reported through the Docker API
+    let Ok(docker) = runtime_config().connect_docker().await else {
+        return;
+    };
+    let _ = docker
+        .remove_image(
+            image,
+            Some(RemoveImageOptions {
+                force: true,
+                ..Default::default()
+            }),
+            None,
+        )
+        .await;
+
+    let client = SosClient::new(start_test_server().await);
+    let mut events = Box::pin(client.events().await.expect("Failed to open event stream"));
+
+    let id = client
+        .create(&CreatePayload {
+            image: image.to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let mut progress = Vec::new();
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_secs(5), events.next()).await {
+        let event = event.unwrap();
+        match event.kind {
+            ServerEventKind::Pulling => progress.push(event.progress.unwrap()),
+            ServerEventKind::Removed => break,
+            _ => {}
+        }
+    }
+    let last = progress.last().expect("No pull progress reported");
+    assert!(last.layers > 0);
+    assert_eq!(last.layers_done, last.layers);
+}
+
+#[tokio::test]
+async fn test_reads_during_exec() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo first").await.unwrap();
+
+    let exec = tokio::spawn({
+        let client = client.clone();
+        let id = id.clone();
+        async move { client.exec(&id, "sleep 5").await }
+    });
+    sleep(Duration::from_millis(500)).await;
+
+    // Neither read waits for the running command
+    let sandboxes = tokio::time::timeout(Duration::from_secs(1), client.list())
+        .await
+        .expect("Listing sandboxes waited for the command")
+        .unwrap();
+    let info = sandboxes.iter().find(|s| s.id == id).unwrap();
+    assert_eq!(info.session_command_count, 1);
+    let trajectory = tokio::time::timeout(Duration::from_secs(1), client.trajectory(&id))
+        .await
+        .expect("Getting the trajectory waited for the command")
+        .unwrap();
+    assert_eq!(trajectory.command_count, 1);
+
+    assert_eq!(exec.await.unwrap().unwrap().exit_code, 0);
+    assert_eq!(client.trajectory(&id).await.unwrap().command_count, 2);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_start_queued_at_capacity() {
+    use futures::StreamExt;
+
+    let config = ServerConfig {
+        max_sandboxes: 1,
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+    let payload = CreatePayload {
+        image: "ubuntu:latest".to_string(),
+        ..Default::default()
+    };
+
+    let first = client.create(&payload).await.unwrap();
+    let second = client.create(&payload).await.unwrap();
+    let third = client.create(&payload).await.unwrap();
+    let started = client.start(&first).await.unwrap();
+    assert_eq!(started.status, StartStatus::Started);
+
+    // The server is full, so the start answers right away instead of waiting
+    let events = client.events().await.unwrap();
+    let queued = tokio::time::timeout(Duration::from_secs(2), client.start(&second))
+        .await
+        .expect("Start waited for capacity")
+        .unwrap();
+    assert_eq!(queued.status, StartStatus::Queued);
+    assert_eq!(client.start(&second).await.unwrap().status, StartStatus::Queued);
+    assert_eq!(client.start(&third).await.unwrap().status, StartStatus::Queued);
+    let status = |id: String| {
+        let client = client.clone();
+        async move {
+            let sandboxes = client.list().await.unwrap();
+            sandboxes.into_iter().find(|s| s.id == id).unwrap().status
+        }
+    };
+    assert_eq!(status(second.clone()).await, "queued");
+
+    // Stopping a queued sandbox cancels its start
+    client.stop(&third, false).await.unwrap();
+    assert_eq!(status(third.clone()).await, "stopped");
+
+    client.stop(&first, true).await.unwrap();
+    let mut events = Box::pin(events);
+    let started = tokio::time::timeout(Duration::from_secs(30), async {
+        while let Some(event) = events.next().await {
+            let event = event.unwrap();
+            if event.sandbox_id == second && event.kind == ServerEventKind::Started {
+                return;
+            }
+        }
+    });
+    started.await.expect("Queued sandbox did not start");
+    assert_eq!(status(second.clone()).await, "started");
+    assert_eq!(client.exec(&second, "echo ready").await.unwrap().output, "ready");
+
+    client.stop(&second, true).await.unwrap();
+    // Already stopped, only removed
+    let _ = client.stop(&third, true).await;
+}
+
+#[tokio::test]
+async fn test_trajectory_stream() {
+    use futures::StreamExt;
+
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "echo before").await.unwrap();
+
+    let mut events = Box::pin(
+        client
+            .stream_trajectory(&id)
+            .await
+            .expect("Failed to open trajectory stream"),
+    );
+
+    // Commands executed before subscribing are replayed
+    let entry = events.next().await.unwrap().unwrap();
+    assert_eq!(entry.index, 0);
+    assert_eq!(entry.command, "echo before");
+
+    let exec_client = client.clone();
+    let exec_id = id.clone();
+    tokio::spawn(async move {
+        exec_client.exec(&exec_id, "echo after").await.unwrap();
+    });
+    let entry = tokio::time::timeout(Duration::from_secs(10), events.next())
+        .await
+        .expect("No event for the new command")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.index, 1);
+    assert_eq!(entry.result.unwrap().output, "after");
+
+    // Removing the sandbox ends the stream
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+    let end = tokio::time::timeout(Duration::from_secs(10), events.next())
+        .await
+        .expect("Stream not closed");
+    assert!(end.is_none());
+}
+
+#[tokio::test]
+async fn test_server_events() {
+    use futures::StreamExt;
+
+    let client = SosClient::new(start_test_server().await);
+    let mut events = Box::pin(client.events().await.expect("Failed to open event stream"));
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    client.exec(&id, "(exit 3)").await.unwrap();
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let mut received = Vec::new();
+    while received.len() < 6 {
+        let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+            .await
+            .expect("Missing server event")
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.sandbox_id, id);
+        received.push(event);
+    }
+    let kinds: Vec<_> = received.iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            ServerEventKind::Created,
+            ServerEventKind::Started,
+            ServerEventKind::ExecStarted,
+            ServerEventKind::ExecFinished,
+            ServerEventKind::Stopped,
+            ServerEventKind::Removed,
+        ]
+    );
+    assert_eq!(received[2].command.as_deref(), Some("(exit 3)"));
+    assert_eq!(received[3].exit_code, Some(3));
+}
+
+#[tokio::test]
+async fn test_stdout_stderr_separation() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client
+        .exec(&id, "echo out; echo err >&2; echo more")
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "out\nmore");
+    assert_eq!(result.stderr, "err");
+    assert!(result.output.contains("out") && result.output.contains("err"));
+    assert_eq!(result.exit_code, 0);
+
+    let result = client.exec(&id, "ls /nonexistent").await.unwrap();
+    assert_eq!(result.stdout, "");
+    assert!(result.stderr.contains("No such file or directory"));
+    assert_ne!(result.exit_code, 0);
+
+    let result = client
+        .exec_standalone(&id, "echo out; echo err >&2")
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "out\n");
+    assert_eq!(result.stderr, "err\n");
+
+    // Both streams are kept in the trajectory
+    let trajectory = client.trajectory(&id).await.unwrap();
+    let first = trajectory.trajectory[0].result.as_ref().unwrap();
+    assert_eq!(first.stderr, "err");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_output_truncation() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "echo small").await.unwrap();
+    assert!(!result.truncated);
+
+    // Only the most recent output is kept past the per-command limit
+    let result = client
+        .exec_standalone(&id, "head -c 40000000 /dev/zero | tr '\\0' a; echo; echo end")
+        .await
+        .unwrap();
+    assert!(result.truncated);
+    assert!(result.output.len() <= 16 * 1024 * 1024);
+    assert!(result.output.ends_with("end\n"));
+    assert_eq!(result.exit_code, 0);
+
+    // The session keeps working after a large output
+    let result = client.exec(&id, "echo after").await.unwrap();
+    assert_eq!(result.output, "after");
+
+    // Flooding the session with more chunks than are buffered still ends the command
+    let result = client
+        .exec(&id, "for i in $(seq 1 5000); do seq 1 200; sleep 0.001; done; sh -c 'exit 7'")
+        .await
+        .unwrap();
+    assert_eq!(result.exit_code, 7);
+    assert!(result.output.ends_with("200"));
+    let result = client.exec(&id, "echo after").await.unwrap();
+    assert_eq!(result.output, "after");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+/// Runtime whose registry only has the images under `cached/`, and whose engine creates
+/// containers but starts none. It keeps the containers it created until removed.
+#[derive(Default)]
+struct BrokenRuntime {
+    containers: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait::async_trait]
+impl ContainerRuntime for BrokenRuntime {
+    async fn pull(
+        &self,
+        image: &str,
+        _on_progress: &mut (dyn for<'p> FnMut(&'p PullProgress) + Send),
+    ) -> sos::sandbox::Result<bool> {
+        match image.starts_with("cached/") {
+            true => Ok(false),
+            false => Err(SandboxError::PullImageFailed {
+                source: format!("{} not found", image).into(),
+            }),
+        }
+    }
+
+    async fn create(&self, _spec: &ContainerSpec) -> sos::sandbox::Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.containers.lock().unwrap().push(id.clone());
+        Ok(id)
+    }
+
+    async fn start(&self, _id: &str) -> sos::sandbox::Result<()> {
+        Err(SandboxError::NotStarted)
+    }
+
+    async fn attach(&self, _id: &str, _cmd: Vec<String>) -> sos::sandbox::Result<Attached> {
+        Err(SandboxError::NotStarted)
+    }
+
+    async fn exec(&self, _id: &str, _cmd: Vec<String>) -> sos::sandbox::Result<Exec> {
+        Err(SandboxError::NotStarted)
+    }
+
+    async fn stats(&self, _id: &str) -> sos::sandbox::Result<ResourceUsage> {
+        Err(SandboxError::NotStarted)
+    }
+
+    async fn remove(&self, id: &str) -> sos::sandbox::Result<()> {
+        self.containers.lock().unwrap().retain(|container| container != id);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_runtime_errors() {
+    let runtime = Arc::new(BrokenRuntime::default());
+    let state = Arc::new(SoSState::new(runtime.clone(), ServerConfig::default()));
+    let client = SosClient::new(start_test_server_with_state(state).await);
+
+    let response = client
+        .pull_images(&["ubuntu:latest".to_string()])
+        .await
+        .expect("Failed to pull images");
+    match &response.results["ubuntu:latest"] {
+        PullResult::Failed { error } => assert!(error.contains("not found")),
+        result => panic!("Unexpected pull result: {:?}", result),
+    }
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let err = client.start(&id).await.unwrap_err();
+    assert_eq!(err.code(), Some("IMAGE_PULL_FAILED"));
+
+    // A sandbox that failed to start is dropped, with the container it got
+    let id = client
+        .create(&CreatePayload {
+            image: "cached/ubuntu".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let err = client.start(&id).await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_STARTED"));
+    assert!(client.list().await.expect("Failed to list sandboxes").is_empty());
+    assert!(runtime.containers.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_isolated_networks() {
+    use bollard::query_parameters::ListNetworksOptions;
+
+    let config = RuntimeConfig {
+        isolate_networks: true,
+        ..runtime_config()
+    };
+    if !matches!(config.kind, Runtime::Docker | Runtime::Podman) {
+        return;
+    }
+    let docker = config.connect_docker().await.expect("Failed to connect");
+    let sandbox_networks = || async {
+        docker
+            .list_networks(None::<ListNetworksOptions>)
+            .await
+            .expect("Failed to list networks")
+            .into_iter()
+            .filter_map(|network| network.name)
+            .filter(|name| name.starts_with("sos-sandbox-"))
+            .count()
+    };
+    let before = sandbox_networks().await;
+
+    let runtime = config.connect().await.expect("Failed to connect");
+    let state = Arc::new(SoSState::new(runtime, ServerConfig::default()));
+    let client = SosClient::new(start_test_server_with_state(state).await);
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let id = client
+            .create(&CreatePayload {
+                image: "ubuntu:latest".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("Failed to create sandbox");
+        client.start(&id).await.expect("Failed to start sandbox");
+        ids.push(id);
+    }
+    assert_eq!(sandbox_networks().await, before + 2);
+
+    // Docker writes the address of the container last in its hosts file
+    let hosts = client
+        .exec(&ids[1], "tail -n1 /etc/hosts")
+        .await
+        .expect("Failed to read hosts file");
+    let address = hosts.output.split_whitespace().next().expect("No address");
+    if config.kind == Runtime::Docker {
+        // Refused right away on a shared network, dropped between networks
+        let probe = client
+            .exec(
+                &ids[0],
+                &format!("timeout 3 bash -c '</dev/tcp/{}/9'", address),
+            )
+            .await
+            .expect("Failed to probe");
+        assert_eq!(probe.exit_code, 124, "Reached the other sandbox: {}", probe.output);
+    }
+
+    for id in &ids {
+        client.stop(id, true).await.expect("Failed to stop sandbox");
+    }
+    assert_eq!(sandbox_networks().await, before);
+}
+
+#[tokio::test]
+async fn test_recording_proxy() {
+    use sos::proxy::ProxyConfig;
+
+    // Containers reach the proxy through the host gateway of Docker
+    if runtime_config().kind != Runtime::Docker {
+        return;
+    }
+    let config = ServerConfig {
+        proxy: Some(ProxyConfig {
+            listen: "0.0.0.0:0".parse().unwrap(),
+            // The test server listens on loopback
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let base_url = start_test_server_with_config(config).await;
+    let client = SosClient::new(base_url.clone());
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    // Speaks HTTP to the proxy by hand, the image has no HTTP client
+    let url = format!("{}/templates", base_url);
+    let command = format!(
+        r#"p=${{HTTP_PROXY#http://}}; token=${{p%@*}}; addr=${{p#*@}}; exec 3<>/dev/tcp/${{addr%:*}}/${{addr##*:}}; printf 'GET {url} HTTP/1.1\r\nHost: x\r\nProxy-Authorization: Basic %s\r\nConnection: close\r\n\r\n' "$(printf '%s:' "$token" | base64)" >&3; head -n1 <&3; exec 3<&-"#
+    );
+    let response = client.exec(&id, &command).await.expect("Failed to exec");
+    assert!(response.output.contains("200"), "Unexpected response: {}", response.output);
+
+    let trajectory = client.trajectory(&id).await.expect("Failed to get trajectory");
+    assert_eq!(trajectory.requests.len(), 1);
+    let request = &trajectory.requests[0];
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.url, url);
+    assert_eq!(request.status, Some(200));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_proxy_refuses_private_destinations() {
+    use base64::Engine;
+    use sos::proxy::{Proxy, ProxyConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let proxy = Proxy::start(&ProxyConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        host: "127.0.0.1".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut sandbox = Sandbox::builder()
+        .image("ubuntu:latest")
+        .runtime(connect_runtime().await)
+        .build()
+        .unwrap();
+    let _registration = proxy.register(&mut sandbox);
+    let url = sandbox.env["HTTP_PROXY"].clone();
+    let (token, addr) = url.trim_start_matches("http://").split_once('@').unwrap();
+    let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:", token));
+
+    // The proxy itself, standing in for the server, and the metadata service of clouds
+    for destination in [addr, "169.254.169.254:80", "[::1]:80"] {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "CONNECT {destination} HTTP/1.1\r\nHost: {destination}\r\nProxy-Authorization: Basic {credentials}\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0; 64];
+        let n = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with("HTTP/1.1 403"), "{}: {}", destination, response);
+    }
+
+    let requests = sandbox.view().snapshot().requests;
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|r| r.status.is_none() && r.error.is_some()));
+}
+
+#[tokio::test]
+async fn test_dns_options() {
+    let client = SosClient::new(start_test_server().await);
+
+    let err = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            dns: vec!["resolver.internal".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("INVALID_REQUEST"));
+    let err = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            extra_hosts: vec!["api.internal".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("INVALID_REQUEST"));
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            dns: vec!["10.0.0.53".to_string()],
+            dns_search: vec!["corp.internal".to_string()],
+            extra_hosts: vec!["api.internal:10.0.0.7".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let resolv_conf = client
+        .exec(&id, "cat /etc/resolv.conf")
+        .await
+        .expect("Failed to read resolv.conf");
+    assert!(resolv_conf.output.contains("nameserver 10.0.0.53"), "{}", resolv_conf.output);
+    assert!(resolv_conf.output.contains("corp.internal"), "{}", resolv_conf.output);
+    let hosts = client
+        .exec(&id, "getent hosts api.internal")
+        .await
+        .expect("Failed to resolve host");
+    assert_eq!(hosts.exit_code, 0);
+    assert!(hosts.output.contains("10.0.0.7"), "{}", hosts.output);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_sh_session() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "alpine:latest".to_string(),
+            shell: Some(Shell::Sh),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "echo hi").await.expect("Failed to exec");
+    assert_eq!(result.output, "hi");
+    assert_eq!(result.exit_code, 0);
+
+    let result = client
+        .exec(&id, "ls /nonexistent")
+        .await
+        .expect("Failed to exec");
+    assert_ne!(result.exit_code, 0);
+    assert!(result.stderr.contains("nonexistent"), "{}", result.stderr);
+    assert!(result.stdout.is_empty(), "{}", result.stdout);
+
+    client.exec(&id, "cd /tmp").await.expect("Failed to exec");
+    let result = client.exec(&id, "pwd").await.expect("Failed to exec");
+    assert_eq!(result.output, "/tmp");
+
+    let result = client.exec(&id, "exit 3").await.expect("Failed to exec");
+    assert!(result.exited);
+    assert_eq!(result.exit_code, 3);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_sh_fallback() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "busybox:latest".to_string(),
+            setup_commands: vec!["echo ready > /tmp/setup".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "cat /tmp/setup").await.expect("Failed to exec");
+    assert_eq!(result.output, "ready");
+    assert_eq!(result.exit_code, 0);
+    let result = client.exec(&id, "false").await.expect("Failed to exec");
+    assert_eq!(result.exit_code, 1);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_jupyter_kernel() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "python:3.12-slim".to_string(),
+            setup_commands: vec!["pip install --quiet ipykernel".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let reply = client
+        .exec_kernel(&id, "x = 40\nprint('hello')\nx + 2")
+        .await
+        .expect("Failed to execute code");
+    assert_eq!(reply.status, "ok");
+    assert!(matches!(
+        &reply.outputs[0],
+        KernelOutput::Stream { name, text } if name == "stdout" && text == "hello\n"
+    ));
+    match &reply.outputs[1] {
+        KernelOutput::ExecuteResult { data } => assert_eq!(data["text/plain"], "42"),
+        output => panic!("Unexpected output: {:?}", output),
+    }
+
+    // State is kept between executions
+    let reply = client.exec_kernel(&id, "x").await.expect("Failed to execute code");
+    assert!(matches!(&reply.outputs[0], KernelOutput::ExecuteResult { data } if data["text/plain"] == "40"));
+
+    let reply = client
+        .exec_kernel(&id, "1 / 0")
+        .await
+        .expect("Failed to execute code");
+    assert_eq!(reply.status, "error");
+    assert!(matches!(&reply.outputs[0], KernelOutput::Error { ename, .. } if ename == "ZeroDivisionError"));
+
+    let trajectory = client.trajectory(&id).await.expect("Failed to get trajectory");
+    assert_eq!(trajectory.trajectory.len(), 3);
+
+    let err = client
+        .exec_kernel(&id, &"x".repeat(64 * 1024 + 1))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("COMMAND_TOO_LARGE"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_terminal_size() {
+    let client = SosClient::new(start_test_server().await);
+
+    let err = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            cols: Some(0),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("INVALID_REQUEST"));
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            cols: Some(200),
+            rows: Some(50),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let err = client.resize(&id, 120, 40).await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_STARTED"));
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "stty size").await.expect("Failed to exec");
+    assert_eq!(result.output, "50 200");
+
+    client.resize(&id, 120, 40).await.expect("Failed to resize");
+    let result = client.exec(&id, "stty size").await.expect("Failed to exec");
+    assert_eq!(result.output, "40 120");
+    let result = client.exec(&id, "tput cols").await.expect("Failed to exec");
+    assert_eq!(result.output, "120");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_raw_output() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let command = r"printf '\033[31mred\033[0m\n'";
+    let result = client.exec(&id, command).await.expect("Failed to exec");
+    assert_eq!(result.output, "red");
+    assert!(result.raw_output.is_none());
+
+    let payload = ExecPayload {
+        command: command.to_string(),
+        standalone: None,
+        raw: Some(true),
+        timeout_secs: None,
+    };
+    let result = client.exec_with(&id, &payload).await.expect("Failed to exec");
+    assert_eq!(result.output, "red");
+    let raw = result.raw_output.expect("Raw output not returned");
+    assert!(raw.contains("\x1b[31mred\x1b[0m"), "{:?}", raw);
+    assert!(!raw.contains("#PS1-"), "{:?}", raw);
+
+    let payload = ExecPayload {
+        standalone: Some(true),
+        ..payload
+    };
+    let result = client.exec_with(&id, &payload).await.expect("Failed to exec");
+    assert!(result.raw_output.expect("Raw output not returned").contains("\x1b[31m"));
+
+    let trajectory = client.trajectory(&id).await.expect("Failed to get trajectory");
+    let entry = trajectory.trajectory[1].result.as_ref().expect("No result");
+    assert!(!entry.output.contains('\x1b'), "{:?}", entry.output);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_exec_timeout() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    // Longer than the default wait of the session, within the timeout
+    let payload = ExecPayload {
+        command: "sleep 3; echo done".to_string(),
+        timeout_secs: Some(10),
+        ..Default::default()
+    };
+    let result = client.exec_with(&id, &payload).await.expect("Failed to exec");
+    assert_eq!(result.output, "done");
+
+    let payload = ExecPayload {
+        command: "sleep 30".to_string(),
+        timeout_secs: Some(1),
+        ..Default::default()
+    };
+    let error = client.exec_with(&id, &payload).await.unwrap_err();
+    assert_eq!(error.code(), Some("COMMAND_TIMEOUT"));
+    // The session is back at its prompt
+    let result = client.exec(&id, "echo alive").await.expect("Failed to exec");
+    assert_eq!(result.output, "alive");
+    // The interrupted command is recorded
+    let trajectory = client.trajectory(&id).await.unwrap();
+    let timed_out = &trajectory.trajectory[1];
+    assert_eq!(timed_out.command, "sleep 30");
+    assert_eq!(timed_out.result.as_ref().unwrap().exit_code, 124);
+
+    let payload = ExecPayload {
+        standalone: Some(true),
+        ..payload
+    };
+    let error = client.exec_with(&id, &payload).await.unwrap_err();
+    assert_eq!(error.code(), Some("COMMAND_TIMEOUT"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_exit_status_defaults_to_last_command() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "false; exit").await.expect("Failed to exec");
+    assert!(result.exited);
+    assert_eq!(result.exit_code, 1);
+    let err = client.exec(&id, "echo hi").await.unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_EXITED"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_reopen_session() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let err = client.reopen_session(&id).await.unwrap_err();
+    assert_eq!(err.code(), Some("SESSION_NOT_EXITED"));
+
+    client
+        .exec(&id, "echo kept > /tmp/file")
+        .await
+        .expect("Failed to exec");
+    let result = client.exec(&id, "exit 2").await.expect("Failed to exec");
+    assert!(result.exited);
+
+    client
+        .reopen_session(&id)
+        .await
+        .expect("Failed to reopen session");
+    let result = client.exec(&id, "cat /tmp/file").await.expect("Failed to exec");
+    assert_eq!(result.output, "kept");
+    assert!(!result.exited);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_command_usage() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    client.exec(&id, "true").await.expect("Failed to exec");
+    client
+        .exec(&id, "timeout 1 sh -c 'while :; do :; done'; true")
+        .await
+        .expect("Failed to exec");
+
+    let trajectory = client.trajectory(&id).await.unwrap();
+    let idle = trajectory.trajectory[0].usage.expect("No usage recorded");
+    let busy = trajectory.trajectory[1].usage.expect("No usage recorded");
+    assert!(busy.cpu_seconds > 0.5, "{:?}", busy);
+    assert!(busy.cpu_seconds > idle.cpu_seconds);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_shell_init() {
+    let config = ServerConfig {
+        shell_init: vec!["export GREETING=hello".to_string()],
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            shell_init: vec!["greet() { echo \"$GREETING, $1\"; }".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "greet world").await.expect("Failed to exec");
+    assert_eq!(result.output, "hello, world");
+    assert_eq!(client.trajectory(&id).await.unwrap().trajectory.len(), 1);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_forged_markers_are_output() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    // Markers of another sandbox are plain output
+    let forged = "#EXIT-TR0N-F1GHTS-4-TH3-U23R2#:3:";
+    let result = client
+        .exec(&id, &format!("echo '{}'", forged))
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, forged);
+    assert_eq!(result.exit_code, 0);
+    assert!(!result.exited);
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_tool_bundles() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("sos-tools-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    let script = dir.join("greet");
+    std::fs::write(&script, "#!/bin/sh\ncat \"$(dirname \"$0\")/lib/greeting\"\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(dir.join("lib/greeting"), "hello from a tool").unwrap();
+
+    let config = ServerConfig {
+        tool_bundles: vec![ToolBundleConfig {
+            name: "helpers".to_string(),
+            path: Some(dir.clone()),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let err = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            tools: vec!["missing".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("TOOL_BUNDLE_NOT_FOUND"));
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            tools: vec!["helpers".to_string()],
+            setup_commands: vec!["greet > /tmp/setup".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "greet").await.expect("Failed to exec");
+    assert_eq!(result.output, "hello from a tool");
+    let result = client.exec(&id, "cat /tmp/setup").await.expect("Failed to exec");
+    assert_eq!(result.output, "hello from a tool");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_lifecycle_hooks() {
+    let config = ServerConfig {
+        hooks: HooksConfig {
+            on_start: vec!["echo $SOS_SANDBOX_ID > /tmp/started".to_string()],
+            before_exec: vec!["case \"$SOS_COMMAND\" in *forbidden*) exit 1;; esac".to_string()],
+            after_exec: vec!["echo \"$SOS_COMMAND:$SOS_EXIT_CODE\" >> /tmp/execs".to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client.exec(&id, "cat /tmp/started").await.expect("Failed to exec");
+    assert_eq!(result.output, id);
+
+    let err = client.exec(&id, "echo forbidden").await.unwrap_err();
+    assert_eq!(err.code(), Some("HOOK_FAILED"));
+
+    client.exec(&id, "false").await.expect("Failed to exec");
+    let result = client.exec(&id, "cat /tmp/execs").await.expect("Failed to exec");
+    assert_eq!(result.output, "cat /tmp/started:0\nfalse:1");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_copy_files() {
+    let client = SosClient::new(start_test_server().await);
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let id = client
+            .create(&CreatePayload {
+                image: "ubuntu:latest".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("Failed to create sandbox");
+        client.start(&id).await.expect("Failed to start sandbox");
+        ids.push(id);
+    }
+    let (producer, consumer) = (&ids[0], &ids[1]);
+
+    client
+        .exec(producer, "mkdir -p /tmp/out && echo artifact > /tmp/out/result.txt")
+        .await
+        .expect("Failed to exec");
+
+    client
+        .copy(
+            producer,
+            &CopyPayload {
+                path: "/tmp/out".to_string(),
+                destination: consumer.clone(),
+                destination_dir: Some("/tmp/inputs".to_string()),
+            },
+        )
+        .await
+        .expect("Failed to copy");
+    let result = client
+        .exec(consumer, "cat /tmp/inputs/out/result.txt")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "artifact");
+
+    // Without a directory, the copy lands at the same path
+    client
+        .copy(
+            producer,
+            &CopyPayload {
+                path: "/tmp/out/result.txt".to_string(),
+                destination: consumer.clone(),
+                destination_dir: None,
+            },
+        )
+        .await
+        .expect("Failed to copy");
+    let result = client
+        .exec(consumer, "cat /tmp/out/result.txt")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "artifact");
+
+    let err = client
+        .copy(
+            producer,
+            &CopyPayload {
+                path: "/tmp/out".to_string(),
+                destination: "missing".to_string(),
+                destination_dir: None,
+            },
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("SANDBOX_NOT_FOUND"));
+
+    for id in &ids {
+        client.stop(id, true).await.expect("Failed to stop sandbox");
+    }
+}
+
+#[tokio::test]
+async fn test_clone_repo() {
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "python:3.12".to_string(),
+            repo: Some(RepoSpec {
+                url: "https://github.com/octocat/Hello-World.git".to_string(),
+                reference: Some("master".to_string()),
+                depth: Some(1),
+                auth: Some(RepoAuth {
+                    username: None,
+                    token: "not-a-real-token".to_string(),
+                }),
+                dir: None,
+            }),
+            setup_commands: vec!["test -f /workspace/README".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let result = client
+        .exec(&id, "cd /workspace && git log --oneline | wc -l")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "1");
+    let result = client
+        .exec(&id, "grep -r not-a-real-token /workspace/.git; echo $?")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "1");
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let id = client
+        .create(&CreatePayload {
+            image: "python:3.12".to_string(),
+            repo: Some(RepoSpec {
+                url: "https://github.com/octocat/Hello-World.git".to_string(),
+                reference: Some("no-such-branch".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    let err = client.start(&id).await.unwrap_err();
+    assert_eq!(err.code(), Some("REPO_CLONE_FAILED"));
+    let sandboxes = client.list().await.expect("Failed to list sandboxes");
+    assert!(sandboxes.iter().all(|sandbox| sandbox.id != id));
+}
+
+#[tokio::test]
+async fn test_workspace_patch() {
+    let client = SosClient::new(start_test_server().await);
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let id = client
+            .create(&CreatePayload {
+                image: "python:3.12".to_string(),
+                setup_commands: vec![
+                    "mkdir -p /workspace && cd /workspace && git init -q".to_string(),
+                    "cd /workspace && echo one > tracked.txt".to_string(),
+                    "cd /workspace && git add . && git -c user.name=t -c user.email=t@t commit -qm init"
+                        .to_string(),
+                ],
+                ..Default::default()
+            })
+            .await
+            .expect("Failed to create sandbox");
+        client.start(&id).await.expect("Failed to start sandbox");
+        ids.push(id);
+    }
+    let (agent, replay) = (&ids[0], &ids[1]);
+
+    let patch = client.patch(agent, None).await.expect("Failed to get patch");
+    assert_eq!(patch.patch, "");
+    assert!(patch.files.is_empty());
+
+    client
+        .exec(
+            agent,
+            "cd /workspace && echo two >> tracked.txt && echo new > untracked.txt",
+        )
+        .await
+        .expect("Failed to exec");
+    let patch = client.patch(agent, None).await.expect("Failed to get patch");
+    assert_eq!(patch.files, vec!["tracked.txt", "untracked.txt"]);
+    assert!(patch.patch.contains("+two"));
+    // The staging area of the agent is left alone
+    let result = client
+        .exec(agent, "cd /workspace && git status --porcelain")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, " M tracked.txt\n?? untracked.txt");
+
+    client
+        .apply_patch(replay, &patch.patch, Some("/workspace"))
+        .await
+        .expect("Failed to apply patch");
+    let result = client
+        .exec(replay, "cat /workspace/tracked.txt /workspace/untracked.txt")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, "one\ntwo\nnew");
+
+    // Applied twice, the patch no longer applies
+    let err = client
+        .apply_patch(replay, &patch.patch, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("PATCH_FAILED"));
+
+    for id in &ids {
+        client.stop(id, true).await.expect("Failed to stop sandbox");
+    }
+}
+
+#[tokio::test]
+async fn test_download_and_upload_files() {
+    let client = SosClient::new(start_test_server().await);
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let id = client
+            .create(&CreatePayload {
+                image: "ubuntu:latest".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("Failed to create sandbox");
+        client.start(&id).await.expect("Failed to start sandbox");
+        ids.push(id);
+    }
+
+    client
+        .exec(
+            &ids[0],
+            "mkdir -p /tmp/out && head -c 1048576 /dev/urandom > /tmp/out/blob",
+        )
+        .await
+        .expect("Failed to exec");
+    let checksum = client
+        .exec(&ids[0], "sha256sum /tmp/out/blob | cut -c1-64")
+        .await
+        .expect("Failed to exec")
+        .output;
+
+    let mut reported = 0;
+    let archive = client
+        .download(&ids[0], "/tmp/out", |done, _| reported = done)
+        .await
+        .expect("Failed to download");
+    assert_eq!(reported, archive.len() as u64);
+    assert!(archive.len() > 1048576);
+
+    client
+        .upload(&ids[1], "/tmp/inputs", archive, |_, _| {})
+        .await
+        .expect("Failed to upload");
+    let result = client
+        .exec(&ids[1], "sha256sum /tmp/inputs/out/blob | cut -c1-64")
+        .await
+        .expect("Failed to exec");
+    assert_eq!(result.output, checksum);
+
+    let err = client
+        .download(&ids[0], "/tmp/missing", |_, _| {})
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some("CONTAINER_IO_FAILED"));
+
+    for id in &ids {
+        client.stop(id, true).await.expect("Failed to stop sandbox");
+    }
+}
+
+#[tokio::test]
+async fn test_sandbox_logs() {
+    use futures::StreamExt;
+
+    let client = SosClient::new(start_test_server().await);
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    client
+        .exec(&id, "echo session-line")
+        .await
+        .expect("Failed to exec");
+    let mut logs = Box::pin(
+        client
+            .logs(&id, LogSource::Session, false)
+            .await
+            .expect("Failed to get logs"),
+    );
+    let mut text = Vec::new();
+    while let Some(chunk) = logs.next().await {
+        text.extend_from_slice(&chunk.expect("Failed to read logs"));
+    }
+    assert!(String::from_utf8_lossy(&text).contains("session-line"));
+
+    // Output of background processes shows up between commands
+    let mut logs = Box::pin(
+        client
+            .logs(&id, LogSource::All, true)
+            .await
+            .expect("Failed to get logs"),
+    );
+    client
+        .exec(&id, "(sleep 1; echo from-$((1 + 1))-background) &")
+        .await
+        .expect("Failed to exec");
+    let found = tokio::time::timeout(Duration::from_secs(10), async {
+        let mut text = Vec::new();
+        while let Some(chunk) = logs.next().await {
+            text.extend_from_slice(&chunk.expect("Failed to read logs"));
+            if String::from_utf8_lossy(&text).contains("from-2-background") {
+                return true;
+            }
+        }
+        false
+    })
+    .await;
+    assert!(matches!(found, Ok(true)));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
+
+#[tokio::test]
+async fn test_attach() {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    // Attaching escapes the guardrails, the default policy refuses it
+    let client = SosClient::new(start_test_server().await);
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    let error = client.attach(&id, None).await.unwrap_err();
+    assert_eq!(error.code(), Some("POLICY_VIOLATION"));
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let config = ServerConfig {
+        policy: sos::policy::PolicyConfig {
+            allow_attach: Some(true),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = SosClient::new(start_test_server_with_config(config).await);
+
+    // What is typed cannot be checked against blocked commands
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            blocked_commands: vec!["rm -rf".to_string()],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+    let error = client.attach(&id, None).await.unwrap_err();
+    assert_eq!(error.code(), Some("COMMAND_BLOCKED"));
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+
+    let id = client
+        .create(&CreatePayload {
+            image: "ubuntu:latest".to_string(),
+            max_commands: Some(2),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&id).await.expect("Failed to start sandbox");
+
+    let mut socket = client
+        .attach(&id, Some(TerminalSize { cols: 100, rows: 30 }))
+        .await
+        .expect("Failed to attach");
+    socket
+        .send(Message::Binary("stty size; echo $((40 + 2))\n".into()))
+        .await
+        .expect("Failed to send input");
+
+    let output = tokio::time::timeout(Duration::from_secs(10), async {
+        let mut output = String::new();
+        while let Some(Ok(message)) = socket.next().await {
+            if let Message::Binary(bytes) = message {
+                output.push_str(&String::from_utf8_lossy(&bytes));
+                if output.contains("\n42") {
+                    break;
+                }
+            }
+        }
+        output
+    })
+    .await
+    .expect("Timed out waiting for the shell");
+    assert!(output.contains("30 100"));
+
+    // The session is left alone
+    let result = client.exec(&id, "echo session").await.expect("Failed to exec");
+    assert_eq!(result.output, "session");
+
+    socket
+        .send(Message::Binary("exit\n".into()))
+        .await
+        .expect("Failed to send input");
+    let closed = tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(Ok(message)) = socket.next().await {
+            if let Message::Close(_) = message {
+                break;
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok());
+
+    // The shell is recorded once detached, and spends the rest of the budget
+    let recorded = tokio::time::timeout(Duration::from_secs(10), async {
+        while client.trajectory(&id).await.unwrap().trajectory.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    assert!(recorded.is_ok());
+    let trajectory = client.trajectory(&id).await.unwrap();
+    assert!(trajectory.trajectory[1].result.is_none());
+    let error = client.attach(&id, None).await.unwrap_err();
+    assert_eq!(error.code(), Some("BUDGET_EXHAUSTED"));
+
+    client.stop(&id, true).await.expect("Failed to stop sandbox");
+}
@@ -0,0 +1,345 @@
+//! Scenario regression suite.
+//!
+//! Every `tests/scenarios/*.yaml` file describes a sandbox and a script of commands with the
+//! expected output and exit code of each step. After the script runs, the recorded trajectory is
+//! compared against `tests/scenarios/golden/<name>.json` using the scenario's fuzzy matching rules.
+//!
+//! Set `SOS_SCENARIO_SERVER` to run against an already running server instead of an in-process one,
+//! and `SOS_UPDATE_GOLDEN=1` to (re)record the golden trajectories.
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sos::api::{CreatePayload, ExecPayload};
+use sos::client::SosClient;
+use sos::config::ServerConfig;
+use sos::http::{SoSState, create_app};
+use sos::runtime::{ContainerRuntime, Runtime, RuntimeConfig};
+use tokio::time::{Duration, sleep};
+
+#[derive(Deserialize)]
+struct Scenario {
+    name: String,
+    #[serde(default = "default_image")]
+    image: String,
+    #[serde(default)]
+    setup_commands: Vec<String>,
+    #[serde(default)]
+    fuzzy: FuzzyRules,
+    steps: Vec<Step>,
+}
+
+fn default_image() -> String {
+    "ubuntu:latest".to_string()
+}
+
+/// Rules applied to outputs before they are compared against the golden trajectory.
+#[derive(Deserialize, Default)]
+struct FuzzyRules {
+    /// Regexes whose matches are replaced by `<ignored>` (timestamps, PIDs, ...)
+    #[serde(default)]
+    ignore: Vec<String>,
+    /// Collapse runs of whitespace into a single space
+    #[serde(default)]
+    collapse_whitespace: bool,
+}
+
+#[derive(Deserialize)]
+struct Step {
+    command: String,
+    #[serde(default)]
+    standalone: bool,
+    /// Files copied within the sandbox through the file API before the command runs
+    #[serde(default)]
+    upload: Option<Upload>,
+    #[serde(default)]
+    expect: Expect,
+}
+
+/// Downloads the directory `from` of the sandbox and uploads it into `to`.
+#[derive(Deserialize)]
+struct Upload {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Expect {
+    output: Option<OutputMatch>,
+    exit_code: Option<i64>,
+    exited: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutputMatch {
+    Exact(String),
+    Rule(MatchRule),
+}
+
+#[derive(Deserialize)]
+struct MatchRule {
+    contains: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct GoldenStep {
+    command: String,
+    output: String,
+    exit_code: i64,
+}
+
+impl OutputMatch {
+    fn check(&self, output: &str) -> Result<(), String> {
+        match self {
+            OutputMatch::Exact(expected) if expected == output => Ok(()),
+            OutputMatch::Exact(expected) => Err(format!("expected {:?}, got {:?}", expected, output)),
+            OutputMatch::Rule(rule) => {
+                if let Some(needle) = &rule.contains {
+                    if !output.contains(needle.as_str()) {
+                        return Err(format!("{:?} does not contain {:?}", output, needle));
+                    }
+                }
+                if let Some(prefix) = &rule.prefix {
+                    if !output.starts_with(prefix.as_str()) {
+                        return Err(format!("{:?} does not start with {:?}", output, prefix));
+                    }
+                }
+                if let Some(pattern) = &rule.regex {
+                    let re = Regex::new(pattern).map_err(|e| e.to_string())?;
+                    if !re.is_match(output) {
+                        return Err(format!("{:?} does not match /{}/", output, pattern));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FuzzyRules {
+    fn normalize(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for pattern in &self.ignore {
+            let re = Regex::new(pattern).expect("Invalid ignore regex in scenario");
+            text = re.replace_all(&text, "<ignored>").to_string();
+        }
+        if self.collapse_whitespace {
+            text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        text
+    }
+}
+
+// Helpers
+/// Runtime the tests run on: Docker, or the one named by `SOS_RUNTIME` (`podman`,
+/// `containerd` or `local`). `SOS_RUNTIME_SOCKET` overrides the socket of the runtime and
+/// `SOS_OCI_RUNTIME` runs the containers with another OCI runtime, such as `kata`.
+/// `SOS_BUBBLEWRAP=1` runs local sandboxes under bubblewrap.
+fn runtime_config() -> RuntimeConfig {
+    RuntimeConfig {
+        kind: std::env::var("SOS_RUNTIME")
+            .map_or(Runtime::Docker, |runtime| runtime.parse().expect("Invalid SOS_RUNTIME")),
+        socket: std::env::var_os("SOS_RUNTIME_SOCKET").map(PathBuf::from),
+        oci_runtime: std::env::var("SOS_OCI_RUNTIME").ok(),
+        bubblewrap: std::env::var("SOS_BUBBLEWRAP").is_ok_and(|v| v == "1"),
+        ..Default::default()
+    }
+}
+
+async fn connect_runtime() -> Arc<dyn ContainerRuntime> {
+    runtime_config()
+        .connect()
+        .await
+        .expect("Failed to connect to the container runtime")
+}
+
+async fn server_url() -> String {
+    if let Ok(url) = std::env::var("SOS_SCENARIO_SERVER") {
+        return url;
+    }
+
+    let state = Arc::new(SoSState::new(
+        connect_runtime().await,
+        ServerConfig::default(),
+    ));
+    let app = create_app(state);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service())
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    format!("http://127.0.0.1:{}", addr.port())
+}
+
+fn scenario_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("scenarios")
+}
+
+fn load_scenarios() -> Vec<Scenario> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(scenario_dir())
+        .expect("Failed to read scenario directory")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml"))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let text = std::fs::read_to_string(path).expect("Failed to read scenario");
+            serde_yaml::from_str(&text)
+                .unwrap_or_else(|e| panic!("Invalid scenario {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+/// Runs a scenario and returns the list of failures (empty on success).
+async fn run_scenario(client: &SosClient, scenario: &Scenario) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    let sandbox_id = client
+        .create(&CreatePayload {
+            image: scenario.image.clone(),
+            setup_commands: scenario.setup_commands.clone(),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create sandbox");
+    client.start(&sandbox_id).await.expect("Failed to start sandbox");
+
+    for (i, step) in scenario.steps.iter().enumerate() {
+        if let Some(upload) = &step.upload {
+            let copied = match client.download(&sandbox_id, &upload.from, |_, _| {}).await {
+                Ok(archive) => client.upload(&sandbox_id, &upload.to, archive, |_, _| {}).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = copied {
+                failures.push(format!("step {} ({:?}): upload failed: {}", i, step.command, e));
+                continue;
+            }
+        }
+        let payload = ExecPayload {
+            command: step.command.clone(),
+            standalone: Some(step.standalone),
+            raw: None,
+            timeout_secs: None,
+        };
+        let result = match client.exec_with(&sandbox_id, &payload).await {
+            Ok(result) => result,
+            Err(e) => {
+                failures.push(format!("step {} ({:?}): {}", i, step.command, e));
+                continue;
+            }
+        };
+
+        if let Some(expected) = &step.expect.output {
+            if let Err(e) = expected.check(&result.output) {
+                failures.push(format!("step {} ({:?}): output {}", i, step.command, e));
+            }
+        }
+        if let Some(code) = step.expect.exit_code {
+            if result.exit_code != code {
+                failures.push(format!(
+                    "step {} ({:?}): expected exit code {}, got {}",
+                    i, step.command, code, result.exit_code
+                ));
+            }
+        }
+        if let Some(exited) = step.expect.exited {
+            if result.exited != exited {
+                failures.push(format!(
+                    "step {} ({:?}): expected exited={}, got {}",
+                    i, step.command, exited, result.exited
+                ));
+            }
+        }
+    }
+
+    let trajectory = client
+        .trajectory(&sandbox_id)
+        .await
+        .expect("Failed to fetch trajectory");
+    let recorded: Vec<GoldenStep> = trajectory
+        .trajectory
+        .into_iter()
+        .map(|entry| GoldenStep {
+            command: entry.command,
+            output: entry.result.as_ref().map(|r| r.output.clone()).unwrap_or_default(),
+            exit_code: entry.result.map(|r| r.exit_code).unwrap_or(-1),
+        })
+        .collect();
+    failures.extend(compare_golden(scenario, &recorded));
+
+    client
+        .stop(&sandbox_id, true)
+        .await
+        .expect("Failed to cleanup sandbox");
+
+    failures
+}
+
+fn compare_golden(scenario: &Scenario, recorded: &[GoldenStep]) -> Vec<String> {
+    let path = scenario_dir()
+        .join("golden")
+        .join(format!("{}.json", scenario.name));
+
+    if std::env::var("SOS_UPDATE_GOLDEN").is_ok_and(|v| v == "1") {
+        let golden = serde_json::to_string_pretty(recorded).unwrap();
+        std::fs::write(&path, golden + "\n").expect("Failed to write golden trajectory");
+        return Vec::new();
+    }
+
+    let golden: Vec<GoldenStep> = match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).expect("Invalid golden trajectory"),
+        Err(_) => {
+            return vec![format!(
+                "missing golden trajectory {} (run with SOS_UPDATE_GOLDEN=1)",
+                path.display()
+            )];
+        }
+    };
+
+    if golden.len() != recorded.len() {
+        return vec![format!(
+            "golden trajectory has {} steps, recorded {}",
+            golden.len(),
+            recorded.len()
+        )];
+    }
+
+    let rules = &scenario.fuzzy;
+    golden
+        .iter()
+        .zip(recorded)
+        .enumerate()
+        .filter_map(|(i, (expected, actual))| {
+            let same = expected.command == actual.command
+                && expected.exit_code == actual.exit_code
+                && rules.normalize(&expected.output) == rules.normalize(&actual.output);
+            (!same).then(|| format!("golden step {}: expected {:?}, got {:?}", i, expected, actual))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_scenarios() {
+    let client = SosClient::new(server_url().await);
+
+    let mut failures = Vec::new();
+    for scenario in load_scenarios() {
+        for failure in run_scenario(&client, &scenario).await {
+            failures.push(format!("[{}] {}", scenario.name, failure));
+        }
+    }
+
+    assert!(failures.is_empty(), "Scenario failures:\n{}", failures.join("\n"));
+}